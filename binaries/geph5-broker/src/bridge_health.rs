@@ -0,0 +1,77 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use async_io::Timer;
+use futures_util::{stream, StreamExt as _};
+use geph5_broker_protocol::BridgeHealth;
+use sillad::{dialer::Dialer, tcp::TcpDialer};
+use smol_timeout2::TimeoutExt;
+
+use crate::database::query_all_bridges;
+
+/// The latest probe result for every bridge, keyed by its control address.
+static BRIDGE_HEALTH: LazyLock<Mutex<HashMap<SocketAddr, BridgeHealth>>> =
+    LazyLock::new(Default::default);
+
+/// How often every bridge gets probed.
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a bridge has to accept a connection before we consider it unreachable.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many bridges get probed at the same time, so that a large fleet does not exhaust our sockets.
+const PROBE_CONCURRENCY: usize = 64;
+
+/// Gets the latest probe result for a bridge, if it has been probed yet.
+pub fn bridge_health(control_listen: SocketAddr) -> Option<BridgeHealth> {
+    BRIDGE_HEALTH.lock().unwrap().get(&control_listen).copied()
+}
+
+/// This loop actively probes every bridge, by connecting to its control port, so that clients can ask which bridges are healthy.
+#[tracing::instrument]
+pub async fn bridge_health_loop() -> anyhow::Result<()> {
+    tracing::info!("starting the bridge health loop");
+    loop {
+        let bridges = query_all_bridges().await?;
+        let results: Vec<_> = stream::iter(bridges.iter().map(|bridge| async move {
+            let start = Instant::now();
+            let reachable = matches!(
+                TcpDialer {
+                    dest_addr: bridge.control_listen
+                }
+                .dial()
+                .timeout(PROBE_TIMEOUT)
+                .await,
+                Some(Ok(_))
+            );
+            let latency = start.elapsed();
+            let last_checked = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            (
+                bridge.control_listen,
+                BridgeHealth {
+                    reachable,
+                    latency_ms: reachable.then_some(latency.as_millis() as u32),
+                    last_checked,
+                },
+            )
+        }))
+        .buffer_unordered(PROBE_CONCURRENCY)
+        .collect()
+        .await;
+        let reachable = results
+            .iter()
+            .filter(|(_, health)| health.reachable)
+            .count();
+        tracing::debug!(total = results.len(), reachable, "probed bridges");
+        // replacing the whole map also forgets bridges that have gone away
+        *BRIDGE_HEALTH.lock().unwrap() = results.into_iter().collect();
+        Timer::after(PROBE_INTERVAL).await;
+    }
+}
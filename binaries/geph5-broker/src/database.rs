@@ -64,19 +64,27 @@ pub struct ExitRow {
     pub city: String,
     pub load: f32,
     pub expiry: i64,
+    /// Needs `ALTER TABLE exits_new ADD COLUMN probe_magic BOOLEAN NOT NULL DEFAULT FALSE` on databases that predate it.
+    #[sqlx(default)]
+    pub probe_magic: bool,
+    /// Needs `ALTER TABLE exits_new ADD COLUMN pmtud_echo BOOLEAN NOT NULL DEFAULT FALSE` on databases that predate it.
+    #[sqlx(default)]
+    pub pmtud_echo: bool,
 }
 
 pub async fn insert_exit(exit: &ExitRow) -> anyhow::Result<()> {
     sqlx::query(
-        r"INSERT INTO exits_new (pubkey, c2e_listen, b2e_listen, country, city, load, expiry)
-        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        r"INSERT INTO exits_new (pubkey, c2e_listen, b2e_listen, country, city, load, expiry, probe_magic, pmtud_echo)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
         ON CONFLICT (pubkey) DO UPDATE 
         SET c2e_listen = EXCLUDED.c2e_listen, 
             b2e_listen = EXCLUDED.b2e_listen, 
             country = EXCLUDED.country, 
             city = EXCLUDED.city, 
             load = EXCLUDED.load, 
-            expiry = EXCLUDED.expiry
+            expiry = EXCLUDED.expiry,
+            probe_magic = EXCLUDED.probe_magic,
+            pmtud_echo = EXCLUDED.pmtud_echo
         ",
     )
     .bind(exit.pubkey)
@@ -86,11 +94,93 @@ pub async fn insert_exit(exit: &ExitRow) -> anyhow::Result<()> {
     .bind(&exit.city)
     .bind(exit.load)
     .bind(exit.expiry)
+    .bind(exit.probe_magic)
+    .bind(exit.pmtud_echo)
     .execute(POSTGRES.deref())
     .await?;
     Ok(())
 }
 
+/// The public key and client-facing address of every registered exit.
+pub async fn query_exit_addrs() -> anyhow::Result<Vec<([u8; 32], String)>> {
+    Ok(sqlx::query_as("select pubkey, c2e_listen from exits_new")
+        .fetch_all(POSTGRES.deref())
+        .await?)
+}
+
+/// Removes an exit before its descriptor expires. It comes back the next time it registers.
+pub async fn delete_exit(pubkey: [u8; 32]) -> anyhow::Result<()> {
+    sqlx::query("delete from exits_new where pubkey = $1")
+        .bind(pubkey)
+        .execute(POSTGRES.deref())
+        .await?;
+    Ok(())
+}
+
+/// Records that an exit retired the given key, whose descriptors stop being accepted at the given time, in seconds since the Unix epoch. Later notices for the same key do not push that time back.
+///
+/// Needs `CREATE TABLE exit_key_retirements (pubkey BYTEA PRIMARY KEY, retire_at BIGINT NOT NULL)` on databases that predate it.
+pub async fn retire_exit_key(pubkey: [u8; 32], retire_at: i64) -> anyhow::Result<()> {
+    sqlx::query(
+        "insert into exit_key_retirements (pubkey, retire_at) values ($1, $2) on conflict (pubkey) do nothing",
+    )
+    .bind(pubkey)
+    .bind(retire_at)
+    .execute(POSTGRES.deref())
+    .await?;
+    Ok(())
+}
+
+/// Whether the given exit key has been retired, and is past the time it was still accepted until.
+pub async fn exit_key_retired(pubkey: [u8; 32], now: i64) -> anyhow::Result<bool> {
+    let retire_at: Option<(i64,)> =
+        sqlx::query_as("select retire_at from exit_key_retirements where pubkey = $1")
+            .bind(pubkey)
+            .fetch_optional(POSTGRES.deref())
+            .await?;
+    Ok(retire_at.is_some_and(|(retire_at,)| retire_at <= now))
+}
+
+/// Every bridge we know of, regardless of which pool it is in.
+pub async fn query_all_bridges() -> anyhow::Result<Vec<BridgeDescriptor>> {
+    let raw: Vec<(String, String, String, i64)> =
+        sqlx::query_as("select listen, cookie, pool, expiry from bridges_new")
+            .fetch_all(POSTGRES.deref())
+            .await?;
+    Ok(raw
+        .into_iter()
+        .map(|row| BridgeDescriptor {
+            control_listen: row.0.parse().unwrap(),
+            control_cookie: row.1,
+            pool: row.2,
+            expiry: row.3 as _,
+        })
+        .collect())
+}
+
+/// The hashes of all connect tokens that have been revoked, for example because their accounts were banned.
+pub async fn query_revoked_tokens() -> anyhow::Result<Vec<[u8; 32]>> {
+    static CACHE: LazyLock<Cache<(), Vec<[u8; 32]>>> = LazyLock::new(|| {
+        Cache::builder()
+            .time_to_live(Duration::from_secs(60))
+            .build()
+    });
+
+    CACHE
+        .try_get_with((), async {
+            let raw: Vec<(Vec<u8>,)> = sqlx::query_as("select token_hash from revoked_tokens")
+                .fetch_all(POSTGRES.deref())
+                .await?;
+            anyhow::Ok(
+                raw.into_iter()
+                    .filter_map(|(hash,)| hash.try_into().ok())
+                    .collect(),
+            )
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!(e))
+}
+
 pub async fn query_bridges(key: &str) -> anyhow::Result<Vec<BridgeDescriptor>> {
     static CACHE: LazyLock<Cache<String, Vec<BridgeDescriptor>>> = LazyLock::new(|| {
         Cache::builder()
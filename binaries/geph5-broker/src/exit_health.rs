@@ -0,0 +1,71 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use futures_util::{stream, StreamExt as _};
+use sillad::{dialer::Dialer, tcp::TcpDialer};
+use smol_timeout2::TimeoutExt;
+
+use crate::{
+    database::{delete_exit, query_exit_addrs},
+    CONFIG_FILE,
+};
+
+/// How long an exit has to accept a connection before the check counts as failed.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many exits get probed at the same time, so that a large fleet does not exhaust our sockets.
+const PROBE_CONCURRENCY: usize = 64;
+
+/// How long the failure count of an exit that no longer shows up is remembered, in case it registers again.
+const FORGET_AFTER: Duration = Duration::from_secs(86400);
+
+/// This loop actively checks that every registered exit accepts connections, removing exits that fail too many checks in a row instead of letting clients see them until their descriptors expire.
+#[tracing::instrument]
+pub async fn exit_health_loop() -> anyhow::Result<()> {
+    tracing::info!("starting the exit health loop");
+    let interval = Duration::from_secs(CONFIG_FILE.wait().exit_health_interval_secs);
+    let threshold = CONFIG_FILE.wait().exit_health_failures;
+    // failures in a row, and when the last one was
+    let mut failures: HashMap<[u8; 32], (u32, Instant)> = HashMap::new();
+    loop {
+        let exits = query_exit_addrs().await?;
+        let results: Vec<_> = stream::iter(exits.iter().map(|(pubkey, c2e_listen)| async move {
+            let reachable = match c2e_listen.parse::<SocketAddr>() {
+                Ok(dest_addr) => matches!(
+                    TcpDialer { dest_addr }.dial().timeout(PROBE_TIMEOUT).await,
+                    Some(Ok(_))
+                ),
+                Err(_) => false,
+            };
+            (*pubkey, c2e_listen, reachable)
+        }))
+        .buffer_unordered(PROBE_CONCURRENCY)
+        .collect()
+        .await;
+        // a count is only reset by a passed check, and not by the exit dropping out of the table, so that an exit that re-registers while still dead is removed again on its next failure
+        for (pubkey, c2e_listen, reachable) in results {
+            if reachable {
+                failures.remove(&pubkey);
+                continue;
+            }
+            let (count, last_failure) = failures.entry(pubkey).or_insert((0, Instant::now()));
+            *count += 1;
+            *last_failure = Instant::now();
+            let count = *count;
+            tracing::debug!(c2e_listen, count, "exit failed a health check");
+            if count >= threshold {
+                tracing::warn!(c2e_listen, count, "removing unreachable exit");
+                // the exit is tried again on its next failure, so one bad write should not stop the loop
+                if let Err(err) = delete_exit(pubkey).await {
+                    tracing::warn!(c2e_listen, err = debug(err), "could not remove exit");
+                }
+            }
+        }
+        failures.retain(|_, (_, last_failure)| last_failure.elapsed() < FORGET_AFTER);
+        Timer::after(interval).await;
+    }
+}
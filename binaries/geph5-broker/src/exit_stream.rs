@@ -0,0 +1,86 @@
+use std::{
+    convert::Infallible,
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::{stream, Stream};
+use geph5_broker_protocol::{ExitList, MultiSigned, DOMAIN_EXIT_DESCRIPTOR};
+use tokio::sync::watch;
+
+use crate::rpc_impl::{signing_secrets, BrokerImpl};
+
+/// The latest signed exit list, serialized as JSON, or None before the first poll.
+static LATEST_EXITS: LazyLock<watch::Sender<Option<String>>> =
+    LazyLock::new(|| watch::channel(None).0);
+
+/// How often the exit table gets checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often the exit list gets republished even when nothing changed, so that subscribers never hold one whose descriptors have expired.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The part of an exit list that clients care about changing: which exits there are, and where they are. Loads change constantly and are not worth a push.
+fn membership(list: &ExitList) -> Vec<String> {
+    let mut members: Vec<String> = list
+        .all_exits
+        .iter()
+        .map(|(pk, exit)| {
+            format!(
+                "{}/{}/{}/{}",
+                hex::encode(pk.as_bytes()),
+                exit.c2e_listen,
+                exit.country.alpha2(),
+                exit.city
+            )
+        })
+        .collect();
+    members.sort_unstable();
+    members
+}
+
+/// This loop watches the exit table, publishing a freshly signed exit list to every stream subscriber whenever exits come or go.
+#[tracing::instrument]
+pub async fn exit_stream_loop() -> anyhow::Result<()> {
+    tracing::info!("starting the exit stream loop");
+    let mut last_membership = None;
+    let mut last_publish = Instant::now();
+    loop {
+        let exits = BrokerImpl {}.get_all_exits().await;
+        match exits {
+            Ok(list) => {
+                let members = membership(&list);
+                let changed = last_membership.as_ref() != Some(&members);
+                if (changed || last_publish.elapsed() > REFRESH_INTERVAL)
+                    && !list.all_exits.is_empty()
+                {
+                    tracing::debug!(count = members.len(), changed, "publishing exit list");
+                    let signed = MultiSigned::new(list, DOMAIN_EXIT_DESCRIPTOR, &signing_secrets());
+                    LATEST_EXITS.send_replace(Some(serde_json::to_string(&signed)?));
+                    last_membership = Some(members);
+                    last_publish = Instant::now();
+                }
+            }
+            Err(err) => tracing::warn!(err = debug(err), "could not poll exits"),
+        }
+        Timer::after(POLL_INTERVAL).await;
+    }
+}
+
+/// Serves `GET /v1/exit-stream`: the current signed exit list right away, then another one every time the exits change.
+pub async fn exit_stream() -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let mut recv = LATEST_EXITS.subscribe();
+    recv.mark_changed();
+    let events = stream::unfold(recv, |mut recv| async move {
+        loop {
+            recv.changed().await.ok()?;
+            let latest = recv.borrow_and_update().clone();
+            if let Some(latest) = latest {
+                return Some((Ok(Event::default().event("exit_update").data(latest)), recv));
+            }
+        }
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
@@ -1,8 +1,17 @@
 use anyhow::Context;
-use axum::{routing::post, Json, Router};
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Redirect},
+    routing::{get, post},
+    Json, Router,
+};
+use bridge_health::bridge_health_loop;
 use clap::Parser;
 use database::database_gc_loop;
 use ed25519_dalek::SigningKey;
+use exit_health::exit_health_loop;
+use exit_stream::{exit_stream, exit_stream_loop};
+use geph5_broker_protocol::SUPPORTED_VERSIONS;
 
 use nanorpc::{JrpcRequest, JrpcResponse, RpcService};
 use once_cell::sync::{Lazy, OnceCell};
@@ -15,7 +24,10 @@ use std::{fmt::Debug, fs, net::SocketAddr, path::PathBuf, sync::LazyLock};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 mod auth;
+mod bridge_health;
 mod database;
+mod exit_health;
+mod exit_stream;
 mod routes;
 mod rpc_impl;
 mod self_stat;
@@ -37,6 +49,24 @@ static MASTER_SECRET: Lazy<SigningKey> = Lazy::new(|| {
     sk
 });
 
+/// Additional secrets that co-sign everything the master secret signs, so that clients can require a threshold of signatures.
+static EXTRA_SECRETS: Lazy<Vec<SigningKey>> = Lazy::new(|| {
+    CONFIG_FILE
+        .wait()
+        .extra_secrets
+        .iter()
+        .map(|path| {
+            let sk =
+                SigningKey::from_bytes(std::fs::read(path).unwrap().as_slice().try_into().unwrap());
+            tracing::info!(
+                "*** extra PK = {} ***",
+                hex::encode(sk.verifying_key().as_bytes())
+            );
+            sk
+        })
+        .collect()
+});
+
 /// The Plus mizaru SK.
 static PLUS_MIZARU_SK: Lazy<mizaru2::SecretKey> = Lazy::new(|| {
     let mizaru = load_mizaru_sk("plus.bin");
@@ -78,6 +108,8 @@ struct ConfigFile {
     listen: SocketAddr,
     tcp_listen: SocketAddr,
     master_secret: PathBuf,
+    #[serde(default)]
+    extra_secrets: Vec<PathBuf>,
     mizaru_keys: PathBuf,
     postgres_url: String,
     #[serde(default)]
@@ -88,6 +120,28 @@ struct ConfigFile {
 
     #[serde(default)]
     statsd_addr: Option<SocketAddr>,
+
+    /// How often every registered exit gets checked for accepting connections
+    #[serde(default = "default_exit_health_interval_secs")]
+    exit_health_interval_secs: u64,
+    /// How many checks in a row an exit may fail before it is removed
+    #[serde(default = "default_exit_health_failures")]
+    exit_health_failures: u32,
+    /// How long after an exit rotates its key we keep accepting descriptors signed by the old one, so that clients that fetched the exit list before the rotation can still reach it
+    #[serde(default = "default_exit_key_overlap_secs")]
+    exit_key_overlap_secs: u64,
+}
+
+fn default_exit_health_interval_secs() -> u64 {
+    30
+}
+
+fn default_exit_health_failures() -> u32 {
+    3
+}
+
+fn default_exit_key_overlap_secs() -> u64 {
+    600
 }
 
 /// Run the Geph5 broker.
@@ -127,6 +181,9 @@ async fn main() -> anyhow::Result<()> {
 
     let _gc_loop = Immortal::respawn(RespawnStrategy::Immediate, database_gc_loop);
     let _self_stat_loop = Immortal::respawn(RespawnStrategy::Immediate, self_stat_loop);
+    let _bridge_health_loop = Immortal::respawn(RespawnStrategy::Immediate, bridge_health_loop);
+    let _exit_health_loop = Immortal::respawn(RespawnStrategy::Immediate, exit_health_loop);
+    let _exit_stream_loop = Immortal::respawn(RespawnStrategy::Immediate, exit_stream_loop);
     let _tcp_loop = Immortal::respawn(RespawnStrategy::Immediate, || async {
         nanorpc_sillad::rpc_serve(
             sillad::tcp::TcpListener::bind(CONFIG_FILE.wait().tcp_listen).await?,
@@ -137,13 +194,26 @@ async fn main() -> anyhow::Result<()> {
     });
 
     let listener = tokio::net::TcpListener::bind(CONFIG_FILE.wait().listen).await?;
-    let app = Router::new().route("/", post(rpc));
+    let app = Router::new()
+        .route("/version", get(|| async { Json(SUPPORTED_VERSIONS) }))
+        .route("/v1/", post(rpc))
+        .route("/v1/exit-stream", get(exit_stream))
+        .route("/v2/", post(rpc))
+        // old clients post to the root, and a permanent redirect preserves the method and body
+        .route("/", post(|| async { Redirect::permanent("/v1/") }));
     axum::serve(listener, app).await?;
     Ok(())
 }
 
-async fn rpc(Json(payload): Json<JrpcRequest>) -> Json<JrpcResponse> {
-    Json(WrappedBrokerService::new().respond_raw(payload).await)
+async fn rpc(Json(payload): Json<JrpcRequest>) -> axum::response::Response {
+    if payload.jsonrpc != "2.0" {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("unsupported JSON-RPC version {:?}", payload.jsonrpc),
+        )
+            .into_response();
+    }
+    Json::<JrpcResponse>(WrappedBrokerService::new().respond_raw(payload).await).into_response()
 }
 
 fn log_error(e: &impl Debug) {
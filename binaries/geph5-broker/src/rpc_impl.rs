@@ -2,12 +2,13 @@ use async_trait::async_trait;
 use bytes::Bytes;
 use cadence::prelude::*;
 use cadence::{StatsdClient, UdpMetricSink};
-use ed25519_dalek::VerifyingKey;
+use ed25519_dalek::{SigningKey, VerifyingKey};
 use futures_util::future::join_all;
 use geph5_broker_protocol::{
-    AccountLevel, AuthError, BridgeDescriptor, BrokerProtocol, BrokerService, Credential,
-    ExitDescriptor, ExitList, GenericError, Mac, RouteDescriptor, Signed, UserInfo,
-    DOMAIN_EXIT_DESCRIPTOR,
+    AccountLevel, AuthError, BridgeDescriptor, BridgeStatus, BrokerFault, BrokerProtocol,
+    BrokerService, Credential, ExitDescriptor, ExitKeyRotation, ExitList, Mac, MultiSigned,
+    RevocationList, RouteDescriptor, Signed, UserInfo, DOMAIN_EXIT_DESCRIPTOR,
+    DOMAIN_EXIT_KEY_ROTATION, DOMAIN_REVOCATION_LIST,
 };
 use isocountry::CountryCode;
 use mizaru2::{BlindedClientToken, BlindedSignature, ClientToken, UnblindedSignature};
@@ -18,17 +19,63 @@ use std::{
     net::SocketAddr,
     ops::Deref,
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 use crate::{auth::get_subscription_expiry, log_error};
 use crate::{
     auth::{new_auth_token, valid_auth_token, validate_username_pwd},
-    database::{insert_exit, query_bridges, ExitRow, POSTGRES},
+    bridge_health::bridge_health,
+    database::{
+        exit_key_retired, insert_exit, query_bridges, query_revoked_tokens, retire_exit_key,
+        ExitRow, POSTGRES,
+    },
     routes::bridge_to_leaf_route,
-    CONFIG_FILE, FREE_MIZARU_SK, MASTER_SECRET, PLUS_MIZARU_SK,
+    CONFIG_FILE, EXTRA_SECRETS, FREE_MIZARU_SK, MASTER_SECRET, PLUS_MIZARU_SK,
 };
 
+/// Authenticates a connect token, then gets the bridges assigned to it that its account level may use.
+async fn assigned_bridges(
+    token: ClientToken,
+    sig: UnblindedSignature,
+) -> Result<Vec<BridgeDescriptor>, BrokerFault> {
+    // authenticate the token
+    let account_level = if PLUS_MIZARU_SK
+        .to_public_key()
+        .blind_verify(token, &sig)
+        .is_ok()
+    {
+        AccountLevel::Plus
+    } else {
+        FREE_MIZARU_SK
+            .to_public_key()
+            .blind_verify(token, &sig)
+            .map_err(|_| BrokerFault::InvalidCredential)?;
+
+        AccountLevel::Free
+    };
+
+    // TODO filter out plus only
+
+    let raw_descriptors = query_bridges(&format!("{:?}", token)).await?;
+
+    let plus_pools = [
+        "ls_ap_northeast_1",
+        "ls_ap_northeast_2",
+        "NEW_ls_ap_northeast_1",
+        "NEW_ls_ap_northeast_2",
+        "yaofan-hk",
+    ];
+    Ok(if account_level == AccountLevel::Free {
+        raw_descriptors
+            .into_iter()
+            .filter(|s| !plus_pools.iter().any(|plus_group| &s.pool == plus_group))
+            .collect()
+    } else {
+        raw_descriptors
+    })
+}
+
 pub struct WrappedBrokerService(BrokerService<BrokerImpl>);
 
 impl WrappedBrokerService {
@@ -51,10 +98,10 @@ impl RpcService for WrappedBrokerService {
     }
 }
 
-struct BrokerImpl {}
+pub(crate) struct BrokerImpl {}
 
 impl BrokerImpl {
-    async fn get_all_exits(&self) -> Result<ExitList, GenericError> {
+    pub(crate) async fn get_all_exits(&self) -> Result<ExitList, BrokerFault> {
         static EXIT_CACHE: Lazy<Cache<(), ExitList>> = Lazy::new(|| {
             Cache::builder()
                 .time_to_live(Duration::from_secs(10))
@@ -63,26 +110,28 @@ impl BrokerImpl {
 
         let exit_list = EXIT_CACHE
             .try_get_with((), async {
-                let exits: Vec<(VerifyingKey, ExitDescriptor)> =
-                    sqlx::query_as("select * from exits_new")
-                        .fetch_all(POSTGRES.deref())
-                        .await?
-                        .into_iter()
-                        .map(|row: ExitRow| {
-                            (
-                                VerifyingKey::from_bytes(&row.pubkey).unwrap(),
-                                ExitDescriptor {
-                                    c2e_listen: row.c2e_listen.parse().unwrap(),
-                                    b2e_listen: row.b2e_listen.parse().unwrap(),
-                                    country: CountryCode::for_alpha2_caseless(&row.country)
-                                        .unwrap(),
-                                    city: row.city,
-                                    load: row.load,
-                                    expiry: row.expiry as _,
-                                },
-                            )
-                        })
-                        .collect();
+                let exits: Vec<(VerifyingKey, ExitDescriptor)> = sqlx::query_as(
+                    "select * from exits_new where expiry > extract(epoch from now())",
+                )
+                .fetch_all(POSTGRES.deref())
+                .await?
+                .into_iter()
+                .map(|row: ExitRow| {
+                    (
+                        VerifyingKey::from_bytes(&row.pubkey).unwrap(),
+                        ExitDescriptor {
+                            c2e_listen: row.c2e_listen.parse().unwrap(),
+                            b2e_listen: row.b2e_listen.parse().unwrap(),
+                            country: CountryCode::for_alpha2_caseless(&row.country).unwrap(),
+                            city: row.city,
+                            load: row.load,
+                            expiry: row.expiry as _,
+                            probe_magic: row.probe_magic,
+                            pmtud_echo: row.pmtud_echo,
+                        },
+                    )
+                })
+                .collect();
                 let exit_list = ExitList {
                     all_exits: exits,
                     city_names: serde_yaml::from_str(include_str!("city_names.yaml")).unwrap(),
@@ -90,9 +139,39 @@ impl BrokerImpl {
                 Ok(exit_list)
             })
             .await
-            .map_err(|e: Arc<GenericError>| e.deref().clone())?;
+            .map_err(|e: Arc<BrokerFault>| e.deref().clone())?;
         Ok(exit_list)
     }
+
+    /// The exit list signed with every broker key, with only the free exits if `free_only` is set, and with only what older clients can check the signature over if `older_clients` is set.
+    async fn signed_exits(
+        &self,
+        free_only: bool,
+        older_clients: bool,
+    ) -> Result<MultiSigned<ExitList>, BrokerFault> {
+        let mut exit_list = self.get_all_exits().await?;
+        if free_only {
+            exit_list.all_exits.retain(|(_, e)| !is_plus_exit(e));
+        }
+        if exit_list.all_exits.is_empty() {
+            return Err(BrokerFault::NoExitsAvailable);
+        }
+        if older_clients {
+            exit_list = exit_list.for_older_clients();
+        }
+        Ok(MultiSigned::new(
+            exit_list,
+            DOMAIN_EXIT_DESCRIPTOR,
+            &signing_secrets(),
+        ))
+    }
+}
+
+/// Every key that signs exit lists. The master key comes first, since its signature is the only one that clients predating multiple keys look at.
+pub(crate) fn signing_secrets() -> Vec<&'static SigningKey> {
+    std::iter::once(MASTER_SECRET.deref())
+        .chain(EXTRA_SECRETS.iter())
+        .collect()
 }
 
 fn is_plus_exit(exit: &ExitDescriptor) -> bool {
@@ -170,24 +249,20 @@ impl BrokerProtocol for BrokerImpl {
         Ok(signed)
     }
 
-    async fn get_exits(&self) -> Result<Signed<ExitList>, GenericError> {
-        let exit_list = self.get_all_exits().await?;
+    async fn get_exits(&self) -> Result<MultiSigned<ExitList>, BrokerFault> {
+        self.signed_exits(false, true).await
+    }
 
-        Ok(Signed::new(
-            exit_list,
-            DOMAIN_EXIT_DESCRIPTOR,
-            MASTER_SECRET.deref(),
-        ))
+    async fn get_free_exits(&self) -> Result<MultiSigned<ExitList>, BrokerFault> {
+        self.signed_exits(true, true).await
     }
 
-    async fn get_free_exits(&self) -> Result<Signed<ExitList>, GenericError> {
-        let mut exit_list = self.get_all_exits().await?;
-        exit_list.all_exits.retain(|(_, e)| !is_plus_exit(e));
-        Ok(Signed::new(
-            exit_list,
-            DOMAIN_EXIT_DESCRIPTOR,
-            MASTER_SECRET.deref(),
-        ))
+    async fn get_exits_v2(&self) -> Result<MultiSigned<ExitList>, BrokerFault> {
+        self.signed_exits(false, false).await
+    }
+
+    async fn get_free_exits_v2(&self) -> Result<MultiSigned<ExitList>, BrokerFault> {
+        self.signed_exits(true, false).await
     }
 
     async fn get_user_info(&self, auth_token: String) -> Result<Option<UserInfo>, AuthError> {
@@ -224,39 +299,8 @@ impl BrokerProtocol for BrokerImpl {
         token: ClientToken,
         sig: UnblindedSignature,
         exit: SocketAddr,
-    ) -> Result<RouteDescriptor, GenericError> {
-        // authenticate the token
-        let account_level = if PLUS_MIZARU_SK
-            .to_public_key()
-            .blind_verify(token, &sig)
-            .is_ok()
-        {
-            AccountLevel::Plus
-        } else {
-            FREE_MIZARU_SK.to_public_key().blind_verify(token, &sig)?;
-
-            AccountLevel::Free
-        };
-
-        // TODO filter out plus only
-
-        let raw_descriptors = query_bridges(&format!("{:?}", token)).await?;
-
-        let plus_pools = [
-            "ls_ap_northeast_1",
-            "ls_ap_northeast_2",
-            "NEW_ls_ap_northeast_1",
-            "NEW_ls_ap_northeast_2",
-            "yaofan-hk",
-        ];
-        let raw_descriptors = if account_level == AccountLevel::Free {
-            raw_descriptors
-                .into_iter()
-                .filter(|s| !plus_pools.iter().any(|plus_group| &s.pool == plus_group))
-                .collect()
-        } else {
-            raw_descriptors
-        };
+    ) -> Result<RouteDescriptor, BrokerFault> {
+        let raw_descriptors = assigned_bridges(token, sig).await?;
 
         let mut routes = vec![];
         for route in join_all(
@@ -277,14 +321,57 @@ impl BrokerProtocol for BrokerImpl {
         Ok(RouteDescriptor::Race(routes))
     }
 
+    async fn get_bridge_status(
+        &self,
+        token: ClientToken,
+        sig: UnblindedSignature,
+    ) -> Result<BridgeStatus, BrokerFault> {
+        let bridges = assigned_bridges(token, sig)
+            .await?
+            .into_iter()
+            .filter_map(|bridge| {
+                Some((bridge.control_listen, bridge_health(bridge.control_listen)?))
+            })
+            .collect();
+        Ok(BridgeStatus { bridges })
+    }
+
+    async fn get_revocation_list(&self) -> Result<Signed<RevocationList>, BrokerFault> {
+        let revocation_list = RevocationList {
+            revoked_tokens: query_revoked_tokens().await?,
+            // exits refetch much more often than this, so this only matters if we become unreachable
+            expiry: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+                + 3600,
+        };
+        Ok(Signed::new(
+            revocation_list,
+            DOMAIN_REVOCATION_LIST,
+            &MASTER_SECRET,
+        ))
+    }
+
     async fn insert_exit(
         &self,
         descriptor: Mac<Signed<ExitDescriptor>>,
-    ) -> Result<(), GenericError> {
-        let descriptor =
-            descriptor.verify(blake3::hash(CONFIG_FILE.wait().exit_token.as_bytes()).as_bytes())?;
+    ) -> Result<(), BrokerFault> {
+        let descriptor = descriptor
+            .verify(blake3::hash(CONFIG_FILE.wait().exit_token.as_bytes()).as_bytes())
+            .map_err(|_| BrokerFault::InvalidCredential)?;
         let pubkey = descriptor.pubkey;
-        let descriptor = descriptor.verify(DOMAIN_EXIT_DESCRIPTOR, |_| true)?;
+        let descriptor = descriptor
+            .verify(DOMAIN_EXIT_DESCRIPTOR, |_| true)
+            .map_err(|_| BrokerFault::InvalidSignature)?;
+        // once an exit has moved on to a new key, whoever still has the old one must not keep it listed
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if exit_key_retired(pubkey.to_bytes(), now as i64).await? {
+            return Err(BrokerFault::InvalidCredential);
+        }
         let exit = ExitRow {
             pubkey: pubkey.to_bytes(),
             c2e_listen: descriptor.c2e_listen.to_string(),
@@ -293,14 +380,42 @@ impl BrokerProtocol for BrokerImpl {
             city: descriptor.city.clone(),
             load: descriptor.load,
             expiry: descriptor.expiry as _,
+            probe_magic: descriptor.probe_magic,
+            pmtud_echo: descriptor.pmtud_echo,
         };
         insert_exit(&exit).await?;
         Ok(())
     }
 
-    async fn insert_bridge(&self, descriptor: Mac<BridgeDescriptor>) -> Result<(), GenericError> {
+    async fn retire_exit_key(
+        &self,
+        notice: Mac<Signed<ExitKeyRotation>>,
+    ) -> Result<(), BrokerFault> {
+        let notice = notice
+            .verify(blake3::hash(CONFIG_FILE.wait().exit_token.as_bytes()).as_bytes())
+            .map_err(|_| BrokerFault::InvalidCredential)?;
+        let retired = notice.pubkey;
+        let rotation = notice
+            .verify(DOMAIN_EXIT_KEY_ROTATION, |_| true)
+            .map_err(|_| BrokerFault::InvalidSignature)?;
+        tracing::info!(
+            retired = hex::encode(retired.as_bytes()),
+            new = hex::encode(rotation.new_pubkey.as_bytes()),
+            "exit rotated its key"
+        );
+        let retire_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + CONFIG_FILE.wait().exit_key_overlap_secs;
+        retire_exit_key(retired.to_bytes(), retire_at as i64).await?;
+        Ok(())
+    }
+
+    async fn insert_bridge(&self, descriptor: Mac<BridgeDescriptor>) -> Result<(), BrokerFault> {
         let descriptor = descriptor
-            .verify(blake3::hash(CONFIG_FILE.wait().bridge_token.as_bytes()).as_bytes())?;
+            .verify(blake3::hash(CONFIG_FILE.wait().bridge_token.as_bytes()).as_bytes())
+            .map_err(|_| BrokerFault::InvalidCredential)?;
 
         sqlx::query(
             r#"
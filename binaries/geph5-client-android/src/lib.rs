@@ -1,5 +1,7 @@
 #[cfg(target_os = "android")]
 mod keyboard;
+#[cfg(target_os = "android")]
+mod tunnel;
 
 #[allow(dead_code)]
 #[cfg(target_os = "android")]
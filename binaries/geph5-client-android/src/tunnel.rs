@@ -0,0 +1,98 @@
+use std::{fs::File, os::fd::FromRawFd, sync::Mutex};
+
+use geph5_client::{Client, Config};
+use jni::{
+    objects::{JClass, JString},
+    sys::jint,
+    JNIEnv,
+};
+use smol::{
+    future::FutureExt as _,
+    io::{AsyncReadExt, AsyncWriteExt},
+    Async,
+};
+
+/// The running tunnel, if any.
+static TUNNEL: Mutex<Option<Tunnel>> = Mutex::new(None);
+
+/// Dropping this stops the client, and closes the TUN device.
+struct Tunnel {
+    _client: Client,
+    _shuffle: Option<smol::Task<()>>,
+}
+
+/// Starts the client with the given JSON config, tunneling the packets of the TUN device behind `vpn_fd`, as returned by `ParcelFileDescriptor.detachFd()`, unless it is negative. Returns 0 on success, or -1 if the config is invalid or a tunnel is already running. Either way, `vpn_fd` is ours from then on, and closed on failure.
+///
+/// Backs `static native int startTunnel(String configJson, int vpnFd)` in `io.geph.geph5.Geph5Tunnel`.
+#[no_mangle]
+pub extern "system" fn Java_io_geph_geph5_Geph5Tunnel_startTunnel(
+    mut env: JNIEnv,
+    _class: JClass,
+    config_json: JString,
+    vpn_fd: jint,
+) -> jint {
+    match start_tunnel(&mut env, config_json, vpn_fd) {
+        Ok(()) => 0,
+        Err(err) => {
+            tracing::error!(err = debug(err), "could not start tunnel");
+            -1
+        }
+    }
+}
+
+/// Stops the running tunnel, if any, closing its TUN device.
+///
+/// Backs `static native void stopTunnel()` in `io.geph.geph5.Geph5Tunnel`.
+#[no_mangle]
+pub extern "system" fn Java_io_geph_geph5_Geph5Tunnel_stopTunnel(_env: JNIEnv, _class: JClass) {
+    if TUNNEL.lock().unwrap().take().is_some() {
+        tracing::info!("stopped tunnel");
+    }
+}
+
+fn start_tunnel(env: &mut JNIEnv, config_json: JString, vpn_fd: jint) -> anyhow::Result<()> {
+    // taking ownership before anything can fail means that every early return closes the TUN device
+    let tun = (vpn_fd >= 0).then(|| unsafe { File::from_raw_fd(vpn_fd) });
+    let config_json: String = env.get_string(&config_json)?.into();
+    let mut config: Config = serde_json::from_str(&config_json)?;
+    // the packets come from the VpnService rather than from a TUN device we set up ourselves
+    config.vpn = false;
+    let mut tunnel = TUNNEL.lock().unwrap();
+    anyhow::ensure!(tunnel.is_none(), "tunnel already running");
+    let client = Client::start(config);
+    let shuffle = match tun {
+        // the TUN device is closed when the task is dropped
+        Some(tun) => Some(smolscale::spawn(packet_shuffle(
+            client.clone(),
+            Async::new(tun)?,
+        ))),
+        None => None,
+    };
+    *tunnel = Some(Tunnel {
+        _client: client,
+        _shuffle: shuffle,
+    });
+    tracing::info!(vpn = vpn_fd >= 0, "started tunnel");
+    Ok(())
+}
+
+/// Passes the packets that apps send into the TUN device to the client, and writes the packets the client injects back out to the TUN device.
+async fn packet_shuffle(client: Client, tun: Async<File>) {
+    let up_loop = async {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let n = (&tun).read(&mut buf).await?;
+            client.send_vpn_packet(buf[..n].to_vec().into()).await?;
+        }
+    };
+    let dn_loop = async {
+        loop {
+            let packet = client.recv_vpn_packet().await?;
+            (&tun).write_all(&packet).await?;
+        }
+    };
+    let result: anyhow::Result<()> = up_loop.race(dn_loop).await;
+    if let Err(err) = result {
+        tracing::warn!(err = debug(err), "VPN packet shuffle stopped");
+    }
+}
@@ -0,0 +1,23 @@
+#ifndef GEPH5_H
+#define GEPH5_H
+
+#include <stddef.h>
+#include <sys/types.h>
+
+/* Starts the client with the given JSON config. Returns 0 on success, or -1
+ * if the config is invalid or the client is already running. */
+int geph5_start(const char *config_json);
+
+/* Stops the client, if it is running. */
+void geph5_stop(void);
+
+/* Sends a packet read from the packet flow into the tunnel. Returns 0 on
+ * success, or -1 if the client is not running. */
+int geph5_send_packet(const unsigned char *packet, size_t len);
+
+/* Blocks until there is a packet to write to the packet flow, and copies it
+ * into buf. Returns its length, or -1 once the client is stopped. Packets
+ * longer than buf_len are dropped. */
+ssize_t geph5_recv_packet(unsigned char *buf, size_t buf_len);
+
+#endif
@@ -0,0 +1,120 @@
+//! A C interface for running the geph5 client from a `NEPacketTunnelProvider`, declared in `include/geph5.h`. The Swift side, in `swift/PacketTunnelProvider.swift`, moves packets between the provider's `packetFlow` and the client.
+
+use std::{
+    ffi::{c_char, c_int, CStr},
+    sync::Mutex,
+};
+
+use geph5_client::{Client, Config};
+use smol::{
+    channel::{Receiver, Sender},
+    future::FutureExt as _,
+};
+
+/// The running client, if any.
+static RUNNING: Mutex<Option<Running>> = Mutex::new(None);
+
+struct Running {
+    client: Client,
+    /// Dropped on stop, which wakes up whoever is waiting in [geph5_recv_packet].
+    _stop: Sender<()>,
+    stopped: Receiver<()>,
+}
+
+/// Starts the client with the given JSON config, a NUL-terminated UTF-8 string. Returns 0 on success, or -1 if the config is invalid or the client is already running.
+///
+/// # Safety
+///
+/// `config_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn geph5_start(config_json: *const c_char) -> c_int {
+    let start = || {
+        let config_json = CStr::from_ptr(config_json).to_str()?;
+        let mut config: Config = serde_json::from_str(config_json)?;
+        // the packets come from the packet flow rather than from a TUN device we set up ourselves
+        config.vpn = false;
+        let mut running = RUNNING.lock().unwrap();
+        anyhow::ensure!(running.is_none(), "client already running");
+        let (stop, stopped) = smol::channel::bounded(1);
+        *running = Some(Running {
+            client: Client::start(config),
+            _stop: stop,
+            stopped,
+        });
+        anyhow::Ok(())
+    };
+    match start() {
+        Ok(()) => 0,
+        Err(err) => {
+            tracing::error!(err = debug(err), "could not start client");
+            -1
+        }
+    }
+}
+
+/// Stops the client, if it is running.
+#[no_mangle]
+pub extern "C" fn geph5_stop() {
+    RUNNING.lock().unwrap().take();
+}
+
+/// Sends a packet that an app sent into the tunnel, as read from the packet flow. Returns 0 on success, or -1 if the client is not running.
+///
+/// # Safety
+///
+/// `packet` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn geph5_send_packet(packet: *const u8, len: usize) -> c_int {
+    let Some(client) = running_client() else {
+        return -1;
+    };
+    let packet = std::slice::from_raw_parts(packet, len).to_vec();
+    match smol::future::block_on(client.send_vpn_packet(packet.into())) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Waits for the next packet for the apps, to be written to the packet flow, and copies it into `buf`. Returns its length, or -1 once the client is stopped. Packets longer than `buf_len` are dropped, so `buf` should be at least as large as the tunnel MTU.
+///
+/// # Safety
+///
+/// `buf` must point to `buf_len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn geph5_recv_packet(buf: *mut u8, buf_len: usize) -> isize {
+    let Some((client, stopped)) = RUNNING
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|running| (running.client.clone(), running.stopped.clone()))
+    else {
+        return -1;
+    };
+    loop {
+        let packet = smol::future::block_on(client.recv_vpn_packet().race(async {
+            let _ = stopped.recv().await;
+            anyhow::bail!("client stopped")
+        }));
+        let Ok(packet) = packet else {
+            return -1;
+        };
+        if packet.len() > buf_len {
+            tracing::warn!(
+                len = packet.len(),
+                buf_len,
+                "dropping packet too long for the buffer"
+            );
+            continue;
+        }
+        std::ptr::copy_nonoverlapping(packet.as_ptr(), buf, packet.len());
+        return packet.len() as isize;
+    }
+}
+
+fn running_client() -> Option<Client> {
+    RUNNING
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|running| running.client.clone())
+}
@@ -0,0 +1,83 @@
+import NetworkExtension
+
+/// Runs the geph5 client for the system VPN. The client config, as JSON, is the `config` key of the provider configuration that the app saves with its `NETunnelProviderManager`.
+class PacketTunnelProvider: NEPacketTunnelProvider {
+    override func startTunnel(
+        options: [String: NSObject]?,
+        completionHandler: @escaping (Error?) -> Void
+    ) {
+        guard
+            let proto = protocolConfiguration as? NETunnelProviderProtocol,
+            let configJson = proto.providerConfiguration?["config"] as? String
+        else {
+            completionHandler(Geph5Error.noConfig)
+            return
+        }
+        let settings = NEPacketTunnelNetworkSettings(tunnelRemoteAddress: "127.0.0.1")
+        let ipv4 = NEIPv4Settings(addresses: ["100.64.89.64"], subnetMasks: ["255.192.0.0"])
+        ipv4.includedRoutes = [NEIPv4Route.default()]
+        settings.ipv4Settings = ipv4
+        settings.dnsSettings = NEDNSSettings(servers: ["1.1.1.1"])
+        settings.mtu = 1450
+        setTunnelNetworkSettings(settings) { error in
+            if let error = error {
+                completionHandler(error)
+                return
+            }
+            // the provider's own connections, including ours to the exits, never go through its tunnel
+            guard geph5_start(configJson) == 0 else {
+                completionHandler(Geph5Error.startFailed)
+                return
+            }
+            self.readPackets()
+            self.writePackets()
+            completionHandler(nil)
+        }
+    }
+
+    override func stopTunnel(
+        with reason: NEProviderStopReason,
+        completionHandler: @escaping () -> Void
+    ) {
+        geph5_stop()
+        completionHandler()
+    }
+
+    /// Passes what apps send into the tunnel to the client.
+    private func readPackets() {
+        packetFlow.readPackets { packets, _ in
+            for packet in packets {
+                let sent = packet.withUnsafeBytes { bytes in
+                    geph5_send_packet(
+                        bytes.bindMemory(to: UInt8.self).baseAddress, bytes.count)
+                }
+                if sent != 0 {
+                    return
+                }
+            }
+            self.readPackets()
+        }
+    }
+
+    /// Writes what the client sends back to the apps. Waiting for packets blocks, so this gets a thread of its own, which ends when the client stops.
+    private func writePackets() {
+        let packetFlow = self.packetFlow
+        Thread.detachNewThread {
+            var buf = [UInt8](repeating: 0, count: 65536)
+            while true {
+                let n = geph5_recv_packet(&buf, buf.count)
+                if n < 0 {
+                    return
+                }
+                let family = buf[0] >> 4 == 6 ? AF_INET6 : AF_INET
+                packetFlow.writePackets(
+                    [Data(buf[0..<n])], withProtocols: [NSNumber(value: family)])
+            }
+        }
+    }
+}
+
+enum Geph5Error: Error {
+    case noConfig
+    case startFailed
+}
@@ -201,6 +201,8 @@ impl Settings {
                     BridgeMode::Auto => "Auto",
                     BridgeMode::ForceBridges => "Force bridges",
                     BridgeMode::ForceDirect => "Force direct",
+                    BridgeMode::SmartBridges { .. } => "Smart bridges",
+                    BridgeMode::Multipath => "Multipath",
                 };
                 ui.horizontal(|ui| {
                     ui.label("Bridge mode");
@@ -212,6 +214,8 @@ impl Settings {
                                 BridgeMode::Auto,
                                 BridgeMode::ForceBridges,
                                 BridgeMode::ForceDirect,
+                                BridgeMode::SmartBridges { threshold: 3 },
+                                BridgeMode::Multipath,
                             ] {
                                 ui.selectable_value(bridge_mode, this_mode, mode_label(this_mode));
                             }
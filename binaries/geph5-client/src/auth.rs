@@ -12,7 +12,7 @@ use rand::Rng;
 use stdcode::StdcodeSerializeExt;
 
 use crate::{
-    broker::broker_client,
+    broker::{broker_client, broker_source},
     client::Config,
     database::{db_read, db_read_or_wait, db_remove, db_write},
 };
@@ -24,7 +24,7 @@ pub async fn get_connect_token(
 ) -> anyhow::Result<(AccountLevel, ClientToken, UnblindedSignature)> {
     while !CONN_TOKEN_READY.load(Ordering::SeqCst) {
         tracing::debug!("waiting for connection token");
-        smol::Timer::after(Duration::from_secs(1)).await;
+        geph5_rt::Timer::after(Duration::from_secs(1)).await;
     }
     let epoch = mizaru2::current_epoch();
     Ok(stdcode::deserialize(
@@ -46,18 +46,18 @@ pub async fn get_auth_token(ctx: &AnyCtx<Config>) -> anyhow::Result<String> {
 }
 
 pub async fn auth_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
-    if ctx.init().broker.is_none() {
-        return smol::future::pending().await;
+    if broker_source(ctx.init()).is_none() {
+        return futures_lite::future::pending().await;
     }
 
     let auth_token = get_auth_token(ctx).await?;
     loop {
         if let Err(err) = refresh_conn_token(ctx, &auth_token).await {
             tracing::warn!(err = debug(err), "failed to refresh conn token");
-            smol::Timer::after(Duration::from_secs(10)).await;
+            geph5_rt::Timer::after(Duration::from_secs(10)).await;
         } else {
             let sleep_secs = rand::thread_rng().gen_range(3600..86400);
-            smol::Timer::after(Duration::from_secs(sleep_secs)).await;
+            geph5_rt::Timer::after(Duration::from_secs(sleep_secs)).await;
         }
     }
 }
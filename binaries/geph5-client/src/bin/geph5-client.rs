@@ -1,23 +1,182 @@
-use std::path::PathBuf;
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
-use clap::Parser;
-use geph5_client::{logs::LOGS, Client, Config};
+use anyhow::Context;
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::{
+    engine::{ArgValueCompleter, CompletionCandidate},
+    CompleteEnv, Shell,
+};
+use geph5_client::{
+    change_exit_constraint, diagnose, exit_constraint_candidates, list_exits, load_exit_stats,
+    logs::{RotatingFile, LOGS},
+    query_health_report, Client, Config, ExitConstraint,
+};
 use tracing_subscriber::{prelude::*, EnvFilter};
 
 /// Run the Geph5 client.
 #[derive(Parser)]
+#[command(subcommand_negates_reqs = true)]
 struct CliArgs {
     /// path to a YAML-based config file
-    #[arg(short, long)]
-    config: PathBuf,
+    #[cfg_attr(not(windows), arg(short, long, required = true))]
+    #[cfg_attr(windows, arg(short, long, required_unless_present = "service"))]
+    config: Option<PathBuf>,
+
+    #[cfg(windows)]
+    #[arg(long, value_enum)]
+    /// install, uninstall, start, or stop the Windows service
+    service: Option<geph5_client::windows_service::ServiceAction>,
 
     #[arg(short, long)]
-    /// don't start the client, but instead dump authentication info
+    /// don't start the client, but log in, handshake with the best few exits, and print a diagnostic report, exiting with an error if no exit is reachable
     dry_run: bool,
+
+    #[arg(long, value_name = "RATE")]
+    /// randomly stall this fraction (e.g. 0.05) of reads and writes on the direct connection to the exit, for testing
+    chaos_loss: Option<f32>,
+
+    #[arg(long, add = ArgValueCompleter::new(complete_exit_constraint))]
+    /// override the exit constraint in the config file, e.g. Country(DE) or CountryCity(DE, Frankfurt)
+    exit_constraint: Option<ExitConstraint>,
+
+    #[arg(long)]
+    /// show a system tray icon with the connection status (Linux only)
+    tray: bool,
+
+    #[cfg(debug_assertions)]
+    #[arg(long, requires = "keylog_file")]
+    /// write the session keys of every exit connection to the key log file, for Wireshark (debug builds only)
+    export_keys: bool,
+
+    #[cfg(debug_assertions)]
+    #[arg(long, value_name = "PATH")]
+    /// where to write the session keys exported by --export-keys, in NSS key log format
+    keylog_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// show the recent per-exit statistics used by the autonomous exit constraint
+    ShowExitStats,
+    /// log in and list every available exit, grouped by country
+    ListExits {
+        /// print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// ask the running client for a health report over its control socket
+    Status,
+    /// tell the running client to switch to a different exit constraint over its control socket, without restarting it
+    SetExitConstraint {
+        /// the new exit constraint, e.g. Country(DE) or CountryCity(DE, Frankfurt)
+        #[arg(add = ArgValueCompleter::new(complete_exit_constraint))]
+        constraint: ExitConstraint,
+    },
+    /// print a shell completion script, which asks geph5-client for the exits available whenever it completes an exit constraint
+    GenerateCompletions {
+        #[arg(long)]
+        shell: Shell,
+    },
 }
 
 fn main() -> anyhow::Result<()> {
     smolscale::permanently_single_threaded();
+    // when the shell calls us back to complete a command line, this answers and exits
+    CompleteEnv::with_factory(CliArgs::command).complete();
+    let args = CliArgs::parse();
+    let config = args.config.as_deref().map(load_config).transpose()?;
+    init_logging(config.as_ref())?;
+
+    #[cfg(windows)]
+    if let Some(action) = args.service {
+        return geph5_client::windows_service::service_command(action, args.config.as_deref());
+    }
+    if let Some(Command::GenerateCompletions { shell }) = args.command {
+        // with the variable set, but no command line to complete, this prints the script that registers us with the shell
+        std::env::set_var("COMPLETE", shell.to_string());
+        CompleteEnv::with_factory(CliArgs::command).complete();
+        return Ok(());
+    }
+    let mut config = config.context("no config file given")?;
+    if let Some(loss) = args.chaos_loss {
+        config.chaos_loss = loss;
+    }
+    if let Some(exit_constraint) = args.exit_constraint {
+        config.exit_constraint = exit_constraint;
+    }
+    #[cfg(debug_assertions)]
+    if args.export_keys {
+        tracing::warn!(
+            keylog_file = debug(&args.keylog_file),
+            "exporting session keys, anybody who can read the key log can decrypt the tunnel"
+        );
+        config.keylog_file = args.keylog_file;
+    }
+    if args.dry_run {
+        return dry_run(config);
+    }
+    if let Some(Command::ShowExitStats) = args.command {
+        return show_exit_stats(config);
+    }
+    if let Some(Command::ListExits { json }) = args.command {
+        return show_exits(config, json);
+    }
+    if let Some(Command::Status) = args.command {
+        let report = smolscale::block_on(query_health_report(config))?;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    if let Some(Command::SetExitConstraint { constraint }) = args.command {
+        return smolscale::block_on(change_exit_constraint(config, constraint));
+    }
+    let client = Client::start(config);
+    if args.tray {
+        #[cfg(all(feature = "tray", target_os = "linux"))]
+        {
+            let control = client.control_client();
+            std::thread::spawn(move || {
+                if let Err(err) = geph5_client::tray::run_tray(control) {
+                    tracing::warn!(err = debug(err), "tray icon stopped");
+                }
+            });
+        }
+        #[cfg(not(all(feature = "tray", target_os = "linux")))]
+        tracing::warn!("tray icon support is not compiled in, ignoring --tray");
+    }
+    smolscale::block_on(client.wait_until_dead())?;
+    Ok(())
+}
+
+fn load_config(path: &Path) -> anyhow::Result<Config> {
+    let config: serde_json::Value = serde_yaml::from_slice(&std::fs::read(path)?)?;
+    Ok(serde_json::from_value(config)?)
+}
+
+/// Logs to stderr and to the in-memory buffer the control protocol serves, and to the JSON log file if the config asks for one. The config's per-module levels apply on top of `RUST_LOG`.
+fn init_logging(config: Option<&Config>) -> anyhow::Result<()> {
+    let mut filter = EnvFilter::builder()
+        .with_default_directive("geph5_client=debug".parse()?)
+        .from_env_lossy();
+    let mut log_file = None;
+    if let Some(config) = config {
+        for (target, level) in config.log_filters.iter() {
+            filter = filter.add_directive(
+                format!("{target}={level}")
+                    .parse()
+                    .with_context(|| format!("invalid log filter {target}: {level}"))?,
+            );
+        }
+        if let Some(path) = &config.log_file {
+            log_file = Some(Mutex::new(RotatingFile::open(path.clone())?));
+        }
+    }
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::fmt::layer()
@@ -29,18 +188,127 @@ fn main() -> anyhow::Result<()> {
                 .compact()
                 .with_writer(|| &*LOGS),
         )
-        .with(
-            EnvFilter::builder()
-                .with_default_directive("geph5_client=debug".parse()?)
-                .from_env_lossy(),
-        )
+        .with(log_file.map(|log_file| {
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_writer(log_file)
+        }))
+        .with(filter)
         .init();
+    Ok(())
+}
 
-    let args = CliArgs::parse();
-    let config: serde_json::Value = serde_yaml::from_slice(&std::fs::read(args.config)?)?;
-    let mut config: Config = serde_json::from_value(config)?;
-    config.dry_run = args.dry_run;
-    let client = Client::start(config);
-    smolscale::block_on(client.wait_until_dead())?;
+fn show_exit_stats(config: Config) -> anyhow::Result<()> {
+    let stats = smolscale::block_on(load_exit_stats(config))?;
+    println!(
+        "{:<66} {:<8} {:<16} {:>8} {:>8} {:>10} {:>8} {:>8}",
+        "EXIT", "COUNTRY", "CITY", "ATTEMPTS", "SUCCESS", "LATENCY", "UPTIME", "SCORE"
+    );
+    for stat in stats {
+        println!(
+            "{:<66} {:<8} {:<16} {:>8} {:>7.1}% {:>10} {:>8} {:>8.3}",
+            stat.exit_key,
+            stat.country,
+            stat.city,
+            stat.attempts,
+            stat.success_rate * 100.0,
+            stat.avg_latency
+                .map(|l| format!("{}ms", l.as_millis()))
+                .unwrap_or_else(|| "-".into()),
+            stat.uptime
+                .map(|u| format!("{:.1}%", u * 100.0))
+                .unwrap_or_else(|| "-".into()),
+            stat.score,
+        );
+    }
+    Ok(())
+}
+
+fn dry_run(config: Config) -> anyhow::Result<()> {
+    let report = smolscale::block_on(diagnose(config.inert()));
+    match (&report.broker_error, report.login_time) {
+        (Some(err), _) => println!("broker:  FAILED ({err})"),
+        (None, Some(login_time)) => {
+            println!("broker:  ok, logged in in {}ms", login_time.as_millis())
+        }
+        (None, None) => println!("broker:  ok"),
+    }
+    if report.broker_error.is_none() && report.exits.is_empty() {
+        println!("exits:   none fit the exit constraint");
+    }
+    for exit in report.exits.iter() {
+        let status = match (&exit.rtt, &exit.error) {
+            (Some(rtt), _) => format!("ok, {}ms", rtt.as_millis()),
+            (None, Some(err)) => format!("FAILED ({err})"),
+            (None, None) => "FAILED".into(),
+        };
+        println!(
+            "exit:    {:<16} {:<4} {:<20} {:>5.1}%  {status}",
+            exit.fingerprint,
+            exit.country,
+            exit.city,
+            exit.load * 100.0,
+        );
+    }
+    if !report.any_reachable() {
+        eprintln!("no exit is reachable");
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn show_exits(config: Config, json: bool) -> anyhow::Result<()> {
+    let exits = smolscale::block_on(list_exits(config.inert()))?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&exits)?);
+        return Ok(());
+    }
+    println!(
+        "{:<8} {:<20} {:>6} {:<16}",
+        "COUNTRY", "CITY", "LOAD", "FINGERPRINT"
+    );
+    for (idx, exit) in exits.iter().enumerate() {
+        // a blank line between countries keeps the groups apart
+        if idx > 0 && exits[idx - 1].country != exit.country {
+            println!();
+        }
+        println!(
+            "{:<8} {:<20} {:>5.1}% {:<16}",
+            exit.country,
+            exit.city,
+            exit.load * 100.0,
+            exit.fingerprint,
+        );
+    }
     Ok(())
 }
+
+/// Suggests exit constraints while the shell completes a command line, from the exits known to the config given on that command line.
+fn complete_exit_constraint(current: &OsStr) -> Vec<CompletionCandidate> {
+    let current = current.to_string_lossy();
+    // without a config, we cannot reach the broker or the cache, so we only suggest the constraints that need no exit list
+    let candidates = match completing_config().and_then(|path| load_config(&path).ok()) {
+        Some(config) => smolscale::block_on(exit_constraint_candidates(config.inert())),
+        None => vec![ExitConstraint::Auto, ExitConstraint::Autonomous],
+    };
+    candidates
+        .iter()
+        .map(|c| c.to_string())
+        .filter(|c| c.starts_with(&*current))
+        .map(CompletionCandidate::new)
+        .collect()
+}
+
+/// The config file given on the command line being completed, which the shell passes after a `--`.
+fn completing_config() -> Option<PathBuf> {
+    let mut args = std::env::args_os().skip_while(|arg| arg != "--");
+    while let Some(arg) = args.next() {
+        if arg == "-c" || arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(path.into());
+        }
+    }
+    None
+}
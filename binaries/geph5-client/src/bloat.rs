@@ -0,0 +1,153 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use web_time::Instant;
+
+use anyctx::AnyCtx;
+use parking_lot::Mutex;
+use picomux::PicoMux;
+
+use crate::{
+    client::{Config, CtxField},
+    exit_health::ExitHealth,
+    stats::stat_get_num,
+};
+
+/// How often we probe the tunnel's latency.
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How much traffic, in bytes per probe interval, makes the tunnel count as loaded rather than idle.
+const LOADED_BYTES: f64 = 1_000_000.0;
+
+/// The smallest the proxy buffer ever gets, however much bloat we see.
+const MIN_PROXY_BUFFER_SIZE: usize = 8 * 1024;
+
+/// The current size of the buffers used to copy proxied connections, which starts out at `proxy_buffer_size`, shrinks when we detect buffer bloat, and grows back once the bloat is gone.
+static PROXY_BUFFER_SIZE: CtxField<AtomicUsize> =
+    |ctx| AtomicUsize::new(ctx.init().proxy_buffer_size.max(MIN_PROXY_BUFFER_SIZE));
+
+/// When the proxy buffers were last resized. Every tunnel runs its own monitor, and they all see the same load, so this keeps them from resizing the buffers several times over for the same measurement.
+static LAST_RESIZE: CtxField<Mutex<Option<Instant>>> = |_| Mutex::new(None);
+
+/// Gets the current size of the buffers used to copy proxied connections.
+pub fn proxy_buffer_size(ctx: &AnyCtx<Config>) -> usize {
+    ctx.get(PROXY_BUFFER_SIZE).load(Ordering::Relaxed)
+}
+
+/// Watches the tunnel for buffer bloat, and reports its pings to [ExitHealth] as well. We periodically send pings, which queue up behind any data, and take the queuing delay to be how much slower they come back when the tunnel is loaded than when it is idle. Too much queuing delay shrinks the proxy buffers, and once the delay is well below the threshold again, or the tunnel is idle, they grow back towards `proxy_buffer_size`.
+pub async fn bloat_monitor_loop(ctx: &AnyCtx<Config>, mux: &PicoMux) -> anyhow::Result<()> {
+    let threshold = Duration::from_millis(ctx.init().bloat_threshold_ms);
+    let max_size = ctx.init().proxy_buffer_size.max(MIN_PROXY_BUFFER_SIZE);
+    let mut idle_rtt: Option<Duration> = None;
+    let mut last_bytes = total_bytes(ctx);
+    let mut health = ExitHealth::new();
+    loop {
+        geph5_rt::Timer::after(PROBE_INTERVAL).await;
+        let bytes = total_bytes(ctx);
+        let loaded = bytes - last_bytes > LOADED_BYTES;
+        last_bytes = bytes;
+        let rtt = mux.ping().await?;
+        health.record_rtt(ctx, rtt);
+        let queuing_delay = if loaded {
+            // without an idle baseline yet, we cannot tell queuing from distance
+            let Some(idle_rtt) = idle_rtt else {
+                continue;
+            };
+            tracing::trace!(
+                idle_rtt = debug(idle_rtt),
+                rtt = debug(rtt),
+                "measured queuing delay under load"
+            );
+            Some(rtt.saturating_sub(idle_rtt))
+        } else {
+            idle_rtt = Some(idle_rtt.map_or(rtt, |idle| idle.min(rtt)));
+            None
+        };
+
+        let buffer_size = ctx.get(PROXY_BUFFER_SIZE);
+        let old_size = buffer_size.load(Ordering::Relaxed);
+        let new_size = resized(old_size, max_size, queuing_delay, threshold);
+        if new_size == old_size {
+            continue;
+        }
+        {
+            let mut last_resize = ctx.get(LAST_RESIZE).lock();
+            if last_resize.is_some_and(|last| last.elapsed() < PROBE_INTERVAL) {
+                continue;
+            }
+            *last_resize = Some(Instant::now());
+        }
+        buffer_size.store(new_size, Ordering::Relaxed);
+        if new_size < old_size {
+            tracing::warn!(
+                queuing_delay = debug(queuing_delay),
+                old_size,
+                new_size,
+                "buffer bloat detected, shrinking proxy buffers"
+            );
+        } else {
+            tracing::debug!(
+                old_size,
+                new_size,
+                "buffer bloat gone, growing proxy buffers"
+            );
+        }
+    }
+}
+
+/// The next size of the proxy buffers, given the queuing delay under load, or None if the tunnel is idle. Bloat halves the size, while a delay under half the threshold, or no load at all, doubles it, up to `max_size`. In between, the size stays put, so that it does not flap around the threshold.
+fn resized(
+    size: usize,
+    max_size: usize,
+    queuing_delay: Option<Duration>,
+    threshold: Duration,
+) -> usize {
+    match queuing_delay {
+        Some(delay) if delay > threshold => (size / 2).max(MIN_PROXY_BUFFER_SIZE),
+        Some(delay) if delay > threshold / 2 => size,
+        _ => (size * 2).min(max_size),
+    }
+}
+
+fn total_bytes(ctx: &AnyCtx<Config>) -> f64 {
+    stat_get_num(ctx, "total_rx_bytes") + stat_get_num(ctx, "total_tx_bytes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn shrinks_under_bloat_and_recovers() {
+        let max = 64 * 1024;
+        let bloat = Some(Duration::from_millis(200));
+        let mut size = max;
+        for expected in [32768, 16384, 8192, 8192] {
+            size = resized(size, max, bloat, THRESHOLD);
+            assert_eq!(size, expected);
+        }
+        // a delay near the threshold leaves the size alone
+        assert_eq!(
+            resized(size, max, Some(Duration::from_millis(40)), THRESHOLD),
+            size
+        );
+        size = resized(size, max, Some(Duration::from_millis(5)), THRESHOLD);
+        assert_eq!(size, 16384);
+        size = resized(size, max, None, THRESHOLD);
+        assert_eq!(size, 32768);
+        for _ in 0..3 {
+            size = resized(size, max, None, THRESHOLD);
+        }
+        assert_eq!(size, max);
+    }
+
+    #[test]
+    fn never_shrinks_below_8_kb() {
+        let bloat = Some(Duration::from_millis(200));
+        assert_eq!(resized(12 * 1024, 64 * 1024, bloat, THRESHOLD), 8 * 1024);
+        assert_eq!(resized(8 * 1024, 64 * 1024, bloat, THRESHOLD), 8 * 1024);
+    }
+}
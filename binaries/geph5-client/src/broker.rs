@@ -1,20 +1,25 @@
+#[cfg(not(target_arch = "wasm32"))]
 mod aws_lambda;
+mod fallback;
 mod fronted_http;
 mod race;
 
 use anyctx::AnyCtx;
 use anyhow::Context;
 
+#[cfg(not(target_arch = "wasm32"))]
 use aws_lambda::AwsLambdaTransport;
+use fallback::FallbackTransport;
 use fronted_http::FrontedHttpTransport;
-use geph5_broker_protocol::BrokerClient;
+use geph5_broker_protocol::{BrokerClient, BrokerFault, ExitList, MultiSigned};
 use itertools::Itertools;
 use nanorpc::DynRpcTransport;
 use race::RaceTransport;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+#[cfg(not(target_arch = "wasm32"))]
 use sillad::tcp::TcpDialer;
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
 use crate::client::{Config, CtxField};
 
@@ -34,28 +39,36 @@ pub enum BrokerSource {
         secret_access_key: String,
     },
     Race(Vec<BrokerSource>),
+    /// Tries each source in order, moving on to the next if one fails or takes more than 5 seconds, and sticks with the first that works.
+    Fallback(Vec<BrokerSource>),
 }
 
 impl BrokerSource {
     /// Converts to a RpcTransport.
     pub fn rpc_transport(&self) -> DynRpcTransport {
+        #[cfg(not(target_arch = "wasm32"))]
         let client = Client::builder().no_proxy().build().unwrap();
+        // browsers do their own proxying, out of our hands
+        #[cfg(target_arch = "wasm32")]
+        let client = Client::new();
         match self {
-            BrokerSource::Direct(s) => DynRpcTransport::new(FrontedHttpTransport {
-                url: s.clone(),
-                host: None,
-                client,
-            }),
+            BrokerSource::Direct(s) => {
+                DynRpcTransport::new(FrontedHttpTransport::new(s.clone(), None, client))
+            }
+            #[cfg(not(target_arch = "wasm32"))]
             BrokerSource::DirectTcp(dest_addr) => {
                 DynRpcTransport::new(nanorpc_sillad::DialerTransport(TcpDialer {
                     dest_addr: *dest_addr,
                 }))
             }
-            BrokerSource::Fronted { front, host } => DynRpcTransport::new(FrontedHttpTransport {
-                url: front.clone(),
-                host: Some(host.clone()),
-                client,
-            }),
+            #[cfg(target_arch = "wasm32")]
+            BrokerSource::DirectTcp(dest_addr) => DynRpcTransport::new(
+                nanorpc_sillad::DialerTransport(crate::wasm::ws_dialer(&dest_addr.to_string())),
+            ),
+            BrokerSource::Fronted { front, host } => DynRpcTransport::new(
+                FrontedHttpTransport::new(front.clone(), Some(host.clone()), client),
+            ),
+            #[cfg(not(target_arch = "wasm32"))]
             BrokerSource::AwsLambda {
                 function_name,
                 region,
@@ -67,6 +80,11 @@ impl BrokerSource {
                 access_key_id: access_key_id.clone(),
                 secret_access_key: secret_access_key.clone(),
             }),
+            // the AWS SDK brings its own HTTP stack, which cannot run in the browser
+            #[cfg(target_arch = "wasm32")]
+            BrokerSource::AwsLambda { .. } => DynRpcTransport::new(
+                nanorpc_sillad::DialerTransport(sillad::dialer::FailingDialer),
+            ),
             BrokerSource::Race(race_between) => {
                 let transports = race_between
                     .iter()
@@ -74,6 +92,22 @@ impl BrokerSource {
                     .collect_vec();
                 DynRpcTransport::new(RaceTransport::new(transports))
             }
+            BrokerSource::Fallback(fallbacks) => {
+                let transports = fallbacks.iter().map(|bs| bs.rpc_transport()).collect_vec();
+                DynRpcTransport::new(FallbackTransport::new(transports))
+            }
+        }
+    }
+
+    /// The base URL, and Host header if fronted, for streaming over plain HTTP. Only HTTP brokers can stream; for a race or fallback list, the first one that can is used.
+    pub fn sse_endpoint(&self) -> Option<(String, Option<String>)> {
+        match self {
+            BrokerSource::Direct(s) => Some((s.clone(), None)),
+            BrokerSource::Fronted { front, host } => Some((front.clone(), Some(host.clone()))),
+            BrokerSource::DirectTcp(_) | BrokerSource::AwsLambda { .. } => None,
+            BrokerSource::Race(race_between) | BrokerSource::Fallback(race_between) => {
+                race_between.iter().find_map(|bs| bs.sse_endpoint())
+            }
         }
     }
 }
@@ -84,9 +118,55 @@ pub fn broker_client(ctx: &AnyCtx<Config>) -> anyhow::Result<&BrokerClient> {
     )
 }
 
-static BROKER_CLIENT: CtxField<Option<BrokerClient>> = |ctx| {
-    ctx.init()
-        .broker
-        .as_ref()
-        .map(|src| BrokerClient::from(src.rpc_transport()))
-};
+/// Where to reach the broker: the configured `broker`, followed by each of the `broker_urls` in turn if there are any.
+pub fn broker_source(cfg: &Config) -> Option<BrokerSource> {
+    if cfg.broker_urls.is_empty() {
+        return cfg.broker.clone();
+    }
+    Some(BrokerSource::Fallback(
+        cfg.broker
+            .iter()
+            .cloned()
+            .chain(cfg.broker_urls.iter().cloned().map(BrokerSource::Direct))
+            .collect(),
+    ))
+}
+
+static BROKER_CLIENT: CtxField<Option<BrokerClient>> =
+    |ctx| broker_source(ctx.init()).map(|src| BrokerClient::from(src.rpc_transport()));
+
+/// Gets the exit list from the broker, with the descriptor fields that only newer brokers serve if the broker is new enough. The outer error is for failing to reach the broker, and the inner one for the broker's own refusal.
+pub async fn get_exits(
+    ctx: &AnyCtx<Config>,
+) -> anyhow::Result<Result<MultiSigned<ExitList>, BrokerFault>> {
+    let broker = broker_client(ctx)?;
+    let exits = match broker.get_exits_v2().await {
+        Ok(exits) => exits,
+        Err(err) => {
+            tracing::debug!(err = debug(err), "falling back to the older exit list");
+            broker.get_exits().await?
+        }
+    };
+    Ok(exits)
+}
+
+/// Turns an error from the broker into something to report, first waiting out any rate limit so that retrying immediately makes sense.
+pub async fn broker_error(what: &str, err: BrokerFault) -> anyhow::Error {
+    match err {
+        BrokerFault::RateLimited { retry_after_secs } => {
+            tracing::warn!(what, retry_after_secs, "rate limited by broker");
+            geph5_rt::Timer::after(Duration::from_secs(retry_after_secs)).await;
+            anyhow::anyhow!("broker rate limited us while getting {what}")
+        }
+        BrokerFault::InvalidCredential => {
+            anyhow::anyhow!("broker rejected our credentials while getting {what}")
+        }
+        BrokerFault::NoExitsAvailable => anyhow::anyhow!("broker has no exits available"),
+        BrokerFault::InvalidSignature => {
+            anyhow::anyhow!("broker rejected a signature while getting {what}")
+        }
+        BrokerFault::InternalError(msg) => {
+            anyhow::anyhow!("broker failed to serve {what}: {msg}")
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures_concurrency::future::FutureGroup;
+use futures_lite::FutureExt as _;
+use futures_util::StreamExt;
+use nanorpc::{DynRpcTransport, JrpcRequest, JrpcResponse, RpcTransport};
+use parking_lot::Mutex;
+
+/// How long to give one transport before also trying the next.
+const FALLBACK_DELAY: Duration = Duration::from_secs(5);
+
+/// Tries transports in order, starting the next one whenever the last one started fails or takes longer than [FALLBACK_DELAY], and remembers the first that works. Unlike [RaceTransport](super::race::RaceTransport), this does not bother the later transports at all while the first is healthy.
+pub struct FallbackTransport {
+    choices: Vec<DynRpcTransport>,
+    /// Only ever locked briefly, never across a call, so that one slow call does not hold up the others.
+    selected: Mutex<Option<usize>>,
+}
+
+impl FallbackTransport {
+    pub fn new(choices: Vec<DynRpcTransport>) -> Self {
+        Self {
+            choices,
+            selected: Default::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl RpcTransport for FallbackTransport {
+    type Error = anyhow::Error;
+
+    async fn call_raw(&self, req: JrpcRequest) -> Result<JrpcResponse, Self::Error> {
+        let selected = *self.selected.lock();
+        if let Some(idx) = selected {
+            tracing::debug!(method = &req.method, idx, "using cached working transport");
+            let res = self.choices[idx].call_raw(req).await;
+            if res.is_err() {
+                // another call may have found a working transport in the meantime
+                let mut selected = self.selected.lock();
+                if *selected == Some(idx) {
+                    *selected = None;
+                }
+            }
+            return res;
+        }
+        let mut err = None;
+        let mut future_group = FutureGroup::new();
+        let mut next = 0;
+        loop {
+            if next < self.choices.len() {
+                tracing::debug!(method = &req.method, idx = next, "trying transport");
+                let req = req.clone();
+                let choice = &self.choices[next];
+                let idx = next;
+                future_group.insert(Box::pin(async move { (idx, choice.call_raw(req).await) }));
+                next += 1;
+            }
+            // once every transport is started, there is nothing to wait for but their results
+            let result = if next < self.choices.len() {
+                future_group
+                    .next()
+                    .race(async {
+                        geph5_rt::Timer::after(FALLBACK_DELAY).await;
+                        None
+                    })
+                    .await
+            } else {
+                match future_group.next().await {
+                    Some(result) => Some(result),
+                    None => {
+                        return Err(err.unwrap_or_else(|| anyhow::anyhow!("no transports to try")))
+                    }
+                }
+            };
+            match result {
+                Some((idx, Ok(res))) => {
+                    tracing::debug!(method = &req.method, idx, "found working transport");
+                    *self.selected.lock() = Some(idx);
+                    return Ok(res);
+                }
+                Some((idx, Err(e))) => {
+                    tracing::debug!(
+                        method = &req.method,
+                        idx,
+                        err = debug(&e),
+                        "transport failed"
+                    );
+                    err = Some(e);
+                }
+                None => {
+                    tracing::debug!(method = &req.method, "transport too slow, trying the next");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use serde_json::json;
+
+    use super::*;
+
+    /// A transport that answers after a delay, or fails, counting its calls.
+    struct MockTransport {
+        delay: Duration,
+        works: Arc<AtomicBool>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl RpcTransport for MockTransport {
+        type Error = anyhow::Error;
+
+        async fn call_raw(&self, req: JrpcRequest) -> Result<JrpcResponse, Self::Error> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            smol::Timer::after(self.delay).await;
+            anyhow::ensure!(self.works.load(Ordering::SeqCst), "transport down");
+            Ok(serde_json::from_value(
+                json!({"jsonrpc": "2.0", "result": null, "id": req.id}),
+            )?)
+        }
+    }
+
+    struct Mock {
+        works: Arc<AtomicBool>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    fn mock(delay: Duration, works: bool) -> (Mock, DynRpcTransport) {
+        let works = Arc::new(AtomicBool::new(works));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let transport = DynRpcTransport::new(MockTransport {
+            delay,
+            works: works.clone(),
+            calls: calls.clone(),
+        });
+        (Mock { works, calls }, transport)
+    }
+
+    fn request() -> JrpcRequest {
+        serde_json::from_value(json!({"jsonrpc": "2.0", "method": "ping", "params": [], "id": 1}))
+            .unwrap()
+    }
+
+    #[test]
+    fn falls_back_and_remembers() {
+        smolscale::block_on(async {
+            let (first, first_transport) = mock(Duration::ZERO, false);
+            let (second, second_transport) = mock(Duration::ZERO, true);
+            let transport = FallbackTransport::new(vec![first_transport, second_transport]);
+            transport.call_raw(request()).await.unwrap();
+            transport.call_raw(request()).await.unwrap();
+            assert_eq!(first.calls.load(Ordering::SeqCst), 1);
+            assert_eq!(second.calls.load(Ordering::SeqCst), 2);
+
+            // once the remembered transport fails, every transport gets tried again
+            second.works.store(false, Ordering::SeqCst);
+            assert!(transport.call_raw(request()).await.is_err());
+            first.works.store(true, Ordering::SeqCst);
+            transport.call_raw(request()).await.unwrap();
+            assert_eq!(first.calls.load(Ordering::SeqCst), 2);
+        })
+    }
+
+    #[test]
+    fn slow_calls_do_not_block_others() {
+        smolscale::block_on(async {
+            let (_, slow_transport) = mock(Duration::from_secs(1), true);
+            let transport = Arc::new(FallbackTransport::new(vec![slow_transport]));
+            transport.call_raw(request()).await.unwrap();
+
+            let start = std::time::Instant::now();
+            let calls: Vec<_> = (0..4)
+                .map(|_| {
+                    let transport = transport.clone();
+                    smolscale::spawn(async move { transport.call_raw(request()).await })
+                })
+                .collect();
+            for call in calls {
+                call.await.unwrap();
+            }
+            // one call after another would have taken 4 seconds
+            assert!(start.elapsed() < Duration::from_secs(3));
+        })
+    }
+}
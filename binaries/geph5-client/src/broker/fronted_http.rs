@@ -1,14 +1,67 @@
-use std::time::Instant;
+use web_time::Instant;
 
 use anyhow::Context;
+use async_lock::OnceCell;
 use async_trait::async_trait;
+use geph5_broker_protocol::{VersionRange, SUPPORTED_VERSIONS};
 use nanorpc::{JrpcRequest, JrpcResponse, RpcTransport};
 use reqwest::Client;
 
 pub struct FrontedHttpTransport {
-    pub url: String,
-    pub host: Option<String>,
-    pub client: Client,
+    url: String,
+    host: Option<String>,
+    client: Client,
+    /// The negotiated API version, or None if the broker predates versioning.
+    version: OnceCell<Option<u32>>,
+}
+
+impl FrontedHttpTransport {
+    pub fn new(url: String, host: Option<String>, client: Client) -> Self {
+        Self {
+            url,
+            host,
+            client,
+            version: OnceCell::new(),
+        }
+    }
+
+    /// The URL to post requests to, negotiating the API version with the broker the first time.
+    async fn endpoint(&self) -> anyhow::Result<String> {
+        let version = self
+            .version
+            .get_or_try_init(|| {
+                geph5_rt::single_threaded(async {
+                    let mut request_builder = self
+                        .client
+                        .get(format!("{}/version", self.url.trim_end_matches('/')));
+                    if let Some(host) = &self.host {
+                        request_builder = request_builder.header("Host", host);
+                    }
+                    let response = request_builder
+                        .send()
+                        .await
+                        .context("cannot get broker version")?;
+                    if !response.status().is_success() {
+                        tracing::debug!(
+                            status = display(response.status()),
+                            "broker does not serve versions, using legacy API"
+                        );
+                        return anyhow::Ok(None);
+                    }
+                    let theirs: VersionRange = serde_json::from_slice(&response.bytes().await?)?;
+                    let version = SUPPORTED_VERSIONS
+                        .negotiate(&theirs)
+                        .with_context(|| format!("no common API version with broker {theirs:?}"))?;
+                    tracing::debug!(version, "negotiated broker API version");
+                    anyhow::Ok(Some(version))
+                })
+            })
+            .await?;
+        Ok(match version {
+            Some(version) => VersionRange::versioned_url(&self.url, *version),
+            None => self.url.clone(),
+        })
+    }
 }
 
 #[async_trait]
@@ -17,23 +70,27 @@ impl RpcTransport for FrontedHttpTransport {
     async fn call_raw(&self, req: JrpcRequest) -> Result<JrpcResponse, Self::Error> {
         tracing::trace!(method = req.method, "calling broker");
         let start = Instant::now();
-        let mut request_builder = self
-            .client
-            .post(&self.url)
-            .header("content-type", "application/json");
+        let endpoint = self.endpoint().await?;
+        let request_body = serde_json::to_vec(&req)?;
+        let resp_bytes = geph5_rt::single_threaded(async {
+            let mut request_builder = self
+                .client
+                .post(endpoint)
+                .header("content-type", "application/json");
 
-        if let Some(host) = &self.host {
-            request_builder = request_builder.header("Host", host);
-        }
+            if let Some(host) = &self.host {
+                request_builder = request_builder.header("Host", host);
+            }
 
-        let request_body = serde_json::to_vec(&req)?;
-        let response = request_builder
-            .body(request_body)
-            .send()
-            .await
-            .context("cannot send request to front")?;
+            let response = request_builder
+                .body(request_body)
+                .send()
+                .await
+                .context("cannot send request to front")?;
 
-        let resp_bytes = response.bytes().await?;
+            anyhow::Ok(response.bytes().await?)
+        })
+        .await?;
         tracing::debug!(
             method = req.method,
             resp_len = resp_bytes.len(),
@@ -0,0 +1,183 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::{AsyncRead, AsyncWrite};
+use geph5_rt::Timer;
+use parking_lot::Mutex;
+use pin_project::pin_project;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use sillad::{
+    dialer::{Dialer, DynDialer},
+    Pipe,
+};
+
+/// How long a "lost" read or write stalls for, roughly a retransmission timeout.
+const LOSS_DELAY: Duration = Duration::from_millis(200);
+
+/// PacketLossInjector wraps a dialer, producing pipes that randomly stall a fraction of reads and writes, as if the packets carrying them had been lost. This lets us test resilience to bad networks without netem.
+pub struct PacketLossInjector {
+    pub inner: DynDialer,
+    pub loss_rate: f32,
+    pub rng: Mutex<StdRng>,
+}
+
+impl Dialer for PacketLossInjector {
+    type P = LossyPipe;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let inner = self.inner.dial().await?;
+        Ok(LossyPipe {
+            inner,
+            loss_rate: self.loss_rate,
+            rng: StdRng::from_rng(&mut *self.rng.lock()).expect("StdRng cannot fail to seed"),
+            read_stall: None,
+            write_stall: None,
+        })
+    }
+}
+
+/// The pipe produced by a [PacketLossInjector].
+#[pin_project]
+pub struct LossyPipe {
+    #[pin]
+    inner: Box<dyn Pipe>,
+    loss_rate: f32,
+    rng: StdRng,
+    read_stall: Option<Timer>,
+    write_stall: Option<Timer>,
+}
+
+/// Decides whether the current call gets "lost". If so, or if we are still waiting out an earlier loss, returns Pending. Once a stall is over, the call goes through without rolling again.
+fn poll_loss(
+    stall: &mut Option<Timer>,
+    rng: &mut StdRng,
+    loss_rate: f32,
+    cx: &mut Context<'_>,
+) -> Poll<()> {
+    if stall.is_none() {
+        if rng.gen::<f32>() >= loss_rate {
+            return Poll::Ready(());
+        }
+        *stall = Some(Timer::after(LOSS_DELAY));
+    }
+    let timer = stall.as_mut().unwrap();
+    if Pin::new(timer).poll(cx).is_pending() {
+        return Poll::Pending;
+    }
+    *stall = None;
+    Poll::Ready(())
+}
+
+impl AsyncRead for LossyPipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        if poll_loss(this.read_stall, this.rng, *this.loss_rate, cx).is_pending() {
+            return Poll::Pending;
+        }
+        this.inner.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for LossyPipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        if poll_loss(this.write_stall, this.rng, *this.loss_rate, cx).is_pending() {
+            return Poll::Pending;
+        }
+        this.inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl Pipe for LossyPipe {
+    fn shared_secret(&self) -> Option<&[u8]> {
+        self.inner.shared_secret()
+    }
+
+    fn protocol(&self) -> &str {
+        self.inner.protocol()
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.inner.remote_addr()
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        self.inner.raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use futures_util::{task::noop_waker_ref, AsyncReadExt as _, AsyncWriteExt as _};
+    use sillad::{dialer::DialerExt as _, tcp::TcpDialer};
+
+    use super::*;
+
+    /// How many of 1000 calls a fresh pipe seeded this way would stall.
+    fn stalls(seed: u64, loss_rate: f32) -> usize {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        (0..1000)
+            .filter(|_| poll_loss(&mut None, &mut rng, loss_rate, &mut cx).is_pending())
+            .count()
+    }
+
+    #[test]
+    fn seeded_losses_are_reproducible() {
+        assert_eq!(stalls(8964, 0.3), stalls(8964, 0.3));
+        assert!((250..350).contains(&stalls(8964, 0.3)));
+        assert_eq!(stalls(8964, 0.0), 0);
+        assert_eq!(stalls(8964, 1.0), 1000);
+    }
+
+    #[test]
+    fn lost_calls_are_delayed_not_dropped() {
+        smolscale::block_on(async {
+            let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let injector = PacketLossInjector {
+                inner: TcpDialer {
+                    dest_addr: listener.local_addr().unwrap(),
+                }
+                .dynamic(),
+                loss_rate: 1.0,
+                rng: Mutex::new(StdRng::seed_from_u64(8964)),
+            };
+            let start = Instant::now();
+            let (dialed, accepted) = futures_util::join!(injector.dial(), listener.accept());
+            let mut pipe = dialed.unwrap();
+            let (mut server, _) = accepted.unwrap();
+            pipe.write_all(b"hello").await.unwrap();
+            server.write_all(b"world").await.unwrap();
+            let mut buf = [0u8; 5];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            pipe.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"world");
+            // both the write and the read were lost once
+            assert!(start.elapsed() >= LOSS_DELAY * 2);
+        });
+    }
+}
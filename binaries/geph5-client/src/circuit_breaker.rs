@@ -0,0 +1,299 @@
+use std::{collections::HashMap, future::Future, hash::Hash, net::SocketAddr, time::Duration};
+use web_time::Instant;
+
+use anyctx::AnyCtx;
+use anyhow::Context as _;
+use geph5_broker_protocol::{ExitList, MultiSigned, RouteDescriptor};
+use geph5_rt::TimeoutExt as _;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::client::{Config, CtxField};
+
+/// How long a broker call may take before it counts as a failure, so that a slow broker trips the breaker just like a dead one.
+const BROKER_CALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Guards fetching the exit list from the broker.
+pub static EXITS_BREAKER: CtxField<CircuitBreaker<(), MultiSigned<ExitList>>> =
+    |_| CircuitBreaker::new("exits");
+
+/// Guards fetching the bridge routes to each exit from the broker.
+pub static ROUTES_BREAKER: CtxField<CircuitBreaker<SocketAddr, RouteDescriptor>> =
+    |_| CircuitBreaker::new("routes");
+
+/// Whether a [CircuitBreaker] lets calls through.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Calls go through as usual.
+    Closed,
+    /// Calls failed too many times in a row, so for now they are answered from the cache.
+    Open,
+    /// The cooldown is over, so one call at a time goes through to see if the broker is back, and a single failure opens the circuit once more.
+    HalfOpen,
+}
+
+/// The state of a [CircuitBreaker], for the metrics endpoint.
+#[derive(Serialize, Clone, Debug)]
+pub struct CircuitStatus {
+    pub name: &'static str,
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+}
+
+/// Stops calling the broker once a call has failed several times in a row, answering from the results of the last successful calls instead until a cooldown has passed, so that a struggling broker does not stall every connection attempt.
+pub struct CircuitBreaker<K, T> {
+    name: &'static str,
+    inner: Mutex<BreakerInner<K, T>>,
+}
+
+struct BreakerInner<K, T> {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    /// Whether a call is already finding out if the broker is back, while half-open.
+    probing: bool,
+    last_success: HashMap<K, T>,
+}
+
+fn state_of<K, T>(inner: &BreakerInner<K, T>, cooldown: Duration) -> CircuitState {
+    match inner.opened_at {
+        None => CircuitState::Closed,
+        Some(opened_at) if opened_at.elapsed() < cooldown => CircuitState::Open,
+        Some(_) => CircuitState::HalfOpen,
+    }
+}
+
+/// Lets the next call probe the broker once this one is done, even if it was cancelled.
+struct ProbeGuard<'a, K, T>(&'a Mutex<BreakerInner<K, T>>);
+
+impl<K, T> Drop for ProbeGuard<'_, K, T> {
+    fn drop(&mut self) {
+        self.0.lock().probing = false;
+    }
+}
+
+impl<K: Hash + Eq, T: Clone> CircuitBreaker<K, T> {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            inner: Mutex::new(BreakerInner {
+                consecutive_failures: 0,
+                opened_at: None,
+                probing: false,
+                last_success: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Makes a broker call through the breaker, falling back to the last successful result for the same key while the circuit is open. The call resolves to the broker's own answer inside a transport result, and only transport failures count against the broker, since a broker that answers with an error is still up.
+    pub async fn call<E>(
+        &self,
+        ctx: &AnyCtx<Config>,
+        key: K,
+        call: impl Future<Output = anyhow::Result<Result<T, E>>>,
+    ) -> anyhow::Result<Result<T, E>> {
+        self.call_with(
+            ctx.init().broker_breaker_threshold,
+            Duration::from_secs(ctx.init().broker_breaker_cooldown_secs),
+            key,
+            call,
+        )
+        .await
+    }
+
+    async fn call_with<E>(
+        &self,
+        threshold: u32,
+        cooldown: Duration,
+        key: K,
+        call: impl Future<Output = anyhow::Result<Result<T, E>>>,
+    ) -> anyhow::Result<Result<T, E>> {
+        let probe = {
+            let mut inner = self.inner.lock();
+            match state_of(&inner, cooldown) {
+                CircuitState::Closed => None,
+                // while one call probes whether the broker is back, the rest wait it out like when open
+                CircuitState::HalfOpen if !inner.probing => {
+                    inner.probing = true;
+                    Some(ProbeGuard(&self.inner))
+                }
+                _ => {
+                    drop(inner);
+                    return self.cached(&key).map(Ok);
+                }
+            }
+        };
+        let result = call
+            .timeout(BROKER_CALL_TIMEOUT)
+            .await
+            .context("broker call timed out")
+            .and_then(|r| r);
+        drop(probe);
+        let mut inner = self.inner.lock();
+        match result {
+            Ok(answer) => {
+                if inner.opened_at.is_some() {
+                    tracing::info!(name = self.name, "broker circuit closed again");
+                }
+                inner.consecutive_failures = 0;
+                inner.opened_at = None;
+                if let Ok(value) = &answer {
+                    inner.last_success.insert(key, value.clone());
+                }
+                Ok(answer)
+            }
+            Err(err) => {
+                inner.consecutive_failures += 1;
+                if inner.opened_at.is_some() || inner.consecutive_failures >= threshold {
+                    tracing::warn!(
+                        name = self.name,
+                        failures = inner.consecutive_failures,
+                        err = debug(&err),
+                        "broker circuit opened"
+                    );
+                    inner.opened_at = Some(Instant::now());
+                    drop(inner);
+                    return self.cached(&key).map(Ok).map_err(|_| err);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// The current state of the breaker.
+    pub fn status(&self, ctx: &AnyCtx<Config>) -> CircuitStatus {
+        let inner = self.inner.lock();
+        CircuitStatus {
+            name: self.name,
+            state: state_of(
+                &inner,
+                Duration::from_secs(ctx.init().broker_breaker_cooldown_secs),
+            ),
+            consecutive_failures: inner.consecutive_failures,
+        }
+    }
+
+    fn cached(&self, key: &K) -> anyhow::Result<T> {
+        self.inner
+            .lock()
+            .last_success
+            .get(key)
+            .cloned()
+            .with_context(|| {
+                format!(
+                    "broker circuit for {} is open, and nothing is cached",
+                    self.name
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::pin;
+
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_secs(3600);
+
+    type Breaker = CircuitBreaker<(), u32>;
+
+    async fn succeed(breaker: &Breaker, cooldown: Duration, value: u32) {
+        let answer = breaker
+            .call_with(3, cooldown, (), async { Ok(Ok::<_, ()>(value)) })
+            .await;
+        assert_eq!(answer.unwrap(), Ok(value));
+    }
+
+    async fn fail(breaker: &Breaker, cooldown: Duration) -> anyhow::Result<Result<u32, ()>> {
+        breaker
+            .call_with(3, cooldown, (), async {
+                anyhow::bail!("connection refused")
+            })
+            .await
+    }
+
+    fn state(breaker: &Breaker, cooldown: Duration) -> CircuitState {
+        state_of(&breaker.inner.lock(), cooldown)
+    }
+
+    #[test]
+    fn opens_after_transport_failures() {
+        smol::future::block_on(async {
+            let breaker = Breaker::new("test");
+            assert!(fail(&breaker, COOLDOWN).await.is_err());
+            succeed(&breaker, COOLDOWN, 1).await;
+            for _ in 0..2 {
+                assert!(fail(&breaker, COOLDOWN).await.is_err());
+            }
+            assert_eq!(state(&breaker, COOLDOWN), CircuitState::Closed);
+            // the third failure in a row opens the circuit, and the cache answers from then on
+            assert_eq!(fail(&breaker, COOLDOWN).await.unwrap(), Ok(1));
+            assert_eq!(state(&breaker, COOLDOWN), CircuitState::Open);
+            let answer = breaker
+                .call_with(3, COOLDOWN, (), async {
+                    panic!("called the broker while open")
+                })
+                .await;
+            assert_eq!(answer.unwrap(), Ok::<_, ()>(1));
+        })
+    }
+
+    #[test]
+    fn broker_faults_are_not_failures() {
+        smol::future::block_on(async {
+            let breaker = Breaker::new("test");
+            for _ in 0..10 {
+                let answer = breaker
+                    .call_with(3, COOLDOWN, (), async { Ok(Err("no exits available")) })
+                    .await;
+                assert_eq!(answer.unwrap(), Err("no exits available"));
+            }
+            assert_eq!(state(&breaker, COOLDOWN), CircuitState::Closed);
+            assert_eq!(breaker.inner.lock().consecutive_failures, 0);
+            // nor are they cached
+            assert!(breaker.cached(&()).is_err());
+        })
+    }
+
+    #[test]
+    fn half_open_lets_one_probe_through() {
+        smol::future::block_on(async {
+            let breaker = Breaker::new("test");
+            succeed(&breaker, Duration::ZERO, 1).await;
+            for _ in 0..3 {
+                fail(&breaker, Duration::ZERO).await.unwrap();
+            }
+            assert_eq!(state(&breaker, Duration::ZERO), CircuitState::HalfOpen);
+
+            let (send, recv) = smol::channel::bounded(1);
+            let mut probe = pin!(breaker.call_with(3, Duration::ZERO, (), async {
+                Ok(Ok::<_, ()>(recv.recv().await?))
+            }));
+            assert!(futures_util::poll!(probe.as_mut()).is_pending());
+            // everyone else gets the cache while the probe is out
+            let answer = breaker
+                .call_with(3, Duration::ZERO, (), async { panic!("a second probe") })
+                .await;
+            assert_eq!(answer.unwrap(), Ok::<_, ()>(1));
+
+            send.send(2).await.unwrap();
+            assert_eq!(probe.await.unwrap(), Ok(2));
+            assert_eq!(state(&breaker, Duration::ZERO), CircuitState::Closed);
+        })
+    }
+
+    #[test]
+    fn failed_probe_reopens() {
+        smol::future::block_on(async {
+            let breaker = Breaker::new("test");
+            for _ in 0..3 {
+                assert!(fail(&breaker, Duration::ZERO).await.is_err());
+            }
+            // a single failure while half-open is enough
+            assert!(fail(&breaker, Duration::ZERO).await.is_err());
+            assert_eq!(state(&breaker, COOLDOWN), CircuitState::Open);
+            assert!(!breaker.inner.lock().probing);
+        })
+    }
+}
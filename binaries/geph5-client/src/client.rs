@@ -3,64 +3,328 @@ use anyctx::AnyCtx;
 use anyhow::Context;
 use bytes::Bytes;
 use clone_macro::clone;
+use ed25519_dalek::VerifyingKey;
+use futures_lite::FutureExt as _;
 use futures_util::{future::Shared, task::noop_waker, FutureExt, TryFutureExt};
 use geph5_broker_protocol::{Credential, ExitList, UserInfo};
+use isocountry::CountryCode;
 use nanorpc::DynRpcTransport;
+use rand::Rng;
 use sillad::Pipe;
-use smol::future::FutureExt as _;
-use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use serde::{Deserialize, Serialize};
-use smolscale::immortal::{Immortal, RespawnStrategy};
+use url::Url;
 
 use crate::{
     auth::{auth_loop, get_auth_token},
-    broker::{broker_client, BrokerSource},
+    broker::{broker_client, broker_error, BrokerSource},
     client_inner::{client_once, open_conn},
     control_prot::{
         ControlClient, ControlProtocolImpl, ControlService, DummyControlProtocolTransport,
     },
     database::db_read_or_wait,
+    exit_stream::exit_stream_loop,
+    key_transparency::check_key_transparency,
+    route::{restore_route_shitlist, route_penalty_decay_loop, ExitConstraint},
+    vpn::{recv_vpn_packet, send_vpn_packet, vpn_loop, AppRoute, DnsRoute, SplitTunnel},
+};
+#[cfg(unix)]
+use crate::{coalesce::coalesce_loop, control_datagram::control_datagram_loop};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{
+    crash::install_crash_hook,
+    dns_server::dns_server_loop,
     http_proxy::run_http_proxy,
-    route::ExitConstraint,
+    metrics::metrics_loop,
+    multi_user::check_multi_user_config,
+    proxy_detect::capture_env_proxy,
     socks5::socks5_loop,
-    vpn::{recv_vpn_packet, send_vpn_packet, vpn_loop},
+    wireguard::{wireguard_loop, WireguardConfig},
 };
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
     pub socks5_listen: Option<SocketAddr>,
     pub http_proxy_listen: Option<SocketAddr>,
+    /// Serve DNS here, over UDP and TCP, resolving every query through the exit
+    #[serde(default)]
+    pub dns_listen: Option<SocketAddr>,
+    /// Let other hosts on the LAN use the local proxies, which then listen on all interfaces, at the ports of `socks5_listen` and `http_proxy_listen`, with traffic rate-limited by source address. Users must authenticate if `auth_map` has any logins
+    #[serde(default)]
+    pub multi_user: bool,
+    #[serde(default = "default_multi_user_ratelimit")]
+    pub multi_user_ratelimit: u32,
+    /// The account level, `plus` or `free`, of each login to the local proxies, keyed by `username:password`. The password may instead be an Argon2 hash of it in the PHC string format, starting with `$argon2`, to keep it out of the config. Any logins here make both proxies ask for credentials, but outside multi-user mode the account level makes no difference
+    #[serde(default)]
+    pub auth_map: HashMap<String, String>,
+    /// The size of the buffers used to copy proxied connections. They shrink, down to 8 KB, while buffer bloat is detected
+    #[serde(default = "default_proxy_buffer_size")]
+    pub proxy_buffer_size: usize,
+    #[serde(default = "default_bloat_threshold_ms")]
+    pub bloat_threshold_ms: u64,
 
     pub control_listen: Option<SocketAddr>,
+    /// Serve a JSON snapshot of connection attempts, deprioritized routes, and the current exit over HTTP here
+    #[serde(default)]
+    pub metrics_listen: Option<SocketAddr>,
+    #[serde(default)]
+    pub control_listen_unix: Option<PathBuf>,
     pub exit_constraint: ExitConstraint,
+    /// Fail with [ExitConstraintUnsatisfied](crate::route::ExitConstraintUnsatisfied) when no exit satisfies a country, city, or hostname constraint, instead of silently using any exit
+    #[serde(default = "default_strict_country")]
+    pub strict_country: bool,
     #[serde(default)]
     pub bridge_mode: BridgeMode,
+    /// How randomly to pick among exits by load. Lower values favor the least loaded exits more strongly, and zero always picks the least loaded one.
+    #[serde(default = "default_exit_load_temperature")]
+    pub exit_load_temperature: f64,
+    /// Once connected to an exit, prefer it in later exit selections, as long as it stays reachable and its load stays under `pin_exit_max_load`. The pin is persisted next to the route shitlist, if that is persisted.
+    #[serde(default)]
+    pub pin_exit: bool,
+    #[serde(default = "default_pin_exit_max_load")]
+    pub pin_exit_max_load: f32,
+    /// Connect to this many exits at once, all satisfying the exit constraint, spreading new connections round-robin across them
+    #[serde(default)]
+    pub multi_exit: Option<usize>,
+    /// Cap uploads to the exits, across all connections, at this many kilobits per second
+    #[serde(default)]
+    pub upload_limit_kbps: Option<u64>,
+    /// Share one exit connection among all clients on this machine configured with the same Unix socket path (Unix only)
+    #[serde(default)]
+    pub coalesce_socket: Option<PathBuf>,
     pub cache: Option<PathBuf>,
+    /// Where to persist recently failed routes, so that a restarted client does not immediately retry them
+    #[serde(default)]
+    pub route_shitlist_path: Option<PathBuf>,
+    /// How often the penalties of recently failed routes decay
+    #[serde(default = "default_route_penalty_decay_interval_secs")]
+    pub route_penalty_decay_interval_secs: u64,
+    /// How long it takes for the penalty of a failed route to halve, if it does not fail again
+    #[serde(default = "default_route_penalty_half_life_secs")]
+    pub route_penalty_half_life_secs: u64,
 
     pub broker: Option<BrokerSource>,
+    /// More broker URLs to fall back to, in order, in case `broker` is blocked or down
+    #[serde(default)]
+    pub broker_urls: Vec<String>,
     pub broker_keys: Option<BrokerKeys>,
+    /// Get exit list updates pushed over a server-sent event stream, falling back to polling when the stream is unavailable
+    #[serde(default)]
+    pub use_sse: bool,
+    #[serde(default)]
+    pub upstream_proxy: Option<Url>,
+    /// When no upstream proxy is configured, also look for one through WPAD, which asks the local network for `http://wpad/wpad.dat`. Off by default, since whoever answers for that name gets to choose our proxy.
+    #[serde(default)]
+    pub wpad: bool,
+    /// Only in the browser, which cannot open TCP connections itself: the WebSocket relay that opens them for us, with `{addr}` standing for the destination, as in `wss://relay.example.com/{addr}`
+    #[serde(default)]
+    pub ws_relay: Option<String>,
+    /// The DNS-over-HTTPS endpoint for looking up exit and bridge hostnames, so that those lookups do not go through the system resolver. Null uses the system resolver.
+    #[serde(default = "default_doh_url")]
+    pub doh_url: Option<String>,
+    #[serde(default)]
+    pub client_country: Option<CountryCode>,
 
     #[serde(default)]
     pub vpn: bool,
     #[serde(default)]
+    pub per_app_routing: Vec<AppRoute>,
+    #[serde(default)]
+    pub vpn_dns_leak_prevention: bool,
+    #[serde(default)]
+    pub vpn_ipv6: bool,
+    #[serde(default = "default_auto_lan_bypass")]
+    pub auto_lan_bypass: bool,
+    #[serde(default)]
+    pub dns_routing: Vec<DnsRoute>,
+    /// Only tunnel VPN traffic to some networks, sending the rest through the default gateway. Null tunnels everything. Only on Linux and Windows, and on Linux, DNS goes to the system resolver rather than into the tunnel once `include` is set.
+    #[serde(default)]
+    pub split_tunnel: Option<SplitTunnel>,
+    /// Act as a WireGuard peer for a stock WireGuard client, tunneling whatever it sends. Only without `vpn`, which would take the packets for this machine instead.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[serde(default)]
+    pub wireguard: Option<WireguardConfig>,
+    /// The LAN's DNS server, for names that dns_routing resolves locally. Local rules do nothing without it, since in VPN mode every query is redirected into the tunnel before we see it.
+    #[serde(default)]
+    pub local_dns: Option<SocketAddr>,
+    /// Claim membership in a tenant of multi-tenant exits
+    #[serde(default)]
+    pub tenant: Option<TenantCredential>,
+    /// Ask exits to pad and re-chunk the encrypted tunnel into fixed-size cells, hiding the sizes of the frames inside
+    #[serde(default)]
+    pub obfuscate_frames: bool,
+    #[serde(default)]
     pub spoof_dns: bool,
     #[serde(default)]
     pub passthrough_china: bool,
     #[serde(default)]
     pub dry_run: bool,
     #[serde(default)]
+    pub chaos_loss: f32,
+    #[serde(default)]
+    pub dialer_pool_size: usize,
+    #[serde(default)]
+    pub watch_mode: bool,
+    #[serde(default = "default_policy_check_interval_secs")]
+    pub policy_check_interval_secs: u64,
+    #[serde(default)]
     pub credentials: Credential,
+
+    #[serde(default)]
+    pub key_transparency_url: Option<String>,
+    #[serde(default = "default_key_log_grace_secs")]
+    pub key_log_grace_secs: u64,
+
+    #[serde(default = "default_handshake_timeout_ms")]
+    pub handshake_timeout_ms: u64,
+    /// How many broker calls in a row may fail before we stop making them for a while, and make do with what the last successful ones returned
+    #[serde(default = "default_broker_breaker_threshold")]
+    pub broker_breaker_threshold: u32,
+    /// How long to stop making broker calls for, once too many in a row have failed
+    #[serde(default = "default_broker_breaker_cooldown_secs")]
+    pub broker_breaker_cooldown_secs: u64,
+    /// How often to ping the exit to keep an idle tunnel from being dropped by NAT boxes along the way. The exit pings us at least as often.
+    #[serde(default = "default_keepalive_interval_secs")]
+    pub keepalive_interval_secs: u64,
+    /// How long to wait for the exit to answer a keepalive ping before giving up the tunnel as dead
+    #[serde(default = "default_keepalive_timeout_secs")]
+    pub keepalive_timeout_secs: u64,
+    #[serde(default = "default_key_transparency_timeout_ms")]
+    pub key_transparency_timeout_ms: u64,
+
+    #[serde(default)]
+    pub crash_reporting: bool,
+    #[serde(default)]
+    pub crash_endpoint: Option<String>,
+
+    /// Log levels for particular modules, keyed by target as in `RUST_LOG`, e.g. `geph5_client::route: debug`. They take precedence over `RUST_LOG`.
+    #[serde(default)]
+    pub log_filters: HashMap<String, String>,
+    /// Where to also write logs, as JSON lines. The file is rotated whenever it grows past 10 MB.
+    #[serde(default)]
+    pub log_file: Option<PathBuf>,
+
+    /// Where to append the session keys of every exit connection, in NSS key log format. Only for debugging, so release builds do not have it.
+    #[cfg(debug_assertions)]
+    #[serde(default)]
+    pub keylog_file: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+/// A tenant ID, with an ed25519 secret key in hexadecimal format that the tenant's exits accept.
+pub struct TenantCredential {
+    pub id: String,
+    pub secret_key: String,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 /// Broker keys, in hexadecimal format.
 pub struct BrokerKeys {
     pub master: String,
+    /// Other broker signing keys that we trust alongside the master key.
+    #[serde(default)]
+    pub extra: Vec<String>,
+    /// How many of the trusted keys must have signed the exit list.
+    #[serde(default = "default_threshold")]
+    pub threshold: usize,
     pub mizaru_free: String,
     pub mizaru_plus: String,
 }
 
+fn default_auto_lan_bypass() -> bool {
+    true
+}
+
+fn default_doh_url() -> Option<String> {
+    Some("https://1.1.1.1/dns-query".into())
+}
+
+fn default_pin_exit_max_load() -> f32 {
+    0.8
+}
+
+fn default_strict_country() -> bool {
+    true
+}
+
+fn default_exit_load_temperature() -> f64 {
+    0.1
+}
+
+fn default_broker_breaker_threshold() -> u32 {
+    3
+}
+
+fn default_broker_breaker_cooldown_secs() -> u64 {
+    30
+}
+
+fn default_keepalive_interval_secs() -> u64 {
+    120
+}
+
+fn default_keepalive_timeout_secs() -> u64 {
+    10
+}
+
+fn default_route_penalty_decay_interval_secs() -> u64 {
+    60
+}
+
+fn default_route_penalty_half_life_secs() -> u64 {
+    60
+}
+
+fn default_threshold() -> usize {
+    1
+}
+
+fn default_multi_user_ratelimit() -> u32 {
+    2000
+}
+
+fn default_proxy_buffer_size() -> usize {
+    64 * 1024
+}
+
+fn default_bloat_threshold_ms() -> u64 {
+    50
+}
+
+fn default_policy_check_interval_secs() -> u64 {
+    300
+}
+
+fn default_key_log_grace_secs() -> u64 {
+    86400 * 3
+}
+
+fn default_handshake_timeout_ms() -> u64 {
+    15000
+}
+
+fn default_key_transparency_timeout_ms() -> u64 {
+    30000
+}
+
+impl BrokerKeys {
+    /// Decodes the trusted broker signing keys, master key first.
+    pub fn trusted_keys(&self) -> anyhow::Result<Vec<VerifyingKey>> {
+        std::iter::once(&self.master)
+            .chain(self.extra.iter())
+            .map(|key| {
+                let bts: [u8; 32] = hex::decode(key)
+                    .context("cannot decode trusted key as hex")?
+                    .try_into()
+                    .ok()
+                    .context("trusted key wrong length")?;
+                Ok(VerifyingKey::from_bytes(&bts)?)
+            })
+            .collect()
+    }
+}
+
 impl Config {
     /// Create an "inert" version of this config that does not start any processes.
     pub fn inert(&self) -> Self {
@@ -68,8 +332,15 @@ impl Config {
         this.dry_run = true;
         this.socks5_listen = None;
         this.http_proxy_listen = None;
+        this.dns_listen = None;
 
         this.control_listen = None;
+        this.control_listen_unix = None;
+        this.coalesce_socket = None;
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            this.wireguard = None;
+        }
         this
     }
 }
@@ -79,6 +350,12 @@ pub enum BridgeMode {
     Auto,
     ForceBridges,
     ForceDirect,
+    /// Connects directly until the direct path has failed `threshold` times, and from then on behaves like [BridgeMode::Auto].
+    SmartBridges {
+        threshold: u32,
+    },
+    /// Connects both directly and through bridges at the same time, handing each new stream to whichever path has the shorter queue, and only to the survivor while one of them is down.
+    Multipath,
 }
 
 impl Default for BridgeMode {
@@ -87,20 +364,30 @@ impl Default for BridgeMode {
     }
 }
 
+#[derive(Clone)]
 pub struct Client {
-    task: Shared<smol::Task<Result<(), Arc<anyhow::Error>>>>,
+    task: Shared<geph5_rt::Task<Result<(), Arc<anyhow::Error>>>>,
     ctx: AnyCtx<Config>,
 }
 
 impl Client {
     /// Starts the client logic in the loop, returning the handle.
     pub fn start(cfg: Config) -> Self {
-        std::env::remove_var("http_proxy");
-        std::env::remove_var("https_proxy");
-        std::env::remove_var("HTTP_PROXY");
-        std::env::remove_var("HTTPS_PROXY");
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            capture_env_proxy();
+            std::env::remove_var("http_proxy");
+            std::env::remove_var("https_proxy");
+            std::env::remove_var("HTTP_PROXY");
+            std::env::remove_var("HTTPS_PROXY");
+            install_crash_hook(&cfg);
+        }
+        #[cfg(target_arch = "wasm32")]
+        if let Some(relay) = &cfg.ws_relay {
+            crate::wasm::set_ws_relay(relay.clone());
+        }
         let ctx = AnyCtx::new(cfg);
-        let task = smolscale::spawn(client_main(ctx.clone()).map_err(Arc::new));
+        let task = geph5_rt::spawn(client_main(ctx.clone()).map_err(Arc::new));
         Client {
             task: task.shared(),
             ctx,
@@ -174,14 +461,34 @@ async fn client_main(ctx: AnyCtx<Config>) -> anyhow::Result<()> {
 
     tracing::info!("loaded config: {}", serde_yaml::to_string(ctx.init())?);
 
+    if let Err(err) = restore_route_shitlist(&ctx) {
+        tracing::warn!(err = debug(err), "could not restore route shitlist");
+    }
+
+    check_key_transparency(&ctx)
+        .await
+        .context("refusing to connect, broker key failed the key transparency check")?;
+    #[cfg(not(target_arch = "wasm32"))]
+    check_multi_user_config(&ctx).context("invalid auth_map")?;
+    // a zero limit would stall every upload forever
+    anyhow::ensure!(
+        ctx.init().upload_limit_kbps != Some(0),
+        "upload_limit_kbps must be positive, or left out for no limit"
+    );
+    // a zero interval would re-check the exit in a tight loop
+    anyhow::ensure!(
+        !ctx.init().watch_mode || ctx.init().policy_check_interval_secs > 0,
+        "policy_check_interval_secs must be positive"
+    );
+
     if ctx.init().dry_run {
         auth_loop(&ctx)
             .race(async {
                 let broker_client = broker_client(&ctx)?;
-                let exits = broker_client
-                    .get_exits()
-                    .await?
-                    .map_err(|e| anyhow::anyhow!("{e}"))?;
+                let exits = match broker_client.get_exits().await? {
+                    Ok(exits) => exits,
+                    Err(err) => return Err(broker_error("exits", err).await),
+                };
                 let auth_token = db_read_or_wait(&ctx, "auth_token").await?;
                 let exits = exits.inner;
                 println!(
@@ -197,38 +504,88 @@ async fn client_main(ctx: AnyCtx<Config>) -> anyhow::Result<()> {
     } else {
         let vpn_loop = vpn_loop(&ctx);
 
-        let _client_loop = Immortal::respawn(
-            RespawnStrategy::JitterDelay(Duration::from_secs(1), Duration::from_secs(5)),
-            clone!([ctx], move || client_once(ctx.clone()).inspect_err(
-                |e| tracing::warn!("client died and restarted: {:?}", e)
-            )),
-        );
-
-        let rpc_serve = async {
-            if let Some(control_listen) = ctx.init().control_listen {
-                nanorpc_sillad::rpc_serve(
-                    sillad::tcp::TcpListener::bind(control_listen).await?,
-                    ControlService(ControlProtocolImpl { ctx: ctx.clone() }),
-                )
-                .await?;
-                anyhow::Ok(())
-            } else {
-                smol::future::pending().await
+        let _client_loop = geph5_rt::spawn(clone!([ctx], async move {
+            loop {
+                if let Err(e) = client_once(ctx.clone()).await {
+                    tracing::warn!("client died and restarted: {:?}", e);
+                }
+                let jitter = rand::thread_rng().gen_range(1.0..5.0);
+                geph5_rt::Timer::after(Duration::from_secs_f64(jitter)).await;
             }
-        };
-
-        socks5_loop(&ctx)
-            .inspect_err(|e| tracing::error!(err = debug(e), "socks5 loop stopped"))
-            .race(vpn_loop.inspect_err(|e| tracing::error!(err = debug(e), "vpn loop stopped")))
-            .race(
-                run_http_proxy(&ctx)
-                    .inspect_err(|e| tracing::error!(err = debug(e), "http proxy stopped")),
-            )
-            .race(
-                auth_loop(&ctx)
-                    .inspect_err(|e| tracing::error!(err = debug(e), "auth loop stopped")),
-            )
-            .race(rpc_serve)
-            .await
+        }));
+
+        // the browser gives us nothing to serve the proxies or the control protocol on, so all there is to run is the tunnel
+        #[cfg(target_arch = "wasm32")]
+        {
+            vpn_loop
+                .inspect_err(|e| tracing::error!(err = debug(e), "vpn loop stopped"))
+                .race(
+                    auth_loop(&ctx)
+                        .inspect_err(|e| tracing::error!(err = debug(e), "auth loop stopped")),
+                )
+                .race(
+                    exit_stream_loop(&ctx)
+                        .inspect_err(|e| tracing::error!(err = debug(e), "exit stream stopped")),
+                )
+                .race(route_penalty_decay_loop(&ctx))
+                .await
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let rpc_serve = async {
+                if let Some(control_listen) = ctx.init().control_listen {
+                    nanorpc_sillad::rpc_serve(
+                        sillad::tcp::TcpListener::bind(control_listen).await?,
+                        ControlService(ControlProtocolImpl { ctx: ctx.clone() }),
+                    )
+                    .await?;
+                    anyhow::Ok(())
+                } else {
+                    futures_lite::future::pending().await
+                }
+            };
+            #[cfg(unix)]
+            let rpc_serve = rpc_serve.race(control_datagram_loop(&ctx));
+
+            socks5_loop(&ctx)
+                .inspect_err(|e| tracing::error!(err = debug(e), "socks5 loop stopped"))
+                .race(vpn_loop.inspect_err(|e| tracing::error!(err = debug(e), "vpn loop stopped")))
+                .race(
+                    wireguard_loop(&ctx)
+                        .inspect_err(|e| tracing::error!(err = debug(e), "WireGuard loop stopped")),
+                )
+                .race(
+                    dns_server_loop(&ctx)
+                        .inspect_err(|e| tracing::error!(err = debug(e), "DNS server stopped")),
+                )
+                .race(
+                    run_http_proxy(&ctx)
+                        .inspect_err(|e| tracing::error!(err = debug(e), "http proxy stopped")),
+                )
+                .race(
+                    auth_loop(&ctx)
+                        .inspect_err(|e| tracing::error!(err = debug(e), "auth loop stopped")),
+                )
+                .race(
+                    metrics_loop(&ctx)
+                        .inspect_err(|e| tracing::error!(err = debug(e), "metrics server stopped")),
+                )
+                .race(
+                    exit_stream_loop(&ctx)
+                        .inspect_err(|e| tracing::error!(err = debug(e), "exit stream stopped")),
+                )
+                .race(route_penalty_decay_loop(&ctx))
+                .race(rpc_serve)
+                .race(async {
+                    #[cfg(unix)]
+                    coalesce_loop(&ctx)
+                        .inspect_err(|e| {
+                            tracing::error!(err = debug(e), "coalescing socket stopped")
+                        })
+                        .await?;
+                    futures_lite::future::pending().await
+                })
+                .await
+        }
     }
 }
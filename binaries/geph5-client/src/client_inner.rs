@@ -2,45 +2,73 @@ use anyctx::AnyCtx;
 use anyhow::Context;
 use bytes::Bytes;
 use clone_macro::clone;
-use ed25519_dalek::VerifyingKey;
-use futures_util::{future::try_join_all, AsyncReadExt as _};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use futures_lite::FutureExt as _;
+use futures_util::{
+    future::{select_ok, try_join_all},
+    AsyncReadExt as _, AsyncWriteExt as _,
+};
 use geph5_broker_protocol::ExitDescriptor;
 use geph5_misc_rpc::{
-    exit::{ClientCryptHello, ClientExitCryptPipe, ClientHello, ExitHello, ExitHelloInner},
+    exit::{
+        exit_x25519_public, probe_magic, resumed_shared_secret, resumption_secret,
+        ClientCryptHello, ClientExitCryptPipe, ClientHello, ExitHello, ExitHelloInner,
+        HelloTimestamp, Keepalive, PresentedTicket, SessionResumption, TenantClaim, EXT_KEEPALIVE,
+        EXT_OBFUSCATE_FRAMES, EXT_SESSION_RESUMPTION, EXT_TENANT, EXT_TIMESTAMP,
+    },
+    obfs::ObfuscatedPipe,
     read_prepend_length, write_prepend_length,
 };
 use nursery_macro::nursery;
 use picomux::{LivenessConfig, PicoMux};
 use rand::Rng;
 use sillad::{
-    dialer::{Dialer as _, DynDialer},
+    dialer::{Dialer as _, DialerExt as _, DynDialer},
     EitherPipe, Pipe,
 };
-use smol::future::FutureExt as _;
-use smol_timeout2::TimeoutExt;
 use std::{
+    collections::HashMap,
     net::{IpAddr, SocketAddr},
     str::FromStr,
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
-    time::{Duration, Instant},
+    time::Duration,
 };
+use web_time::{Instant, SystemTime, UNIX_EPOCH};
 
 use stdcode::StdcodeSerializeExt;
 
 use crate::{
     auth::get_connect_token,
+    bloat::bloat_monitor_loop,
+    broker::broker_source,
     china::is_chinese_host,
-    client::CtxField,
+    client::{BridgeMode, CtxField},
     control_prot::{ConnectedInfo, CURRENT_CONN_INFO},
-    route::{deprioritize_route, get_dialer},
+    dialer_pool::DialerPool,
+    exit_health::wait_exit_degraded,
+    lan_bypass::refresh_lan_bypass,
+    metrics::record_connection_attempt,
+    multi_exit::multi_exit_once,
+    multipath::multipath_once,
+    net_change::NetChangeDetector,
+    route::{
+        best_exit, deprioritize_route, direct_route_failed, exit_connected, exit_still_allowed,
+        get_dialer_candidates, wait_exit_constraint_changed,
+    },
+    shaper::shape_upload,
+    smart_routing::{record_attempt, record_session},
     stats::{stat_incr_num, stat_set_num},
+    timeout::geph5_timeout,
     vpn::{fake_dns_backtranslate, vpn_whitelist},
     ConnInfo,
 };
 
+#[cfg(unix)]
+use crate::{coalesce::coalesce_follow, tcp_stats::tcp_stats_loop};
+
 use super::Config;
 
 pub async fn open_conn(
@@ -68,15 +96,22 @@ pub async fn open_conn(
         .map(|s| s.to_string())
         .unwrap_or_default();
     if whitelist_host(ctx, &dest_host) {
-        let addrs = smol::net::resolve(&dest_addr).await?;
-        for addr in addrs.iter() {
-            vpn_whitelist(addr.ip());
-        }
+        #[cfg(not(target_arch = "wasm32"))]
+        let dialer = {
+            let addrs = smol::net::resolve(&dest_addr).await?;
+            for addr in addrs.iter() {
+                vpn_whitelist(addr.ip());
+            }
+            sillad::tcp::HappyEyeballsTcpDialer(addrs)
+        };
+        // the relay looks up the name itself
+        #[cfg(target_arch = "wasm32")]
+        let dialer = crate::wasm::ws_dialer(&dest_addr);
         tracing::debug!(
             dest_addr = debug(dest_addr),
             "passing through whitelisted address"
         );
-        return Ok(sillad::tcp::HappyEyeballsTcpDialer(addrs).dial().await?);
+        return Ok(dialer.dial().await?);
     }
 
     let (send, recv) = oneshot::channel();
@@ -117,35 +152,81 @@ fn whitelist_host(ctx: &AnyCtx<Config>, host: &str) -> bool {
     }
 }
 
-type ChanElem = (String, oneshot::Sender<picomux::Stream>);
+pub type ChanElem = (String, oneshot::Sender<picomux::Stream>);
+
+/// A queue of stream requests, with the receiving side shared by whichever sessions serve it.
+pub type ConnReqChan = (
+    async_channel::Sender<ChanElem>,
+    async_lock::Mutex<async_channel::Receiver<ChanElem>>,
+);
 
-static CONN_REQ_CHAN: CtxField<(
-    smol::channel::Sender<ChanElem>,
-    smol::lock::Mutex<smol::channel::Receiver<ChanElem>>,
-)> = |_| {
-    let (a, b) = smol::channel::unbounded();
+pub fn conn_req_chan() -> ConnReqChan {
+    let (a, b) = async_channel::unbounded();
     (a, b.into())
-};
+}
+
+pub static CONN_REQ_CHAN: CtxField<ConnReqChan> = |_| conn_req_chan();
 
 static COUNTER: AtomicU64 = AtomicU64::new(0);
 
-static CONCURRENCY: usize = 6;
+pub static CONCURRENCY: usize = 6;
+
+/// How long to wait before the next attempt to connect, after failing over from an exit that stopped working. Zero while connected.
+static FAILOVER_BACKOFF: CtxField<parking_lot::Mutex<Duration>> =
+    |_| parking_lot::Mutex::new(Duration::ZERO);
+
+const MIN_FAILOVER_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_FAILOVER_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The authenticated pipe that won the race to pick an exit, along with how long its handshake took. The next session to that exit starts on it instead of dialing again.
+static READY_PIPE: CtxField<parking_lot::Mutex<Option<(VerifyingKey, Box<dyn Pipe>, Duration)>>> =
+    |_| parking_lot::Mutex::new(None);
 
 #[tracing::instrument(skip_all)]
 pub async fn client_once(ctx: AnyCtx<Config>) -> anyhow::Result<()> {
     tracing::info!("(re)starting main logic");
+    // the network may well have changed since the last time we connected
+    refresh_lan_bypass(&ctx);
     *ctx.get(CURRENT_CONN_INFO).lock() = ConnInfo::Connecting;
 
-    static DIALER: CtxField<smol::lock::Mutex<Option<(VerifyingKey, ExitDescriptor, DynDialer)>>> =
-        |_| smol::lock::Mutex::new(None);
+    // another client on this machine may already have an exit connection we can share
+    #[cfg(unix)]
+    if let Some(pipe) = coalesce_follow(&ctx).await {
+        return client_inner(
+            ctx.clone(),
+            pipe,
+            &ctx.get(CONN_REQ_CHAN).1,
+            &ctx.get(CONN_REQ_CHAN).0,
+        )
+        .await;
+    }
+
+    if let Some(count) = ctx.init().multi_exit.filter(|count| *count > 1) {
+        return multi_exit_once(&ctx, count).await;
+    }
+
+    if ctx.init().bridge_mode == BridgeMode::Multipath {
+        return multipath_once(&ctx).await;
+    }
+
+    static DIALER: CtxField<async_lock::Mutex<Option<(VerifyingKey, ExitDescriptor, DynDialer)>>> =
+        |_| async_lock::Mutex::new(None);
+
+    // pending connection requests stay queued in the meantime, so the proxies and the VPN only see a pause
+    let backoff = *ctx.get(FAILOVER_BACKOFF).lock();
+    if !backoff.is_zero() {
+        tracing::info!(backoff = debug(backoff), "backing off before failing over");
+        geph5_rt::Timer::after(backoff).await;
+    }
 
     let start = Instant::now();
     {
         let mut dialer = ctx.get(DIALER).lock().await;
         if dialer.is_none() {
-            let (pubkey, exit, raw_dialer) =
+            let (pubkey, exit, raw_dialer, ready) =
                 get_dialer(&ctx).await.context("could not get initially")?;
-            *dialer = Some((pubkey, exit, raw_dialer));
+            set_ready_pipe(&ctx, pubkey, ready);
+            *dialer = Some((pubkey, exit, pooled(&ctx, raw_dialer)));
         }
     }
 
@@ -153,14 +234,15 @@ pub async fn client_once(ctx: AnyCtx<Config>) -> anyhow::Result<()> {
         loop {
             tracing::info!("refreshing dialer");
             match get_dialer(&ctx).await {
-                Ok((pubkey, exit, raw_dialer)) => {
-                    *ctx.get(DIALER).lock().await = Some((pubkey, exit, raw_dialer));
+                // the sessions we have are fine, so the winning pipe is not needed
+                Ok((pubkey, exit, raw_dialer, _)) => {
+                    *ctx.get(DIALER).lock().await = Some((pubkey, exit, pooled(&ctx, raw_dialer)));
                 }
                 Err(e) => tracing::warn!(err = debug(e), "failed to refresh dialer"),
             }
             let secs = rand::thread_rng().gen_range(300..2000);
             tracing::info!(secs, "waiting until refresh");
-            smol::Timer::after(Duration::from_secs(secs)).await;
+            geph5_rt::Timer::after(Duration::from_secs(secs)).await;
         }
     };
 
@@ -171,34 +253,71 @@ pub async fn client_once(ctx: AnyCtx<Config>) -> anyhow::Result<()> {
     #[allow(unreachable_code)]
     let once = || async {
         loop {
-            let authed_pipe = async {
-                let raw_pipe = raw_dialer.dial().await.context("could not dial")?;
-                tracing::debug!(
-                    elapsed = debug(start.elapsed()),
-                    protocol = raw_pipe.protocol(),
-                    "dial completed"
-                );
-                let died = AtomicBool::new(true);
-                let addr: SocketAddr = raw_pipe.remote_addr().unwrap_or("").parse()?;
-                scopeguard::defer!({
-                    if died.load(Ordering::SeqCst) {
-                        tracing::debug!(addr = display(addr), "deprioritizing route");
-                        deprioritize_route(addr);
-                    }
-                });
-                let authed_pipe = client_auth(&ctx, raw_pipe, pubkey)
-                    .await
-                    .context("could not client auth")?;
-                died.store(false, Ordering::SeqCst);
-                tracing::debug!(
-                    elapsed = debug(start.elapsed()),
-                    "authentication done, starting mux system"
-                );
-                anyhow::Ok(authed_pipe)
+            let ready = ctx
+                .get(READY_PIPE)
+                .lock()
+                .take()
+                .filter(|(ready_pubkey, _, _)| *ready_pubkey == pubkey);
+            let (authed_pipe, attempt_latency) = if let Some((_, pipe, latency)) = ready {
+                tracing::debug!("starting on the pipe that won the exit race");
+                (Ok(pipe), latency)
+            } else {
+                let attempt_start = Instant::now();
+                let authed_pipe = geph5_timeout!(ctx, handshake, async {
+                    let raw_pipe = raw_dialer.dial().await.context("could not dial")?;
+                    tracing::debug!(
+                        elapsed = debug(start.elapsed()),
+                        protocol = raw_pipe.protocol(),
+                        "dial completed"
+                    );
+                    let died = AtomicBool::new(true);
+                    // pipes over Unix sockets have no address to deprioritize
+                    let addr: Option<SocketAddr> =
+                        raw_pipe.remote_addr().and_then(|addr| addr.parse().ok());
+                    scopeguard::defer!({
+                        if let Some(addr) = addr.filter(|_| died.load(Ordering::SeqCst)) {
+                            tracing::debug!(addr = display(addr), "deprioritizing route");
+                            deprioritize_route(addr);
+                        }
+                    });
+                    let authed_pipe = client_auth(&ctx, raw_pipe, pubkey, exit.probe_magic)
+                        .await
+                        .context("could not client auth")?;
+                    died.store(false, Ordering::SeqCst);
+                    tracing::debug!(
+                        elapsed = debug(start.elapsed()),
+                        "authentication done, starting mux system"
+                    );
+                    anyhow::Ok(authed_pipe)
+                })
+                .and_then(|r| r)
+                .map(|pipe| Box::new(pipe) as Box<dyn Pipe>);
+                (authed_pipe, attempt_start.elapsed())
+            };
+            record_connection_attempt(&ctx, &exit, &authed_pipe, attempt_latency);
+            let latency = authed_pipe.as_ref().ok().map(|_| attempt_latency);
+            if let Err(err) = record_attempt(&ctx, pubkey, &exit, latency).await {
+                tracing::warn!(err = debug(err), "could not record exit attempt");
             }
-            .timeout(Duration::from_secs(15))
-            .await
-            .context("overall dial/mux/auth timeout")??;
+            let authed_pipe = match authed_pipe {
+                Ok(authed_pipe) => {
+                    *ctx.get(FAILOVER_BACKOFF).lock() = Duration::ZERO;
+                    exit_connected(
+                        &ctx,
+                        pubkey,
+                        authed_pipe.remote_addr().and_then(|addr| addr.parse().ok()),
+                    );
+                    authed_pipe
+                }
+                Err(err) => {
+                    // the exit may well be dead, so the next attempt picks whichever exit now fits the exit constraint best
+                    direct_route_failed(&ctx, exit.c2e_listen);
+                    *ctx.get(DIALER).lock().await = None;
+                    let mut backoff = ctx.get(FAILOVER_BACKOFF).lock();
+                    *backoff = (*backoff * 2).clamp(MIN_FAILOVER_BACKOFF, MAX_FAILOVER_BACKOFF);
+                    return Err(err.context("lost the exit, failing over"));
+                }
+            };
             *ctx.get(CURRENT_CONN_INFO).lock() = ConnInfo::Connected(ConnectedInfo {
                 protocol: authed_pipe.protocol().to_string(),
                 bridge: authed_pipe
@@ -206,36 +325,195 @@ pub async fn client_once(ctx: AnyCtx<Config>) -> anyhow::Result<()> {
                     .map(|s| s.to_string())
                     .unwrap_or_default(),
                 exit: exit.clone(),
+                exits: vec![],
             });
-            if let Err(err) = client_inner(ctx.clone(), authed_pipe).await {
+            let session_start = SystemTime::now();
+            if let Err(err) = client_inner(
+                ctx.clone(),
+                authed_pipe,
+                &ctx.get(CONN_REQ_CHAN).1,
+                &ctx.get(CONN_REQ_CHAN).0,
+            )
+            .await
+            {
                 tracing::warn!(err = debug(err), "client_inner restarted");
             }
+            if let Err(err) = record_session(&ctx, pubkey, session_start).await {
+                tracing::warn!(err = debug(err), "could not record exit session");
+            }
         }
         anyhow::Ok(())
     };
 
+    // in watch mode, we periodically re-run exit selection, and reconnect if the exit no longer satisfies the exit constraint or a different exit has become the best one
+    let watch = async {
+        if !ctx.init().watch_mode {
+            return futures_lite::future::pending().await;
+        }
+        // we compare against the previous pick rather than the exit we are on, since the initial pick was randomized over loads
+        let mut last_best = None;
+        loop {
+            geph5_rt::Timer::after(Duration::from_secs(ctx.init().policy_check_interval_secs))
+                .await;
+            match exit_still_allowed(&ctx, pubkey).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    *ctx.get(DIALER).lock().await = None;
+                    anyhow::bail!("exit no longer satisfies the exit constraint, reconnecting");
+                }
+                Err(err) => {
+                    tracing::warn!(err = debug(err), "could not re-evaluate exit constraint");
+                    continue;
+                }
+            }
+            match best_exit(&ctx).await {
+                Ok(Some(best)) => {
+                    if last_best.is_some_and(|last| last != best) && best != pubkey {
+                        *ctx.get(DIALER).lock().await = None;
+                        anyhow::bail!("best exit changed, reconnecting");
+                    }
+                    last_best = Some(best);
+                }
+                Ok(None) => {}
+                Err(err) => tracing::warn!(err = debug(err), "could not re-run exit selection"),
+            }
+        }
+    };
+
+    // when the default route changes, connections over the old one, including pooled ones, usually die silently, so we reconnect proactively
+    let net_change = async {
+        NetChangeDetector::new().wait_change().await;
+        *ctx.get(DIALER).lock().await = None;
+        anyhow::bail!("default route changed, reconnecting")
+    };
+
+    // a new exit constraint from the control protocol takes effect by reconnecting to an exit that satisfies it
+    let constraint_changed = async {
+        wait_exit_constraint_changed(&ctx).await;
+        *ctx.get(DIALER).lock().await = None;
+        anyhow::bail!("exit constraint changed, reconnecting")
+    };
+
+    // hot standby: when the exit degrades, we find a fresh one while staying connected, and only cut over once its handshake has succeeded
+    let degraded = async {
+        loop {
+            wait_exit_degraded(&ctx).await;
+            tracing::warn!("exit degraded, looking for a fresh exit");
+            // so that ranking prefers other exits, and a pin to this one is dropped
+            deprioritize_route(exit.c2e_listen);
+            match get_dialer(&ctx).await {
+                Ok((new_pubkey, new_exit, new_dialer, ready)) if new_pubkey != pubkey => {
+                    set_ready_pipe(&ctx, new_pubkey, ready);
+                    *ctx.get(DIALER).lock().await =
+                        Some((new_pubkey, new_exit, pooled(&ctx, new_dialer)));
+                    anyhow::bail!("switching to a fresh exit");
+                }
+                Ok(_) => tracing::info!("no other exit available, staying"),
+                Err(err) => tracing::warn!(err = debug(err), "could not get a fresh exit"),
+            }
+        }
+    };
+
     try_join_all((0..CONCURRENCY).map(|_| once()))
         .or(dial_refresh)
+        .or(watch)
+        .or(net_change)
+        .or(constraint_changed)
+        .or(degraded)
         .await?;
     Ok(())
 }
 
+/// Gets the dialer to use. The candidate exits are dialed at the same time, and whichever completes the full handshake first wins. The others are cancelled without counting against their routes, since losing a race says nothing about whether they work. The winner's authenticated pipe is returned along with its handshake latency, so that it need not be dialed again.
+async fn get_dialer(
+    ctx: &AnyCtx<Config>,
+) -> anyhow::Result<(
+    VerifyingKey,
+    ExitDescriptor,
+    DynDialer,
+    Option<(Box<dyn Pipe>, Duration)>,
+)> {
+    let mut candidates = get_dialer_candidates(ctx).await?;
+    if candidates.len() == 1 {
+        let (pubkey, exit, dialer) = candidates.pop().unwrap();
+        return Ok((pubkey, exit, dialer, None));
+    }
+    let attempts = candidates
+        .iter()
+        .enumerate()
+        .map(|(idx, (pubkey, exit, dialer))| {
+            Box::pin(async move {
+                let start = Instant::now();
+                let handshake = async {
+                    let raw_pipe = dialer.dial().await.context("could not dial")?;
+                    client_auth(ctx, raw_pipe, *pubkey, exit.probe_magic).await
+                };
+                match geph5_timeout!(ctx, handshake, handshake).and_then(|r| r) {
+                    Ok(pipe) => {
+                        tracing::debug!(exit = debug(exit), "speculative handshake won");
+                        anyhow::Ok((idx, Box::new(pipe) as Box<dyn Pipe>, start.elapsed()))
+                    }
+                    Err(err) => {
+                        // these count towards promoting bridges just like failures after the race
+                        direct_route_failed(ctx, exit.c2e_listen);
+                        Err(err)
+                    }
+                }
+            })
+        });
+    let ((winner, pipe, latency), _) = select_ok(attempts).await?;
+    let (pubkey, exit, dialer) = candidates.swap_remove(winner);
+    Ok((pubkey, exit, dialer, Some((pipe, latency))))
+}
+
+/// Keeps the pipe that won the race to pick an exit for the next session to that exit.
+fn set_ready_pipe(
+    ctx: &AnyCtx<Config>,
+    pubkey: VerifyingKey,
+    ready: Option<(Box<dyn Pipe>, Duration)>,
+) {
+    *ctx.get(READY_PIPE).lock() = ready.map(|(pipe, latency)| (pubkey, pipe, latency));
+}
+
+/// Wraps a dialer in a [DialerPool] if configured. Since the dialer is kept in the context, the pool is shared across reconnects, and dropped when the dialer is refreshed.
+fn pooled(ctx: &AnyCtx<Config>, raw_dialer: DynDialer) -> DynDialer {
+    match ctx.init().dialer_pool_size {
+        0 => raw_dialer,
+        size => DialerPool::new(size, raw_dialer).dynamic(),
+    }
+}
+
 #[tracing::instrument(skip_all, fields(instance=COUNTER.fetch_add(1, Ordering::Relaxed), server=display(authed_pipe.remote_addr().unwrap_or("(none)"))))]
-async fn client_inner(ctx: AnyCtx<Config>, authed_pipe: impl Pipe) -> anyhow::Result<()> {
+/// Serves stream requests taken from `requests` over a single authenticated session. Requests that fail get put back on `requeue`.
+pub async fn client_inner(
+    ctx: AnyCtx<Config>,
+    authed_pipe: impl Pipe,
+    requests: &async_lock::Mutex<async_channel::Receiver<ChanElem>>,
+    requeue: &async_channel::Sender<ChanElem>,
+) -> anyhow::Result<()> {
+    // we hold our own descriptor for the socket, since the pipe closes its own once the connection fails, which may be well before the mux notices
+    #[cfg(unix)]
+    let stats_fd = authed_pipe.raw_fd().and_then(|fd| {
+        unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) }
+            .try_clone_to_owned()
+            .ok()
+    });
     let (read, write) = authed_pipe.split();
-    let mut mux = PicoMux::new(read, write);
+    let mut mux = PicoMux::new(read, shape_upload(&ctx, write));
+    let keepalive = keepalive(&ctx);
     mux.set_liveness(LivenessConfig {
-        ping_interval: Duration::from_secs(120),
-        timeout: Duration::from_secs(10),
+        ping_interval: Duration::from_secs(keepalive.interval_secs),
+        timeout: Duration::from_secs(keepalive.timeout_secs),
     });
     let mux = Arc::new(mux);
+    let bloat_monitor = bloat_monitor_loop(&ctx, &mux);
 
     async {
         nursery!({
             loop {
                 let mux = mux.clone();
-                let ctx = ctx.clone();
-                let (remote_addr, send_back) = ctx.get(CONN_REQ_CHAN).1.lock().await.recv().await?;
+                let requeue = requeue.clone();
+                let (remote_addr, send_back) = requests.lock().await.recv().await?;
                 if let Some(latency) = mux.last_latency() {
                     stat_set_num(&ctx, "ping", latency.as_secs_f64());
                 }
@@ -248,7 +526,7 @@ async fn client_inner(ctx: AnyCtx<Config>, authed_pipe: impl Pipe) -> anyhow::Re
                         }
                         Err(err) => {
                             tracing::warn!(remote_addr = display(&remote_addr), err = debug(&err), "session is dead, hot-potatoing the connection request to somebody else");
-                            let _ = ctx.get(CONN_REQ_CHAN).0.try_send((remote_addr, send_back));
+                            let _ = requeue.try_send((remote_addr, send_back));
                         }
                     }
                     anyhow::Ok(())
@@ -257,18 +535,30 @@ async fn client_inner(ctx: AnyCtx<Config>, authed_pipe: impl Pipe) -> anyhow::Re
             }
         })
     }.or(mux.wait_until_dead())
+    .or(bloat_monitor)
+    .or(async {
+        // sampling stops together with the mux, which releases the socket
+        #[cfg(unix)]
+        if let Some(fd) = stats_fd {
+            tcp_stats_loop(&ctx, fd).await;
+        }
+        futures_lite::future::pending().await
+    })
     .await
 }
 
 #[tracing::instrument(skip_all, fields(pubkey = hex::encode(pubkey.as_bytes())))]
-async fn client_auth(
+pub async fn client_auth(
     ctx: &AnyCtx<Config>,
     mut pipe: impl Pipe,
     pubkey: VerifyingKey,
+    send_probe_magic: bool,
 ) -> anyhow::Result<impl Pipe> {
     let server = pipe.remote_addr().unwrap_or("").to_string();
 
-    let credentials = if ctx.init().broker.is_none() {
+    // a ticket is good for one try only, whether or not it works
+    let ticket = ctx.get(SESSION_TICKETS).lock().remove(&pubkey);
+    let credentials = if broker_source(ctx.init()).is_none() || ticket.is_some() {
         Bytes::new()
     } else {
         let (level, token, sig) = get_connect_token(ctx)
@@ -276,13 +566,21 @@ async fn client_auth(
             .context("cannot get connect token")?;
         (level, token, sig).stdcode().into()
     };
+    // exits that do not understand the magic would take it for the start of a hello
+    if send_probe_magic {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        pipe.write_all(&probe_magic(&pubkey, rand::random(), now))
+            .await?;
+    }
     match pipe.shared_secret().map(|s| s.to_owned()) {
         Some(ss) => {
             tracing::debug!(server, "using shared secret for authentication");
             let challenge = rand::random();
+            let crypt_hello = ClientCryptHello::SharedSecretChallenge(challenge);
             let client_hello = ClientHello {
                 credentials,
-                crypt_hello: ClientCryptHello::SharedSecretChallenge(challenge),
+                extensions: hello_extensions(ctx, &crypt_hello, ticket, &ss)?,
+                crypt_hello,
             };
             write_prepend_length(&client_hello.stdcode(), &mut pipe).await?;
 
@@ -290,10 +588,14 @@ async fn client_auth(
             let exit_response: ExitHello =
                 stdcode::deserialize(&read_prepend_length(&mut pipe).await?)
                     .context("cannot deserialize exit hello")?;
-            match exit_response.inner {
+            let (inner, new_ticket) = exit_response.inner.take_ticket();
+            match inner {
                 ExitHelloInner::SharedSecretResponse(response_mac) => {
                     if mac == response_mac {
                         tracing::debug!(server, "authentication successful with shared secret");
+                        if let Some(new_ticket) = new_ticket {
+                            store_ticket(ctx, pubkey, new_ticket, resumption_secret(&ss));
+                        }
                         Ok(EitherPipe::Left(pipe))
                     } else {
                         anyhow::bail!("authentication failed with shared secret");
@@ -303,11 +605,33 @@ async fn client_auth(
             }
         }
         None => {
-            tracing::debug!(server, "requiring full authentication");
-            let my_esk = x25519_dalek::EphemeralSecret::random_from_rng(rand::thread_rng());
+            // a ticket lets us skip the key exchange, since the exit already shares a secret with us
+            let resuming_with = ticket.as_ref().map(|(_, secret)| *secret);
+            let (crypt_hello, timestamp_secret, my_esk) = match resuming_with {
+                Some(secret) => {
+                    tracing::debug!(server, "resuming session with a ticket");
+                    (
+                        ClientCryptHello::Resume(rand::random()),
+                        secret.to_vec(),
+                        None,
+                    )
+                }
+                None => {
+                    tracing::debug!(server, "requiring full authentication");
+                    let my_esk = x25519_dalek::ReusableSecret::random_from_rng(rand::thread_rng());
+                    let my_epk = x25519_dalek::PublicKey::from(&my_esk);
+                    let timestamp_secret = my_esk.diffie_hellman(&exit_x25519_public(&pubkey));
+                    (
+                        ClientCryptHello::X25519(my_epk),
+                        timestamp_secret.as_bytes().to_vec(),
+                        Some(my_esk),
+                    )
+                }
+            };
             let client_hello = ClientHello {
                 credentials,
-                crypt_hello: ClientCryptHello::X25519((&my_esk).into()),
+                extensions: hello_extensions(ctx, &crypt_hello, ticket, &timestamp_secret)?,
+                crypt_hello,
             };
             write_prepend_length(&client_hello.stdcode(), &mut pipe).await?;
             tracing::trace!(server, "wrote client hello");
@@ -315,29 +639,149 @@ async fn client_auth(
                 stdcode::deserialize(&read_prepend_length(&mut pipe).await?)
                     .context("could not deserialize exit hello")?;
             tracing::trace!(server, "received exit hello");
-            // verify the exit hello
+            // verify the exit hello, which exits that predate extensions sign as if our hello had none
             let signed_value = (&client_hello, &exit_hello.inner).stdcode();
             pubkey
                 .verify_strict(&signed_value, &exit_hello.signature)
+                .or_else(|_| {
+                    pubkey.verify_strict(
+                        &client_hello.legacy_signed_value(&exit_hello.inner),
+                        &exit_hello.signature,
+                    )
+                })
                 .context("exit hello failed validation")?;
-            match exit_hello.inner {
+            let (inner, new_ticket) = exit_hello.inner.take_ticket();
+            let (shared_secret, obfuscated) = match inner {
                 ExitHelloInner::Reject(reason) => {
                     anyhow::bail!("exit rejected our authentication attempt: {reason}")
                 }
+                ExitHelloInner::ReplayDetected => {
+                    anyhow::bail!("exit rejected our client hello as a replay")
+                }
                 ExitHelloInner::SharedSecretResponse(_) => {
                     anyhow::bail!(
                         "exit sent a shared-secret response to our full authentication request"
                     )
                 }
-                ExitHelloInner::X25519(their_epk) => {
-                    let shared_secret = my_esk.diffie_hellman(&their_epk);
-                    let read_key = blake3::derive_key("e2c", shared_secret.as_bytes());
-                    let write_key = blake3::derive_key("c2e", shared_secret.as_bytes());
-                    Ok(EitherPipe::Right(ClientExitCryptPipe::new(
-                        pipe, read_key, write_key,
-                    )))
+                ExitHelloInner::Resumable { .. } => {
+                    anyhow::bail!("exit sent a session ticket inside a session ticket")
                 }
+                ExitHelloInner::X25519(their_epk) | ExitHelloInner::X25519Obfuscated(their_epk) => {
+                    let my_esk =
+                        my_esk.context("exit answered our resumption with a key exchange")?;
+                    let obfuscated = matches!(inner, ExitHelloInner::X25519Obfuscated(_));
+                    (*my_esk.diffie_hellman(&their_epk).as_bytes(), obfuscated)
+                }
+                ExitHelloInner::Resumed { nonce, obfuscated } => {
+                    let secret = resuming_with
+                        .context("exit resumed a session we did not present a ticket for")?;
+                    (
+                        resumed_shared_secret(&secret, &client_hello.nonce(), &nonce),
+                        obfuscated,
+                    )
+                }
+            };
+            if ctx.init().obfuscate_frames && !obfuscated {
+                tracing::debug!(server, "exit does not support frame obfuscation");
+            }
+            if let Some(new_ticket) = new_ticket {
+                store_ticket(ctx, pubkey, new_ticket, resumption_secret(&shared_secret));
+            }
+            let read_key = blake3::derive_key("e2c", &shared_secret);
+            let write_key = blake3::derive_key("c2e", &shared_secret);
+            #[cfg(debug_assertions)]
+            if let Some(path) = &ctx.init().keylog_file {
+                if let Err(err) = export_keys(path, &client_hello.nonce(), &read_key, &write_key) {
+                    tracing::warn!(err = debug(err), "could not export session keys");
+                }
+            }
+            let crypt_pipe = ClientExitCryptPipe::new(pipe, read_key, write_key);
+            if obfuscated {
+                Ok(EitherPipe::Right(EitherPipe::Left(ObfuscatedPipe::new(
+                    crypt_pipe,
+                ))))
+            } else {
+                Ok(EitherPipe::Right(EitherPipe::Right(crypt_pipe)))
             }
         }
     }
 }
+
+/// The session tickets that exits gave us, each with its resumption secret, keyed by the exit's public key.
+static SESSION_TICKETS: CtxField<parking_lot::Mutex<HashMap<VerifyingKey, (Bytes, [u8; 32])>>> =
+    |_| Default::default();
+
+/// Keeps a session ticket for the next connection to the same exit, replacing any older one.
+fn store_ticket(ctx: &AnyCtx<Config>, pubkey: VerifyingKey, ticket: Bytes, secret: [u8; 32]) {
+    tracing::trace!(pubkey = debug(pubkey), "got session ticket");
+    ctx.get(SESSION_TICKETS)
+        .lock()
+        .insert(pubkey, (ticket, secret));
+}
+
+/// The extensions to put in our client hello, presenting the given session ticket if there is one. The timestamp is keyed with the given secret, as [HelloTimestamp] describes.
+fn hello_extensions(
+    ctx: &AnyCtx<Config>,
+    crypt_hello: &ClientCryptHello,
+    ticket: Option<(Bytes, [u8; 32])>,
+    timestamp_secret: &[u8],
+) -> anyhow::Result<HashMap<String, Vec<u8>>> {
+    let mut extensions = HashMap::new();
+    if let Some(tenant) = &ctx.init().tenant {
+        let seckey = SigningKey::from_bytes(
+            &hex::decode(&tenant.secret_key)?
+                .try_into()
+                .ok()
+                .context("tenant secret key must be 32 bytes")?,
+        );
+        let claim = TenantClaim::new(tenant.id.clone(), crypt_hello, &seckey);
+        extensions.insert(EXT_TENANT.to_string(), claim.stdcode());
+    }
+    if ctx.init().obfuscate_frames {
+        extensions.insert(EXT_OBFUSCATE_FRAMES.to_string(), vec![]);
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    extensions.insert(
+        EXT_TIMESTAMP.to_string(),
+        HelloTimestamp::new(now, timestamp_secret, crypt_hello).stdcode(),
+    );
+    extensions.insert(EXT_KEEPALIVE.to_string(), keepalive(ctx).stdcode());
+    // tickets only save us from presenting a connect token, so they are useless without a broker
+    if broker_source(ctx.init()).is_some() {
+        let nonce = crypt_hello.nonce();
+        let resumption = SessionResumption {
+            presented: ticket.map(|(ticket, secret)| PresentedTicket::new(ticket, &secret, &nonce)),
+        };
+        extensions.insert(EXT_SESSION_RESUMPTION.to_string(), resumption.stdcode());
+    }
+    Ok(extensions)
+}
+
+fn keepalive(ctx: &AnyCtx<Config>) -> Keepalive {
+    Keepalive {
+        interval_secs: ctx.init().keepalive_interval_secs,
+        timeout_secs: ctx.init().keepalive_timeout_secs,
+    }
+}
+
+/// Appends the session keys to a key log file in NSS key log format, for dissecting tunnel traffic in Wireshark. NSS lines take a single secret, so we use the TLS 1.3 labels, which distinguish the two directions: the nonce of our crypt hello, which is our ephemeral X25519 public key unless we resumed, stands in for the client random, `CLIENT_TRAFFIC_SECRET_0` is our write key, and `SERVER_TRAFFIC_SECRET_0` is our read key.
+#[cfg(debug_assertions)]
+fn export_keys(
+    path: &std::path::Path,
+    client_random: &[u8; 32],
+    read_key: &[u8; 32],
+    write_key: &[u8; 32],
+) -> std::io::Result<()> {
+    use std::io::Write;
+    let random = hex::encode(client_random);
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    write!(
+        file,
+        "CLIENT_TRAFFIC_SECRET_0 {random} {}\nSERVER_TRAFFIC_SECRET_0 {random} {}\n",
+        hex::encode(write_key),
+        hex::encode(read_key)
+    )
+}
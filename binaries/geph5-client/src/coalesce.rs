@@ -0,0 +1,173 @@
+use std::{
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    os::fd::AsRawFd as _,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use anyctx::AnyCtx;
+use anyhow::Context as _;
+use futures_util::{io::copy, AsyncReadExt as _};
+use picomux::PicoMux;
+use sillad::{
+    dialer::Dialer as _,
+    listener::Listener as _,
+    unix::{UnixDialer, UnixListener, UnixPipe},
+};
+use smol::future::FutureExt as _;
+
+use crate::{
+    client::{Config, CtxField},
+    client_inner::CONN_REQ_CHAN,
+};
+
+/// How often a client that is not serving the coalescing socket checks whether it should take over.
+const TAKEOVER_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Whether this client is the one serving the coalescing socket.
+static SERVING: CtxField<AtomicBool> = |_| AtomicBool::new(false);
+
+/// Connects to the client serving the coalescing socket, if another client on this machine already does. The returned pipe speaks the same multiplexing protocol as an exit, so it can stand in for a connection to one.
+pub async fn coalesce_follow(ctx: &AnyCtx<Config>) -> Option<UnixPipe> {
+    let path = ctx.init().coalesce_socket.as_ref()?;
+    if ctx.get(SERVING).load(Ordering::SeqCst) {
+        return None;
+    }
+    let pipe = UnixDialer {
+        dest_path: path.clone(),
+    }
+    .dial()
+    .await
+    .ok()?;
+    tracing::info!(
+        path = display(path.display()),
+        "sharing the exit connection of another client"
+    );
+    Some(pipe)
+}
+
+/// Serves the coalescing socket whenever no other client on this machine does, so that other clients can share our exit connection. A client that crashes only closes its own connection to the socket, and if the serving client goes away, another one takes over.
+///
+/// Which client serves is decided by an exclusive lock on a file next to the socket, not by whether the socket answers, since two clients could both find it silent and then unlink each other's socket. The kernel drops the lock when its holder exits, however it exits, and only the holder ever touches the socket file.
+pub async fn coalesce_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let Some(path) = &ctx.init().coalesce_socket else {
+        return smol::future::pending().await;
+    };
+    loop {
+        if let Some(_lock) = try_lock_serving(path)? {
+            // holding the lock, whatever socket is there was left behind by a server that is gone, and binding removes it
+            let listener = UnixListener::bind(path).await?;
+            ctx.get(SERVING).store(true, Ordering::SeqCst);
+            tracing::info!(
+                path = display(path.display()),
+                "serving the coalescing socket"
+            );
+            let res = serve(ctx, listener).await;
+            ctx.get(SERVING).store(false, Ordering::SeqCst);
+            res?;
+        }
+        smol::Timer::after(TAKEOVER_INTERVAL).await;
+    }
+}
+
+/// Takes the lock that makes us the client serving the coalescing socket at the given path, without waiting for it. Returns `None` if another client holds it. The lock is held for as long as the returned file is open.
+fn try_lock_serving(socket_path: &Path) -> std::io::Result<Option<File>> {
+    let mut lock_path = OsString::from(socket_path.as_os_str());
+    lock_path.push(".lock");
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(Some(file));
+    }
+    let err = std::io::Error::last_os_error();
+    if err.kind() == std::io::ErrorKind::WouldBlock {
+        Ok(None)
+    } else {
+        Err(err)
+    }
+}
+
+async fn serve(ctx: &AnyCtx<Config>, mut listener: UnixListener) -> anyhow::Result<()> {
+    loop {
+        let follower = listener.accept().await?;
+        let ctx = ctx.clone();
+        smolscale::spawn(async move {
+            if let Err(err) = serve_follower(&ctx, follower).await {
+                tracing::debug!(err = debug(err), "coalesced client went away");
+            }
+        })
+        .detach();
+    }
+}
+
+/// Relays the streams another client opens over its connection to the coalescing socket, through our own exit connection.
+async fn serve_follower(ctx: &AnyCtx<Config>, follower: UnixPipe) -> anyhow::Result<()> {
+    let (read, write) = follower.split();
+    let mux = PicoMux::new(read, write);
+    loop {
+        let stream = mux.accept().await?;
+        let ctx = ctx.clone();
+        smolscale::spawn(async move {
+            let metadata = String::from_utf8_lossy(stream.metadata()).to_string();
+            let (send, recv) = oneshot::channel();
+            ctx.get(CONN_REQ_CHAN)
+                .0
+                .send((metadata, send))
+                .await
+                .ok()
+                .context("connection requests closed")?;
+            let upstream = recv.await?;
+            let (read_stream, mut write_stream) = stream.split();
+            let (read_upstream, mut write_upstream) = upstream.split();
+            copy(read_stream, &mut write_upstream)
+                .race(copy(read_upstream, &mut write_stream))
+                .await?;
+            anyhow::Ok(())
+        })
+        .detach();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("geph5-coalesce-{name}-{}.sock", std::process::id()))
+    }
+
+    #[test]
+    fn only_one_client_serves() {
+        let path = socket_path("lock");
+        let first = try_lock_serving(&path).unwrap();
+        assert!(first.is_some());
+        // the lock belongs to the open file, so a second open in this process contends like another client would
+        assert!(try_lock_serving(&path).unwrap().is_none());
+        drop(first);
+        assert!(try_lock_serving(&path).unwrap().is_some());
+    }
+
+    #[test]
+    fn waiting_client_leaves_the_socket_alone() {
+        let path = socket_path("socket");
+        smolscale::block_on(async {
+            let _lock = try_lock_serving(&path).unwrap().unwrap();
+            let mut listener = UnixListener::bind(&path).await.unwrap();
+            // a second client finds the lock taken and does not get to bind
+            assert!(try_lock_serving(&path).unwrap().is_none());
+            assert!(path.exists());
+            let dial = UnixDialer {
+                dest_path: path.clone(),
+            }
+            .dial();
+            let (dialed, accepted) = futures_util::join!(dial, listener.accept());
+            dialed.unwrap();
+            accepted.unwrap();
+        });
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use async_trait::async_trait;
+use nanorpc::{JrpcError, JrpcRequest, JrpcResponse, RpcService, RpcTransport};
+use smol::net::unix::UnixDatagram;
+use smol_timeout2::TimeoutExt;
+
+use crate::{
+    control_prot::{ControlProtocolImpl, ControlService},
+    Config,
+};
+
+/// The largest request or response that fits in one control datagram.
+const MAX_DATAGRAM: usize = 65536;
+
+/// How long we wait for the daemon to answer over the control socket.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves the control protocol over a Unix datagram socket, one JSON-RPC request or response per datagram. This avoids the connection setup of a stream socket, which matters for quick status checks.
+pub async fn control_datagram_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let Some(path) = &ctx.init().control_listen_unix else {
+        return smol::future::pending().await;
+    };
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    let socket = UnixDatagram::bind(path)?;
+    let service = ControlService(ControlProtocolImpl { ctx: ctx.clone() });
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let (n, from) = socket.recv_from(&mut buf).await?;
+        // an unbound peer cannot receive a reply, so there is no point answering it
+        let Some(from) = from.as_pathname() else {
+            tracing::debug!("ignoring control datagram from an unbound socket");
+            continue;
+        };
+        let req: JrpcRequest = match serde_json::from_slice(&buf[..n]) {
+            Ok(req) => req,
+            Err(err) => {
+                tracing::debug!(err = debug(err), "ignoring malformed control datagram");
+                continue;
+            }
+        };
+        let id = req.id.clone();
+        let mut resp = serde_json::to_vec(&service.respond_raw(req).await)?;
+        if resp.len() > MAX_DATAGRAM {
+            resp = serde_json::to_vec(&JrpcResponse {
+                jsonrpc: "2.0".into(),
+                result: None,
+                error: Some(JrpcError {
+                    code: -32000,
+                    message: "response too large for a datagram".into(),
+                    data: serde_json::Value::Null,
+                }),
+                id,
+            })?;
+        }
+        if let Err(err) = socket.send_to(&resp, from).await {
+            tracing::debug!(err = debug(err), "could not reply to control datagram");
+        }
+    }
+}
+
+/// A transport that talks to [control_datagram_loop]. It binds its own temporary socket, since replies need an address to go to.
+pub struct ControlDatagramTransport {
+    socket: UnixDatagram,
+    local_path: PathBuf,
+}
+
+impl ControlDatagramTransport {
+    /// Connects to the control datagram socket at the given path.
+    pub fn connect(dest: &Path) -> anyhow::Result<Self> {
+        let local_path = std::env::temp_dir().join(format!(
+            "geph5-control-{}-{}.sock",
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+        let socket = UnixDatagram::bind(&local_path)?;
+        socket
+            .connect(dest)
+            .context("cannot connect to control socket")?;
+        Ok(Self { socket, local_path })
+    }
+}
+
+impl Drop for ControlDatagramTransport {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.local_path);
+    }
+}
+
+#[async_trait]
+impl RpcTransport for ControlDatagramTransport {
+    type Error = anyhow::Error;
+
+    async fn call_raw(&self, req: JrpcRequest) -> Result<JrpcResponse, Self::Error> {
+        let req = serde_json::to_vec(&req)?;
+        anyhow::ensure!(
+            req.len() <= MAX_DATAGRAM,
+            "request too large for a datagram"
+        );
+        self.socket.send(&req).await?;
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let n = self
+            .socket
+            .recv(&mut buf)
+            .timeout(REPLY_TIMEOUT)
+            .await
+            .context("timed out waiting for control socket")??;
+        Ok(serde_json::from_slice(&buf[..n])?)
+    }
+}
@@ -12,7 +12,19 @@ use nanorpc::{nanorpc_derive, JrpcRequest, JrpcResponse, RpcService, RpcTranspor
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 
-use crate::{client::CtxField, logs::LOGS, stats::stat_get_num, Config};
+#[cfg(unix)]
+use crate::control_datagram::ControlDatagramTransport;
+#[cfg(not(target_arch = "wasm32"))]
+use {anyhow::Context, sillad::tcp::TcpDialer};
+
+use crate::{
+    client::CtxField,
+    logs::LOGS,
+    route::{set_exit_constraint, ExitConstraint},
+    stats::stat_get_num,
+    tcp_stats::TUNNEL_STATS,
+    Config,
+};
 
 #[nanorpc_derive]
 #[async_trait]
@@ -23,6 +35,17 @@ pub trait ControlProtocol {
     async fn stop(&self);
 
     async fn recent_logs(&self) -> Vec<String>;
+
+    async fn health_report(&self) -> HealthReport;
+
+    /// Switches to a different exit constraint without restarting, reconnecting to an exit that satisfies it. Like every other method, this is a JSON-RPC 2.0 call, either over `control_listen` or as a single datagram to `control_listen_unix`. The constraint is passed in its serialized form, so for example
+    ///
+    /// `{"jsonrpc": "2.0", "method": "set_exit_constraint", "params": [{"country": "DE"}], "id": 1}`
+    ///
+    /// picks an exit in Germany, while `"params": ["auto"]` goes back to automatic selection.
+    ///
+    /// Anything that can reach the control socket can call this, so it only picks among the exits the broker vouches for. `Direct` constraints, which send all traffic to an arbitrary server, are refused, and can only be set in the config.
+    async fn set_exit_constraint(&self, constraint: ExitConstraint) -> Result<(), String>;
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -37,6 +60,26 @@ pub struct ConnectedInfo {
     pub bridge: String,
 
     pub exit: ExitDescriptor,
+    /// When connected to several exits at once, every one of them that has a session up. Empty otherwise.
+    #[serde(default)]
+    pub exits: Vec<ExitDescriptor>,
+}
+
+/// A summary of the client's health, small enough to fit in a single control datagram.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HealthReport {
+    pub conn_info: ConnInfo,
+    pub start_time: SystemTime,
+    pub total_rx_bytes: f64,
+    pub total_tx_bytes: f64,
+    pub ping: f64,
+    /// The following come from the last sample of the TCP connection carrying the tunnel, if there is one.
+    #[serde(default)]
+    pub tcp_rtt_us: Option<u64>,
+    #[serde(default)]
+    pub tcp_retransmits: Option<u64>,
+    #[serde(default)]
+    pub tcp_send_window: Option<u64>,
 }
 
 pub struct ControlProtocolImpl {
@@ -56,13 +99,12 @@ impl ControlProtocol for ControlProtocolImpl {
     }
 
     async fn start_time(&self) -> SystemTime {
-        static START_TIME: CtxField<SystemTime> = |_| SystemTime::now();
         *self.ctx.get(START_TIME)
     }
 
     async fn stop(&self) {
-        smolscale::spawn(async move {
-            smol::Timer::after(Duration::from_millis(100)).await;
+        geph5_rt::spawn(async move {
+            geph5_rt::Timer::after(Duration::from_millis(100)).await;
             std::process::exit(0);
         })
         .detach();
@@ -75,6 +117,73 @@ impl ControlProtocol for ControlProtocolImpl {
             .map(|s| s.to_string())
             .collect_vec()
     }
+
+    async fn health_report(&self) -> HealthReport {
+        // a sample that is no longer being refreshed belongs to a tunnel that is gone
+        let tcp_stats = self.ctx.get(TUNNEL_STATS).lock().filter(|s| {
+            s.sampled_at
+                .elapsed()
+                .is_ok_and(|age| age < Duration::from_secs(60))
+        });
+        HealthReport {
+            conn_info: self.ctx.get(CURRENT_CONN_INFO).lock().clone(),
+            start_time: *self.ctx.get(START_TIME),
+            total_rx_bytes: stat_get_num(&self.ctx, "total_rx_bytes"),
+            total_tx_bytes: stat_get_num(&self.ctx, "total_tx_bytes"),
+            ping: stat_get_num(&self.ctx, "ping"),
+            tcp_rtt_us: tcp_stats.map(|s| s.rtt_us),
+            tcp_retransmits: tcp_stats.map(|s| s.retransmits),
+            tcp_send_window: tcp_stats.map(|s| s.send_window),
+        }
+    }
+
+    async fn set_exit_constraint(&self, constraint: ExitConstraint) -> Result<(), String> {
+        if matches!(constraint, ExitConstraint::Direct(_)) {
+            return Err("direct exit constraints cannot be set over the control socket".into());
+        }
+        set_exit_constraint(&self.ctx, constraint);
+        Ok(())
+    }
+}
+
+// std cannot read the clock in the browser, but the time still has to be a std one to go over the wire
+static START_TIME: CtxField<SystemTime> = |_| {
+    SystemTime::UNIX_EPOCH
+        + web_time::SystemTime::now()
+            .duration_since(web_time::UNIX_EPOCH)
+            .unwrap_or_default()
+};
+
+/// Asks a running client for its health report, over the control datagram socket if there is one, or else the TCP control socket.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn query_health_report(cfg: Config) -> anyhow::Result<HealthReport> {
+    #[cfg(unix)]
+    if let Some(path) = &cfg.control_listen_unix {
+        let client = ControlClient::from(ControlDatagramTransport::connect(path)?);
+        return Ok(client.health_report().await?);
+    }
+    let dest_addr = cfg.control_listen.context("no control socket configured")?;
+    let client = ControlClient::from(nanorpc_sillad::DialerTransport(TcpDialer { dest_addr }));
+    Ok(client.health_report().await?)
+}
+
+/// Tells a running client to switch to a different exit constraint, over the same sockets as [query_health_report].
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn change_exit_constraint(cfg: Config, constraint: ExitConstraint) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    if let Some(path) = &cfg.control_listen_unix {
+        let client = ControlClient::from(ControlDatagramTransport::connect(path)?);
+        return client
+            .set_exit_constraint(constraint)
+            .await?
+            .map_err(anyhow::Error::msg);
+    }
+    let dest_addr = cfg.control_listen.context("no control socket configured")?;
+    let client = ControlClient::from(nanorpc_sillad::DialerTransport(TcpDialer { dest_addr }));
+    client
+        .set_exit_constraint(constraint)
+        .await?
+        .map_err(anyhow::Error::msg)
 }
 
 pub struct DummyControlProtocolTransport(pub ControlService<ControlProtocolImpl>);
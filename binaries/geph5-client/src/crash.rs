@@ -0,0 +1,94 @@
+use std::{
+    backtrace::Backtrace,
+    panic::PanicInfo,
+    sync::{mpsc::RecvTimeoutError, Once},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::client::Config;
+
+/// How long a panicking process waits for its crash report to be submitted before giving up on it.
+const SUBMIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+static HOOK_INSTALLED: Once = Once::new();
+
+/// A crash report, as submitted to the crash endpoint.
+#[derive(Serialize)]
+struct CrashReport {
+    session_id: String,
+    timestamp: String,
+    geph5_version: String,
+    os_version: String,
+    message: String,
+    backtrace: String,
+}
+
+/// Installs a panic hook that submits crash reports, if crash reporting is turned on in the config. The hook is process-wide, so only the first client started with crash reporting installs it, and restarted clients do not stack up hooks.
+pub fn install_crash_hook(cfg: &Config) {
+    if !cfg.crash_reporting {
+        return;
+    }
+    HOOK_INSTALLED.call_once(|| set_crash_hook(cfg));
+}
+
+fn set_crash_hook(cfg: &Config) {
+    let Some(endpoint) = cfg.crash_endpoint.clone() else {
+        tracing::warn!("crash reporting is on, but no crash endpoint is configured");
+        return;
+    };
+    // this is deliberately not derived from anything linked to the user account
+    let session_id = hex::encode(rand::random::<[u8; 16]>());
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let report = CrashReport {
+            session_id: session_id.clone(),
+            timestamp: chrono::Local::now().to_rfc3339(),
+            geph5_version: env!("CARGO_PKG_VERSION").to_string(),
+            os_version: sysinfo::System::long_os_version()
+                .unwrap_or_else(|| format!("{} {}", std::env::consts::OS, std::env::consts::ARCH)),
+            message: panic_message(info),
+            backtrace: Backtrace::force_capture().to_string(),
+        };
+        // we build with panic=abort, so the process dies as soon as the hook returns. we submit on a separate thread and wait for its result, for at most SUBMIT_TIMEOUT, after which the thread dies with the process.
+        let endpoint = endpoint.clone();
+        let (send_result, recv_result) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let result = smolscale::block_on(async move {
+                reqwest::Client::builder()
+                    .no_proxy()
+                    .build()?
+                    .post(&endpoint)
+                    .header("content-type", "application/json")
+                    .body(serde_json::to_vec(&report)?)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                anyhow::Ok(())
+            });
+            let _ = send_result.send(result);
+        });
+        match recv_result.recv_timeout(SUBMIT_TIMEOUT) {
+            Ok(Ok(())) => eprintln!("crash report submitted"),
+            Ok(Err(err)) => eprintln!("could not submit crash report: {:?}", err),
+            Err(RecvTimeoutError::Timeout) => eprintln!("could not submit crash report: timed out"),
+            Err(RecvTimeoutError::Disconnected) => eprintln!("crash reporter itself panicked"),
+        }
+        default_hook(info)
+    }));
+}
+
+fn panic_message(info: &PanicInfo) -> String {
+    let payload = if let Some(s) = info.payload().downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = info.payload().downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "(non-string panic payload)".to_string()
+    };
+    match info.location() {
+        Some(location) => format!("{payload} at {location}"),
+        None => payload,
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+use web_time::Instant;
+
+use anyctx::AnyCtx;
+use serde::Serialize;
+use sillad::dialer::Dialer as _;
+
+use crate::{
+    auth::get_auth_token, client::Config, client_inner::client_auth, route::get_exit_dialers,
+    timeout::geph5_timeout,
+};
+
+/// How many exits the diagnostic report tries to reach.
+const DIAGNOSED_EXITS: usize = 3;
+
+/// What `--dry-run` found out about reaching the broker and the exits.
+#[derive(Serialize, Clone, Debug)]
+pub struct DiagnosticReport {
+    /// How long logging in to the broker took, if it worked.
+    pub login_time: Option<Duration>,
+    /// Why we could not log in or get the exit list, if we could not.
+    pub broker_error: Option<String>,
+    /// The best exits for the exit constraint, and how the handshake with each went.
+    pub exits: Vec<ExitProbe>,
+}
+
+impl DiagnosticReport {
+    /// Whether at least one exit completed the handshake.
+    pub fn any_reachable(&self) -> bool {
+        self.exits.iter().any(|exit| exit.rtt.is_some())
+    }
+}
+
+/// The result of handshaking with one exit.
+#[derive(Serialize, Clone, Debug)]
+pub struct ExitProbe {
+    pub country: String,
+    pub city: String,
+    pub load: f32,
+    /// The first 8 bytes of the exit's public key, in hex.
+    pub fingerprint: String,
+    /// How long dialing and the full handshake took, if they worked.
+    pub rtt: Option<Duration>,
+    pub error: Option<String>,
+}
+
+/// Logs in, gets the exit list, and handshakes with the best few exits for the exit constraint at the same time, without starting the tunnel. This goes through exactly the same code as connecting, so whatever fails here would fail there too.
+pub async fn diagnose(cfg: Config) -> DiagnosticReport {
+    let ctx = &AnyCtx::new(cfg);
+    let mut report = DiagnosticReport {
+        login_time: None,
+        broker_error: None,
+        exits: vec![],
+    };
+    let start = Instant::now();
+    if let Err(err) = get_auth_token(ctx).await {
+        report.broker_error = Some(format!("could not log in: {err:#}"));
+        return report;
+    }
+    report.login_time = Some(start.elapsed());
+    let candidates = match get_exit_dialers(ctx, DIAGNOSED_EXITS).await {
+        Ok(candidates) => candidates,
+        Err(err) => {
+            report.broker_error = Some(format!("could not get exits: {err:#}"));
+            return report;
+        }
+    };
+    report.exits = futures_util::future::join_all(candidates.into_iter().map(
+        |(pubkey, exit, dialer)| async move {
+            let start = Instant::now();
+            let handshake = async {
+                let raw_pipe = dialer.dial().await?;
+                client_auth(ctx, raw_pipe, pubkey, exit.probe_magic).await?;
+                anyhow::Ok(())
+            };
+            let result = geph5_timeout!(ctx, handshake, handshake).and_then(|r| r);
+            ExitProbe {
+                country: exit.country.alpha2().to_string(),
+                city: exit.city,
+                load: exit.load,
+                fingerprint: hex::encode(&pubkey.as_bytes()[..8]),
+                rtt: result.as_ref().ok().map(|_| start.elapsed()),
+                error: result.err().map(|err| format!("{err:#}")),
+            }
+        },
+    ))
+    .await;
+    report
+}
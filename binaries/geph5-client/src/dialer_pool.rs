@@ -0,0 +1,72 @@
+use std::time::Duration;
+use web_time::Instant;
+
+use sillad::{
+    dialer::{Dialer, DynDialer},
+    Pipe,
+};
+
+/// How long a pre-established connection is kept before we assume some middlebox has silently dropped it.
+const MAX_IDLE: Duration = Duration::from_secs(60);
+
+/// DialerPool wraps a dialer, keeping a pool of connections established ahead of time so that dialing returns immediately. The pool is replenished in the background for as long as the DialerPool is alive.
+pub struct DialerPool {
+    dialer: DynDialer,
+    ready: async_channel::Receiver<(Instant, Box<dyn Pipe>)>,
+    _task: geph5_rt::Task<()>,
+}
+
+impl DialerPool {
+    /// Creates a new pool that keeps `size` connections ready.
+    pub fn new(size: usize, dialer: DynDialer) -> Self {
+        let (send_ready, ready) = async_channel::bounded(size.max(1));
+        let task = geph5_rt::spawn({
+            let dialer = dialer.clone();
+            async move {
+                let mut backoff = Duration::from_secs(1);
+                loop {
+                    match dialer.dial().await {
+                        Ok(pipe) => {
+                            backoff = Duration::from_secs(1);
+                            // blocks until there is room in the pool
+                            if send_ready.send((Instant::now(), pipe)).await.is_err() {
+                                return;
+                            }
+                        }
+                        Err(err) => {
+                            tracing::debug!(
+                                err = debug(err),
+                                backoff = debug(backoff),
+                                "could not replenish dialer pool"
+                            );
+                            geph5_rt::Timer::after(backoff).await;
+                            backoff = (backoff * 2).min(Duration::from_secs(60));
+                        }
+                    }
+                }
+            }
+        });
+        Self {
+            dialer,
+            ready,
+            _task: task,
+        }
+    }
+}
+
+impl Dialer for DialerPool {
+    type P = Box<dyn Pipe>;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        while let Ok((established, pipe)) = self.ready.try_recv() {
+            if established.elapsed() < MAX_IDLE {
+                tracing::debug!(
+                    age = debug(established.elapsed()),
+                    "using pre-established connection"
+                );
+                return Ok(pipe);
+            }
+        }
+        self.dialer.dial().await
+    }
+}
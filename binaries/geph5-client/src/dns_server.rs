@@ -0,0 +1,169 @@
+use anyctx::AnyCtx;
+use futures_util::{AsyncReadExt as _, AsyncWriteExt as _};
+use nursery_macro::nursery;
+use parking_lot::Mutex;
+use smol::{
+    future::FutureExt as _,
+    net::{TcpListener, UdpSocket},
+};
+use smol_timeout2::TimeoutExt as _;
+use std::time::{Duration, Instant};
+
+use crate::{
+    client::{Config, CtxField},
+    client_inner::open_conn,
+};
+
+/// How long we wait for the exit's resolver to answer before giving up on a query, so that the asking program retries.
+const DNS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long an idle `dns` stream is kept around for the next query. The exit closes streams that sit idle for a minute, so we let go of them a bit before that.
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(50);
+
+/// At most this many idle `dns` streams are kept, which bounds the streams left over from a burst of queries.
+const MAX_IDLE_STREAMS: usize = 16;
+
+/// The `dns` streams not answering a query right now, each with when it was last used.
+static IDLE_STREAMS: CtxField<Mutex<Vec<(Instant, Box<dyn sillad::Pipe>)>>> =
+    |_| Mutex::new(Vec::new());
+
+/// Serves DNS on `dns_listen`, over both UDP and TCP, answering every query through the exit's resolver so that lookups neither leak to nor can be tampered with by the local network.
+pub async fn dns_server_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let Some(listen) = ctx.init().dns_listen else {
+        return smol::future::pending().await;
+    };
+    let udp = UdpSocket::bind(listen).await?;
+    let tcp = TcpListener::bind(listen).await?;
+    tracing::info!(listen = display(listen), "serving DNS through the tunnel");
+    let udp_loop = async {
+        nursery!({
+            let mut buf = vec![0u8; 65536];
+            loop {
+                let (n, src) = udp.recv_from(&mut buf).await?;
+                let query = buf[..n].to_vec();
+                let udp = &udp;
+                spawn!(async move {
+                    match tunnel_exchange(ctx, &query).await {
+                        Ok(response) => {
+                            let _ = udp.send_to(&response, src).await;
+                        }
+                        Err(err) => {
+                            tracing::debug!(
+                                src = display(src),
+                                err = debug(err),
+                                "DNS query failed"
+                            )
+                        }
+                    }
+                })
+                .detach();
+            }
+        })
+    };
+    let tcp_loop = async {
+        nursery!({
+            loop {
+                let (conn, src) = tcp.accept().await?;
+                spawn!(async move {
+                    if let Err(err) = serve_tcp(ctx, conn).await {
+                        tracing::debug!(
+                            src = display(src),
+                            err = debug(err),
+                            "DNS connection failed"
+                        )
+                    }
+                })
+                .detach();
+            }
+        })
+    };
+    udp_loop.race(tcp_loop).await
+}
+
+/// Answers the queries on a DNS-over-TCP connection, one after another.
+async fn serve_tcp(ctx: &AnyCtx<Config>, mut conn: smol::net::TcpStream) -> anyhow::Result<()> {
+    loop {
+        let mut len_buf = [0u8; 2];
+        if conn.read_exact(&mut len_buf).await.is_err() {
+            return Ok(());
+        }
+        let mut query = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+        conn.read_exact(&mut query).await?;
+        let response = tunnel_exchange(ctx, &query).await?;
+        conn.write_all(&(response.len() as u16).to_be_bytes())
+            .await?;
+        conn.write_all(&response).await?;
+    }
+}
+
+/// Sends a DNS query over a `dns` stream to the exit, which has its own resolver answer it. Streams are reused from one query to the next, so that most queries do not cost a stream open.
+async fn tunnel_exchange(ctx: &AnyCtx<Config>, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let reused = take_idle(&mut ctx.get(IDLE_STREAMS).lock(), Instant::now());
+    if let Some(mut stream) = reused {
+        // the exit may have closed the stream in the meantime, in which case we go on to a new one
+        match stream_exchange(&mut stream, query).await {
+            Ok(response) => {
+                put_idle(ctx, stream);
+                return Ok(response);
+            }
+            Err(err) => tracing::debug!(err = debug(err), "reused DNS stream failed"),
+        }
+    }
+    let mut stream = open_conn(ctx, "dns", "").await?;
+    let response = stream_exchange(&mut stream, query).await?;
+    put_idle(ctx, stream);
+    Ok(response)
+}
+
+async fn stream_exchange(
+    stream: &mut Box<dyn sillad::Pipe>,
+    query: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    stream
+        .write_all(&(query.len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(query).await?;
+    stream.flush().await?;
+    async {
+        let mut len_buf = [0u8; 2];
+        stream.read_exact(&mut len_buf).await?;
+        let mut response = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+        stream.read_exact(&mut response).await?;
+        anyhow::Ok(response)
+    }
+    .timeout(DNS_TIMEOUT)
+    .await
+    .ok_or_else(|| anyhow::anyhow!("timed out waiting for the exit's resolver"))?
+}
+
+fn put_idle(ctx: &AnyCtx<Config>, stream: Box<dyn sillad::Pipe>) {
+    let mut idle = ctx.get(IDLE_STREAMS).lock();
+    if idle.len() < MAX_IDLE_STREAMS {
+        idle.push((Instant::now(), stream));
+    }
+}
+
+/// Takes the most recently used idle stream, dropping the ones idle for too long.
+fn take_idle<P>(idle: &mut Vec<(Instant, P)>, now: Instant) -> Option<P> {
+    idle.retain(|(since, _)| now.saturating_duration_since(*since) < STREAM_IDLE_TIMEOUT);
+    idle.pop().map(|(_, stream)| stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_the_freshest_stream() {
+        let now = Instant::now();
+        let mut idle = vec![
+            (now - STREAM_IDLE_TIMEOUT, "stale"),
+            (now - Duration::from_secs(20), "older"),
+            (now - Duration::from_secs(1), "newest"),
+        ];
+        assert_eq!(take_idle(&mut idle, now), Some("newest"));
+        assert_eq!(take_idle(&mut idle, now), Some("older"));
+        assert_eq!(take_idle(&mut idle, now), None);
+        assert!(idle.is_empty());
+    }
+}
@@ -0,0 +1,212 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use geph5_rt::TimeoutExt;
+use rand::Rng;
+use reqwest::Client;
+#[cfg(not(target_arch = "wasm32"))]
+use reqwest::Proxy;
+use simple_dns::{rdata::RData, Name, Packet, Question, CLASS, TYPE};
+use url::Url;
+
+use crate::client::Config;
+
+/// How long a single DNS-over-HTTPS query may take.
+const DOH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Resolves a `host:port` string to socket addresses. Names go to the configured DNS-over-HTTPS endpoint, so that lookups made before the tunnel is up do not leak to the local resolver. Without an endpoint, this falls back to the system resolver.
+///
+/// The endpoint is reached through the given upstream proxy if there is one. Otherwise it is dialed like any other connection, which in VPN mode means through the tunnel, since routing it around the tunnel would also route around it whatever else goes to the same address, such as plain DNS.
+pub async fn resolve(
+    ctx: &AnyCtx<Config>,
+    host_port: &str,
+    proxy_addr: Option<SocketAddr>,
+) -> anyhow::Result<Vec<SocketAddr>> {
+    if let Ok(addr) = host_port.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+    let (host, port) = host_port
+        .rsplit_once(':')
+        .context("address to resolve has no port")?;
+    let port: u16 = port.parse().context("address to resolve has a bad port")?;
+    if let Some(addrs) = localhost_addrs(host, port) {
+        return Ok(addrs);
+    }
+    let Some(doh_url) = &ctx.init().doh_url else {
+        #[cfg(not(target_arch = "wasm32"))]
+        return Ok(smol::net::resolve(host_port).await?);
+        // the browser resolves names only as part of the requests it makes itself
+        #[cfg(target_arch = "wasm32")]
+        anyhow::bail!("cannot look up {host} without a DNS-over-HTTPS endpoint in the browser");
+    };
+    let doh_url = Url::parse(doh_url).context("bad DNS-over-HTTPS URL")?;
+    // the tunnel may well be waiting on this very lookup
+    anyhow::ensure!(
+        !ctx.init().vpn || proxy_addr.is_some(),
+        "cannot look up {host} in VPN mode before the tunnel is up, without an upstream proxy"
+    );
+    #[cfg(not(target_arch = "wasm32"))]
+    let client = {
+        let mut client = Client::builder().no_proxy();
+        if let Some(proxy_addr) = proxy_addr {
+            client = client.proxy(Proxy::all(format!("http://{proxy_addr}"))?);
+        }
+        client.build()?
+    };
+    // browsers do their own proxying, out of our hands
+    #[cfg(target_arch = "wasm32")]
+    let client = Client::new();
+
+    let (v4, v6) = futures_util::join!(
+        doh_query(&client, &doh_url, host, TYPE::A),
+        doh_query(&client, &doh_url, host, TYPE::AAAA)
+    );
+    let mut addrs: Vec<SocketAddr> = [v4, v6]
+        .into_iter()
+        .filter_map(|ips| {
+            ips.inspect_err(|err| tracing::debug!(host, err = debug(err), "DoH query failed"))
+                .ok()
+        })
+        .flatten()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect();
+    addrs.dedup();
+    anyhow::ensure!(
+        !addrs.is_empty(),
+        "{host} did not resolve over DNS-over-HTTPS"
+    );
+    tracing::debug!(host, addrs = debug(&addrs), "resolved over DNS-over-HTTPS");
+    Ok(addrs)
+}
+
+/// The loopback addresses for `localhost` and the names under it, which RFC 6761 reserves for this machine, so that no resolver need be asked.
+fn localhost_addrs(host: &str, port: u16) -> Option<Vec<SocketAddr>> {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    (host == "localhost" || host.ends_with(".localhost")).then(|| {
+        vec![
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port),
+        ]
+    })
+}
+
+/// Asks the DNS-over-HTTPS endpoint for one type of record, using the wire format of RFC 8484.
+async fn doh_query(
+    client: &Client,
+    doh_url: &Url,
+    host: &str,
+    qtype: TYPE,
+) -> anyhow::Result<Vec<IpAddr>> {
+    let request = client
+        .post(doh_url.clone())
+        .header("Content-Type", "application/dns-message")
+        .header("Accept", "application/dns-message")
+        .body(doh_request(host, qtype)?);
+    let response = geph5_rt::single_threaded(async {
+        anyhow::Ok(
+            request
+                .send()
+                .timeout(DOH_TIMEOUT)
+                .await
+                .context("DNS-over-HTTPS query timed out")??
+                .error_for_status()?
+                .bytes()
+                .await?,
+        )
+    })
+    .await?;
+    doh_response_addrs(&response)
+}
+
+/// Builds the DNS query for one type of record of a name.
+fn doh_request(host: &str, qtype: TYPE) -> anyhow::Result<Vec<u8>> {
+    let mut query = Packet::new_query(rand::thread_rng().gen());
+    query.set_flags(simple_dns::PacketFlag::RECURSION_DESIRED);
+    query.questions.push(Question::new(
+        Name::new(host)?,
+        qtype.into(),
+        CLASS::IN.into(),
+        false,
+    ));
+    Ok(query.build_bytes_vec()?)
+}
+
+/// Extracts the addresses from a DNS response, skipping records of other types, such as the CNAMEs leading up to them.
+fn doh_response_addrs(response: &[u8]) -> anyhow::Result<Vec<IpAddr>> {
+    let response = Packet::parse(response)?;
+    Ok(response
+        .answers
+        .iter()
+        .filter_map(|answer| match &answer.rdata {
+            RData::A(a) => Some(IpAddr::from(Ipv4Addr::from(a.address))),
+            RData::AAAA(aaaa) => Some(IpAddr::from(Ipv6Addr::from(aaaa.address))),
+            _ => None,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use simple_dns::{
+        rdata::{A, AAAA, CNAME},
+        ResourceRecord,
+    };
+
+    use super::*;
+
+    #[test]
+    fn localhost_needs_no_resolver() {
+        for host in ["localhost", "LocalHost.", "exit.localhost"] {
+            let addrs = localhost_addrs(host, 8964).unwrap();
+            assert!(addrs.iter().all(|addr| addr.ip().is_loopback()));
+            assert!(addrs.iter().all(|addr| addr.port() == 8964));
+        }
+        assert!(localhost_addrs("localhost.example.com", 1).is_none());
+        assert!(localhost_addrs("notlocalhost", 1).is_none());
+    }
+
+    #[test]
+    fn doh_round_trip() {
+        let request = doh_request("exit.example.com", TYPE::A).unwrap();
+        let query = Packet::parse(&request).unwrap();
+        assert_eq!(query.questions.len(), 1);
+        assert_eq!(query.questions[0].qname.to_string(), "exit.example.com");
+
+        let name = Name::new("exit.example.com").unwrap();
+        let target = Name::new("cdn.example.net").unwrap();
+        let mut reply = query.into_reply();
+        reply.answers = vec![
+            ResourceRecord::new(
+                name.clone(),
+                CLASS::IN,
+                60,
+                RData::CNAME(CNAME(target.clone())),
+            ),
+            ResourceRecord::new(
+                target.clone(),
+                CLASS::IN,
+                60,
+                RData::A(A::from(Ipv4Addr::new(192, 0, 2, 1))),
+            ),
+            ResourceRecord::new(
+                target,
+                CLASS::IN,
+                60,
+                RData::AAAA(AAAA::from(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))),
+            ),
+        ];
+        let addrs = doh_response_addrs(&reply.build_bytes_vec().unwrap()).unwrap();
+        assert_eq!(
+            addrs,
+            vec![
+                IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)),
+                IpAddr::from(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+            ]
+        );
+        assert!(doh_response_addrs(b"not a DNS message").is_err());
+    }
+}
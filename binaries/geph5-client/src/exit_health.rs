@@ -0,0 +1,113 @@
+use std::time::Duration;
+use web_time::Instant;
+
+use anyctx::AnyCtx;
+use event_listener::Event;
+
+use crate::client::{Config, CtxField};
+
+/// How long the RTT has to stay degraded before we look for another exit.
+const DEGRADED_FOR: Duration = Duration::from_secs(30);
+
+/// How much each new ping counts in the moving average.
+const EWMA_WEIGHT: f64 = 0.3;
+
+/// Below this much over the baseline, we never consider the RTT degraded, so that jitter on very fast connections does not cause switching.
+const MIN_DEGRADATION: Duration = Duration::from_millis(50);
+
+/// Notified when a tunnel to the current exit has been degraded for long enough that we should look for a better one.
+static EXIT_DEGRADED: CtxField<Event> = |_| Event::new();
+
+/// Waits until some tunnel reports that the current exit is degraded.
+pub async fn wait_exit_degraded(ctx: &AnyCtx<Config>) {
+    ctx.get(EXIT_DEGRADED).listen().await
+}
+
+/// Tracks the RTT of one tunnel, from the pings the tunnel already sends, and reports the exit as degraded when the moving-average RTT has stayed above twice its baseline, the best average seen so far, for a while.
+pub struct ExitHealth {
+    average: Option<f64>,
+    baseline: f64,
+    degraded_since: Option<Instant>,
+}
+
+impl ExitHealth {
+    pub fn new() -> Self {
+        Self {
+            average: None,
+            baseline: f64::INFINITY,
+            degraded_since: None,
+        }
+    }
+
+    /// Takes in one ping's RTT, notifying whoever waits in [wait_exit_degraded] if the exit has now been degraded for long enough.
+    pub fn record_rtt(&mut self, ctx: &AnyCtx<Config>, rtt: Duration) {
+        if self.observe(rtt, Instant::now()) {
+            ctx.get(EXIT_DEGRADED).notify(usize::MAX);
+        }
+    }
+
+    /// Takes in one ping's RTT as of the given time, returning whether the exit has now been degraded for long enough.
+    fn observe(&mut self, rtt: Duration, now: Instant) -> bool {
+        let rtt = rtt.as_secs_f64();
+        let avg = self
+            .average
+            .map_or(rtt, |avg| avg * (1.0 - EWMA_WEIGHT) + rtt * EWMA_WEIGHT);
+        self.average = Some(avg);
+        self.baseline = self.baseline.min(avg);
+        if avg <= 2.0 * self.baseline || avg - self.baseline <= MIN_DEGRADATION.as_secs_f64() {
+            self.degraded_since = None;
+            return false;
+        }
+        let since = *self.degraded_since.get_or_insert(now);
+        if now.saturating_duration_since(since) < DEGRADED_FOR {
+            return false;
+        }
+        tracing::warn!(
+            avg_rtt = debug(Duration::from_secs_f64(avg)),
+            baseline_rtt = debug(Duration::from_secs_f64(self.baseline)),
+            "exit RTT degraded"
+        );
+        self.degraded_since = None;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+    /// Feeds the RTTs in, one ping interval apart, returning after which ones the exit was reported degraded.
+    fn reports(rtts: &[u64]) -> Vec<usize> {
+        let mut health = ExitHealth::new();
+        let start = Instant::now();
+        rtts.iter()
+            .enumerate()
+            .filter(|(i, rtt)| {
+                health.observe(
+                    Duration::from_millis(**rtt),
+                    start + PING_INTERVAL * *i as u32,
+                )
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    #[test]
+    fn reports_lasting_degradation() {
+        let mut rtts = vec![100; 5];
+        rtts.extend([1000; 10]);
+        // the average crosses twice the baseline at the first slow ping, and must then stay there for 30 seconds
+        assert_eq!(reports(&rtts), vec![8, 12]);
+    }
+
+    #[test]
+    fn ignores_spikes_and_small_changes() {
+        let mut rtts = vec![100; 5];
+        rtts.extend([1000, 100, 100, 100, 100, 1000, 100, 100, 100, 100]);
+        assert!(reports(&rtts).is_empty());
+        // tripling a very fast RTT is still within the jitter we tolerate
+        assert!(reports(&[10, 10, 10, 30, 30, 30, 30, 30, 30, 30]).is_empty());
+    }
+}
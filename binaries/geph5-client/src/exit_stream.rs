@@ -0,0 +1,213 @@
+use std::{sync::Mutex, time::Duration};
+use web_time::Instant;
+
+use anyctx::AnyCtx;
+use geph5_broker_protocol::{ExitList, MultiSigned};
+
+use crate::client::{Config, CtxField};
+#[cfg(not(target_arch = "wasm32"))]
+use {crate::broker::broker_source, anyhow::Context, geph5_rt::TimeoutExt, reqwest::Client};
+
+/// The latest exit list pushed by the broker.
+static STREAMED_EXITS: CtxField<Mutex<Option<StreamedExits>>> = |_| Mutex::new(None);
+
+struct StreamedExits {
+    exits: MultiSigned<ExitList>,
+    /// When we last heard anything from the stream.
+    last_heard: Instant,
+}
+
+/// How long a pushed exit list is trusted without hearing anything else from the stream. The broker republishes every minute and sends keep-alives more often than that.
+const STREAM_STALENESS: Duration = Duration::from_secs(180);
+
+/// How long the stream may stay completely silent before we consider it dead.
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The exit list most recently pushed by the broker, if streaming is on and the stream is alive.
+pub fn streamed_exits(ctx: &AnyCtx<Config>) -> Option<MultiSigned<ExitList>> {
+    let streamed = ctx.get(STREAMED_EXITS).lock().unwrap();
+    streamed
+        .as_ref()
+        .filter(|streamed| streamed.last_heard.elapsed() < STREAM_STALENESS)
+        .map(|streamed| streamed.exits.clone())
+}
+
+/// Keeps a server-sent event stream of exit list updates open to the broker, so that exit changes show up immediately instead of on the next poll. While the stream is down, everything falls back to polling the broker.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn exit_stream_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let endpoint = broker_source(ctx.init()).and_then(|broker| broker.sse_endpoint());
+    let Some((url, host)) = endpoint.filter(|_| ctx.init().use_sse) else {
+        return futures_lite::future::pending().await;
+    };
+    let url = format!("{}/v1/exit-stream", url.trim_end_matches('/'));
+    let client = Client::builder().no_proxy().build()?;
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let start = Instant::now();
+        if let Err(err) = stream_once(ctx, &client, &url, host.as_deref()).await {
+            tracing::debug!(err = debug(err), "exit stream broke, polling instead");
+        }
+        *ctx.get(STREAMED_EXITS).lock().unwrap() = None;
+        if start.elapsed() > STREAM_STALENESS {
+            backoff = Duration::from_secs(1);
+        }
+        geph5_rt::Timer::after(backoff).await;
+        backoff = (backoff * 2).min(Duration::from_secs(300));
+    }
+}
+
+/// In the browser, reqwest cannot hand us a response body bit by bit, so we only ever poll.
+#[cfg(target_arch = "wasm32")]
+pub async fn exit_stream_loop(_ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    futures_lite::future::pending().await
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn stream_once(
+    ctx: &AnyCtx<Config>,
+    client: &Client,
+    url: &str,
+    host: Option<&str>,
+) -> anyhow::Result<()> {
+    let mut request = client.get(url).header("Accept", "text/event-stream");
+    if let Some(host) = host {
+        request = request.header("Host", host);
+    }
+    let mut response = request.send().await?.error_for_status()?;
+    tracing::debug!(url, "exit stream connected");
+    let mut parser = SseParser::default();
+    loop {
+        let chunk = response
+            .chunk()
+            .timeout(READ_TIMEOUT)
+            .await
+            .context("exit stream timed out")??
+            .context("exit stream closed by broker")?;
+        // keep-alives also count as signs of life
+        if let Some(streamed) = ctx.get(STREAMED_EXITS).lock().unwrap().as_mut() {
+            streamed.last_heard = Instant::now();
+        }
+        for event in parser.feed(&chunk)? {
+            if event.event != "exit_update" {
+                continue;
+            }
+            let exits: MultiSigned<ExitList> =
+                serde_json::from_str(&event.data).context("bad exit update")?;
+            tracing::debug!(
+                count = exits.inner.all_exits.len(),
+                "broker pushed an exit update"
+            );
+            *ctx.get(STREAMED_EXITS).lock().unwrap() = Some(StreamedExits {
+                exits,
+                last_heard: Instant::now(),
+            });
+        }
+    }
+}
+
+/// One server-sent event.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, PartialEq)]
+struct SseEvent {
+    event: String,
+    data: String,
+}
+
+/// Reassembles server-sent events from a byte stream that may be cut anywhere, as described in the HTML standard. Only the `event` and `data` fields are kept, and comments and other fields are skipped.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Default)]
+struct SseParser {
+    buffer: Vec<u8>,
+    event: String,
+    data: String,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl SseParser {
+    /// Feeds in the next chunk of the stream, returning the events it completed.
+    fn feed(&mut self, chunk: &[u8]) -> anyhow::Result<Vec<SseEvent>> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = vec![];
+        while let Some(newline) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=newline).collect();
+            let line = std::str::from_utf8(&line).context("exit stream not UTF-8")?;
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                events.push(SseEvent {
+                    event: std::mem::take(&mut self.event),
+                    data: std::mem::take(&mut self.data),
+                });
+            } else if let Some(value) = line.strip_prefix("event:") {
+                self.event = value.trim_start().to_string();
+            } else if let Some(value) = line.strip_prefix("data:") {
+                if !self.data.is_empty() {
+                    self.data.push('\n');
+                }
+                self.data.push_str(value.strip_prefix(' ').unwrap_or(value));
+            }
+        }
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event: &str, data: &str) -> SseEvent {
+        SseEvent {
+            event: event.into(),
+            data: data.into(),
+        }
+    }
+
+    #[test]
+    fn joins_multi_line_data() {
+        let mut parser = SseParser::default();
+        let events = parser
+            .feed(b"event: exit_update\ndata: {\"a\":\ndata:1}\n\n")
+            .unwrap();
+        assert_eq!(events, vec![event("exit_update", "{\"a\":\n1}")]);
+    }
+
+    #[test]
+    fn skips_comments_and_unknown_fields() {
+        let mut parser = SseParser::default();
+        let events = parser
+            .feed(
+                b": keep-alive\n\nid: 7\nevent: exit_update\n: still here\ndata: x\nretry: 10\n\n",
+            )
+            .unwrap();
+        // a keep-alive on its own still ends an (empty) event, which callers ignore by its type
+        assert_eq!(events, vec![event("", ""), event("exit_update", "x")]);
+    }
+
+    #[test]
+    fn reassembles_split_chunks() {
+        let stream = b"event: exit_update\r\ndata: hello\r\n\r\nevent: other\ndata: world\n\n";
+        // every way of cutting the stream in two gives the same events
+        for cut in 0..stream.len() {
+            let mut parser = SseParser::default();
+            let mut events = parser.feed(&stream[..cut]).unwrap();
+            events.extend(parser.feed(&stream[cut..]).unwrap());
+            assert_eq!(
+                events,
+                vec![event("exit_update", "hello"), event("other", "world")],
+                "cut at {cut}"
+            );
+        }
+        // even byte by byte, and with the event not yet finished, nothing comes out early
+        let mut parser = SseParser::default();
+        for byte in &stream[..stream.len() - 1] {
+            let events = parser.feed(&[*byte]).unwrap();
+            assert!(events.len() <= 1);
+        }
+        assert_eq!(parser.feed(b"\n").unwrap(), vec![event("other", "world")]);
+    }
+
+    #[test]
+    fn rejects_invalid_utf8() {
+        let mut parser = SseParser::default();
+        assert!(parser.feed(b"data: \xff\n").is_err());
+    }
+}
@@ -2,13 +2,19 @@ mod address;
 mod http_client;
 mod rt_compat;
 
-use std::{net::SocketAddr, str::FromStr as _};
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    str::FromStr as _,
+    sync::Mutex,
+    task::{ready, Context, Poll},
+};
 
 pub async fn run_http_proxy(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
     let shared_server: SharedProxyServer = ProxyServer::new_shared(ctx.clone());
     let listen = ctx.init().http_proxy_listen;
     if let Some(listen) = listen {
-        let tcp_listener = tokio::net::TcpListener::bind(&listen).await?;
+        let tcp_listener = tokio::net::TcpListener::bind(listen_addr(ctx, listen)).await?;
         let mut join_set = JoinSet::new();
         loop {
             let (stream, addr) = match tcp_listener.accept().await {
@@ -52,6 +58,18 @@ async fn server_dispatch(
     proxy_server: SharedProxyServer,
     ctx: AnyCtx<Config>,
 ) -> std::io::Result<Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty<Bytes>>>> {
+    let level = if auth_required(&ctx) {
+        match proxy_auth(&ctx, req.headers()) {
+            Some(level) => Some(level),
+            None => {
+                tracing::debug!(client_addr = %client_addr, "HTTP proxy auth failed");
+                return Ok(make_auth_required());
+            }
+        }
+    } else {
+        None
+    };
+    let limiter = source_limiter(&ctx, client_addr.ip(), level).await;
     let host = match host_addr(req.uri()) {
         None => {
             if req.uri().authority().is_some() {
@@ -154,7 +172,9 @@ async fn server_dispatch(
                     );
                     let stream = open_conn(&ctx, "tcp", &host.to_string()).await;
                     if let Ok(stream) = stream {
-                        establish_connect_tunnel(upgraded, stream, client_addr).await
+                        let buf_size = proxy_buffer_size(&ctx);
+                        establish_connect_tunnel(upgraded, stream, client_addr, limiter, buf_size)
+                            .await
                     }
                 }
                 Err(e) => {
@@ -176,16 +196,27 @@ async fn server_dispatch(
         set_conn_keep_alive(req.version(), req.headers_mut(), conn_keep_alive);
         let (parts, body) = req.into_parts();
         let body = match body.collect().await {
-            Ok(c) => c.boxed(),
+            Ok(c) => c.to_bytes(),
             Err(_) => return Ok(make_bad_request()),
         };
+        limiter.wait(body.len()).await;
+        let body = Full::new(body).map_err(|_| unreachable!()).boxed();
         let mut res: Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty<Bytes>>> =
             match proxy_server
                 .client
                 .request(Request::from_parts(parts, body))
                 .await
             {
-                Ok(res) => res.map(|b| HttpEither::Left(b.boxed())),
+                Ok(res) => res.map(|b| {
+                    HttpEither::Left(
+                        MeteredBody {
+                            inner: b.boxed(),
+                            limiter: limiter.clone(),
+                            waiting: Mutex::new(None),
+                        }
+                        .boxed(),
+                    )
+                }),
                 Err(err) => {
                     tracing::trace!(
                         method = %method,
@@ -213,18 +244,23 @@ async fn server_dispatch(
 }
 use anyctx::AnyCtx;
 use async_compat::CompatExt;
+use base64::{prelude::BASE64_STANDARD, Engine as _};
 use bytes::Bytes;
 use futures_util::{
-    future::{self, Either},
-    FutureExt,
+    future::{self, BoxFuture, Either},
+    AsyncReadExt as _, FutureExt,
 };
+use geph5_broker_protocol::AccountLevel;
 use http::{
     uri::{Authority, Scheme},
     HeaderMap, HeaderValue, Method, Uri, Version,
 };
 use http_body_util::{combinators::BoxBody, BodyExt, Either as HttpEither, Empty, Full};
 use hyper::{
-    body::Incoming, service::service_fn, upgrade::Upgraded, Request, Response, StatusCode,
+    body::{Body, Frame, Incoming, SizeHint},
+    service::service_fn,
+    upgrade::Upgraded,
+    Request, Response, StatusCode,
 };
 use tokio::task::JoinSet;
 
@@ -232,14 +268,14 @@ async fn establish_connect_tunnel(
     upgraded: Upgraded,
     stream: impl sillad::Pipe,
     client_addr: SocketAddr,
+    limiter: SourceLimiter,
+    buf_size: usize,
 ) {
-    use tokio::io::{copy, split};
-
-    let (mut r, mut w) = split(rt_compat::HyperRtCompat::new(upgraded));
-    let (mut svr_r, mut svr_w) = split(stream.compat());
+    let (r, w) = rt_compat::HyperRtCompat::new(upgraded).compat().split();
+    let (svr_r, svr_w) = stream.split();
 
-    let rhalf = copy(&mut r, &mut svr_w);
-    let whalf = copy(&mut svr_r, &mut w);
+    let rhalf = limiter.io_copy(buf_size, r, svr_w);
+    let whalf = limiter.io_copy(buf_size, svr_r, w);
 
     tracing::trace!(
         client_addr = %client_addr,
@@ -277,6 +313,49 @@ async fn establish_connect_tunnel(
     );
 }
 
+/// A response body that waits for the limiter before passing on each chunk, so that what gets metered is what the server actually sends, however the body is framed.
+struct MeteredBody {
+    inner: BoxBody<Bytes, hyper::Error>,
+    limiter: SourceLimiter,
+    /// The chunk we are waiting to pass on. Bodies must be `Sync`, which the limiter's future is not, but a mutex makes it so.
+    waiting: Mutex<Option<(Frame<Bytes>, BoxFuture<'static, ()>)>>,
+}
+
+impl Body for MeteredBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        let this = &mut *self;
+        loop {
+            let waiting = this.waiting.get_mut().unwrap();
+            if let Some((_, wait)) = waiting.as_mut() {
+                ready!(wait.poll_unpin(cx));
+                let (frame, _) = waiting.take().unwrap();
+                return Poll::Ready(Some(Ok(frame)));
+            }
+            let frame = match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => frame,
+                other => return Poll::Ready(other),
+            };
+            let len = frame.data_ref().map_or(0, |data| data.len());
+            let limiter = this.limiter.clone();
+            *waiting = Some((frame, async move { limiter.wait(len).await }.boxed()));
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
 fn make_bad_request() -> Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty<Bytes>>> {
     let mut resp: Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty<Bytes>>> = Response::new(
         HttpEither::Left(Empty::new().map_err(|_| unreachable!()).boxed()),
@@ -285,9 +364,38 @@ fn make_bad_request() -> Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty
     resp
 }
 
+fn make_auth_required() -> Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty<Bytes>>> {
+    let mut resp: Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty<Bytes>>> = Response::new(
+        HttpEither::Left(Empty::new().map_err(|_| unreachable!()).boxed()),
+    );
+    *resp.status_mut() = StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+    resp.headers_mut().insert(
+        "Proxy-Authenticate",
+        HeaderValue::from_static("Basic realm=\"geph5\""),
+    );
+    resp
+}
+
+/// Checks the Basic credentials in the Proxy-Authorization header, returning the account level of the user.
+fn proxy_auth(ctx: &AnyCtx<Config>, headers: &HeaderMap<HeaderValue>) -> Option<AccountLevel> {
+    let header = headers.get("Proxy-Authorization")?.to_str().ok()?;
+    let (scheme, encoded) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(BASE64_STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    authenticate(ctx, username, password)
+}
+
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-use crate::{client_inner::open_conn, Config};
+use crate::{
+    bloat::proxy_buffer_size,
+    client_inner::open_conn,
+    multi_user::{auth_required, authenticate, listen_addr, source_limiter, SourceLimiter},
+    Config,
+};
 
 use self::address::{host_addr, Address};
 fn authority_addr(scheme_str: Option<&str>, authority: &Authority) -> Option<Address> {
@@ -0,0 +1,92 @@
+use web_time::{SystemTime, UNIX_EPOCH};
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use ed25519_dalek::VerifyingKey;
+use geph5_broker_protocol::DOMAIN_EXIT_DESCRIPTOR;
+
+use crate::{
+    broker::{broker_client, broker_error},
+    client::Config,
+    database::{db_read, db_write},
+    timeout::geph5_timeout,
+};
+
+/// The database key under which the last key log we saw is stored, so that we can tell whether the log was rewritten.
+const KEY_LOG_KEY: &str = "key_transparency_log";
+
+/// One entry of the key log: when a broker signing key was added, and the key itself in hex.
+type KeyLogEntry = (u64, String);
+
+/// Checks the keys the broker is currently signing with against the key transparency log, if one is configured. Fails if any of them is missing from the log or was added too recently, or if the log is not an extension of the one we saw last time.
+pub async fn check_key_transparency(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let Some(url) = &ctx.init().key_transparency_url else {
+        return Ok(());
+    };
+    let log = geph5_rt::single_threaded(async {
+        anyhow::Ok(
+            geph5_timeout!(
+                ctx,
+                key_transparency,
+                reqwest::Client::new().get(url).send()
+            )??
+            .error_for_status()?
+            .bytes()
+            .await?,
+        )
+    })
+    .await?;
+    let log: Vec<KeyLogEntry> = serde_json::from_slice(&log).context("could not parse key log")?;
+
+    // the log must be append-only
+    if let Some(old_log) = db_read(ctx, KEY_LOG_KEY).await? {
+        let old_log: Vec<KeyLogEntry> = serde_json::from_slice(&old_log)?;
+        anyhow::ensure!(
+            log.starts_with(&old_log),
+            "key log was rewritten since we last saw it"
+        );
+    }
+    db_write(ctx, KEY_LOG_KEY, &serde_json::to_vec(&log)?).await?;
+
+    let exits = match broker_client(ctx)?.get_exits().await? {
+        Ok(exits) => exits,
+        Err(err) => return Err(broker_error("exits", err).await),
+    };
+    let trusted = match &ctx.init().broker_keys {
+        Some(broker_keys) => Some(broker_keys.trusted_keys()?),
+        None => None,
+    };
+    // only the keys whose signatures we actually rely on matter
+    let seen_keys: Vec<VerifyingKey> = exits
+        .signatures
+        .iter()
+        .map(|(key, _)| *key)
+        .filter(|key| trusted.as_ref().is_none_or(|t| t.contains(key)))
+        .filter(|key| {
+            exits
+                .clone()
+                .verify(DOMAIN_EXIT_DESCRIPTOR, &[*key], 1)
+                .is_ok()
+        })
+        .collect();
+    anyhow::ensure!(!seen_keys.is_empty(), "broker did not sign with any key");
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let grace = ctx.init().key_log_grace_secs;
+    for key in seen_keys {
+        let key = hex::encode(key.as_bytes());
+        let added = log
+            .iter()
+            .filter(|(_, logged)| logged.eq_ignore_ascii_case(&key))
+            .map(|(timestamp, _)| *timestamp)
+            .min()
+            .with_context(|| format!("broker key {key} is not in the key log"))?;
+        anyhow::ensure!(
+            added.saturating_add(grace) <= now,
+            "broker key {key} was added to the key log only {} seconds ago",
+            now.saturating_sub(added)
+        );
+    }
+    tracing::debug!("broker keys passed the key transparency check");
+    Ok(())
+}
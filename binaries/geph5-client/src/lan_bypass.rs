@@ -0,0 +1,75 @@
+use anyctx::AnyCtx;
+use ipnet::IpNet;
+
+use crate::{client::Config, vpn::vpn_bypass_network};
+
+/// Detects the private (RFC 1918) networks we are directly attached to, and sends traffic to them around the VPN, so that LAN traffic does not go out to the exit and back. Since we might have moved to a different network, this runs again on every reconnect.
+pub fn refresh_lan_bypass(ctx: &AnyCtx<Config>) {
+    if !ctx.init().vpn || !ctx.init().auto_lan_bypass {
+        return;
+    }
+    match local_networks() {
+        Ok(networks) => {
+            for net in networks {
+                tracing::debug!(net = display(net), "bypassing VPN for LAN");
+                vpn_bypass_network(net);
+            }
+        }
+        Err(err) => tracing::warn!(err = debug(err), "could not detect LAN"),
+    }
+}
+
+/// Lists the private IPv4 networks of our interfaces, from their addresses and netmasks.
+#[cfg(unix)]
+fn local_networks() -> anyhow::Result<Vec<IpNet>> {
+    use std::net::Ipv4Addr;
+
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let mut networks = vec![];
+    let mut cursor = ifaddrs;
+    while let Some(ifaddr) = unsafe { cursor.as_ref() } {
+        cursor = ifaddr.ifa_next;
+        if ifaddr.ifa_addr.is_null()
+            || ifaddr.ifa_netmask.is_null()
+            || unsafe { (*ifaddr.ifa_addr).sa_family } != libc::AF_INET as libc::sa_family_t
+        {
+            continue;
+        }
+        let (addr, mask) = unsafe {
+            (
+                *(ifaddr.ifa_addr as *const libc::sockaddr_in),
+                *(ifaddr.ifa_netmask as *const libc::sockaddr_in),
+            )
+        };
+        let addr = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+        let prefix_len = u32::from_be(mask.sin_addr.s_addr).leading_ones() as u8;
+        if !addr.is_private() {
+            continue;
+        }
+        if let Ok(net) = ipnet::Ipv4Net::new(addr, prefix_len) {
+            networks.push(IpNet::V4(net.trunc()));
+        }
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    networks.sort();
+    networks.dedup();
+    Ok(networks)
+}
+
+/// Without getifaddrs, we find our address on the default route, and assume the common /24 netmask.
+#[cfg(not(unix))]
+fn local_networks() -> anyhow::Result<Vec<IpNet>> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    // connecting a UDP socket sends no packets
+    socket.connect("8.8.8.8:53")?;
+    let std::net::IpAddr::V4(addr) = socket.local_addr()?.ip() else {
+        return Ok(vec![]);
+    };
+    if !addr.is_private() {
+        return Ok(vec![]);
+    }
+    Ok(vec![IpNet::V4(ipnet::Ipv4Net::new(addr, 24)?.trunc())])
+}
@@ -1,20 +1,79 @@
 pub use broker::broker_client;
 pub use broker::BrokerSource;
 pub use client::Client;
-pub use client::{BridgeMode, BrokerKeys, Config};
-pub use control_prot::{ConnInfo, ControlClient};
-pub use route::ExitConstraint;
+pub use client::{BridgeMode, BrokerKeys, Config, TenantCredential};
+#[cfg(not(target_arch = "wasm32"))]
+pub use control_prot::{change_exit_constraint, query_health_report};
+pub use control_prot::{ConnInfo, ControlClient, HealthReport};
+pub use diagnose::{diagnose, DiagnosticReport, ExitProbe};
+pub use route::{exit_constraint_candidates, list_exits, ExitConstraint, ExitSummary};
+pub use smart_routing::{load_exit_stats, ExitStats};
+pub use vpn::{AppAction, AppRoute, DnsResolver, DnsRoute, SplitTunnel};
+#[cfg(not(target_arch = "wasm32"))]
+pub use wireguard::WireguardConfig;
+
+#[cfg(all(feature = "vpn", target_arch = "wasm32"))]
+compile_error!(
+    "the VPN mode needs a TUN device, so build for the browser with --no-default-features"
+);
 
 mod auth;
+mod bloat;
 mod broker;
+mod chaos;
 mod china;
+mod circuit_breaker;
 mod client;
 mod client_inner;
+#[cfg(unix)]
+mod coalesce;
+#[cfg(unix)]
+mod control_datagram;
 mod control_prot;
+#[cfg(not(target_arch = "wasm32"))]
+mod crash;
+#[cfg(not(target_arch = "wasm32"))]
 mod database;
+mod diagnose;
+mod dialer_pool;
+#[cfg(not(target_arch = "wasm32"))]
+mod dns_server;
+mod doh;
+mod exit_health;
+mod exit_stream;
+#[cfg(not(target_arch = "wasm32"))]
 mod http_proxy;
+mod key_transparency;
+mod lan_bypass;
+mod load_balance;
 pub mod logs;
+mod metrics;
+mod multi_exit;
+#[cfg(not(target_arch = "wasm32"))]
+mod multi_user;
+mod multipath;
+mod net_change;
+#[cfg(not(target_arch = "wasm32"))]
+mod proxy_detect;
+#[cfg(not(target_arch = "wasm32"))]
+mod quic;
 mod route;
+mod route_condition;
+mod shaper;
+mod smart_routing;
+#[cfg(not(target_arch = "wasm32"))]
 mod socks5;
 mod stats;
+mod tcp_stats;
+mod timeout;
+#[cfg(all(feature = "tray", target_os = "linux"))]
+pub mod tray;
 mod vpn;
+#[cfg(target_arch = "wasm32")]
+mod wasm;
+#[cfg(target_arch = "wasm32")]
+use wasm::database;
+#[cfg(windows)]
+pub mod windows_service;
+#[cfg(not(target_arch = "wasm32"))]
+mod wireguard;
@@ -0,0 +1,236 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use futures_util::{AsyncRead, AsyncWrite};
+use geph5_broker_protocol::LoadBalanceStrategy;
+use pin_project::pin_project;
+use rand::Rng;
+use sillad::{
+    dialer::{Dialer, DynDialer},
+    Pipe,
+};
+
+/// A dialer that spreads connections across several dialers according to a [LoadBalanceStrategy], as directed by the broker.
+pub struct LoadBalanceDialer {
+    strategy: LoadBalanceStrategy,
+    routes: Vec<DynDialer>,
+    next: AtomicUsize,
+    /// How many connections are currently open through each route.
+    open: Arc<Vec<AtomicUsize>>,
+}
+
+impl LoadBalanceDialer {
+    /// Creates a new load-balancing dialer. There must be at least one route.
+    pub fn new(strategy: LoadBalanceStrategy, routes: Vec<DynDialer>) -> Self {
+        assert!(!routes.is_empty(), "cannot load-balance across zero routes");
+        let open = Arc::new(routes.iter().map(|_| AtomicUsize::new(0)).collect());
+        Self {
+            strategy,
+            routes,
+            next: AtomicUsize::new(0),
+            open,
+        }
+    }
+
+    fn pick(&self) -> usize {
+        match self.strategy {
+            LoadBalanceStrategy::RoundRobin => {
+                self.next.fetch_add(1, Ordering::Relaxed) % self.routes.len()
+            }
+            LoadBalanceStrategy::LeastConnections => self
+                .open
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, open)| open.load(Ordering::Relaxed))
+                .map(|(idx, _)| idx)
+                .unwrap_or_default(),
+            LoadBalanceStrategy::Random => rand::thread_rng().gen_range(0..self.routes.len()),
+        }
+    }
+}
+
+impl Dialer for LoadBalanceDialer {
+    type P = CountedPipe;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let idx = self.pick();
+        tracing::debug!(
+            strategy = debug(self.strategy),
+            idx,
+            "load balancer picked route"
+        );
+        // count the connection while it is being established too, so that concurrent dials spread out
+        let guard = OpenGuard::new(self.open.clone(), idx);
+        let inner = self.routes[idx].dial().await?;
+        Ok(CountedPipe {
+            inner,
+            _guard: guard,
+        })
+    }
+}
+
+/// Counts one open connection through a route, for as long as it lives.
+struct OpenGuard {
+    open: Arc<Vec<AtomicUsize>>,
+    idx: usize,
+}
+
+impl OpenGuard {
+    fn new(open: Arc<Vec<AtomicUsize>>, idx: usize) -> Self {
+        open[idx].fetch_add(1, Ordering::Relaxed);
+        Self { open, idx }
+    }
+}
+
+impl Drop for OpenGuard {
+    fn drop(&mut self) {
+        self.open[self.idx].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The pipe produced by a [LoadBalanceDialer], which keeps its route's connection count up to date.
+#[pin_project]
+pub struct CountedPipe {
+    #[pin]
+    inner: Box<dyn Pipe>,
+    _guard: OpenGuard,
+}
+
+impl AsyncRead for CountedPipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.project().inner.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CountedPipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl Pipe for CountedPipe {
+    fn shared_secret(&self) -> Option<&[u8]> {
+        self.inner.shared_secret()
+    }
+
+    fn protocol(&self) -> &str {
+        self.inner.protocol()
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.inner.remote_addr()
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        self.inner.raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sillad::{
+        dialer::{DialerExt as _, FailingDialer},
+        testing::{NullDialer, NullMode},
+    };
+
+    use super::*;
+
+    fn null_routes(n: usize) -> Vec<DynDialer> {
+        (0..n)
+            .map(|_| {
+                NullDialer {
+                    mode: NullMode::Connected,
+                }
+                .dynamic()
+            })
+            .collect()
+    }
+
+    fn open_counts(dialer: &LoadBalanceDialer) -> Vec<usize> {
+        dialer
+            .open
+            .iter()
+            .map(|open| open.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        smol::future::block_on(async {
+            let dialer = LoadBalanceDialer::new(LoadBalanceStrategy::RoundRobin, null_routes(3));
+            let mut pipes = vec![];
+            for expected in [[1, 0, 0], [1, 1, 0], [1, 1, 1], [2, 1, 1]] {
+                pipes.push(dialer.dial().await.unwrap());
+                assert_eq!(open_counts(&dialer), expected);
+            }
+            drop(pipes);
+            assert_eq!(open_counts(&dialer), [0, 0, 0]);
+        });
+    }
+
+    #[test]
+    fn least_connections_fills_the_emptiest_route() {
+        smol::future::block_on(async {
+            let dialer =
+                LoadBalanceDialer::new(LoadBalanceStrategy::LeastConnections, null_routes(3));
+            let mut pipes = vec![];
+            for _ in 0..3 {
+                pipes.push(dialer.dial().await.unwrap());
+            }
+            assert_eq!(open_counts(&dialer), [1, 1, 1]);
+            // closing a connection makes its route the emptiest
+            drop(pipes.remove(1));
+            pipes.push(dialer.dial().await.unwrap());
+            assert_eq!(open_counts(&dialer), [1, 1, 1]);
+            pipes.push(dialer.dial().await.unwrap());
+            assert_eq!(open_counts(&dialer).iter().sum::<usize>(), 4);
+        });
+    }
+
+    #[test]
+    fn random_stays_in_range() {
+        smol::future::block_on(async {
+            let dialer = LoadBalanceDialer::new(LoadBalanceStrategy::Random, null_routes(2));
+            let mut pipes = vec![];
+            for _ in 0..50 {
+                pipes.push(dialer.dial().await.unwrap());
+            }
+            assert_eq!(open_counts(&dialer).iter().sum::<usize>(), 50);
+        });
+    }
+
+    #[test]
+    fn failed_dials_are_not_counted() {
+        smol::future::block_on(async {
+            let dialer = LoadBalanceDialer::new(
+                LoadBalanceStrategy::LeastConnections,
+                vec![FailingDialer.dynamic()],
+            );
+            assert!(dialer.dial().await.is_err());
+            assert_eq!(open_counts(&dialer), [0]);
+        });
+    }
+}
@@ -1,4 +1,62 @@
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
 use arc_writer::ArcWriter;
 use once_cell::sync::Lazy;
 
 pub static LOGS: Lazy<ArcWriter<Vec<u8>>> = Lazy::new(|| ArcWriter::new(vec![]));
+
+/// How big a [RotatingFile] may grow before it is rotated.
+const MAX_LOG_FILE_BYTES: u64 = 10_000_000;
+
+/// A log file that, once it grows past 10 MB, is moved aside to `<path>.1`, replacing the previous one there, and started afresh. Logs thus never take up much more than 20 MB.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+}
+
+impl RotatingFile {
+    /// Opens the log file at the given path, appending to whatever is already there.
+    pub fn open(path: PathBuf) -> std::io::Result<Self> {
+        let file = File::options().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            written,
+        })
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        std::fs::rename(&self.path, Path::new(&rotated))?;
+        self.file = File::options()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // every log line is written at once, so lines are never split across files
+        if self.written >= MAX_LOG_FILE_BYTES {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
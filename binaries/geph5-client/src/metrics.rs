@@ -0,0 +1,132 @@
+use std::{collections::VecDeque, net::SocketAddr, time::Duration};
+
+use anyctx::AnyCtx;
+use parking_lot::Mutex;
+use serde::Serialize;
+use web_time::{SystemTime, UNIX_EPOCH};
+
+use crate::client::{Config, CtxField};
+// the browser cannot serve anything, so there we only keep track of the attempts
+#[cfg(not(target_arch = "wasm32"))]
+use {
+    crate::{
+        circuit_breaker::{CircuitStatus, EXITS_BREAKER, ROUTES_BREAKER},
+        client::BridgeMode,
+        control_prot::{ConnInfo, CURRENT_CONN_INFO},
+        route::{bridges_promoted, route_shitlist, RoutePenalty},
+        vpn::{PathMtu, PATH_MTU},
+    },
+    async_compat::CompatExt,
+    bytes::Bytes,
+    http_body_util::Full,
+    hyper::{body::Incoming, service::service_fn, Request, Response},
+};
+
+/// How many connection attempts the metrics endpoint remembers.
+const MAX_ATTEMPTS: usize = 50;
+
+/// The most recent connection attempts, oldest first.
+static RECENT_ATTEMPTS: CtxField<Mutex<VecDeque<ConnectionAttempt>>> =
+    |_| Mutex::new(VecDeque::new());
+
+#[derive(Serialize, Clone, Debug)]
+struct ConnectionAttempt {
+    /// Unix timestamp of when the attempt finished.
+    timestamp: u64,
+    exit: SocketAddr,
+    country: String,
+    city: String,
+    /// The error, if the attempt failed.
+    error: Option<String>,
+    latency_ms: Option<u64>,
+}
+
+/// Everything the metrics endpoint serves.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Serialize)]
+struct MetricsSnapshot {
+    conn_info: ConnInfo,
+    bridge_mode: BridgeMode,
+    /// Whether [BridgeMode::SmartBridges] has switched to using bridges.
+    bridges_promoted: bool,
+    shitlist: Vec<RoutePenalty>,
+    broker_circuits: Vec<CircuitStatus>,
+    /// What path MTU discovery found, which only runs in VPN mode on Linux, when connecting directly to exits that answer its probes.
+    path_mtu: Option<PathMtu>,
+    recent_attempts: Vec<ConnectionAttempt>,
+}
+
+/// Records the outcome of an attempt to connect to an exit.
+pub fn record_connection_attempt<T>(
+    ctx: &AnyCtx<Config>,
+    exit: &geph5_broker_protocol::ExitDescriptor,
+    outcome: &anyhow::Result<T>,
+    latency: Duration,
+) {
+    let attempt = ConnectionAttempt {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        exit: exit.c2e_listen,
+        country: exit.country.alpha2().to_string(),
+        city: exit.city.clone(),
+        error: outcome.as_ref().err().map(|err| format!("{err:#}")),
+        latency_ms: outcome.is_ok().then_some(latency.as_millis() as u64),
+    };
+    let mut attempts = ctx.get(RECENT_ATTEMPTS).lock();
+    attempts.push_back(attempt);
+    while attempts.len() > MAX_ATTEMPTS {
+        attempts.pop_front();
+    }
+}
+
+/// Takes a snapshot of the metrics. The attempt log stays locked throughout, so a snapshot never shows an attempt without the state it left behind.
+#[cfg(not(target_arch = "wasm32"))]
+fn snapshot(ctx: &AnyCtx<Config>) -> MetricsSnapshot {
+    let attempts = ctx.get(RECENT_ATTEMPTS).lock();
+    MetricsSnapshot {
+        conn_info: ctx.get(CURRENT_CONN_INFO).lock().clone(),
+        bridge_mode: ctx.init().bridge_mode,
+        bridges_promoted: bridges_promoted(ctx),
+        // read straight from the shitlist, so every deprioritized route shows up immediately
+        shitlist: route_shitlist(),
+        broker_circuits: vec![
+            ctx.get(EXITS_BREAKER).status(ctx),
+            ctx.get(ROUTES_BREAKER).status(ctx),
+        ],
+        path_mtu: *ctx.get(PATH_MTU).lock(),
+        recent_attempts: attempts.iter().cloned().collect(),
+    }
+}
+
+/// Serves a JSON snapshot of which exits are being tried and how that is going, over plain HTTP, for clients running as daemons.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn metrics_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let Some(listen) = ctx.init().metrics_listen else {
+        return futures_lite::future::pending().await;
+    };
+    let listener = smol::net::TcpListener::bind(listen).await?;
+    tracing::info!(listen = display(listen), "serving metrics");
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let ctx = ctx.clone();
+        geph5_rt::spawn(async move {
+            let service = service_fn(|_: Request<Incoming>| {
+                let body = serde_json::to_vec(&snapshot(&ctx)).unwrap_or_default();
+                async move {
+                    Response::builder()
+                        .header("Content-Type", "application/json")
+                        .body(Full::new(Bytes::from(body)))
+                }
+            });
+            if let Err(err) = hyper::server::conn::http1::Builder::new()
+                .serve_connection(hyper_util::rt::TokioIo::new(stream.compat()), service)
+                .await
+            {
+                tracing::debug!(err = debug(err), "error serving metrics");
+            }
+        })
+        .detach();
+    }
+}
@@ -0,0 +1,264 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use web_time::Instant;
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use ed25519_dalek::VerifyingKey;
+use event_listener::Event;
+use futures_lite::FutureExt as _;
+use futures_util::future::try_join_all;
+use geph5_broker_protocol::ExitDescriptor;
+use sillad::{
+    dialer::{Dialer as _, DynDialer},
+    Pipe as _,
+};
+
+use crate::{
+    client::Config,
+    client_inner::{
+        client_auth, client_inner, conn_req_chan, ConnReqChan, CONCURRENCY, CONN_REQ_CHAN,
+    },
+    control_prot::{ConnInfo, ConnectedInfo, CURRENT_CONN_INFO},
+    metrics::record_connection_attempt,
+    route::{direct_route_failed, get_exit_dialers, wait_exit_constraint_changed},
+    timeout::geph5_timeout,
+};
+
+/// How long an exit may stay unreachable before we give up on the whole set of exits and pick them afresh.
+const LANE_GIVE_UP: Duration = Duration::from_secs(60);
+
+/// One of the exits we are connected to at the same time, with its own queue of stream requests.
+struct ExitLane {
+    pubkey: VerifyingKey,
+    exit: ExitDescriptor,
+    dialer: DynDialer,
+    /// How many sessions to this exit are currently up.
+    live: AtomicUsize,
+    requests: ConnReqChan,
+}
+
+/// Runs the client over several exits at once, all satisfying the exit constraint, and hands new streams to them in turn. Streams skip exits that are down, and when an exit stays down, this returns an error so that the caller starts over with a fresh set of exits.
+pub async fn multi_exit_once(ctx: &AnyCtx<Config>, count: usize) -> anyhow::Result<()> {
+    let lanes: Vec<ExitLane> = get_exit_dialers(ctx, count)
+        .await
+        .context("could not get exits")?
+        .into_iter()
+        .map(|(pubkey, exit, dialer)| ExitLane {
+            pubkey,
+            exit,
+            dialer,
+            live: AtomicUsize::new(0),
+            requests: conn_req_chan(),
+        })
+        .collect();
+    tracing::info!(
+        exits = debug(lanes.iter().map(|l| l.exit.c2e_listen).collect::<Vec<_>>()),
+        "connecting to multiple exits"
+    );
+
+    // notified whenever a session to any exit comes up
+    let lane_up = Event::new();
+    let dispatch = async {
+        let mut next = 0;
+        loop {
+            let request = ctx.get(CONN_REQ_CHAN).1.lock().await.recv().await?;
+            let lane = wait_for_lane(&lanes, &lane_up, next).await;
+            next = lane + 1;
+            lanes[lane]
+                .requests
+                .0
+                .send(request)
+                .await
+                .ok()
+                .context("exit lane closed")?;
+        }
+    };
+
+    let sessions_per_lane = (CONCURRENCY / lanes.len()).max(1);
+    let all_lanes = &lanes;
+    let lane_up = &lane_up;
+    let sessions = lanes.iter().flat_map(|lane| {
+        (0..sessions_per_lane).map(move |_| lane_session(ctx, all_lanes, lane, lane_up))
+    });
+    let constraint_changed = async {
+        wait_exit_constraint_changed(ctx).await;
+        anyhow::bail!("exit constraint changed, reconnecting")
+    };
+    dispatch
+        .race(try_join_all(sessions))
+        .race(constraint_changed)
+        .await?;
+    Ok(())
+}
+
+/// Waits until some lane has a session up, and returns the first such lane starting from `next`.
+async fn wait_for_lane(lanes: &[ExitLane], lane_up: &Event, next: usize) -> usize {
+    loop {
+        // we listen before looking, so that a lane coming up in between cannot be missed
+        let listener = lane_up.listen();
+        let up = (0..lanes.len())
+            .map(|i| (next + i) % lanes.len())
+            .find(|i| lanes[*i].live.load(Ordering::SeqCst) > 0);
+        if let Some(up) = up {
+            return up;
+        }
+        listener.await;
+    }
+}
+
+/// The exits of every lane that has a session up.
+fn live_exits(lanes: &[ExitLane]) -> Vec<ExitDescriptor> {
+    lanes
+        .iter()
+        .filter(|lane| lane.live.load(Ordering::SeqCst) > 0)
+        .map(|lane| lane.exit.clone())
+        .collect()
+}
+
+/// Keeps one session to a lane's exit going.
+async fn lane_session(
+    ctx: &AnyCtx<Config>,
+    lanes: &[ExitLane],
+    lane: &ExitLane,
+    lane_up: &Event,
+) -> anyhow::Result<()> {
+    let mut last_up = Instant::now();
+    loop {
+        let attempt_start = Instant::now();
+        let authed_pipe = geph5_timeout!(ctx, handshake, async {
+            let raw_pipe = lane.dialer.dial().await.context("could not dial")?;
+            client_auth(ctx, raw_pipe, lane.pubkey, lane.exit.probe_magic).await
+        })
+        .and_then(|r| r);
+        record_connection_attempt(ctx, &lane.exit, &authed_pipe, attempt_start.elapsed());
+        match authed_pipe {
+            Ok(authed_pipe) => {
+                lane.live.fetch_add(1, Ordering::SeqCst);
+                *ctx.get(CURRENT_CONN_INFO).lock() = ConnInfo::Connected(ConnectedInfo {
+                    protocol: authed_pipe.protocol().to_string(),
+                    bridge: authed_pipe
+                        .remote_addr()
+                        .map(|s| s.to_string())
+                        .unwrap_or_default(),
+                    exit: lane.exit.clone(),
+                    exits: live_exits(lanes),
+                });
+                lane_up.notify(usize::MAX);
+                if let Err(err) = client_inner(
+                    ctx.clone(),
+                    authed_pipe,
+                    &lane.requests.1,
+                    &ctx.get(CONN_REQ_CHAN).0,
+                )
+                .await
+                {
+                    tracing::warn!(
+                        exit = display(lane.exit.c2e_listen),
+                        err = debug(err),
+                        "session to one of multiple exits died"
+                    );
+                }
+                if lane.live.fetch_sub(1, Ordering::SeqCst) == 1 {
+                    if let ConnInfo::Connected(info) = &mut *ctx.get(CURRENT_CONN_INFO).lock() {
+                        info.exits = live_exits(lanes);
+                    }
+                    // nobody is left to serve what was queued for this exit, so it goes to the others
+                    while let Ok(request) = lane.requests.1.lock().await.try_recv() {
+                        let _ = ctx.get(CONN_REQ_CHAN).0.try_send(request);
+                    }
+                }
+                last_up = Instant::now();
+            }
+            Err(err) => {
+                tracing::warn!(
+                    exit = display(lane.exit.c2e_listen),
+                    err = debug(err),
+                    "could not connect to one of multiple exits"
+                );
+                direct_route_failed(ctx, lane.exit.c2e_listen);
+                if last_up.elapsed() > LANE_GIVE_UP {
+                    anyhow::bail!(
+                        "exit {} has been unreachable for too long, picking exits afresh",
+                        lane.exit.c2e_listen
+                    );
+                }
+                geph5_rt::Timer::after(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use isocountry::CountryCode;
+    use sillad::{
+        dialer::DialerExt as _,
+        testing::{NullDialer, NullMode},
+    };
+
+    use super::*;
+
+    fn lane(n: u8) -> ExitLane {
+        ExitLane {
+            pubkey: ed25519_dalek::SigningKey::from_bytes(&[n; 32]).verifying_key(),
+            exit: ExitDescriptor {
+                c2e_listen: ([192, 0, 2, n], 1).into(),
+                b2e_listen: ([192, 0, 2, n], 2).into(),
+                country: CountryCode::CAN,
+                city: "Toronto".into(),
+                load: 0.0,
+                expiry: 0,
+                probe_magic: false,
+                pmtud_echo: false,
+            },
+            dialer: NullDialer {
+                mode: NullMode::Connected,
+            }
+            .dynamic(),
+            live: AtomicUsize::new(0),
+            requests: conn_req_chan(),
+        }
+    }
+
+    #[test]
+    fn waits_for_a_lane_to_come_up() {
+        smol::future::block_on(async {
+            let lanes = [lane(1), lane(2), lane(3)];
+            let lane_up = Event::new();
+            let mut waiting = std::pin::pin!(wait_for_lane(&lanes, &lane_up, 0));
+            assert!(futures_util::poll!(waiting.as_mut()).is_pending());
+            lanes[2].live.fetch_add(1, Ordering::SeqCst);
+            lane_up.notify(usize::MAX);
+            assert_eq!(waiting.await, 2);
+        })
+    }
+
+    #[test]
+    fn picks_live_lanes_in_turn() {
+        smol::future::block_on(async {
+            let lanes = [lane(1), lane(2), lane(3)];
+            let lane_up = Event::new();
+            lanes[0].live.fetch_add(1, Ordering::SeqCst);
+            lanes[2].live.fetch_add(1, Ordering::SeqCst);
+            assert_eq!(wait_for_lane(&lanes, &lane_up, 0).await, 0);
+            assert_eq!(wait_for_lane(&lanes, &lane_up, 1).await, 2);
+            assert_eq!(wait_for_lane(&lanes, &lane_up, 3).await, 0);
+        })
+    }
+
+    #[test]
+    fn reports_every_live_exit() {
+        let lanes = [lane(1), lane(2), lane(3)];
+        assert!(live_exits(&lanes).is_empty());
+        lanes[0].live.fetch_add(2, Ordering::SeqCst);
+        lanes[2].live.fetch_add(1, Ordering::SeqCst);
+        let exits: Vec<_> = live_exits(&lanes).iter().map(|e| e.c2e_listen).collect();
+        assert_eq!(
+            exits,
+            vec![lanes[0].exit.c2e_listen, lanes[2].exit.c2e_listen]
+        );
+    }
+}
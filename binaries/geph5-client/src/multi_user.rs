@@ -0,0 +1,278 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    num::NonZeroU32,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use geph5_broker_protocol::AccountLevel;
+use governor::{DefaultDirectRateLimiter, Quota};
+use moka::future::Cache;
+
+use crate::client::{Config, CtxField};
+
+/// Rate limiters for each source IP address, so that one host on the LAN cannot hog a shared client.
+static IP_LIMITERS: CtxField<Cache<IpAddr, SourceLimiter>> = |_| {
+    Cache::builder()
+        .time_to_idle(Duration::from_secs(3600))
+        .build()
+};
+
+/// The address the local proxies should actually listen on. In multi-user mode, we listen on all interfaces so that other hosts on the LAN can connect.
+pub fn listen_addr(ctx: &AnyCtx<Config>, addr: SocketAddr) -> SocketAddr {
+    if !ctx.init().multi_user {
+        return addr;
+    }
+    let ip: IpAddr = match addr {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    SocketAddr::new(ip, addr.port())
+}
+
+/// Refuses an `auth_map` that holds credentials we cannot check, and warns when multi-user mode leaves the proxies open to anyone on the LAN.
+pub fn check_multi_user_config(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    if ctx.init().multi_user && ctx.init().auth_map.is_empty() {
+        tracing::warn!(
+            "multi_user without an auth_map lets anyone who can reach this host use the proxies"
+        );
+    }
+    check_auth_map(&ctx.init().auth_map)
+}
+
+fn check_auth_map(auth_map: &HashMap<String, String>) -> anyhow::Result<()> {
+    for (login, level) in auth_map {
+        let (username, password) = login
+            .split_once(':')
+            .with_context(|| format!("auth_map key {login:?} is not username:password"))?;
+        if password.starts_with(ARGON2_PREFIX) {
+            PasswordHash::new(password)
+                .map_err(|err| anyhow::anyhow!("bad password hash for {username}: {err}"))?;
+        }
+        parse_level(level).with_context(|| format!("unknown account level {level:?}"))?;
+    }
+    Ok(())
+}
+
+/// Whether local proxy users must authenticate with a username and password, which they must whenever there are logins in `auth_map`.
+pub fn auth_required(ctx: &AnyCtx<Config>) -> bool {
+    !ctx.init().auth_map.is_empty()
+}
+
+/// How a password in the `auth_map` starts if it is an Argon2 hash rather than the password itself.
+const ARGON2_PREFIX: &str = "$argon2";
+
+/// Logins that were already checked, keyed by a hash of the username and password, so that the deliberately slow password hash runs once per user rather than on every proxied request.
+static VERIFIED_LOGINS: CtxField<moka::sync::Cache<blake3::Hash, AccountLevel>> = |_| {
+    moka::sync::Cache::builder()
+        .time_to_idle(Duration::from_secs(3600))
+        .build()
+};
+
+/// Looks up the account level of a local proxy user in the `auth_map`, returning None if the credentials are wrong.
+pub fn authenticate(ctx: &AnyCtx<Config>, username: &str, password: &str) -> Option<AccountLevel> {
+    let login = blake3::Hasher::new()
+        .update(username.as_bytes())
+        .update(&[0])
+        .update(password.as_bytes())
+        .finalize();
+    let cache = ctx.get(VERIFIED_LOGINS);
+    if let Some(level) = cache.get(&login) {
+        return Some(level);
+    }
+    let level = verify_login(&ctx.init().auth_map, username, password)?;
+    cache.insert(login, level);
+    Some(level)
+}
+
+/// Checks a username and password against an `auth_map`, whose keys are `username:password`. The password may also be given as an Argon2 hash in the PHC string format, so that the config need not hold the password itself.
+fn verify_login(
+    auth_map: &HashMap<String, String>,
+    username: &str,
+    password: &str,
+) -> Option<AccountLevel> {
+    auth_map.iter().find_map(|(login, level)| {
+        let (user, expected) = login.split_once(':')?;
+        if user != username {
+            return None;
+        }
+        if expected.starts_with(ARGON2_PREFIX) {
+            let hash = PasswordHash::new(expected).ok()?;
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .ok()?;
+        } else if blake3::hash(expected.as_bytes()) != blake3::hash(password.as_bytes()) {
+            // comparing hashes keeps the comparison constant-time
+            return None;
+        }
+        parse_level(level)
+    })
+}
+
+fn parse_level(level: &str) -> Option<AccountLevel> {
+    if level.eq_ignore_ascii_case("plus") {
+        Some(AccountLevel::Plus)
+    } else if level.eq_ignore_ascii_case("free") {
+        Some(AccountLevel::Free)
+    } else {
+        None
+    }
+}
+
+/// Gets the rate limiter for traffic from a particular source IP. Plus users, and everybody outside of multi-user mode, are not limited.
+pub async fn source_limiter(
+    ctx: &AnyCtx<Config>,
+    ip: IpAddr,
+    level: Option<AccountLevel>,
+) -> SourceLimiter {
+    let limit_kb = ctx.init().multi_user_ratelimit;
+    if !ctx.init().multi_user || level == Some(AccountLevel::Plus) || limit_kb == 0 {
+        return SourceLimiter::unlimited();
+    }
+    ctx.get(IP_LIMITERS)
+        .get_with(ip, async { SourceLimiter::new(limit_kb) })
+        .await
+}
+
+/// A rate limiter shared by all connections from one source.
+#[derive(Clone)]
+pub struct SourceLimiter {
+    inner: Option<Arc<DefaultDirectRateLimiter>>,
+}
+
+/// The size of the chunks in which we wait for the limiter, which must fit within the burst size of any limiter.
+const COPY_BUF_SIZE: usize = 8192;
+
+impl SourceLimiter {
+    /// Creates a new rate limiter with the given speed limit, in KB/s.
+    pub fn new(limit_kb: u32) -> Self {
+        let limit = NonZeroU32::new(limit_kb.max(COPY_BUF_SIZE as u32 / 1024) * 1024).unwrap();
+        Self {
+            inner: Some(Arc::new(governor::RateLimiter::direct(Quota::per_second(
+                limit,
+            )))),
+        }
+    }
+
+    /// Creates a limiter that never limits anything.
+    pub fn unlimited() -> Self {
+        Self { inner: None }
+    }
+
+    /// Waits until the given number of bytes can be let through.
+    pub async fn wait(&self, mut bytes: usize) {
+        let Some(inner) = &self.inner else {
+            return;
+        };
+        // wait in chunks, so that we never ask for more than the burst size
+        while let Some(chunk) = NonZeroU32::new(bytes.min(COPY_BUF_SIZE) as u32) {
+            let _ = inner.until_n_ready(chunk).await;
+            bytes -= chunk.get() as usize;
+        }
+    }
+
+    /// Copy one stream to another, rate-limited by this limiter, through a buffer of the given size.
+    pub async fn io_copy(
+        &self,
+        buf_size: usize,
+        mut read_stream: impl AsyncRead + Unpin,
+        mut write_stream: impl AsyncWrite + Unpin,
+    ) -> std::io::Result<u64> {
+        let mut total_bytes = 0;
+        let mut buf = vec![0u8; buf_size];
+        loop {
+            let bytes_read = read_stream.read(&mut buf).await?;
+            if bytes_read == 0 {
+                break;
+            }
+            self.wait(bytes_read).await;
+            write_stream.write_all(&buf[..bytes_read]).await?;
+            total_bytes += bytes_read as u64;
+        }
+        Ok(total_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::{
+        password_hash::{rand_core::OsRng, SaltString},
+        PasswordHasher,
+    };
+
+    use super::*;
+
+    fn hash(password: &str) -> String {
+        Argon2::default()
+            .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+            .unwrap()
+            .to_string()
+    }
+
+    fn auth_map() -> HashMap<String, String> {
+        HashMap::from([
+            (format!("alice:{}", hash("hunter2")), "plus".to_string()),
+            (format!("bob:{}", hash("swordfish")), "Free".to_string()),
+        ])
+    }
+
+    #[test]
+    fn verifies_hashed_passwords() {
+        let auth_map = auth_map();
+        check_auth_map(&auth_map).unwrap();
+        assert_eq!(
+            verify_login(&auth_map, "alice", "hunter2"),
+            Some(AccountLevel::Plus)
+        );
+        assert_eq!(
+            verify_login(&auth_map, "bob", "swordfish"),
+            Some(AccountLevel::Free)
+        );
+        assert_eq!(verify_login(&auth_map, "alice", "swordfish"), None);
+        assert_eq!(verify_login(&auth_map, "carol", "hunter2"), None);
+        assert_eq!(verify_login(&auth_map, "alice", ""), None);
+    }
+
+    #[test]
+    fn verifies_plaintext_passwords() {
+        let auth_map = HashMap::from([
+            ("alice:hunter2".to_string(), "plus".to_string()),
+            ("bob:pass:word".to_string(), "free".to_string()),
+            (format!("carol:{}", hash("swordfish")), "free".to_string()),
+        ]);
+        check_auth_map(&auth_map).unwrap();
+        assert_eq!(
+            verify_login(&auth_map, "alice", "hunter2"),
+            Some(AccountLevel::Plus)
+        );
+        assert_eq!(
+            verify_login(&auth_map, "bob", "pass:word"),
+            Some(AccountLevel::Free)
+        );
+        assert_eq!(
+            verify_login(&auth_map, "carol", "swordfish"),
+            Some(AccountLevel::Free)
+        );
+        assert_eq!(verify_login(&auth_map, "alice", "hunter"), None);
+        assert_eq!(verify_login(&auth_map, "bob", "pass"), None);
+    }
+
+    #[test]
+    fn rejects_bad_hashes_and_levels() {
+        let bad_hash =
+            HashMap::from([("alice:$argon2id$nonsense".to_string(), "plus".to_string())]);
+        assert!(check_auth_map(&bad_hash).is_err());
+        assert_eq!(verify_login(&bad_hash, "alice", "$argon2id$nonsense"), None);
+
+        let bad_level = HashMap::from([(format!("alice:{}", hash("hunter2")), "gold".to_string())]);
+        assert!(check_auth_map(&bad_level).is_err());
+
+        let no_username = HashMap::from([(hash("hunter2"), "plus".to_string())]);
+        assert!(check_auth_map(&no_username).is_err());
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use web_time::Instant;
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use ed25519_dalek::VerifyingKey;
+use event_listener::Event;
+use futures_lite::FutureExt as _;
+use futures_util::future::try_join_all;
+use geph5_broker_protocol::ExitDescriptor;
+use sillad::{
+    dialer::{Dialer as _, DynDialer},
+    Pipe as _,
+};
+
+use crate::{
+    client::Config,
+    client_inner::{
+        client_auth, client_inner, conn_req_chan, ChanElem, ConnReqChan, CONCURRENCY, CONN_REQ_CHAN,
+    },
+    control_prot::{ConnInfo, ConnectedInfo, CURRENT_CONN_INFO},
+    metrics::record_connection_attempt,
+    route::{direct_route_failed, get_exit_paths, wait_exit_constraint_changed},
+    timeout::geph5_timeout,
+};
+
+/// How long every path may stay down at once before we give up on the exit and pick one afresh.
+const ALL_DOWN_GIVE_UP: Duration = Duration::from_secs(60);
+
+/// The longest we wait between attempts to bring a dead path back.
+const MAX_PATH_BACKOFF: Duration = Duration::from_secs(30);
+
+/// One of the paths to the exit, with its own queue of stream requests.
+struct PathLane {
+    /// The direct path is the first, and the bridges the second.
+    idx: usize,
+    dialer: DynDialer,
+    /// How many sessions over this path are currently up.
+    live: AtomicUsize,
+    /// How many requests handed to this path are still waiting for their stream to open, whether queued or being opened.
+    opening: Arc<AtomicUsize>,
+    requests: ConnReqChan,
+}
+
+impl PathLane {
+    /// Hands a request to this path. The request counts as opening until the path opens its stream or gives up on it, in which case it goes back to be dispatched again.
+    fn dispatch(
+        &self,
+        ctx: &AnyCtx<Config>,
+        (remote_addr, send_back): ChanElem,
+    ) -> anyhow::Result<()> {
+        let (lane_send, lane_recv) = oneshot::channel();
+        self.requests
+            .0
+            .try_send((remote_addr.clone(), lane_send))
+            .ok()
+            .context("path lane closed")?;
+        let opening = self.opening.clone();
+        opening.fetch_add(1, Ordering::SeqCst);
+        let ctx = ctx.clone();
+        geph5_rt::spawn(async move {
+            let opened = lane_recv.await;
+            opening.fetch_sub(1, Ordering::SeqCst);
+            match opened {
+                Ok(stream) => {
+                    let _ = send_back.send(stream);
+                }
+                Err(_) => {
+                    let _ = ctx.get(CONN_REQ_CHAN).0.try_send((remote_addr, send_back));
+                }
+            }
+        })
+        .detach();
+        Ok(())
+    }
+
+    /// Gives up on everything queued for this path, once nobody is left to serve it. The dispatcher then hands it to the others.
+    async fn give_up_queued(&self) {
+        let requests = self.requests.1.lock().await;
+        while requests.try_recv().is_ok() {}
+    }
+}
+
+/// Runs the client over every path to one exit at once, handing each new stream to the live path with the fewest streams still waiting to be opened. When a path dies, what was queued for it moves to the others, and new streams only go to the survivors until it comes back.
+pub async fn multipath_once(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let (pubkey, exit, dialers) = get_exit_paths(ctx)
+        .await
+        .context("could not get paths to exit")?;
+    let lanes: Vec<PathLane> = dialers
+        .into_iter()
+        .enumerate()
+        .map(|(idx, dialer)| PathLane {
+            idx,
+            dialer,
+            live: AtomicUsize::new(0),
+            opening: Arc::new(AtomicUsize::new(0)),
+            requests: conn_req_chan(),
+        })
+        .collect();
+    tracing::info!(
+        exit = display(exit.c2e_listen),
+        paths = lanes.len(),
+        "connecting over multiple paths"
+    );
+
+    // notified whenever a session over any path comes up
+    let lane_up = Event::new();
+    let dispatch = async {
+        let mut next = 0;
+        loop {
+            let request = ctx.get(CONN_REQ_CHAN).1.lock().await.recv().await?;
+            let lane = wait_for_lane(&lanes, &lane_up, next).await;
+            next = lane + 1;
+            lanes[lane].dispatch(ctx, request)?;
+        }
+    };
+
+    // one path being down is fine, but with every path down, the exit itself is likely gone
+    let watchdog = async {
+        let mut last_up = Instant::now();
+        loop {
+            geph5_rt::Timer::after(Duration::from_secs(1)).await;
+            if lanes
+                .iter()
+                .any(|lane| lane.live.load(Ordering::SeqCst) > 0)
+            {
+                last_up = Instant::now();
+            } else if last_up.elapsed() > ALL_DOWN_GIVE_UP {
+                anyhow::bail!(
+                    "every path to exit {} has been down for too long, picking an exit afresh",
+                    exit.c2e_listen
+                );
+            }
+        }
+    };
+
+    let sessions_per_lane = (CONCURRENCY / lanes.len()).max(1);
+    let exit = &exit;
+    let lane_up = &lane_up;
+    let sessions = lanes.iter().flat_map(|lane| {
+        (0..sessions_per_lane).map(move |_| path_session(ctx, pubkey, exit, lane, lane_up))
+    });
+    let constraint_changed = async {
+        wait_exit_constraint_changed(ctx).await;
+        anyhow::bail!("exit constraint changed, reconnecting")
+    };
+    dispatch
+        .race(try_join_all(sessions))
+        .race(watchdog)
+        .race(constraint_changed)
+        .await?;
+    Ok(())
+}
+
+/// Waits until some path has a session up, and returns the live path with the fewest streams still waiting to be opened, with ties going to the first starting from `next`.
+async fn wait_for_lane(lanes: &[PathLane], lane_up: &Event, next: usize) -> usize {
+    loop {
+        // we listen before looking, so that a path coming up in between cannot be missed
+        let listener = lane_up.listen();
+        // a path that is slower to open streams has more of them waiting
+        let up = (0..lanes.len())
+            .map(|i| (next + i) % lanes.len())
+            .filter(|i| lanes[*i].live.load(Ordering::SeqCst) > 0)
+            .min_by_key(|i| lanes[*i].opening.load(Ordering::SeqCst));
+        if let Some(up) = up {
+            return up;
+        }
+        listener.await;
+    }
+}
+
+/// How long to wait before the next attempt to bring a dead path back, after waiting `backoff` before this one.
+fn next_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).min(MAX_PATH_BACKOFF)
+}
+
+/// Keeps one session over a path going, backing off while the path is down.
+async fn path_session(
+    ctx: &AnyCtx<Config>,
+    pubkey: VerifyingKey,
+    exit: &ExitDescriptor,
+    lane: &PathLane,
+    lane_up: &Event,
+) -> anyhow::Result<()> {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let attempt_start = Instant::now();
+        let authed_pipe = geph5_timeout!(ctx, handshake, async {
+            let raw_pipe = lane.dialer.dial().await.context("could not dial")?;
+            client_auth(ctx, raw_pipe, pubkey, exit.probe_magic).await
+        })
+        .and_then(|r| r);
+        record_connection_attempt(ctx, exit, &authed_pipe, attempt_start.elapsed());
+        match authed_pipe {
+            Ok(authed_pipe) => {
+                backoff = Duration::from_secs(1);
+                *ctx.get(CURRENT_CONN_INFO).lock() = ConnInfo::Connected(ConnectedInfo {
+                    protocol: authed_pipe.protocol().to_string(),
+                    bridge: authed_pipe
+                        .remote_addr()
+                        .map(|s| s.to_string())
+                        .unwrap_or_default(),
+                    exit: exit.clone(),
+                    exits: vec![],
+                });
+                lane.live.fetch_add(1, Ordering::SeqCst);
+                lane_up.notify(usize::MAX);
+                // streams this session fails to open stay with the path, and still count towards its load
+                if let Err(err) =
+                    client_inner(ctx.clone(), authed_pipe, &lane.requests.1, &lane.requests.0).await
+                {
+                    tracing::warn!(
+                        path = lane.idx,
+                        err = debug(err),
+                        "session over one of multiple paths died"
+                    );
+                }
+                if lane.live.fetch_sub(1, Ordering::SeqCst) == 1 {
+                    lane.give_up_queued().await;
+                }
+            }
+            Err(err) => {
+                tracing::warn!(
+                    path = lane.idx,
+                    err = debug(err),
+                    "could not connect over one of multiple paths"
+                );
+                if lane.idx == 0 {
+                    direct_route_failed(ctx, exit.c2e_listen);
+                }
+                // requests may have reached the queue after the last session died, either from the dispatcher or from opens that failed late
+                if lane.live.load(Ordering::SeqCst) == 0 {
+                    lane.give_up_queued().await;
+                }
+                geph5_rt::Timer::after(backoff).await;
+                backoff = next_backoff(backoff);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sillad::{
+        dialer::DialerExt as _,
+        testing::{NullDialer, NullMode},
+    };
+
+    use super::*;
+
+    fn lane(idx: usize) -> PathLane {
+        PathLane {
+            idx,
+            dialer: NullDialer {
+                mode: NullMode::Connected,
+            }
+            .dynamic(),
+            live: AtomicUsize::new(0),
+            opening: Arc::new(AtomicUsize::new(0)),
+            requests: conn_req_chan(),
+        }
+    }
+
+    #[test]
+    fn waits_for_a_path_to_come_up() {
+        smol::future::block_on(async {
+            let lanes = [lane(0), lane(1)];
+            let lane_up = Event::new();
+            let mut waiting = std::pin::pin!(wait_for_lane(&lanes, &lane_up, 0));
+            assert!(futures_util::poll!(waiting.as_mut()).is_pending());
+            lanes[1].live.fetch_add(1, Ordering::SeqCst);
+            lane_up.notify(usize::MAX);
+            assert_eq!(waiting.await, 1);
+        })
+    }
+
+    #[test]
+    fn prefers_the_path_with_fewest_opening() {
+        smol::future::block_on(async {
+            let lanes = [lane(0), lane(1), lane(2)];
+            let lane_up = Event::new();
+            for lane in &lanes {
+                lane.live.fetch_add(1, Ordering::SeqCst);
+            }
+            // ties go round-robin
+            assert_eq!(wait_for_lane(&lanes, &lane_up, 0).await, 0);
+            assert_eq!(wait_for_lane(&lanes, &lane_up, 1).await, 1);
+            assert_eq!(wait_for_lane(&lanes, &lane_up, 3).await, 0);
+            // a path that is slow to open streams gets passed over
+            lanes[0].opening.fetch_add(2, Ordering::SeqCst);
+            lanes[1].opening.fetch_add(1, Ordering::SeqCst);
+            assert_eq!(wait_for_lane(&lanes, &lane_up, 0).await, 2);
+            lanes[2].opening.fetch_add(3, Ordering::SeqCst);
+            assert_eq!(wait_for_lane(&lanes, &lane_up, 0).await, 1);
+        })
+    }
+
+    #[test]
+    fn fails_over_to_surviving_paths() {
+        smol::future::block_on(async {
+            let lanes = [lane(0), lane(1)];
+            let lane_up = Event::new();
+            lanes[0].live.fetch_add(1, Ordering::SeqCst);
+            lanes[1].live.fetch_add(1, Ordering::SeqCst);
+            // the busier direct path still gets new streams while it is the only one left
+            lanes[0].opening.fetch_add(5, Ordering::SeqCst);
+            lanes[1].live.fetch_sub(1, Ordering::SeqCst);
+            assert_eq!(wait_for_lane(&lanes, &lane_up, 1).await, 0);
+            lanes[0].live.fetch_sub(1, Ordering::SeqCst);
+            lanes[1].live.fetch_add(1, Ordering::SeqCst);
+            assert_eq!(wait_for_lane(&lanes, &lane_up, 0).await, 1);
+        })
+    }
+
+    #[test]
+    fn dead_path_gives_up_its_queue() {
+        smol::future::block_on(async {
+            let lane = lane(0);
+            let (send, recv) = oneshot::channel();
+            lane.requests
+                .0
+                .try_send(("example.com:443".into(), send))
+                .unwrap();
+            lane.give_up_queued().await;
+            assert!(lane.requests.1.lock().await.try_recv().is_err());
+            // dropping the request is what tells the dispatcher to hand it to another path
+            assert!(recv.await.is_err());
+        })
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut backoff = Duration::from_secs(1);
+        let mut seen = vec![];
+        for _ in 0..7 {
+            backoff = next_backoff(backoff);
+            seen.push(backoff.as_secs());
+        }
+        assert_eq!(seen, [2, 4, 8, 16, 30, 30, 30]);
+    }
+}
@@ -0,0 +1,139 @@
+use std::time::Duration;
+
+/// How often we look at the default route when we cannot get change events from the OS.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// NetChangeDetector notices when the default route changes, for example when roaming between WiFi networks. Connections made over the old route usually die silently, so this is a good time to reconnect.
+///
+/// On Linux, we subscribe to rtnetlink route and link events, and look at the default route whenever one arrives. Elsewhere, we poll.
+pub struct NetChangeDetector {
+    last_route: Option<String>,
+    #[cfg(target_os = "linux")]
+    events: Option<smol::Async<std::os::fd::OwnedFd>>,
+}
+
+impl NetChangeDetector {
+    /// Creates a new detector, taking the current default route as the baseline.
+    pub fn new() -> Self {
+        Self {
+            last_route: default_route(),
+            #[cfg(target_os = "linux")]
+            events: route_events()
+                .inspect_err(|err| {
+                    tracing::warn!(
+                        err = debug(err),
+                        "could not subscribe to route events, polling instead"
+                    )
+                })
+                .ok(),
+        }
+    }
+
+    /// Waits until the default route differs from the one last seen.
+    pub async fn wait_change(&mut self) {
+        loop {
+            self.wait_event().await;
+            let route = default_route();
+            if route != self.last_route {
+                tracing::info!(
+                    old = debug(&self.last_route),
+                    new = debug(&route),
+                    "default route changed"
+                );
+                self.last_route = route;
+                return;
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn wait_event(&mut self) {
+        use std::os::fd::AsRawFd;
+
+        let Some(events) = &self.events else {
+            geph5_rt::Timer::after(POLL_INTERVAL).await;
+            return;
+        };
+        if let Err(err) = events.readable().await {
+            tracing::warn!(
+                err = debug(err),
+                "route event socket failed, polling instead"
+            );
+            self.events = None;
+            return;
+        }
+        // we only care that something happened, so the messages themselves are thrown away
+        let mut buf = [0u8; 8192];
+        let fd = events.get_ref().as_raw_fd();
+        loop {
+            let n =
+                unsafe { libc::recv(fd, buf.as_mut_ptr().cast(), buf.len(), libc::MSG_DONTWAIT) };
+            if n <= 0 {
+                break;
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn wait_event(&mut self) {
+        geph5_rt::Timer::after(POLL_INTERVAL).await;
+    }
+}
+
+/// Opens a netlink socket subscribed to changes in links and routes.
+#[cfg(target_os = "linux")]
+fn route_events() -> std::io::Result<smol::Async<std::os::fd::OwnedFd>> {
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as u16;
+    addr.nl_groups = (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_ROUTE | libc::RTMGRP_IPV6_ROUTE) as u32;
+    let res = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as u32,
+        )
+    };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    smol::Async::new(fd)
+}
+
+/// Describes the default routes in the main routing table. We read the main table specifically, since the VPN mode installs its own default route in a separate table.
+#[cfg(target_os = "linux")]
+fn default_route() -> Option<String> {
+    let table = std::fs::read_to_string("/proc/net/route").ok()?;
+    let mut defaults: Vec<String> = table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            // Iface Destination Gateway Flags RefCnt Use Metric Mask ...
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            (fields.get(1) == Some(&"00000000") && fields.get(7) == Some(&"00000000"))
+                .then(|| format!("{} via {}", fields[0], fields[2]))
+        })
+        .collect();
+    defaults.sort();
+    Some(defaults.join(", "))
+}
+
+/// Describes the default route by the local address that traffic to the Internet would leave from. Connecting a UDP socket sends no packets.
+#[cfg(not(target_os = "linux"))]
+fn default_route() -> Option<String> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("8.8.8.8:53").ok()?;
+    Some(socket.local_addr().ok()?.ip().to_string())
+}
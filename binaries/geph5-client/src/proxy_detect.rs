@@ -0,0 +1,266 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::Context;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use moka::future::Cache;
+use once_cell::sync::Lazy;
+use sillad::{
+    dialer::Dialer,
+    tcp::{TcpDialer, TcpPipe},
+};
+use smol_timeout2::TimeoutExt;
+use url::Url;
+
+/// How long fetching a PAC file may take.
+const PAC_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The proxy given in the environment when the process started. [crate::Client::start] clears these variables so that our own HTTP clients don't pick them up, so they must be captured before that.
+static ENV_PROXY: Lazy<Option<Url>> = Lazy::new(env_proxy);
+
+/// Detection may fetch PAC files over the network, so we cache the result for a while rather than detecting again on every dial. Keyed by whether WPAD was allowed.
+static DETECTED_PROXY: Lazy<Cache<bool, Option<Url>>> = Lazy::new(|| {
+    Cache::builder()
+        .time_to_live(Duration::from_secs(300))
+        .build()
+});
+
+/// Captures the proxy environment variables. Must be called before they are cleared.
+pub fn capture_env_proxy() {
+    Lazy::force(&ENV_PROXY);
+}
+
+/// Detects the proxy configured for this system, looking in turn at the environment, the Windows Internet Options, and, only if `wpad` is set, WPAD.
+pub async fn detect_system_proxy(wpad: bool) -> Option<Url> {
+    DETECTED_PROXY
+        .get_with(wpad, async {
+            let proxy = if let Some(proxy) = ENV_PROXY.clone() {
+                Some(proxy)
+            } else if let Some(proxy) = registry_proxy().await {
+                Some(proxy)
+            } else if wpad {
+                wpad_proxy().await
+            } else {
+                None
+            };
+            tracing::debug!(proxy = debug(&proxy), "detected system proxy");
+            proxy
+        })
+        .await
+}
+
+fn env_proxy() -> Option<Url> {
+    ["https_proxy", "HTTPS_PROXY", "http_proxy", "HTTP_PROXY"]
+        .into_iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find_map(|val| parse_proxy(&val))
+}
+
+#[cfg(windows)]
+async fn registry_proxy() -> Option<Url> {
+    use winreg::{enums::HKEY_CURRENT_USER, RegKey};
+    let (enabled, server, pac_url) = {
+        let settings = RegKey::predef(HKEY_CURRENT_USER)
+            .open_subkey(r"Software\Microsoft\Windows\CurrentVersion\Internet Settings")
+            .ok()?;
+        let enabled: u32 = settings.get_value("ProxyEnable").unwrap_or_default();
+        let server: Option<String> = settings.get_value("ProxyServer").ok();
+        let pac_url: Option<String> = settings.get_value("AutoConfigURL").ok();
+        (enabled, server, pac_url)
+    };
+    if enabled != 0 {
+        let server = server?;
+        // either a single "host:port", or per-scheme entries like "http=host:port;https=host:port"
+        if !server.contains('=') {
+            return parse_proxy(&server);
+        }
+        let entries: Vec<(&str, &str)> = server
+            .split(';')
+            .filter_map(|entry| entry.trim().split_once('='))
+            .collect();
+        return ["https", "http"].into_iter().find_map(|scheme| {
+            entries
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(scheme))
+                .and_then(|(_, v)| parse_proxy(v))
+        });
+    }
+    fetch_pac(&pac_url?).await
+}
+
+#[cfg(not(windows))]
+async fn registry_proxy() -> Option<Url> {
+    None
+}
+
+async fn wpad_proxy() -> Option<Url> {
+    fetch_pac("http://wpad/wpad.dat").await
+}
+
+/// Fetches a PAC file and returns the first proxy it mentions. We do not evaluate the JavaScript, so per-URL rules are not honored.
+async fn fetch_pac(pac_url: &str) -> Option<Url> {
+    let fallible = async {
+        let script = reqwest::Client::builder()
+            .no_proxy()
+            .build()?
+            .get(pac_url)
+            .send()
+            .timeout(PAC_TIMEOUT)
+            .await
+            .context("timed out")??
+            .error_for_status()?
+            .text()
+            .await?;
+        anyhow::Ok(pac_first_proxy(&script))
+    };
+    match fallible.await {
+        Ok(proxy) => proxy,
+        Err(err) => {
+            tracing::debug!(pac_url, err = debug(err), "could not fetch PAC file");
+            None
+        }
+    }
+}
+
+fn pac_first_proxy(script: &str) -> Option<Url> {
+    let mut words = script
+        .split(|c: char| c.is_whitespace() || c == '"' || c == '\'' || c == ';')
+        .filter(|w| !w.is_empty());
+    while let Some(word) = words.next() {
+        if word == "PROXY" {
+            if let Some(proxy) = words.next().and_then(parse_proxy) {
+                return Some(proxy);
+            }
+        }
+    }
+    None
+}
+
+fn parse_proxy(s: &str) -> Option<Url> {
+    let s = s.trim();
+    if s.is_empty() {
+        return None;
+    }
+    if s.contains("://") {
+        Url::parse(s).ok()
+    } else {
+        Url::parse(&format!("http://{s}")).ok()
+    }
+}
+
+/// Resolves the address of an upstream proxy.
+pub async fn resolve_proxy(proxy: &Url) -> anyhow::Result<SocketAddr> {
+    let host = proxy.host_str().context("proxy has no host")?;
+    let port = proxy.port_or_known_default().context("proxy has no port")?;
+    smol::net::resolve((host, port))
+        .await?
+        .first()
+        .copied()
+        .context("proxy resolved to nothing")
+}
+
+/// A dialer that reaches its destination through an HTTP proxy, using CONNECT.
+pub struct HttpConnectDialer {
+    pub proxy_addr: SocketAddr,
+    pub dest_addr: SocketAddr,
+}
+
+impl Dialer for HttpConnectDialer {
+    type P = TcpPipe;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let mut pipe = TcpDialer {
+            dest_addr: self.proxy_addr,
+        }
+        .dial()
+        .await?;
+        let dest = self.dest_addr;
+        pipe.write_all(format!("CONNECT {dest} HTTP/1.1\r\nHost: {dest}\r\n\r\n").as_bytes())
+            .await?;
+        // read byte-by-byte so that nothing past the response header is consumed
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() > 8192 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "proxy response header too long",
+                ));
+            }
+            let mut byte = [0u8];
+            pipe.read_exact(&mut byte).await?;
+            response.push(byte[0]);
+        }
+        let status_line = String::from_utf8_lossy(&response);
+        let status_line = status_line.lines().next().unwrap_or_default();
+        if status_line.split_whitespace().nth(1) != Some("200") {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                format!("proxy refused CONNECT: {status_line}"),
+            ));
+        }
+        Ok(pipe)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use smol::net::TcpListener;
+
+    use super::*;
+
+    /// Answers one CONNECT request with the given status line, returning the request.
+    async fn fake_proxy(
+        listener: &TcpListener,
+        status_line: &str,
+    ) -> (String, smol::net::TcpStream) {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8];
+            conn.read_exact(&mut byte).await.unwrap();
+            request.push(byte[0]);
+        }
+        conn.write_all(format!("{status_line}\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        (String::from_utf8(request).unwrap(), conn)
+    }
+
+    #[test]
+    fn connects_through_ipv6_proxy() {
+        smolscale::block_on(async {
+            let listener = TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).await.unwrap();
+            let dialer = HttpConnectDialer {
+                proxy_addr: listener.local_addr().unwrap(),
+                dest_addr: SocketAddr::from((Ipv6Addr::LOCALHOST, 8964)),
+            };
+            let (dialed, (request, mut proxied)) = futures_util::join!(
+                dialer.dial(),
+                fake_proxy(&listener, "HTTP/1.1 200 Connection established")
+            );
+            assert!(request.starts_with("CONNECT [::1]:8964 HTTP/1.1\r\n"));
+            let mut dialed = dialed.unwrap();
+            dialed.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            proxied.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        });
+    }
+
+    #[test]
+    fn fails_when_proxy_refuses() {
+        smolscale::block_on(async {
+            let listener = TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).await.unwrap();
+            let dialer = HttpConnectDialer {
+                proxy_addr: listener.local_addr().unwrap(),
+                dest_addr: SocketAddr::from((Ipv6Addr::LOCALHOST, 8964)),
+            };
+            let (dialed, _) = futures_util::join!(
+                dialer.dial(),
+                fake_proxy(&listener, "HTTP/1.1 403 Forbidden")
+            );
+            assert!(dialed.is_err());
+        });
+    }
+}
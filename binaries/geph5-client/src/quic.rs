@@ -0,0 +1,77 @@
+use std::{net::SocketAddr, time::Duration};
+
+use moka::future::Cache;
+use once_cell::sync::Lazy;
+use sillad::{dialer::Dialer, Pipe};
+use sillad_quic::QuicDialer;
+use smol::net::UdpSocket;
+use smol_timeout2::TimeoutExt;
+
+/// Results of recent UDP reachability probes, so that we don't probe on every single dial.
+static UDP_REACHABLE: Lazy<Cache<SocketAddr, bool>> = Lazy::new(|| {
+    Cache::builder()
+        .time_to_live(Duration::from_secs(300))
+        .build()
+});
+
+/// How long to wait for a QUIC server to answer a probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A dialer for [geph5_broker_protocol::RouteDescriptor::Quic] routes, which fails right away if UDP does not seem to get through to the server, so that a TCP route raced against it can win.
+pub struct QuicRouteDialer {
+    pub addr: SocketAddr,
+    pub cert_hash: [u8; 32],
+}
+
+impl Dialer for QuicRouteDialer {
+    type P = Box<dyn Pipe>;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let reachable = UDP_REACHABLE
+            .get_with(self.addr, async {
+                let reachable = udp_reachable(self.addr).await;
+                tracing::debug!(addr = display(self.addr), reachable, "probed UDP");
+                reachable
+            })
+            .await;
+        if !reachable {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                "UDP seems to be blocked",
+            ));
+        }
+        let pipe = QuicDialer {
+            dest_addr: self.addr,
+            cert_hash: self.cert_hash,
+        }
+        .dial()
+        .await?;
+        Ok(Box::new(pipe))
+    }
+}
+
+/// Checks whether UDP gets through to a QUIC server, by sending it a padded packet in a reserved version that it must answer with a version negotiation packet.
+async fn udp_reachable(addr: SocketAddr) -> bool {
+    let probe = async {
+        let socket = UdpSocket::bind(if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        })
+        .await?;
+        // long header, then a version of the 0x?a?a?a?a form that is reserved for forcing version negotiation
+        let mut packet = vec![0xc0, 0x1a, 0x2a, 0x3a, 0x4a];
+        // destination and source connection IDs
+        packet.push(8);
+        packet.extend_from_slice(&rand::random::<[u8; 8]>());
+        packet.push(8);
+        packet.extend_from_slice(&rand::random::<[u8; 8]>());
+        // servers ignore anything smaller than the minimum initial packet size
+        packet.resize(1200, 0);
+        socket.send_to(&packet, addr).await?;
+        let mut buf = [0u8; 1500];
+        let (_, from) = socket.recv_from(&mut buf).await?;
+        std::io::Result::Ok(from == addr)
+    };
+    matches!(probe.timeout(PROBE_TIMEOUT).await, Some(Ok(true)))
+}
@@ -1,32 +1,182 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+use web_time::{Instant, SystemTime, UNIX_EPOCH};
 
 use anyctx::AnyCtx;
 use anyhow::Context;
 
 use ed25519_dalek::VerifyingKey;
-use geph5_broker_protocol::{ExitDescriptor, RouteDescriptor, DOMAIN_EXIT_DESCRIPTOR};
+use event_listener::Event;
+use futures_util::future::join_all;
+use geph5_broker_protocol::{
+    BrokerClient, ExitDescriptor, ExitList, RouteCondition, RouteDescriptor, DOMAIN_EXIT_DESCRIPTOR,
+};
+use geph5_rt::TimeoutExt;
 use isocountry::CountryCode;
-use moka::sync::Cache;
-use once_cell::sync::Lazy;
-use rand::seq::SliceRandom;
+use mizaru2::{ClientToken, UnblindedSignature};
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::{Mutex, RwLock};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
-use sillad::{
-    dialer::{DialerExt, DynDialer, FailingDialer},
-    tcp::TcpDialer,
-};
+use sillad::dialer::{Dialer, DialerExt, DynDialer, FailingDialer, HappyEyeballsDialer};
 use sillad_sosistab3::{dialer::SosistabDialer, Cookie};
 
-use crate::{auth::get_connect_token, broker::broker_client, client::Config, vpn::vpn_whitelist};
+use crate::{
+    auth::{get_auth_token, get_connect_token},
+    broker::{broker_client, broker_error, get_exits},
+    chaos::PacketLossInjector,
+    circuit_breaker::{EXITS_BREAKER, ROUTES_BREAKER},
+    client::{Config, CtxField},
+    database::{db_read, db_write},
+    doh::resolve,
+    exit_stream::streamed_exits,
+    load_balance::LoadBalanceDialer,
+    route_condition::{client_country, route_addrs, ConditionalDialer},
+    smart_routing::choose_exit,
+    vpn::vpn_whitelist,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use {
+    crate::{
+        proxy_detect::{detect_system_proxy, resolve_proxy, HttpConnectDialer},
+        quic::QuicRouteDialer,
+    },
+    sillad::tcp::TcpDialer,
+};
+
+/// The routes that recently failed, keyed by address. Failures, decay and saving to disk all happen under this one lock, so that none of them can overwrite another.
+static ROUTE_SHITLIST: Lazy<Mutex<HashMap<SocketAddr, RoutePenalty>>> = Lazy::new(Default::default);
+
+/// Where the shitlist is persisted, if anywhere.
+static ROUTE_SHITLIST_PATH: OnceCell<PathBuf> = OnceCell::new();
 
-static ROUTE_SHITLIST: Lazy<Cache<SocketAddr, usize>> = Lazy::new(|| {
-    Cache::builder()
-        .time_to_live(Duration::from_secs(600))
-        .build()
-});
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct RoutePenalty {
+    pub addr: SocketAddr,
+    pub count: usize,
+    /// Unix timestamp of when the count last failed or decayed, from which the next half-life is measured.
+    #[serde(default)]
+    pub decayed_at: u64,
+}
+
+impl RoutePenalty {
+    /// Halves the count once per half-life that has passed since it last failed or decayed, returning whether it changed.
+    fn decay(&mut self, now: u64, half_life: u64) -> bool {
+        let halvings = now.saturating_sub(self.decayed_at) / half_life;
+        if halvings == 0 {
+            return false;
+        }
+        self.count = self.count.checked_shr(halvings as u32).unwrap_or_default();
+        self.decayed_at += halvings * half_life;
+        true
+    }
+}
+
+/// Decays every penalty, forgetting the routes whose penalty reaches zero. Returns whether anything changed.
+fn decay_all(shitlist: &mut HashMap<SocketAddr, RoutePenalty>, now: u64, half_life: u64) -> bool {
+    let mut changed = false;
+    shitlist.retain(|_, penalty| {
+        changed |= penalty.decay(now, half_life);
+        penalty.count > 0
+    });
+    changed
+}
+
+/// How many times routes with this address have recently failed.
+fn route_penalty(addr: &SocketAddr) -> usize {
+    ROUTE_SHITLIST
+        .lock()
+        .get(addr)
+        .map(|penalty| penalty.count)
+        .unwrap_or_default()
+}
 
 /// Deprioritizes routes with this address.
 pub fn deprioritize_route(addr: SocketAddr) {
-    ROUTE_SHITLIST.insert(addr, ROUTE_SHITLIST.get_with(addr, || 1) + 1)
+    let mut shitlist = ROUTE_SHITLIST.lock();
+    let penalty = shitlist.entry(addr).or_insert(RoutePenalty {
+        addr,
+        count: 0,
+        decayed_at: 0,
+    });
+    penalty.count = penalty.count.max(1) + 1;
+    penalty.decayed_at = unix_now();
+    persist_route_shitlist(&shitlist);
+}
+
+/// Halves the penalty of every route once per half-life that has passed since it last failed or decayed, forgetting routes whose penalty reaches zero. A route that failed once recovers within a couple of half-lives, while one that keeps failing stays deprioritized for longer.
+pub async fn route_penalty_decay_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let interval = Duration::from_secs(ctx.init().route_penalty_decay_interval_secs.max(1));
+    let half_life = ctx.init().route_penalty_half_life_secs.max(1);
+    loop {
+        geph5_rt::Timer::after(interval).await;
+        let mut shitlist = ROUTE_SHITLIST.lock();
+        if decay_all(&mut shitlist, unix_now(), half_life) {
+            persist_route_shitlist(&shitlist);
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Saves the shitlist, which the caller holds the lock of, to the configured path.
+fn persist_route_shitlist(shitlist: &HashMap<SocketAddr, RoutePenalty>) {
+    if let Some(path) = ROUTE_SHITLIST_PATH.get() {
+        if let Err(err) = save_route_shitlist(path, shitlist) {
+            tracing::warn!(err = debug(err), "could not save route shitlist");
+        }
+    }
+}
+
+/// Lists the routes that are currently deprioritized.
+pub fn route_shitlist() -> Vec<RoutePenalty> {
+    ROUTE_SHITLIST.lock().values().copied().collect()
+}
+
+fn save_route_shitlist(
+    path: &Path,
+    shitlist: &HashMap<SocketAddr, RoutePenalty>,
+) -> anyhow::Result<()> {
+    let penalties: Vec<&RoutePenalty> = shitlist.values().collect();
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, serde_json::to_vec(&penalties)?)?;
+    std::fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+/// Reloads the route shitlist persisted at the configured path, if any, and keeps persisting it there from now on. Entries are decayed by the time that passed since they were saved, so those that would have decayed away in the meantime are gone right away.
+pub fn restore_route_shitlist(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let Some(path) = &ctx.init().route_shitlist_path else {
+        return Ok(());
+    };
+    let _ = ROUTE_SHITLIST_PATH.set(path.clone());
+    let penalties: Vec<RoutePenalty> = match std::fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).context("corrupt route shitlist")?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut shitlist = ROUTE_SHITLIST.lock();
+    for penalty in penalties {
+        shitlist.insert(penalty.addr, penalty);
+    }
+    let half_life = ctx.init().route_penalty_half_life_secs.max(1);
+    if decay_all(&mut shitlist, unix_now(), half_life) {
+        persist_route_shitlist(&shitlist);
+    }
+    tracing::debug!(count = shitlist.len(), "restored route shitlist");
+    Ok(())
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -37,156 +187,949 @@ pub enum ExitConstraint {
     Hostname(String),
     Country(CountryCode),
     CountryCity(CountryCode, String),
+    /// Learns which exits are reliably fast from past connections.
+    Autonomous,
+    /// Probes the given number of least loaded exits and picks the one with the lowest round-trip time.
+    Latency {
+        max_candidates: usize,
+    },
+    /// Ranks exits reachable over IPv6 above those only reachable over IPv4.
+    PreferIpv6,
 }
 
-/// Gets a sillad Dialer that produces a single, pre-authentication pipe, as well as the public key.
-pub async fn get_dialer(
-    ctx: &AnyCtx<Config>,
-) -> anyhow::Result<(VerifyingKey, ExitDescriptor, DynDialer)> {
-    let mut country_constraint = None;
-    let mut city_constraint = None;
-    let mut hostname_constraint = None;
-    match &ctx.init().exit_constraint {
-        ExitConstraint::Direct(dir) => {
-            let (dir, pubkey) = dir
-                .split_once('/')
-                .context("did not find / in a direct constraint")?;
-            let pubkey = VerifyingKey::from_bytes(
-                hex::decode(pubkey)
-                    .context("cannot decode pubkey as hex")?
-                    .as_slice()
-                    .try_into()
-                    .context("pubkey wrong length")?,
-            )?;
-            let dest_addr = *smol::net::resolve(dir)
-                .await?
-                .choose(&mut rand::thread_rng())
-                .context("could not resolve destination for direct exit connection")?;
-            vpn_whitelist(dest_addr.ip());
-            return Ok((
-                pubkey,
-                ExitDescriptor {
-                    c2e_listen: "0.0.0.0:0".parse()?,
-                    b2e_listen: "0.0.0.0:0".parse()?,
-                    country: CountryCode::ABW,
-                    city: "".to_string(),
-                    load: 0.0,
-                    expiry: 0,
-                },
-                TcpDialer { dest_addr }.dynamic(),
-            ));
-        }
-        ExitConstraint::Country(country) => country_constraint = Some(*country),
-        ExitConstraint::CountryCity(country, city) => {
-            country_constraint = Some(*country);
-            city_constraint = Some(city.clone())
-        }
-        ExitConstraint::Hostname(hostname) => {
-            hostname_constraint = Some(hostname.clone());
+impl FromStr for ExitConstraint {
+    type Err = anyhow::Error;
+
+    /// Parses the syntax used on the command line, such as `Auto`, `Country(DE)`, or `CountryCity(DE, Frankfurt)`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, arg) = match s.split_once('(') {
+            Some((name, rest)) => (
+                name.trim(),
+                Some(
+                    rest.strip_suffix(')')
+                        .context("exit constraint missing closing parenthesis")?
+                        .trim(),
+                ),
+            ),
+            None => (s, None),
+        };
+        let country = |code: &str| {
+            CountryCode::for_alpha2_caseless(code.trim())
+                .with_context(|| format!("unknown country code {code}"))
+        };
+        Ok(match (name.to_ascii_lowercase().as_str(), arg) {
+            ("auto", None) => ExitConstraint::Auto,
+            ("autonomous", None) => ExitConstraint::Autonomous,
+            ("preferipv6", None) => ExitConstraint::PreferIpv6,
+            ("latency", None) => ExitConstraint::Latency {
+                max_candidates: DEFAULT_LATENCY_CANDIDATES,
+            },
+            ("latency", Some(arg)) => ExitConstraint::Latency {
+                max_candidates: arg
+                    .parse()
+                    .context("Latency needs a number of candidates")?,
+            },
+            ("direct", Some(arg)) => ExitConstraint::Direct(arg.to_string()),
+            ("hostname", Some(arg)) => ExitConstraint::Hostname(arg.to_string()),
+            ("country", Some(arg)) => ExitConstraint::Country(country(arg)?),
+            ("countrycity", Some(arg)) => {
+                let (code, city) = arg
+                    .split_once(',')
+                    .context("CountryCity needs both a country and a city")?;
+                ExitConstraint::CountryCity(country(code)?, city.trim().to_string())
+            }
+            _ => anyhow::bail!("invalid exit constraint {s}"),
+        })
+    }
+}
+
+impl Display for ExitConstraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitConstraint::Auto => write!(f, "Auto"),
+            ExitConstraint::Autonomous => write!(f, "Autonomous"),
+            ExitConstraint::Latency { max_candidates } => write!(f, "Latency({max_candidates})"),
+            ExitConstraint::PreferIpv6 => write!(f, "PreferIpv6"),
+            ExitConstraint::Direct(dir) => write!(f, "Direct({dir})"),
+            ExitConstraint::Hostname(hostname) => write!(f, "Hostname({hostname})"),
+            ExitConstraint::Country(country) => write!(f, "Country({})", country.alpha2()),
+            ExitConstraint::CountryCity(country, city) => {
+                write!(f, "CountryCity({}, {city})", country.alpha2())
+            }
         }
-        ExitConstraint::Auto => {}
     }
-    tracing::debug!(
-        country_constraint = debug(country_constraint),
-        city_constraint = debug(&city_constraint),
-        "created dialer"
+}
+
+/// The exit constraint currently in force. It starts out as [Config::exit_constraint], but can be changed at runtime through the control protocol.
+static EXIT_CONSTRAINT: CtxField<RwLock<ExitConstraint>> =
+    |ctx| RwLock::new(ctx.init().exit_constraint.clone());
+
+/// Notified whenever the exit constraint is changed at runtime.
+static EXIT_CONSTRAINT_CHANGED: CtxField<Event> = |_| Event::new();
+
+/// Gets the exit constraint currently in force.
+pub fn exit_constraint(ctx: &AnyCtx<Config>) -> ExitConstraint {
+    ctx.get(EXIT_CONSTRAINT).read().clone()
+}
+
+/// Replaces the exit constraint, so that the client reconnects to an exit satisfying the new one.
+pub fn set_exit_constraint(ctx: &AnyCtx<Config>, constraint: ExitConstraint) {
+    tracing::info!(
+        constraint = display(&constraint),
+        "changing exit constraint"
     );
+    *ctx.get(EXIT_CONSTRAINT).write() = constraint;
+    ctx.get(EXIT_CONSTRAINT_CHANGED).notify(usize::MAX);
+}
 
-    let broker = broker_client(ctx).context("could not get broker client")?;
-    let exits = broker
-        .get_exits()
+/// Waits until the exit constraint is changed at runtime.
+pub async fn wait_exit_constraint_changed(ctx: &AnyCtx<Config>) {
+    ctx.get(EXIT_CONSTRAINT_CHANGED).listen().await
+}
+
+/// The database key under which the countries and cities of the most recently seen exits are cached.
+const EXIT_LOCATIONS_KEY: &str = "exit_locations";
+
+/// How long fetching the exit list for completions may take.
+const EXIT_LOCATIONS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Lists the exit constraints that would currently be meaningful, for shell completion. The exit locations come from what we cached the last time we saw the exit list, so that completing stays quick, and are only fetched from the broker if nothing is cached yet.
+pub async fn exit_constraint_candidates(cfg: Config) -> Vec<ExitConstraint> {
+    let ctx = &AnyCtx::new(cfg);
+    let cached: Option<Vec<(CountryCode, String)>> = db_read(ctx, EXIT_LOCATIONS_KEY)
+        .await
+        .ok()
+        .flatten()
+        .and_then(|bts| serde_json::from_slice(&bts).ok());
+    let locations = match cached {
+        Some(locations) => locations,
+        None => async {
+            let exits = match broker_client(ctx)?.get_exits().await? {
+                Ok(exits) => exits,
+                Err(err) => return Err(broker_error("exits", err).await),
+            };
+            let locations: Vec<(CountryCode, String)> = exits
+                .inner
+                .all_exits
+                .iter()
+                .map(|(_, exit)| (exit.country, exit.city.clone()))
+                .collect();
+            cache_exit_locations(ctx, &locations).await?;
+            anyhow::Ok(locations)
+        }
+        .timeout(EXIT_LOCATIONS_TIMEOUT)
+        .await
+        .context("timed out fetching exits")
+        .and_then(|r| r)
+        .unwrap_or_else(|err| {
+            tracing::debug!(err = debug(err), "could not fetch exit locations");
+            vec![]
+        }),
+    };
+    let countries: BTreeSet<CountryCode> = locations.iter().map(|(c, _)| *c).collect();
+    let cities: BTreeSet<(CountryCode, String)> = locations.into_iter().collect();
+    [
+        ExitConstraint::Auto,
+        ExitConstraint::Autonomous,
+        ExitConstraint::Latency {
+            max_candidates: DEFAULT_LATENCY_CANDIDATES,
+        },
+        ExitConstraint::PreferIpv6,
+    ]
+    .into_iter()
+    .chain(countries.into_iter().map(ExitConstraint::Country))
+    .chain(
+        cities
+            .into_iter()
+            .map(|(c, city)| ExitConstraint::CountryCity(c, city)),
+    )
+    .collect()
+}
+
+/// One available exit, as listed by the `list-exits` command.
+#[derive(Serialize, Clone, Debug)]
+pub struct ExitSummary {
+    pub country: String,
+    pub city: String,
+    pub load: f32,
+    /// The first 8 bytes of the exit's public key, in hex.
+    pub fingerprint: String,
+}
+
+/// Lists every exit the broker currently offers, sorted by country, then city, then load. This logs in and verifies the exit list exactly as connecting would, so it also tells whether the credentials work.
+pub async fn list_exits(cfg: Config) -> anyhow::Result<Vec<ExitSummary>> {
+    let ctx = &AnyCtx::new(cfg);
+    get_auth_token(ctx).await.context("could not log in")?;
+    let mut exits: Vec<ExitSummary> = verified_exits(ctx)
         .await?
-        .map_err(|e| anyhow::anyhow!("broker refused to serve exits: {e}"))?;
+        .all_exits
+        .into_iter()
+        .map(|(pubkey, exit)| ExitSummary {
+            country: exit.country.alpha2().to_string(),
+            city: exit.city,
+            load: exit.load,
+            fingerprint: hex::encode(&pubkey.as_bytes()[..8]),
+        })
+        .collect();
+    exits.sort_by(|a, b| {
+        (&a.country, &a.city)
+            .cmp(&(&b.country, &b.city))
+            .then(a.load.total_cmp(&b.load))
+    });
+    Ok(exits)
+}
 
-    let exits = exits
-        .verify(DOMAIN_EXIT_DESCRIPTOR, |their_pk| {
-            if let Some(broker_pk) = &ctx.init().broker_keys {
-                hex::encode(their_pk.as_bytes()) == broker_pk.master
-            } else {
-                true
+/// Caches the countries and cities of the exits we just saw, for shell completion. The database is only written when they changed, since this runs on every exit list fetch.
+async fn cache_exit_locations(
+    ctx: &AnyCtx<Config>,
+    locations: &[(CountryCode, String)],
+) -> anyhow::Result<()> {
+    let locations: BTreeSet<&(CountryCode, String)> = locations.iter().collect();
+    let serialized = serde_json::to_vec(&locations)?;
+    if db_read(ctx, EXIT_LOCATIONS_KEY).await?.as_ref() != Some(&serialized) {
+        db_write(ctx, EXIT_LOCATIONS_KEY, &serialized).await?;
+    }
+    Ok(())
+}
+
+/// How many exits we dial speculatively at the same time.
+const SPECULATIVE_EXITS: usize = 2;
+
+/// Gets sillad Dialers that each produce a single, pre-authentication pipe, together with the public keys and descriptors of their exits. Unless the exit is given directly, there are up to [SPECULATIVE_EXITS] of them, best first, to be dialed at the same time.
+pub async fn get_dialer_candidates(
+    ctx: &AnyCtx<Config>,
+) -> anyhow::Result<Vec<(VerifyingKey, ExitDescriptor, DynDialer)>> {
+    get_exit_dialers(ctx, SPECULATIVE_EXITS).await
+}
+
+/// Gets dialers for up to `count` distinct exits, best first, all satisfying the exit constraint.
+pub async fn get_exit_dialers(
+    ctx: &AnyCtx<Config>,
+    count: usize,
+) -> anyhow::Result<Vec<(VerifyingKey, ExitDescriptor, DynDialer)>> {
+    let proxy_addr = upstream_proxy_addr(ctx).await?;
+    let constraint = &exit_constraint(ctx);
+    if let ExitConstraint::Direct(dir) = constraint {
+        let (dir, pubkey) = dir
+            .rsplit_once('/')
+            .context("did not find / in a direct constraint")?;
+        let pubkey = VerifyingKey::from_bytes(
+            hex::decode(pubkey)
+                .context("cannot decode pubkey as hex")?
+                .as_slice()
+                .try_into()
+                .context("pubkey wrong length")?,
+        )?;
+        // an exit on the same machine can be reached through its Unix socket, as in unix:/run/geph5-exit.sock/<pubkey>
+        let dialer = if let Some(path) = dir.strip_prefix("unix:") {
+            #[cfg(unix)]
+            {
+                sillad::unix::UnixDialer {
+                    dest_path: path.into(),
+                }
+                .dynamic()
             }
-        })
-        .context("could not verify")?;
-    // filter for things that fit
-    let (pubkey, exit) = if let Some(min) = exits
-        .all_exits
-        .iter()
-        .filter(|(_, exit)| {
-            let country_pass = if let Some(country) = &country_constraint {
-                exit.country == *country
-            } else {
-                true
-            };
-            let city_pass = if let Some(city) = &city_constraint {
-                &exit.city == city
-            } else {
-                true
-            };
-            let hostname_pass = if let Some(hostname) = &hostname_constraint {
-                &exit.b2e_listen.ip().to_string() == hostname
-            } else {
-                true
+            #[cfg(not(unix))]
+            anyhow::bail!("cannot connect to {path}: Unix sockets are not supported here")
+        } else {
+            let dest_addrs = resolve(ctx, dir, proxy_addr).await?;
+            anyhow::ensure!(
+                !dest_addrs.is_empty(),
+                "could not resolve destination for direct exit connection"
+            );
+            // a hostname may resolve to several addresses, so we dial them Happy Eyeballs-style rather than betting on one
+            HappyEyeballsDialer::new(
+                dest_addrs
+                    .into_iter()
+                    .map(|dest_addr| {
+                        vpn_whitelist(dest_addr.ip());
+                        tcp_dialer(proxy_addr, dest_addr)
+                    })
+                    .collect(),
+            )
+            .dynamic()
+        };
+        return Ok(vec![(
+            pubkey,
+            ExitDescriptor {
+                c2e_listen: "0.0.0.0:0".parse()?,
+                b2e_listen: "0.0.0.0:0".parse()?,
+                country: CountryCode::ABW,
+                city: "".to_string(),
+                load: 0.0,
+                expiry: 0,
+                // we know nothing about exits we are told to dial directly
+                probe_magic: false,
+                pmtud_echo: false,
+            },
+            dialer,
+        )]);
+    }
+    tracing::debug!(exit_constraint = display(constraint), "created dialer");
+
+    let mut candidates = vec![];
+    let temperature = ctx.init().exit_load_temperature;
+    for (pubkey, exit) in choose_exits(ctx, proxy_addr, count, temperature).await? {
+        let dialer = exit_dialer(ctx, proxy_addr, &exit).await?;
+        candidates.push((pubkey, exit, dialer));
+    }
+    Ok(candidates)
+}
+
+/// Gets the best exit satisfying the exit constraint, with a dialer for each path to it: the direct path first, then the bridges. An exit given directly has only the one path.
+pub async fn get_exit_paths(
+    ctx: &AnyCtx<Config>,
+) -> anyhow::Result<(VerifyingKey, ExitDescriptor, Vec<DynDialer>)> {
+    if let ExitConstraint::Direct(_) = exit_constraint(ctx) {
+        let (pubkey, exit, dialer) = get_exit_dialers(ctx, 1)
+            .await?
+            .pop()
+            .context("no direct exit")?;
+        return Ok((pubkey, exit, vec![dialer]));
+    }
+    let proxy_addr = upstream_proxy_addr(ctx).await?;
+    let (pubkey, exit) = choose_exits(ctx, proxy_addr, 1, ctx.init().exit_load_temperature)
+        .await?
+        .pop()
+        .context("no exits that fit the criterion")?;
+    let (direct_dialer, bridge_dialer) = exit_paths(ctx, proxy_addr, &exit).await?;
+    Ok((pubkey, exit, vec![direct_dialer, bridge_dialer]))
+}
+
+/// Re-runs exit selection and returns the exit it now picks, for watch mode to notice when the best exit changes. Loads are ranked without the usual randomness, so that the pick stays put while the exit list and constraint do. Constraints that give the exit directly or pick it by probing latencies have no pick to watch, so they return None.
+pub async fn best_exit(ctx: &AnyCtx<Config>) -> anyhow::Result<Option<VerifyingKey>> {
+    if let ExitConstraint::Direct(_) | ExitConstraint::Latency { .. } = exit_constraint(ctx) {
+        return Ok(None);
+    }
+    let proxy_addr = upstream_proxy_addr(ctx).await?;
+    let (pubkey, _) = choose_exits(ctx, proxy_addr, 1, 0.0)
+        .await?
+        .pop()
+        .context("no exits that fit the criterion")?;
+    Ok(Some(pubkey))
+}
+
+/// Chooses up to `count` distinct exits, best first, all satisfying an exit constraint that does not give the exit directly. Loads are weighed at the given temperature, as in [load_preference].
+async fn choose_exits(
+    ctx: &AnyCtx<Config>,
+    proxy_addr: Option<SocketAddr>,
+    count: usize,
+    temperature: f64,
+) -> anyhow::Result<Vec<(VerifyingKey, ExitDescriptor)>> {
+    let constraint = &exit_constraint(ctx);
+    let exits = verified_exits(ctx).await?;
+    let chosen = if let ExitConstraint::Autonomous = constraint {
+        let mut remaining = exits.all_exits.clone();
+        let mut chosen = vec![];
+        while chosen.len() < count {
+            let Some(best) = choose_exit(ctx, &remaining).await?.cloned() else {
+                break;
             };
-            country_pass && city_pass && hostname_pass
+            remaining.retain(|(key, _)| *key != best.0);
+            chosen.push(best);
+        }
+        chosen
+    } else if let ExitConstraint::Latency { max_candidates } = constraint {
+        lowest_latency_exits(
+            ctx,
+            proxy_addr,
+            exits.all_exits.clone(),
+            *max_candidates,
+            count,
+        )
+        .await
+    } else {
+        rank_exits(
+            constraint,
+            &exits.all_exits,
+            ctx.init().strict_country,
+            temperature,
+            count,
+        )?
+    };
+    let chosen = prefer_pinned_exit(ctx, constraint, &exits.all_exits, chosen, count);
+    anyhow::ensure!(!chosen.is_empty(), "no exits that fit the criterion");
+    tracing::debug!(
+        chosen = debug(chosen.iter().map(|(_, exit)| exit).collect::<Vec<_>>()),
+        "narrowed down choice of exits"
+    );
+    Ok(chosen)
+}
+
+/// Ranks up to `count` of the exits that fit the constraint, best first. If none fit, that is an error when `strict`, and otherwise every exit is ranked instead.
+fn rank_exits(
+    constraint: &ExitConstraint,
+    all_exits: &[(VerifyingKey, ExitDescriptor)],
+    strict: bool,
+    temperature: f64,
+    count: usize,
+) -> Result<Vec<(VerifyingKey, ExitDescriptor)>, ExitConstraintUnsatisfied> {
+    let fitting: Vec<(VerifyingKey, ExitDescriptor)> = all_exits
+        .iter()
+        .filter(|(_, exit)| exit_fits(constraint, exit))
+        .cloned()
+        .collect();
+    let ranked = if !fitting.is_empty() {
+        fitting
+    } else if strict {
+        return Err(ExitConstraintUnsatisfied {
+            constraint: constraint.clone(),
+        });
+    } else {
+        all_exits.to_vec()
+    };
+    // exits whose routes recently failed go last, and otherwise less loaded exits tend to go first
+    let preference = load_preference(&ranked, temperature, &mut rand::thread_rng());
+    let mut ranked: Vec<_> = ranked.into_iter().zip(preference).collect();
+    let prefer_ipv6 = matches!(constraint, ExitConstraint::PreferIpv6);
+    ranked.sort_by(|((_, a), a_pref), ((_, b), b_pref)| {
+        let family = |exit: &ExitDescriptor| prefer_ipv6 && exit.c2e_listen.is_ipv4();
+        family(a)
+            .cmp(&family(b))
+            .then(route_penalty(&a.c2e_listen).cmp(&route_penalty(&b.c2e_listen)))
+            .then(b_pref.total_cmp(a_pref))
+    });
+    Ok(ranked
+        .into_iter()
+        .map(|(exit, _)| exit)
+        .take(count)
+        .collect())
+}
+
+/// How many exits a bare `Latency` constraint probes.
+const DEFAULT_LATENCY_CANDIDATES: usize = 5;
+
+/// How long latency probes may take altogether. Exits that have not answered by then are considered unreachable.
+const LATENCY_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Times how long connecting to each of the least loaded exits whose routes have not recently failed takes, returning up to `count` of them fastest first. Exits that did not answer go last. The probes connect however the bridge mode says to, so that they never reach exits directly when only bridges may.
+async fn lowest_latency_exits(
+    ctx: &AnyCtx<Config>,
+    proxy_addr: Option<SocketAddr>,
+    exits: Vec<(VerifyingKey, ExitDescriptor)>,
+    max_candidates: usize,
+    count: usize,
+) -> Vec<(VerifyingKey, ExitDescriptor)> {
+    let candidates = latency_candidates(exits, max_candidates);
+    let rtts = join_all(candidates.iter().map(|(_, exit)| async move {
+        async {
+            let dialer = exit_dialer(ctx, proxy_addr, exit).await.ok()?;
+            let start = Instant::now();
+            dialer.dial().await.ok()?;
+            Some(start.elapsed())
+        }
+        .timeout(LATENCY_PROBE_TIMEOUT)
+        .await
+        .flatten()
+    }))
+    .await;
+    tracing::debug!(
+        rtts = debug(
+            candidates
+                .iter()
+                .zip(rtts.iter())
+                .map(|((_, exit), rtt)| (exit.c2e_listen, *rtt))
+                .collect::<Vec<_>>()
+        ),
+        "probed exit latencies"
+    );
+    rank_by_latency(candidates, rtts, count)
+}
+
+/// Picks the exits worth probing for latency: the least loaded ones, skipping those whose routes recently failed unless that would leave nothing.
+fn latency_candidates(
+    exits: Vec<(VerifyingKey, ExitDescriptor)>,
+    max_candidates: usize,
+) -> Vec<(VerifyingKey, ExitDescriptor)> {
+    let mut candidates: Vec<_> = exits
+        .iter()
+        .filter(|(_, exit)| route_penalty(&exit.c2e_listen) == 0)
+        .cloned()
+        .collect();
+    if candidates.is_empty() {
+        candidates = exits;
+    }
+    candidates.sort_by(|(_, a), (_, b)| a.load.total_cmp(&b.load));
+    candidates.truncate(max_candidates.max(1));
+    candidates
+}
+
+/// Orders probed exits fastest first, returning up to `count` of them.
+fn rank_by_latency(
+    candidates: Vec<(VerifyingKey, ExitDescriptor)>,
+    rtts: Vec<Option<Duration>>,
+    count: usize,
+) -> Vec<(VerifyingKey, ExitDescriptor)> {
+    let mut ranked: Vec<_> = candidates.into_iter().zip(rtts).collect();
+    // the sort is stable, so exits that did not answer stay in order of load
+    ranked.sort_by_key(|(_, rtt)| rtt.unwrap_or(Duration::MAX));
+    ranked
+        .into_iter()
+        .map(|(exit, _)| exit)
+        .take(count)
+        .collect()
+}
+
+/// Gives each exit a random preference, so that sorting by descending preference samples the exits without replacement, each with softmax probability over negated load at the given temperature. This keeps every client from piling onto the same least-loaded exit at once. A temperature of zero always prefers the less loaded exit.
+fn load_preference(
+    exits: &[(VerifyingKey, ExitDescriptor)],
+    temperature: f64,
+    rng: &mut impl Rng,
+) -> Vec<f64> {
+    if temperature <= 0.0 {
+        return exits.iter().map(|(_, exit)| -exit.load as f64).collect();
+    }
+    exits
+        .iter()
+        .map(|(_, exit)| {
+            // adding Gumbel noise to the logits and taking the largest is the same as sampling from their softmax
+            let uniform: f64 = rng.gen_range(f64::MIN_POSITIVE..1.0);
+            -exit.load as f64 / temperature - (-uniform.ln()).ln()
         })
-        .min_by_key(|e| (e.1.load * 1000.0) as u64)
-    {
-        min
+        .collect()
+}
+
+/// Fetches the exit list from the broker and verifies its signatures, caching the exit locations on the way.
+async fn verified_exits(ctx: &AnyCtx<Config>) -> anyhow::Result<ExitList> {
+    let exits = match streamed_exits(ctx) {
+        Some(exits) => exits,
+        None => match ctx.get(EXITS_BREAKER).call(ctx, (), get_exits(ctx)).await? {
+            Ok(exits) => exits,
+            Err(err) => return Err(broker_error("exits", err).await),
+        },
+    };
+
+    let exits = if let Some(broker_keys) = &ctx.init().broker_keys {
+        exits.verify(
+            DOMAIN_EXIT_DESCRIPTOR,
+            &broker_keys.trusted_keys()?,
+            broker_keys.threshold,
+        )
     } else {
-        exits
+        exits.verify(DOMAIN_EXIT_DESCRIPTOR, &[], 0)
+    }
+    .context("could not verify")?;
+    let locations: Vec<(CountryCode, String)> = exits
+        .all_exits
+        .iter()
+        .map(|(_, exit)| (exit.country, exit.city.clone()))
+        .collect();
+    if let Err(err) = cache_exit_locations(ctx, &locations).await {
+        tracing::warn!(err = debug(err), "could not cache exit locations");
+    }
+    Ok(exits)
+}
+
+/// Whether an exit satisfies the country, city, and hostname parts of an exit constraint.
+fn exit_fits(constraint: &ExitConstraint, exit: &ExitDescriptor) -> bool {
+    match constraint {
+        ExitConstraint::Country(country) => exit.country == *country,
+        ExitConstraint::CountryCity(country, city) => {
+            exit.country == *country && &exit.city == city
+        }
+        ExitConstraint::Hostname(hostname) => &exit.b2e_listen.ip().to_string() == hostname,
+        ExitConstraint::Auto
+        | ExitConstraint::Autonomous
+        | ExitConstraint::Latency { .. }
+        | ExitConstraint::PreferIpv6
+        | ExitConstraint::Direct(_) => true,
+    }
+}
+
+/// Returned when no exit satisfies the exit constraint and [Config::strict_country] rules out falling back to other exits.
+#[derive(thiserror::Error, Debug)]
+#[error("no exits satisfy the exit constraint {constraint}")]
+pub struct ExitConstraintUnsatisfied {
+    pub constraint: ExitConstraint,
+}
+
+/// Re-fetches the exit list and checks whether the given exit still satisfies the exit constraint. When no exit at all satisfies it and [Config::strict_country] is off, any exit does, just as when choosing one.
+pub async fn exit_still_allowed(
+    ctx: &AnyCtx<Config>,
+    pubkey: VerifyingKey,
+) -> anyhow::Result<bool> {
+    let constraint = &exit_constraint(ctx);
+    if let ExitConstraint::Direct(_) = constraint {
+        return Ok(true);
+    }
+    let exits = verified_exits(ctx).await?;
+    let none_fit = !ctx.init().strict_country
+        && !exits
             .all_exits
             .iter()
-            .min_by_key(|e| (e.1.load * 1000.0) as u64)
-            .context("no exits that fit the criterion")?
-    };
+            .any(|(_, exit)| exit_fits(constraint, exit));
+    Ok(exits
+        .all_exits
+        .iter()
+        .any(|(key, exit)| *key == pubkey && (none_fit || exit_fits(constraint, exit))))
+}
+
+/// Gets the dialer for a particular exit, either directly or through bridges depending on the bridge mode.
+async fn exit_dialer(
+    ctx: &AnyCtx<Config>,
+    proxy_addr: Option<SocketAddr>,
+    exit: &ExitDescriptor,
+) -> anyhow::Result<DynDialer> {
+    let (direct_dialer, bridge_dialer) = exit_paths(ctx, proxy_addr, exit).await?;
+    Ok(match ctx.init().bridge_mode {
+        crate::BridgeMode::SmartBridges { .. } if !bridges_promoted(ctx) => direct_dialer,
+        // where a single path is needed even in multipath mode, such as for probing exits, the first to connect will do
+        crate::BridgeMode::Auto
+        | crate::BridgeMode::SmartBridges { .. }
+        | crate::BridgeMode::Multipath => direct_dialer
+            .race(bridge_dialer.delay(Duration::from_secs(1)))
+            .dynamic(),
+        crate::BridgeMode::ForceBridges => bridge_dialer,
+        crate::BridgeMode::ForceDirect => direct_dialer,
+    })
+}
 
-    tracing::debug!(exit = debug(&exit), "narrowed down choice of exit");
+/// Gets the dialers for the direct path to a particular exit and for the bridges to it, in that order.
+async fn exit_paths(
+    ctx: &AnyCtx<Config>,
+    proxy_addr: Option<SocketAddr>,
+    exit: &ExitDescriptor,
+) -> anyhow::Result<(DynDialer, DynDialer)> {
     vpn_whitelist(exit.c2e_listen.ip());
-    let direct_dialer = TcpDialer {
-        dest_addr: exit.c2e_listen,
-    }
-    .delay(Duration::from_secs(
-        ROUTE_SHITLIST.get(&exit.c2e_listen).unwrap_or_default() as _,
-    ));
+    let direct_dialer = tcp_dialer(proxy_addr, exit.c2e_listen)
+        .delay(Duration::from_secs(route_penalty(&exit.c2e_listen) as _))
+        .dynamic();
+    let direct_dialer = if ctx.init().chaos_loss > 0.0 {
+        tracing::warn!(
+            loss_rate = ctx.init().chaos_loss,
+            "injecting packet loss into the direct connection"
+        );
+        PacketLossInjector {
+            inner: direct_dialer,
+            loss_rate: ctx.init().chaos_loss,
+            rng: Mutex::new(StdRng::from_entropy()),
+        }
+        .dynamic()
+    } else {
+        direct_dialer
+    };
 
     // Also obtain the bridges
+    let broker = broker_client(ctx).context("could not get broker client")?;
     let (_, conn_token, sig) = get_connect_token(ctx)
         .await
         .context("could not get connect token")?;
-    let bridge_routes = broker
-        .get_routes(conn_token, sig, exit.b2e_listen)
+    let bridge_routes = match ctx
+        .get(ROUTES_BREAKER)
+        .call(ctx, exit.b2e_listen, async {
+            Ok(broker
+                .get_routes(conn_token, sig.clone(), exit.b2e_listen)
+                .await?)
+        })
         .await?
-        .map_err(|e| anyhow::anyhow!("broker refused to serve bridge routes: {e}"))?;
+    {
+        Ok(routes) => routes,
+        Err(err) => return Err(broker_error("bridge routes", err).await),
+    };
+    let bridge_routes = prune_unreachable_bridges(broker, conn_token, sig, bridge_routes).await;
     tracing::debug!(
         bridge_routes = debug(&bridge_routes),
         "bridge routes obtained too"
     );
 
-    let bridge_dialer = route_to_dialer(&bridge_routes);
+    let bridge_dialer = route_to_dialer(proxy_addr, client_country(ctx), &bridge_routes);
+    Ok((direct_dialer, bridge_dialer))
+}
 
-    let final_dialer = match ctx.init().bridge_mode {
-        crate::BridgeMode::Auto => direct_dialer
-            .race(bridge_dialer.delay(Duration::from_millis(500)))
-            .dynamic(),
-        crate::BridgeMode::ForceBridges => bridge_dialer,
-        crate::BridgeMode::ForceDirect => direct_dialer.dynamic(),
+/// An exit that `pin_exit` pinned.
+#[derive(Clone, Copy)]
+struct ExitPin {
+    pubkey: VerifyingKey,
+    /// The address we last reached the exit at, which is a bridge's when we went through one. Not known for a pin restored from disk.
+    via: Option<SocketAddr>,
+}
+
+/// The exit to prefer in exit selections, when `pin_exit` is set. Restored from next to the persisted route shitlist, if any.
+static PINNED_EXIT: CtxField<Mutex<Option<ExitPin>>> = |ctx| {
+    let restored = pinned_exit_path(ctx).and_then(|path| {
+        let hex_key = std::fs::read_to_string(path).ok()?;
+        let key = VerifyingKey::from_bytes(
+            hex::decode(hex_key.trim())
+                .ok()?
+                .as_slice()
+                .try_into()
+                .ok()?,
+        )
+        .ok()?;
+        tracing::debug!(pubkey = hex::encode(key.as_bytes()), "restored exit pin");
+        Some(ExitPin {
+            pubkey: key,
+            via: None,
+        })
+    });
+    Mutex::new(restored)
+};
+
+fn pinned_exit_path(ctx: &AnyCtx<Config>) -> Option<PathBuf> {
+    ctx.init()
+        .route_shitlist_path
+        .as_ref()
+        .filter(|_| ctx.init().pin_exit)
+        .map(|path| path.with_extension("pin"))
+}
+
+fn set_pinned_exit(ctx: &AnyCtx<Config>, pin: Option<ExitPin>) {
+    let changed = ctx.get(PINNED_EXIT).lock().map(|old| old.pubkey) != pin.map(|new| new.pubkey);
+    *ctx.get(PINNED_EXIT).lock() = pin;
+    if !changed {
+        return;
+    }
+    if let Some(path) = pinned_exit_path(ctx) {
+        let res = match pin {
+            Some(pin) => std::fs::write(path, hex::encode(pin.pubkey.as_bytes())),
+            None => std::fs::remove_file(path).or_else(|err| {
+                if err.kind() == std::io::ErrorKind::NotFound {
+                    Ok(())
+                } else {
+                    Err(err)
+                }
+            }),
+        };
+        if let Err(err) = res {
+            tracing::warn!(err = debug(err), "could not save exit pin");
+        }
+    }
+}
+
+/// Notes that we connected to an exit at the given address, pinning it if `pin_exit` is set.
+pub fn exit_connected(ctx: &AnyCtx<Config>, pubkey: VerifyingKey, via: Option<SocketAddr>) {
+    if !ctx.init().pin_exit {
+        return;
+    }
+    if ctx.get(PINNED_EXIT).lock().map(|old| old.pubkey) != Some(pubkey) {
+        tracing::info!(pubkey = hex::encode(pubkey.as_bytes()), "pinning exit");
+    }
+    set_pinned_exit(ctx, Some(ExitPin { pubkey, via }));
+}
+
+/// Puts the pinned exit first among the chosen ones, even if it was not chosen at all. If it no longer fits the exit constraint, is too loaded, or the route we reached it over recently failed, the pin is dropped instead, leaving the choice to the usual selection.
+fn prefer_pinned_exit(
+    ctx: &AnyCtx<Config>,
+    constraint: &ExitConstraint,
+    all_exits: &[(VerifyingKey, ExitDescriptor)],
+    mut chosen: Vec<(VerifyingKey, ExitDescriptor)>,
+    count: usize,
+) -> Vec<(VerifyingKey, ExitDescriptor)> {
+    let Some(pin) = *ctx.get(PINNED_EXIT).lock() else {
+        return chosen;
+    };
+    match check_pin(pin, constraint, all_exits, ctx.init().pin_exit_max_load) {
+        Ok(pinned) => {
+            chosen.retain(|(key, _)| *key != pin.pubkey);
+            chosen.insert(0, pinned);
+            chosen.truncate(count.max(1));
+        }
+        Err(reason) => {
+            tracing::info!(
+                pubkey = hex::encode(pin.pubkey.as_bytes()),
+                reason,
+                "dropping exit pin"
+            );
+            set_pinned_exit(ctx, None);
+        }
+    }
+    chosen
+}
+
+/// Returns the pinned exit if the pin still holds, or why it should be dropped. The route we last reached the exit over is checked, and not only its direct address, since through bridges a failure penalizes the bridge.
+fn check_pin(
+    pin: ExitPin,
+    constraint: &ExitConstraint,
+    all_exits: &[(VerifyingKey, ExitDescriptor)],
+    max_load: f32,
+) -> Result<(VerifyingKey, ExitDescriptor), &'static str> {
+    let Some((_, exit)) = all_exits.iter().find(|(key, _)| *key == pin.pubkey) else {
+        return Err("no longer listed");
+    };
+    if !exit_fits(constraint, exit) {
+        return Err("does not fit the exit constraint");
+    }
+    if exit.load > max_load {
+        return Err("too loaded");
+    }
+    if std::iter::once(exit.c2e_listen)
+        .chain(pin.via)
+        .any(|addr| route_penalty(&addr) > 0)
+    {
+        return Err("recently unreachable");
+    }
+    Ok((pin.pubkey, exit.clone()))
+}
+
+/// How many times connecting to an exit has failed, which, until bridges are promoted in [BridgeMode::SmartBridges](crate::BridgeMode::SmartBridges), means the direct path failed.
+static DIRECT_FAILURES: CtxField<AtomicU32> = |_| AtomicU32::new(0);
+
+/// Deprioritizes the direct route to an exit that could not be connected to, and counts the failure towards promoting bridges.
+pub fn direct_route_failed(ctx: &AnyCtx<Config>, addr: SocketAddr) {
+    deprioritize_route(addr);
+    let failures = ctx.get(DIRECT_FAILURES).fetch_add(1, Ordering::SeqCst) + 1;
+    if let crate::BridgeMode::SmartBridges { threshold } = ctx.init().bridge_mode {
+        if failures == threshold {
+            tracing::warn!(
+                failures,
+                "direct connections keep failing, switching to bridges"
+            );
+        }
+    }
+}
+
+/// Whether [BridgeMode::SmartBridges](crate::BridgeMode::SmartBridges) has seen enough direct failures to start using bridges.
+pub fn bridges_promoted(ctx: &AnyCtx<Config>) -> bool {
+    match ctx.init().bridge_mode {
+        crate::BridgeMode::SmartBridges { threshold } => {
+            ctx.get(DIRECT_FAILURES).load(Ordering::SeqCst) >= threshold
+        }
+        _ => false,
+    }
+}
+
+/// Drops the bridges that the broker's probes found unreachable from the raced bridge routes, unless that would drop all of them. Bridge health is only a hint, so if we cannot get it, nothing changes.
+async fn prune_unreachable_bridges(
+    broker: &BrokerClient,
+    token: ClientToken,
+    sig: UnblindedSignature,
+    routes: RouteDescriptor,
+) -> RouteDescriptor {
+    let RouteDescriptor::Race(inside) = routes else {
+        return routes;
+    };
+    let status = match broker.get_bridge_status(token, sig).await {
+        Ok(Ok(status)) => status,
+        Ok(Err(err)) => {
+            tracing::debug!(err = display(err), "broker could not give bridge status");
+            return RouteDescriptor::Race(inside);
+        }
+        Err(err) => {
+            tracing::debug!(err = debug(err), "could not get bridge status");
+            return RouteDescriptor::Race(inside);
+        }
+    };
+    // bridges forward from the same host as their control port
+    let unreachable: BTreeSet<IpAddr> = status
+        .bridges
+        .iter()
+        .filter(|(_, health)| !health.reachable)
+        .map(|(addr, _)| addr.ip())
+        .collect();
+    let reachable: Vec<RouteDescriptor> = inside
+        .iter()
+        .filter(|route| {
+            let addrs = route_addrs(route);
+            addrs.is_empty() || !addrs.iter().all(|addr| unreachable.contains(&addr.ip()))
+        })
+        .cloned()
+        .collect();
+    tracing::debug!(
+        total = inside.len(),
+        reachable = reachable.len(),
+        "pruned unreachable bridges"
+    );
+    if reachable.is_empty() {
+        RouteDescriptor::Race(inside)
+    } else {
+        RouteDescriptor::Race(reachable)
+    }
+}
+
+/// Figures out the address of the upstream proxy to dial through, if any. An explicitly configured proxy takes precedence over the system proxy.
+#[cfg(not(target_arch = "wasm32"))]
+async fn upstream_proxy_addr(ctx: &AnyCtx<Config>) -> anyhow::Result<Option<SocketAddr>> {
+    if let Some(proxy) = &ctx.init().upstream_proxy {
+        anyhow::ensure!(
+            proxy.scheme() == "http",
+            "only http upstream proxies are supported"
+        );
+        return Ok(Some(resolve_proxy(proxy).await?));
+    }
+    let Some(proxy) = detect_system_proxy(ctx.init().wpad).await else {
+        return Ok(None);
     };
+    if proxy.scheme() != "http" {
+        tracing::debug!(proxy = display(&proxy), "ignoring non-http system proxy");
+        return Ok(None);
+    }
+    let proxy_addr = match resolve_proxy(&proxy).await {
+        Ok(addr) => addr,
+        Err(err) => {
+            tracing::warn!(err = debug(err), "could not resolve system proxy");
+            return Ok(None);
+        }
+    };
+    // the system proxy might well be ourselves, if we set it
+    let is_ourselves = proxy_addr.ip().is_loopback()
+        && [ctx.init().http_proxy_listen, ctx.init().socks5_listen]
+            .into_iter()
+            .flatten()
+            .any(|listen| listen.port() == proxy_addr.port());
+    if is_ourselves {
+        return Ok(None);
+    }
+    Ok(Some(proxy_addr))
+}
 
-    Ok((*pubkey, exit.clone(), final_dialer))
+/// The browser does its own proxying, which we have no say in.
+#[cfg(target_arch = "wasm32")]
+async fn upstream_proxy_addr(_ctx: &AnyCtx<Config>) -> anyhow::Result<Option<SocketAddr>> {
+    Ok(None)
 }
 
-fn route_to_dialer(route: &RouteDescriptor) -> DynDialer {
+#[cfg(not(target_arch = "wasm32"))]
+fn tcp_dialer(proxy_addr: Option<SocketAddr>, dest_addr: SocketAddr) -> DynDialer {
+    if let Some(proxy_addr) = proxy_addr {
+        vpn_whitelist(proxy_addr.ip());
+        HttpConnectDialer {
+            proxy_addr,
+            dest_addr,
+        }
+        .dynamic()
+    } else {
+        TcpDialer { dest_addr }.dynamic()
+    }
+}
+
+/// The browser cannot open TCP connections, so a WebSocket relay opens them for us.
+#[cfg(target_arch = "wasm32")]
+fn tcp_dialer(_proxy_addr: Option<SocketAddr>, dest_addr: SocketAddr) -> DynDialer {
+    crate::wasm::ws_dialer(&dest_addr.to_string())
+}
+
+fn route_to_dialer(
+    proxy_addr: Option<SocketAddr>,
+    client_country: Option<CountryCode>,
+    route: &RouteDescriptor,
+) -> DynDialer {
     match route {
         RouteDescriptor::Tcp(addr) => {
             vpn_whitelist(addr.ip());
-            TcpDialer { dest_addr: *addr }
-                .delay(Duration::from_secs(
-                    ROUTE_SHITLIST.get(addr).unwrap_or_default() as _,
-                ))
+            tcp_dialer(proxy_addr, *addr)
+                .delay(Duration::from_secs(route_penalty(addr) as _))
                 .dynamic()
         }
+        #[cfg(not(target_arch = "wasm32"))]
+        RouteDescriptor::Quic { addr, cert_hash } => {
+            // UDP cannot go through an HTTP proxy
+            if proxy_addr.is_some() {
+                return FailingDialer.dynamic();
+            }
+            vpn_whitelist(addr.ip());
+            QuicRouteDialer {
+                addr: *addr,
+                cert_hash: *cert_hash,
+            }
+            .delay(Duration::from_secs(route_penalty(addr) as _))
+            .dynamic()
+        }
+        // browsers have no UDP sockets at all
+        #[cfg(target_arch = "wasm32")]
+        RouteDescriptor::Quic { .. } => FailingDialer.dynamic(),
         RouteDescriptor::Sosistab3 { cookie, lower } => {
-            let inner = route_to_dialer(lower);
+            let inner = route_to_dialer(proxy_addr, client_country, lower);
             SosistabDialer {
                 inner,
                 cookie: Cookie::new(cookie),
@@ -195,26 +1138,309 @@ fn route_to_dialer(route: &RouteDescriptor) -> DynDialer {
         }
         RouteDescriptor::Race(inside) => inside
             .iter()
-            .map(route_to_dialer)
+            .map(|route| route_to_dialer(proxy_addr, client_country, route))
             .reduce(|a, b| a.race(b).dynamic())
             .unwrap_or_else(|| FailingDialer.dynamic()),
         RouteDescriptor::Fallback(a) => a
             .iter()
-            .map(route_to_dialer)
+            .map(|route| route_to_dialer(proxy_addr, client_country, route))
             .reduce(|a, b| a.fallback(b).dynamic())
             .unwrap_or_else(|| FailingDialer.dynamic()),
         RouteDescriptor::Timeout {
             milliseconds,
             lower,
-        } => route_to_dialer(lower)
+        } => route_to_dialer(proxy_addr, client_country, lower)
             .timeout(Duration::from_millis(*milliseconds as _))
             .dynamic(),
         RouteDescriptor::Delay {
             milliseconds,
             lower,
-        } => route_to_dialer(lower)
+        } => route_to_dialer(proxy_addr, client_country, lower)
             .delay(Duration::from_millis((*milliseconds).into()))
             .dynamic(),
+        RouteDescriptor::ConditionalRoute {
+            condition,
+            then_,
+            else_,
+        } => {
+            let probes = match condition {
+                RouteCondition::IfPortBlocked(port) => route_addrs(else_)
+                    .into_iter()
+                    .map(|addr| SocketAddr::new(addr.ip(), *port))
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .map(|addr| (addr, tcp_dialer(proxy_addr, addr)))
+                    .collect(),
+                _ => vec![],
+            };
+            ConditionalDialer {
+                condition: condition.clone(),
+                client_country,
+                probes,
+                then_: route_to_dialer(proxy_addr, client_country, then_),
+                else_: route_to_dialer(proxy_addr, client_country, else_),
+            }
+            .dynamic()
+        }
+        RouteDescriptor::LoadBalance { strategy, routes } => {
+            if routes.is_empty() {
+                FailingDialer.dynamic()
+            } else {
+                LoadBalanceDialer::new(
+                    *strategy,
+                    routes
+                        .iter()
+                        .map(|route| route_to_dialer(proxy_addr, client_country, route))
+                        .collect(),
+                )
+                .dynamic()
+            }
+        }
         RouteDescriptor::Other(_) => FailingDialer.dynamic(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use futures_util::{AsyncReadExt as _, AsyncWriteExt as _};
+    use sillad::Pipe as _;
+
+    use super::*;
+
+    #[test]
+    fn penalties_halve_per_half_life() {
+        let addr = SocketAddr::from(([192, 0, 2, 1], 1));
+        let mut shitlist = HashMap::new();
+        shitlist.insert(
+            addr,
+            RoutePenalty {
+                addr,
+                count: 8,
+                decayed_at: 1000,
+            },
+        );
+        // less than a half-life does nothing
+        assert!(!decay_all(&mut shitlist, 1059, 60));
+        assert_eq!(shitlist[&addr].count, 8);
+        // two half-lives quarter it, and the leftover time counts towards the next one
+        assert!(decay_all(&mut shitlist, 1130, 60));
+        assert_eq!(shitlist[&addr].count, 2);
+        assert_eq!(shitlist[&addr].decayed_at, 1120);
+        // a penalty restored long after it was saved decays away at once
+        assert!(decay_all(&mut shitlist, 1120 + 60 * 100, 60));
+        assert!(shitlist.is_empty());
+    }
+
+    fn exit(n: u8, load: f32) -> (VerifyingKey, ExitDescriptor) {
+        (
+            ed25519_dalek::SigningKey::from_bytes(&[n; 32]).verifying_key(),
+            ExitDescriptor {
+                c2e_listen: SocketAddr::from(([192, 0, 2, n], 1)),
+                b2e_listen: SocketAddr::from(([192, 0, 2, n], 2)),
+                country: CountryCode::CAN,
+                city: "Toronto".into(),
+                load,
+                expiry: 0,
+                probe_magic: false,
+                pmtud_echo: false,
+            },
+        )
+    }
+
+    fn loads(exits: &[(VerifyingKey, ExitDescriptor)]) -> Vec<f32> {
+        exits.iter().map(|(_, exit)| exit.load).collect()
+    }
+
+    #[test]
+    fn latency_probes_least_loaded_unpenalized_exits() {
+        let exits = vec![exit(1, 0.9), exit(2, 0.1), exit(3, 0.5), exit(4, 0.3)];
+        assert_eq!(
+            loads(&latency_candidates(exits.clone(), 3)),
+            vec![0.1, 0.3, 0.5]
+        );
+        // at least one exit is always probed
+        assert_eq!(loads(&latency_candidates(exits.clone(), 0)), vec![0.1]);
+
+        deprioritize_route(exits[1].1.c2e_listen);
+        assert_eq!(
+            loads(&latency_candidates(exits.clone(), 3)),
+            vec![0.3, 0.5, 0.9]
+        );
+        // penalized exits are still better than nothing
+        let only_penalized = vec![exits[1].clone()];
+        assert_eq!(loads(&latency_candidates(only_penalized, 3)), vec![0.1]);
+    }
+
+    #[test]
+    fn pin_is_dropped_when_its_route_fails() {
+        let exits = vec![exit(21, 0.2), exit(22, 0.9), exit(23, 0.2)];
+        let pin = |idx: usize, via| ExitPin {
+            pubkey: exits[idx].0,
+            via,
+        };
+        let bridge = SocketAddr::from(([198, 51, 100, 21], 1));
+        assert!(check_pin(pin(0, Some(bridge)), &ExitConstraint::Auto, &exits, 0.8).is_ok());
+        assert_eq!(
+            check_pin(pin(1, None), &ExitConstraint::Auto, &exits, 0.8).unwrap_err(),
+            "too loaded"
+        );
+        assert_eq!(
+            check_pin(pin(0, None), &ExitConstraint::Auto, &exits[1..], 0.8).unwrap_err(),
+            "no longer listed"
+        );
+        assert_eq!(
+            check_pin(
+                pin(0, None),
+                &ExitConstraint::Country(CountryCode::USA),
+                &exits,
+                0.8
+            )
+            .unwrap_err(),
+            "does not fit the exit constraint"
+        );
+
+        // through a bridge, the exit's own address never fails, but the bridge does
+        deprioritize_route(bridge);
+        assert_eq!(
+            check_pin(pin(0, Some(bridge)), &ExitConstraint::Auto, &exits, 0.8).unwrap_err(),
+            "recently unreachable"
+        );
+        assert!(check_pin(pin(0, None), &ExitConstraint::Auto, &exits, 0.8).is_ok());
+        deprioritize_route(exits[2].1.c2e_listen);
+        assert_eq!(
+            check_pin(pin(2, None), &ExitConstraint::Auto, &exits, 0.8).unwrap_err(),
+            "recently unreachable"
+        );
+    }
+
+    #[test]
+    fn unsatisfiable_country_fails_only_when_strict() {
+        // every exit is in Canada
+        let exits = vec![exit(31, 0.7), exit(32, 0.1), exit(33, 0.4)];
+        let usa = ExitConstraint::Country(CountryCode::USA);
+        let err = rank_exits(&usa, &exits, true, 0.0, 2).unwrap_err();
+        assert!(matches!(
+            err.constraint,
+            ExitConstraint::Country(CountryCode::USA)
+        ));
+        // without strictness, the least loaded exits anywhere are used
+        assert_eq!(
+            loads(&rank_exits(&usa, &exits, false, 0.0, 2).unwrap()),
+            vec![0.1, 0.4]
+        );
+        // a constraint that fits is unaffected either way
+        let canada = ExitConstraint::Country(CountryCode::CAN);
+        for strict in [true, false] {
+            assert_eq!(
+                loads(&rank_exits(&canada, &exits, strict, 0.0, 3).unwrap()),
+                vec![0.1, 0.4, 0.7]
+            );
+        }
+    }
+
+    #[test]
+    fn prefer_ipv6_ranks_loopback_v6_exits_first() {
+        let v6_exit = |n: u8, load: f32| {
+            let (key, mut exit) = exit(n, load);
+            exit.c2e_listen = SocketAddr::from((Ipv6Addr::LOCALHOST, 40000 + n as u16));
+            (key, exit)
+        };
+        let exits = vec![
+            exit(41, 0.1),
+            v6_exit(42, 0.8),
+            exit(43, 0.2),
+            v6_exit(44, 0.5),
+        ];
+        assert_eq!(
+            loads(&rank_exits(&ExitConstraint::PreferIpv6, &exits, true, 0.0, 4).unwrap()),
+            vec![0.5, 0.8, 0.1, 0.2]
+        );
+        // other constraints do not care about the address family
+        assert_eq!(
+            loads(&rank_exits(&ExitConstraint::Auto, &exits, true, 0.0, 4).unwrap()),
+            vec![0.1, 0.2, 0.5, 0.8]
+        );
+    }
+
+    #[test]
+    fn direct_route_dials_ipv6_loopback() {
+        smolscale::block_on(async {
+            let listener = smol::net::TcpListener::bind((Ipv6Addr::LOCALHOST, 0))
+                .await
+                .unwrap();
+            let dest_addr = listener.local_addr().unwrap();
+            let (dialed, accepted) =
+                futures_util::join!(tcp_dialer(None, dest_addr).dial(), listener.accept());
+            let mut dialed = dialed.unwrap();
+            let (mut accepted, peer) = accepted.unwrap();
+            assert_eq!(peer.ip(), IpAddr::from(Ipv6Addr::LOCALHOST));
+            assert_eq!(dialed.remote_addr(), Some(dest_addr.to_string().as_str()));
+            dialed.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            accepted.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        });
+    }
+
+    /// How many times each exit comes out on top of the load preference, out of the given number of draws.
+    fn preference_wins(loads: &[f32], temperature: f64, draws: usize) -> Vec<usize> {
+        let exits: Vec<_> = loads
+            .iter()
+            .enumerate()
+            .map(|(n, load)| exit(50 + n as u8, *load))
+            .collect();
+        let mut rng = StdRng::seed_from_u64(8964);
+        let mut wins = vec![0; exits.len()];
+        for _ in 0..draws {
+            let preference = load_preference(&exits, temperature, &mut rng);
+            let best = (0..exits.len())
+                .max_by(|a, b| preference[*a].total_cmp(&preference[*b]))
+                .unwrap();
+            wins[best] += 1;
+        }
+        wins
+    }
+
+    #[test]
+    fn load_preference_favors_less_loaded_exits() {
+        // at zero temperature, the least loaded exit always wins
+        assert_eq!(preference_wins(&[0.5, 0.1, 0.9], 0.0, 100), vec![0, 100, 0]);
+        // otherwise it wins most of the time, but not every time
+        let wins = preference_wins(&[0.9, 0.1], 0.2, 1000);
+        assert!(wins[1] > 900 && wins[0] > 0, "{wins:?}");
+        // a higher temperature spreads clients out more
+        let hot = preference_wins(&[0.9, 0.1], 2.0, 1000);
+        assert!(hot[0] > wins[0], "{hot:?} vs {wins:?}");
+    }
+
+    #[test]
+    fn load_preference_edge_cases() {
+        // equally loaded exits are picked uniformly, whether idle or full
+        for load in [0.0, 1.0] {
+            let wins = preference_wins(&[load; 3], 0.1, 3000);
+            assert!(wins.iter().all(|w| (800..1200).contains(w)), "{wins:?}");
+        }
+        // a lone exit is always picked
+        assert_eq!(preference_wins(&[1.0], 0.1, 10), vec![10]);
+        assert!(load_preference(&[], 0.1, &mut rand::thread_rng()).is_empty());
+    }
+
+    #[test]
+    fn latency_ranks_fastest_first() {
+        let candidates = vec![exit(11, 0.1), exit(12, 0.2), exit(13, 0.3), exit(14, 0.4)];
+        let rtts = vec![
+            None,
+            Some(Duration::from_millis(80)),
+            None,
+            Some(Duration::from_millis(20)),
+        ];
+        // exits that did not answer go last, in order of load
+        assert_eq!(
+            loads(&rank_by_latency(candidates.clone(), rtts.clone(), 4)),
+            vec![0.4, 0.2, 0.1, 0.3]
+        );
+        assert_eq!(loads(&rank_by_latency(candidates, rtts, 1)), vec![0.4]);
+    }
+}
@@ -0,0 +1,120 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyctx::AnyCtx;
+use chrono::Datelike;
+use geph5_broker_protocol::{RouteCondition, RouteDescriptor, Weekday};
+use geph5_rt::TimeoutExt;
+use isocountry::CountryCode;
+use moka::future::Cache;
+use once_cell::sync::Lazy;
+use sillad::dialer::{Dialer, DynDialer};
+
+use crate::client::Config;
+
+/// How long a probe of a possibly blocked port may take before we count the port as blocked.
+const PORT_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Results of recent port probes, so that we don't probe on every single dial.
+static PORT_BLOCKED: Lazy<Cache<SocketAddr, bool>> = Lazy::new(|| {
+    Cache::builder()
+        .time_to_live(Duration::from_secs(300))
+        .build()
+});
+
+/// A dialer that picks between two dialers based on a [RouteCondition], evaluated afresh every time it dials.
+pub struct ConditionalDialer {
+    pub condition: RouteCondition,
+    pub client_country: Option<CountryCode>,
+    /// Addresses whose port gets probed for [RouteCondition::IfPortBlocked], together with the dialer used to probe them.
+    pub probes: Vec<(SocketAddr, DynDialer)>,
+    pub then_: DynDialer,
+    pub else_: DynDialer,
+}
+
+impl Dialer for ConditionalDialer {
+    type P = Box<dyn sillad::Pipe>;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let holds = self.holds().await;
+        tracing::debug!(
+            condition = debug(&self.condition),
+            holds,
+            "evaluated route condition"
+        );
+        if holds {
+            self.then_.dial().await
+        } else {
+            self.else_.dial().await
+        }
+    }
+}
+
+impl ConditionalDialer {
+    async fn holds(&self) -> bool {
+        match &self.condition {
+            RouteCondition::IfPortBlocked(_) => {
+                // the port is blocked only if we cannot reach it anywhere
+                for (addr, probe) in self.probes.iter() {
+                    let blocked = PORT_BLOCKED
+                        .get_with(*addr, async {
+                            !matches!(probe.dial().timeout(PORT_PROBE_TIMEOUT).await, Some(Ok(_)))
+                        })
+                        .await;
+                    if !blocked {
+                        return false;
+                    }
+                }
+                !self.probes.is_empty()
+            }
+            RouteCondition::IfCountry(country) => self.client_country == Some(*country),
+            RouteCondition::IfDayOfWeek { days } => {
+                let today = match chrono::Local::now().weekday() {
+                    chrono::Weekday::Mon => Weekday::Monday,
+                    chrono::Weekday::Tue => Weekday::Tuesday,
+                    chrono::Weekday::Wed => Weekday::Wednesday,
+                    chrono::Weekday::Thu => Weekday::Thursday,
+                    chrono::Weekday::Fri => Weekday::Friday,
+                    chrono::Weekday::Sat => Weekday::Saturday,
+                    chrono::Weekday::Sun => Weekday::Sunday,
+                };
+                days.contains(&today)
+            }
+        }
+    }
+}
+
+/// Figures out which country the client is in: explicitly configured, or else guessed from the region of the system locale.
+pub fn client_country(ctx: &AnyCtx<Config>) -> Option<CountryCode> {
+    if let Some(country) = ctx.init().client_country {
+        return Some(country);
+    }
+    // locales look like zh_CN.UTF-8 or en_US
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .into_iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|locale| !locale.is_empty())
+        .and_then(|locale| {
+            let region = locale.split(['.', '@']).next()?.split_once('_')?.1;
+            CountryCode::for_alpha2_caseless(region).ok()
+        })
+}
+
+/// Collects the TCP addresses that a route would eventually connect to.
+pub fn route_addrs(route: &RouteDescriptor) -> Vec<SocketAddr> {
+    match route {
+        RouteDescriptor::Tcp(addr) => vec![*addr],
+        RouteDescriptor::Sosistab3 { lower, .. }
+        | RouteDescriptor::Timeout { lower, .. }
+        | RouteDescriptor::Delay { lower, .. } => route_addrs(lower),
+        RouteDescriptor::Race(inside)
+        | RouteDescriptor::Fallback(inside)
+        | RouteDescriptor::LoadBalance { routes: inside, .. } => {
+            inside.iter().flat_map(route_addrs).collect()
+        }
+        RouteDescriptor::ConditionalRoute { then_, else_, .. } => route_addrs(then_)
+            .into_iter()
+            .chain(route_addrs(else_))
+            .collect(),
+        RouteDescriptor::Quic { .. } | RouteDescriptor::Other(_) => vec![],
+    }
+}
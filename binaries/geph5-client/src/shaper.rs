@@ -0,0 +1,213 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyctx::AnyCtx;
+use event_listener::{Event, EventListener};
+use futures_util::{AsyncWrite, StreamExt};
+use pin_project::pin_project;
+
+use crate::client::{Config, CtxField};
+
+/// How often the upload shaper's bucket is refilled.
+const REFILL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long a burst at full speed the bucket holds, after the upload has been idle.
+const BURST: Duration = Duration::from_millis(100);
+
+/// The upload shaper shared by all sessions to the exits, if `upload_limit_kbps` is set.
+static UPLOAD_SHAPER: CtxField<Option<Arc<UploadShaper>>> = |ctx| {
+    let limit_kbps = ctx.init().upload_limit_kbps?;
+    let shaper = Arc::new(UploadShaper::new(limit_kbps.saturating_mul(1000) / 8));
+    geph5_rt::spawn(refill_loop(Arc::downgrade(&shaper))).detach();
+    Some(shaper)
+};
+
+/// Shapes the write side of a connection to the exit with the upload shaper, if there is one.
+pub fn shape_upload<W: AsyncWrite>(ctx: &AnyCtx<Config>, inner: W) -> ShapedWrite<W> {
+    ShapedWrite {
+        inner,
+        shaper: ctx.get(UPLOAD_SHAPER).clone(),
+        listener: None,
+    }
+}
+
+/// A token bucket of bytes that may be uploaded. Writers take tokens with compare-and-swap, a timer task adds them back, and writers finding the bucket empty wait for the next refill instead of spinning.
+struct UploadShaper {
+    tokens: AtomicU64,
+    /// Bytes added per [REFILL_INTERVAL].
+    refill: u64,
+    capacity: u64,
+    refilled: Event,
+}
+
+impl UploadShaper {
+    fn new(bytes_per_sec: u64) -> Self {
+        let refill = (bytes_per_sec as f64 * REFILL_INTERVAL.as_secs_f64()).ceil() as u64;
+        let capacity = ((bytes_per_sec as f64 * BURST.as_secs_f64()) as u64).max(refill);
+        Self {
+            tokens: AtomicU64::new(capacity),
+            refill,
+            capacity,
+            refilled: Event::new(),
+        }
+    }
+
+    /// Takes up to `want` tokens, returning how many it got.
+    fn take(&self, want: u64) -> u64 {
+        let mut granted = 0;
+        let _ = self
+            .tokens
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |tokens| {
+                granted = tokens.min(want);
+                (granted > 0).then_some(tokens - granted)
+            });
+        granted
+    }
+
+    /// Gives back tokens that were taken but not used.
+    fn put_back(&self, unused: u64) {
+        if unused > 0 {
+            self.tokens.fetch_add(unused, Ordering::AcqRel);
+            self.refilled.notify(usize::MAX);
+        }
+    }
+}
+
+async fn refill_loop(shaper: Weak<UploadShaper>) {
+    let mut timer = geph5_rt::Timer::interval(REFILL_INTERVAL);
+    loop {
+        timer.next().await;
+        // the shaper goes away together with the client
+        let Some(shaper) = shaper.upgrade() else {
+            return;
+        };
+        let _ = shaper
+            .tokens
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |tokens| {
+                Some((tokens + shaper.refill).min(shaper.capacity))
+            });
+        shaper.refilled.notify(usize::MAX);
+    }
+}
+
+/// The write side of a connection, shaped by the upload shaper. Writes go through partially when there are only some tokens, and wait for a refill when there are none.
+#[pin_project]
+pub struct ShapedWrite<W> {
+    #[pin]
+    inner: W,
+    shaper: Option<Arc<UploadShaper>>,
+    listener: Option<EventListener>,
+}
+
+impl<W: AsyncWrite> AsyncWrite for ShapedWrite<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        let Some(shaper) = this.shaper.as_ref() else {
+            return this.inner.poll_write(cx, buf);
+        };
+        if buf.is_empty() {
+            return this.inner.poll_write(cx, buf);
+        }
+        loop {
+            let granted = shaper.take(buf.len() as u64);
+            if granted > 0 {
+                *this.listener = None;
+                let res = this.inner.poll_write(cx, &buf[..granted as usize]);
+                let written = match &res {
+                    Poll::Ready(Ok(n)) => *n as u64,
+                    _ => 0,
+                };
+                shaper.put_back(granted - written);
+                return res;
+            }
+            // we listen before checking again, so that a refill in between cannot be missed
+            match this.listener.as_mut() {
+                None => *this.listener = Some(shaper.refilled.listen()),
+                Some(listener) => {
+                    if Pin::new(listener).poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    *this.listener = None;
+                }
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt as _;
+    use smol_timeout2::TimeoutExt as _;
+
+    use super::*;
+
+    /// 100 kB/s, which makes for 1000 bytes per refill and a burst of 10000.
+    const BYTES_PER_SEC: u64 = 100_000;
+
+    #[test]
+    fn take_grants_what_is_left() {
+        let shaper = UploadShaper::new(BYTES_PER_SEC);
+        assert_eq!(shaper.refill, 1000);
+        assert_eq!(shaper.capacity, 10_000);
+        assert_eq!(shaper.take(4000), 4000);
+        assert_eq!(shaper.take(10_000), 6000);
+        assert_eq!(shaper.take(1), 0);
+    }
+
+    #[test]
+    fn put_back_returns_unused_tokens() {
+        let shaper = UploadShaper::new(BYTES_PER_SEC);
+        let granted = shaper.take(u64::MAX);
+        let listener = shaper.refilled.listen();
+        shaper.put_back(0);
+        assert!(listener.now_or_never().is_none());
+
+        let listener = shaper.refilled.listen();
+        shaper.put_back(granted - 2500);
+        // writers waiting on the empty bucket get to try again
+        assert!(listener.now_or_never().is_some());
+        assert_eq!(shaper.take(u64::MAX), 7500);
+    }
+
+    #[test]
+    fn refill_loop_tops_up_to_capacity_and_stops_with_the_shaper() {
+        smolscale::block_on(async {
+            let shaper = Arc::new(UploadShaper::new(BYTES_PER_SEC));
+            assert_eq!(shaper.take(u64::MAX), 10_000);
+            let refills = smolscale::spawn(refill_loop(Arc::downgrade(&shaper)));
+
+            shaper.refilled.listen().await;
+            let refilled = shaper.take(u64::MAX);
+            assert!(refilled >= 1000 && refilled % 1000 == 0);
+
+            smol::Timer::after(BURST * 5).await;
+            assert_eq!(shaper.take(u64::MAX), 10_000);
+
+            drop(shaper);
+            refills
+                .timeout(REFILL_INTERVAL * 10)
+                .await
+                .expect("refill loop outlived the shaper");
+        });
+    }
+}
@@ -0,0 +1,243 @@
+use std::{collections::BTreeMap, time::Duration};
+use web_time::{SystemTime, UNIX_EPOCH};
+
+use anyctx::AnyCtx;
+use ed25519_dalek::VerifyingKey;
+use geph5_broker_protocol::ExitDescriptor;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::{Config, CtxField},
+    database::{db_read, db_write},
+};
+
+/// How far back we look when scoring exits.
+const WINDOW: Duration = Duration::from_secs(3600);
+
+/// The database key under which the per-exit history is persisted.
+const DB_KEY: &str = "smart_routing_history";
+
+/// What we remember about a single exit.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct ExitHistory {
+    country: String,
+    city: String,
+    /// Connection attempts, as (unix timestamp, seconds taken to connect, or None if it failed).
+    attempts: Vec<(u64, Option<f64>)>,
+    /// Sessions that have ended, as (start, end) unix timestamps.
+    sessions: Vec<(u64, u64)>,
+}
+
+impl ExitHistory {
+    fn prune(&mut self, cutoff: u64) {
+        self.attempts.retain(|(ts, _)| *ts >= cutoff);
+        self.sessions.retain(|(_, end)| *end >= cutoff);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.attempts.is_empty() && self.sessions.is_empty()
+    }
+}
+
+/// Performance statistics for one exit over the last hour, together with its current UCB1 score.
+#[derive(Clone, Debug)]
+pub struct ExitStats {
+    pub exit_key: String,
+    pub country: String,
+    pub city: String,
+    pub attempts: usize,
+    pub success_rate: f64,
+    pub avg_latency: Option<Duration>,
+    /// Fraction of the observed part of the last hour during which some session to this exit was up.
+    pub uptime: Option<f64>,
+    pub score: f64,
+}
+
+static HISTORY: CtxField<async_lock::Mutex<Option<BTreeMap<String, ExitHistory>>>> =
+    |_| async_lock::Mutex::new(None);
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Runs a closure on the pruned history, loading it from disk first if needed, and persisting it afterwards.
+async fn with_history<R>(
+    ctx: &AnyCtx<Config>,
+    f: impl FnOnce(&mut BTreeMap<String, ExitHistory>) -> R,
+) -> anyhow::Result<R> {
+    let mut history = ctx.get(HISTORY).lock().await;
+    if history.is_none() {
+        let loaded = match db_read(ctx, DB_KEY).await? {
+            Some(bts) => serde_json::from_slice(&bts).unwrap_or_else(|err| {
+                tracing::warn!(err = debug(err), "discarding corrupt exit history");
+                BTreeMap::new()
+            }),
+            None => BTreeMap::new(),
+        };
+        *history = Some(loaded);
+    }
+    let history = history.as_mut().unwrap();
+    let cutoff = unix_now().saturating_sub(WINDOW.as_secs());
+    history.values_mut().for_each(|h| h.prune(cutoff));
+    history.retain(|_, h| !h.is_empty());
+    let result = f(history);
+    db_write(ctx, DB_KEY, &serde_json::to_vec(history)?).await?;
+    Ok(result)
+}
+
+/// Records an attempt to connect to an exit, with the time it took if it succeeded.
+pub async fn record_attempt(
+    ctx: &AnyCtx<Config>,
+    pubkey: VerifyingKey,
+    exit: &ExitDescriptor,
+    latency: Option<Duration>,
+) -> anyhow::Result<()> {
+    with_history(ctx, |history| {
+        let entry = history.entry(hex::encode(pubkey.as_bytes())).or_default();
+        entry.country = exit.country.alpha2().to_string();
+        entry.city = exit.city.clone();
+        entry
+            .attempts
+            .push((unix_now(), latency.map(|l| l.as_secs_f64())));
+    })
+    .await
+}
+
+/// Records a session to an exit that has just ended.
+pub async fn record_session(
+    ctx: &AnyCtx<Config>,
+    pubkey: VerifyingKey,
+    start: SystemTime,
+) -> anyhow::Result<()> {
+    let start = start.duration_since(UNIX_EPOCH)?.as_secs();
+    with_history(ctx, |history| {
+        history
+            .entry(hex::encode(pubkey.as_bytes()))
+            .or_default()
+            .sessions
+            .push((start, unix_now()));
+    })
+    .await
+}
+
+/// Computes the statistics of every exit we have recently used, best first.
+pub async fn exit_stats(ctx: &AnyCtx<Config>) -> anyhow::Result<Vec<ExitStats>> {
+    let mut stats = with_history(ctx, |history| {
+        let total_attempts = history.values().map(|h| h.attempts.len()).sum();
+        history
+            .iter()
+            .map(|(key, h)| compute_stats(key, h, total_attempts))
+            .collect::<Vec<_>>()
+    })
+    .await?;
+    stats.sort_unstable_by(|a, b| b.score.total_cmp(&a.score));
+    Ok(stats)
+}
+
+/// Loads the persisted exit statistics for the given config, without starting a client.
+pub async fn load_exit_stats(cfg: Config) -> anyhow::Result<Vec<ExitStats>> {
+    exit_stats(&AnyCtx::new(cfg)).await
+}
+
+/// Picks the exit with the highest UCB1 score among the candidates. Exits we have no recent history for are tried first.
+pub async fn choose_exit<'a>(
+    ctx: &AnyCtx<Config>,
+    candidates: &'a [(VerifyingKey, ExitDescriptor)],
+) -> anyhow::Result<Option<&'a (VerifyingKey, ExitDescriptor)>> {
+    let scores: BTreeMap<String, f64> = exit_stats(ctx)
+        .await?
+        .into_iter()
+        .map(|stats| (stats.exit_key, stats.score))
+        .collect();
+    Ok(candidates.iter().max_by(|a, b| {
+        let score = |(key, _): &(VerifyingKey, ExitDescriptor)| {
+            scores
+                .get(&hex::encode(key.as_bytes()))
+                .copied()
+                .unwrap_or(f64::INFINITY)
+        };
+        // among equally good exits, prefer the less loaded one
+        score(a)
+            .total_cmp(&score(b))
+            .then(b.1.load.total_cmp(&a.1.load))
+    }))
+}
+
+fn compute_stats(key: &str, history: &ExitHistory, total_attempts: usize) -> ExitStats {
+    let now = unix_now();
+    let attempts = history.attempts.len();
+    let successes: Vec<f64> = history.attempts.iter().filter_map(|a| a.1).collect();
+    let success_rate = if attempts > 0 {
+        successes.len() as f64 / attempts as f64
+    } else {
+        0.0
+    };
+    let avg_latency = if successes.is_empty() {
+        None
+    } else {
+        Some(Duration::from_secs_f64(
+            successes.iter().sum::<f64>() / successes.len() as f64,
+        ))
+    };
+    let uptime = uptime(history, now);
+
+    // each attempt is rewarded between 0 (failure) and 1 (instant connection), discounted by how long sessions stay up
+    let score = if attempts == 0 {
+        f64::INFINITY
+    } else {
+        let mean_reward = history
+            .attempts
+            .iter()
+            .map(|(_, latency)| latency.map(|l| 1.0 / (1.0 + l)).unwrap_or(0.0))
+            .sum::<f64>()
+            / attempts as f64
+            * uptime.unwrap_or(1.0);
+        mean_reward + (2.0 * (total_attempts as f64).ln() / attempts as f64).sqrt()
+    };
+
+    ExitStats {
+        exit_key: key.to_string(),
+        country: history.country.clone(),
+        city: history.city.clone(),
+        attempts,
+        success_rate,
+        avg_latency,
+        uptime,
+        score,
+    }
+}
+
+fn uptime(history: &ExitHistory, now: u64) -> Option<f64> {
+    let window_start = now.saturating_sub(WINDOW.as_secs());
+    let mut intervals: Vec<(u64, u64)> = history
+        .sessions
+        .iter()
+        .map(|(start, end)| ((*start).max(window_start), (*end).min(now)))
+        .filter(|(start, end)| start < end)
+        .collect();
+    let first_seen = history
+        .attempts
+        .iter()
+        .map(|a| a.0)
+        .chain(intervals.iter().map(|i| i.0))
+        .min()?;
+    if intervals.is_empty() || first_seen >= now {
+        return None;
+    }
+    intervals.sort_unstable();
+    let mut covered = 0;
+    let mut current = intervals[0];
+    for &(start, end) in &intervals[1..] {
+        if start <= current.1 {
+            current.1 = current.1.max(end);
+        } else {
+            covered += current.1 - current.0;
+            current = (start, end);
+        }
+    }
+    covered += current.1 - current.0;
+    Some((covered as f64 / (now - first_seen) as f64).min(1.0))
+}
@@ -1,40 +1,104 @@
-use crate::client_inner::open_conn;
+use crate::{
+    bloat::proxy_buffer_size,
+    client_inner::open_conn,
+    multi_user::{auth_required, authenticate, listen_addr, source_limiter, SourceLimiter},
+};
 
 use anyctx::AnyCtx;
+use anyhow::Context as _;
 
-use futures_util::AsyncReadExt as _;
+use futures_util::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use geph5_broker_protocol::AccountLevel;
 use nursery_macro::nursery;
-use sillad::listener::Listener as _;
-use smol::future::FutureExt as _;
+use parking_lot::Mutex;
+use sillad::{listener::Listener as _, Pipe as _};
+use smol::{
+    channel::{Receiver, Sender},
+    future::FutureExt as _,
+    net::UdpSocket,
+};
 use socksv5::v5::{
     read_handshake, read_request, write_auth_method, write_request_status, SocksV5AuthMethod,
-    SocksV5Host, SocksV5RequestStatus,
+    SocksV5Command, SocksV5Host, SocksV5RequestStatus,
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
 };
-use std::net::Ipv4Addr;
 
 use super::Config;
 
+/// How long a UDP flow may go without a datagram in either direction before its tunneled stream is closed. A later datagram to the same destination opens a new one.
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
 #[tracing::instrument(skip_all)]
 pub async fn socks5_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
-    if let Some(listen_addr) = ctx.init().socks5_listen {
-        let mut listener = sillad::tcp::TcpListener::bind(listen_addr).await?;
+    if let Some(listen) = ctx.init().socks5_listen {
+        let mut listener = sillad::tcp::TcpListener::bind(listen_addr(ctx, listen)).await?;
         nursery!({
             loop {
                 let client = listener.accept().await?;
                 spawn!(async {
                     tracing::trace!("socks5 connection accepted");
+                    let source_ip = client
+                        .remote_addr()
+                        .and_then(|addr| addr.parse::<SocketAddr>().ok())
+                        .map(|addr| addr.ip())
+                        .unwrap_or(Ipv4Addr::UNSPECIFIED.into());
                     let (mut read_client, mut write_client) = client.split();
-                    let _handshake = read_handshake(&mut read_client).await?;
-                    write_auth_method(&mut write_client, SocksV5AuthMethod::Noauth).await?;
+                    let handshake = read_handshake(&mut read_client).await?;
+                    let level = if auth_required(ctx) {
+                        if !handshake
+                            .methods
+                            .contains(&SocksV5AuthMethod::UsernamePassword)
+                        {
+                            write_auth_method(
+                                &mut write_client,
+                                SocksV5AuthMethod::NoAcceptableMethod,
+                            )
+                            .await?;
+                            anyhow::bail!("socks5 client does not support password auth");
+                        }
+                        write_auth_method(&mut write_client, SocksV5AuthMethod::UsernamePassword)
+                            .await?;
+                        Some(password_auth(ctx, &mut read_client, &mut write_client).await?)
+                    } else {
+                        write_auth_method(&mut write_client, SocksV5AuthMethod::Noauth).await?;
+                        None
+                    };
+                    let limiter = source_limiter(ctx, source_ip, level).await;
                     let request = read_request(&mut read_client).await?;
+                    match request.command {
+                        SocksV5Command::Connect => {}
+                        SocksV5Command::UdpAssociate => {
+                            let relay_ip = listen_addr(ctx, listen).ip();
+                            return udp_associate(
+                                ctx,
+                                relay_ip,
+                                source_ip,
+                                limiter,
+                                read_client,
+                                write_client,
+                            )
+                            .await;
+                        }
+                        SocksV5Command::Bind => {
+                            write_request_status(
+                                &mut write_client,
+                                SocksV5RequestStatus::CommandNotSupported,
+                                request.host,
+                                request.port,
+                            )
+                            .await?;
+                            anyhow::bail!("socks5 BIND is not supported");
+                        }
+                    }
                     let port = request.port;
                     let domain: String = match &request.host {
                         SocksV5Host::Domain(dom) => String::from_utf8_lossy(dom).parse()?,
-                        SocksV5Host::Ipv4(v4) => {
-                            let v4addr = Ipv4Addr::new(v4[0], v4[1], v4[2], v4[3]);
-                            v4addr.to_string()
-                        }
-                        _ => anyhow::bail!("IPv6 not supported"),
+                        SocksV5Host::Ipv4(v4) => Ipv4Addr::from(*v4).to_string(),
+                        SocksV5Host::Ipv6(v6) => format!("[{}]", Ipv6Addr::from(*v6)),
                     };
                     let remote_addr = format!("{domain}:{port}");
                     tracing::trace!(
@@ -51,8 +115,10 @@ pub async fn socks5_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
                     .await?;
                     tracing::trace!(remote_addr = display(&remote_addr), "connection opened");
                     let (read_stream, write_stream) = stream.split();
-                    smol::io::copy(read_stream, write_client)
-                        .race(smol::io::copy(read_client, write_stream))
+                    let buf_size = proxy_buffer_size(ctx);
+                    limiter
+                        .io_copy(buf_size, read_stream, write_client)
+                        .race(limiter.io_copy(buf_size, read_client, write_stream))
                         .await?;
                     anyhow::Ok(())
                 })
@@ -63,3 +129,239 @@ pub async fn socks5_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
         smol::future::pending().await
     }
 }
+
+/// Serves a UDP ASSOCIATE request. Datagrams the client sends to the relay socket are tunneled to their destinations, one tunneled stream per destination, and the replies come back through the relay socket with the same header. The association lasts as long as the TCP connection that asked for it.
+async fn udp_associate(
+    ctx: &AnyCtx<Config>,
+    relay_ip: IpAddr,
+    source_ip: IpAddr,
+    limiter: SourceLimiter,
+    mut read_client: impl AsyncRead + Unpin,
+    mut write_client: impl AsyncWrite + Unpin,
+) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind((relay_ip, 0)).await?;
+    let relay_addr = socket.local_addr()?;
+    let relay_host = match relay_addr.ip() {
+        IpAddr::V4(v4) => SocksV5Host::Ipv4(v4.octets()),
+        IpAddr::V6(v6) => SocksV5Host::Ipv6(v6.octets()),
+    };
+    write_request_status(
+        &mut write_client,
+        SocksV5RequestStatus::Success,
+        relay_host,
+        relay_addr.port(),
+    )
+    .await?;
+    tracing::trace!(relay_addr = display(relay_addr), "socks5 UDP association");
+
+    let relay = async {
+        let mut flows: HashMap<String, Sender<Vec<u8>>> = HashMap::new();
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let (n, from) = socket.recv_from(&mut buf).await?;
+            // only the client that asked for the association may use it
+            if !source_ip.is_unspecified() && from.ip() != source_ip {
+                continue;
+            }
+            let (dest, header_len) = match parse_udp_header(&buf[..n]) {
+                Ok(parsed) => parsed,
+                Err(err) => {
+                    tracing::debug!(err = debug(err), "dropping bad socks5 UDP datagram");
+                    continue;
+                }
+            };
+            limiter.wait(n).await;
+            // flows whose tunnels died or went idle are opened afresh
+            flows.retain(|_, send| !send.is_closed());
+            let send = flows.entry(dest.clone()).or_insert_with(|| {
+                let (send, recv) = smol::channel::bounded(100);
+                let flow = udp_flow(
+                    ctx.clone(),
+                    socket.clone(),
+                    from,
+                    dest,
+                    buf[..header_len].to_vec(),
+                    limiter.clone(),
+                    recv,
+                );
+                smolscale::spawn(async move {
+                    if let Err(err) = flow.await {
+                        tracing::trace!(err = debug(err), "socks5 UDP flow stopped");
+                    }
+                })
+                .detach();
+                send
+            });
+            // like any UDP, packets get dropped when the tunnel cannot keep up
+            let _ = send.try_send(buf[header_len..n].to_vec());
+        }
+    };
+    let control = async {
+        let mut buf = [0u8; 1];
+        while read_client.read(&mut buf).await? > 0 {}
+        anyhow::Ok(())
+    };
+    relay.race(control).await
+}
+
+/// Tunnels the datagrams to one destination of a UDP association, sending the replies back to the client with the given header. The flow ends once it has been idle for [UDP_IDLE_TIMEOUT], so that an association does not keep a stream open to every destination it ever talked to.
+async fn udp_flow(
+    ctx: AnyCtx<Config>,
+    socket: UdpSocket,
+    client_addr: SocketAddr,
+    dest: String,
+    header: Vec<u8>,
+    limiter: SourceLimiter,
+    packets: Receiver<Vec<u8>>,
+) -> anyhow::Result<()> {
+    let tunneled = open_conn(&ctx, "udp", &dest).await?;
+    let (mut read_tunneled, mut write_tunneled) = tunneled.split();
+    let last_active = Mutex::new(Instant::now());
+    let up_loop = async {
+        loop {
+            let packet = packets.recv().await?;
+            *last_active.lock() = Instant::now();
+            write_tunneled
+                .write_all(&(packet.len() as u16).to_le_bytes())
+                .await?;
+            write_tunneled.write_all(&packet).await?;
+            write_tunneled.flush().await?;
+        }
+    };
+    let dn_loop = async {
+        loop {
+            let mut len_buf = [0u8; 2];
+            read_tunneled.read_exact(&mut len_buf).await?;
+            let mut reply = header.clone();
+            let payload_start = reply.len();
+            reply.resize(payload_start + u16::from_le_bytes(len_buf) as usize, 0);
+            read_tunneled
+                .read_exact(&mut reply[payload_start..])
+                .await?;
+            *last_active.lock() = Instant::now();
+            limiter.wait(reply.len()).await;
+            socket.send_to(&reply, client_addr).await?;
+        }
+    };
+    let idle_loop = async {
+        loop {
+            let deadline = *last_active.lock() + UDP_IDLE_TIMEOUT;
+            if Instant::now() >= deadline {
+                anyhow::bail!("idle for {UDP_IDLE_TIMEOUT:?}");
+            }
+            smol::Timer::at(deadline).await;
+        }
+    };
+    up_loop.race(dn_loop).race(idle_loop).await
+}
+
+/// Parses the header of a socks5 UDP datagram (RFC 1928, section 7), returning the destination as `host:port` and the length of the header. Fragmented datagrams are not supported.
+fn parse_udp_header(datagram: &[u8]) -> anyhow::Result<(String, usize)> {
+    anyhow::ensure!(datagram.len() >= 4, "datagram too short");
+    anyhow::ensure!(datagram[2] == 0, "fragmented datagrams are not supported");
+    let (host, addr_end) = match datagram[3] {
+        1 => {
+            let v4: [u8; 4] = datagram
+                .get(4..8)
+                .context("truncated address")?
+                .try_into()?;
+            (Ipv4Addr::from(v4).to_string(), 8)
+        }
+        3 => {
+            let len = *datagram.get(4).context("truncated address")? as usize;
+            let domain = datagram.get(5..5 + len).context("truncated address")?;
+            (String::from_utf8(domain.to_vec())?, 5 + len)
+        }
+        4 => {
+            let v6: [u8; 16] = datagram
+                .get(4..20)
+                .context("truncated address")?
+                .try_into()?;
+            (format!("[{}]", Ipv6Addr::from(v6)), 20)
+        }
+        atyp => anyhow::bail!("unknown address type {atyp}"),
+    };
+    let port = datagram
+        .get(addr_end..addr_end + 2)
+        .context("truncated port")?;
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    Ok((format!("{host}:{port}"), addr_end + 2))
+}
+
+/// Runs the username/password subnegotiation of RFC 1929, returning the account level of the user.
+async fn password_auth(
+    ctx: &AnyCtx<Config>,
+    mut read_client: impl AsyncRead + Unpin,
+    mut write_client: impl AsyncWrite + Unpin,
+) -> anyhow::Result<AccountLevel> {
+    let mut version = [0u8; 1];
+    read_client.read_exact(&mut version).await?;
+    anyhow::ensure!(version[0] == 1, "unsupported auth version {}", version[0]);
+    let username = read_length_prefixed(&mut read_client).await?;
+    let password = read_length_prefixed(&mut read_client).await?;
+    match authenticate(ctx, &username, &password) {
+        Some(level) => {
+            write_client.write_all(&[1, 0]).await?;
+            Ok(level)
+        }
+        None => {
+            write_client.write_all(&[1, 1]).await?;
+            anyhow::bail!("socks5 auth failed for user {username}")
+        }
+    }
+}
+
+async fn read_length_prefixed(mut read_client: impl AsyncRead + Unpin) -> anyhow::Result<String> {
+    let mut len = [0u8; 1];
+    read_client.read_exact(&mut len).await?;
+    let mut buf = vec![0u8; len[0] as usize];
+    read_client.read_exact(&mut buf).await?;
+    Ok(String::from_utf8(buf)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_address_type() {
+        let v4 = [0, 0, 0, 1, 192, 0, 2, 1, 0x00, 0x35, 0xaa];
+        assert_eq!(
+            parse_udp_header(&v4).unwrap(),
+            ("192.0.2.1:53".to_string(), 10)
+        );
+
+        let mut domain = vec![0, 0, 0, 3, 11];
+        domain.extend_from_slice(b"example.com");
+        domain.extend_from_slice(&443u16.to_be_bytes());
+        domain.extend_from_slice(b"payload");
+        assert_eq!(
+            parse_udp_header(&domain).unwrap(),
+            ("example.com:443".to_string(), 18)
+        );
+
+        let mut v6 = vec![0, 0, 0, 4];
+        v6.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets());
+        v6.extend_from_slice(&8964u16.to_be_bytes());
+        assert_eq!(
+            parse_udp_header(&v6).unwrap(),
+            ("[2001:db8::1]:8964".to_string(), 22)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        // fragments
+        assert!(parse_udp_header(&[0, 0, 1, 1, 192, 0, 2, 1, 0, 53]).is_err());
+        // unknown address type
+        assert!(parse_udp_header(&[0, 0, 0, 2, 192, 0, 2, 1, 0, 53]).is_err());
+        // too short, or cut off in the address or the port
+        assert!(parse_udp_header(&[0, 0, 0]).is_err());
+        assert!(parse_udp_header(&[0, 0, 0, 1, 192, 0]).is_err());
+        assert!(parse_udp_header(&[0, 0, 0, 1, 192, 0, 2, 1, 0]).is_err());
+        assert!(parse_udp_header(&[0, 0, 0, 3, 11, b'e', b'x']).is_err());
+        assert!(parse_udp_header(&[0, 0, 0, 4, 0x20, 0x01]).is_err());
+        // domains must be UTF-8
+        assert!(parse_udp_header(&[0, 0, 0, 3, 1, 0xff, 0, 53]).is_err());
+    }
+}
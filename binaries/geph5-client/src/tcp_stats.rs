@@ -0,0 +1,132 @@
+use std::time::SystemTime;
+#[cfg(unix)]
+use std::{
+    os::fd::{AsRawFd, OwnedFd},
+    time::Duration,
+};
+
+#[cfg(unix)]
+use anyctx::AnyCtx;
+use parking_lot::Mutex;
+
+use crate::client::CtxField;
+#[cfg(unix)]
+use crate::Config;
+
+/// How often we sample the statistics of the TCP connection carrying the tunnel.
+#[cfg(unix)]
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A sample of the kernel's statistics about the TCP connection carrying the tunnel.
+#[derive(Clone, Copy, Debug)]
+pub struct TunnelStats {
+    /// Smoothed round-trip time, in microseconds.
+    pub rtt_us: u64,
+    /// Total segments retransmitted over the lifetime of the connection.
+    pub retransmits: u64,
+    /// The congestion window, in bytes, which bounds how much we can have in flight.
+    pub send_window: u64,
+    pub sampled_at: SystemTime,
+}
+
+/// The most recent sample taken from any tunnel connection.
+pub static TUNNEL_STATS: CtxField<Mutex<Option<TunnelStats>>> = |_| Mutex::new(None);
+
+/// Samples the TCP statistics of the given socket forever. The socket is a duplicate of the one under the tunnel, which the tunnel may close at any time, so that the descriptor cannot be reused for something else while we sample it.
+#[cfg(unix)]
+pub async fn tcp_stats_loop(ctx: &AnyCtx<Config>, fd: OwnedFd) {
+    loop {
+        match read_tcp_stats(fd.as_raw_fd()) {
+            Ok(stats) => *ctx.get(TUNNEL_STATS).lock() = Some(stats),
+            Err(err) => {
+                // not a TCP socket, or a platform without TCP_INFO
+                tracing::debug!(err = debug(err), "cannot sample TCP statistics, giving up");
+                return smol::future::pending().await;
+            }
+        }
+        smol::Timer::after(SAMPLE_INTERVAL).await;
+    }
+}
+
+/// The prefix of Linux's `struct tcp_info` that we need, which has been stable since the struct was introduced. The kernel copies out only as much as we ask for.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[repr(C)]
+#[derive(Default)]
+struct TcpInfo {
+    state: u8,
+    ca_state: u8,
+    retransmits: u8,
+    probes: u8,
+    backoff: u8,
+    options: u8,
+    wscale: u8,
+    flags: u8,
+    rto: u32,
+    ato: u32,
+    snd_mss: u32,
+    rcv_mss: u32,
+    unacked: u32,
+    sacked: u32,
+    lost: u32,
+    retrans: u32,
+    fackets: u32,
+    last_data_sent: u32,
+    last_ack_sent: u32,
+    last_data_recv: u32,
+    last_ack_recv: u32,
+    pmtu: u32,
+    rcv_ssthresh: u32,
+    rtt: u32,
+    rttvar: u32,
+    snd_ssthresh: u32,
+    snd_cwnd: u32,
+    advmss: u32,
+    reordering: u32,
+    rcv_rtt: u32,
+    rcv_space: u32,
+    total_retrans: u32,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn read_tcp_stats(fd: std::os::fd::RawFd) -> std::io::Result<TunnelStats> {
+    let mut info = TcpInfo::default();
+    getsockopt(fd, libc::TCP_INFO, &mut info)?;
+    Ok(TunnelStats {
+        rtt_us: info.rtt as u64,
+        retransmits: info.total_retrans as u64,
+        // Linux counts the congestion window in segments
+        send_window: info.snd_cwnd as u64 * info.snd_mss as u64,
+        sampled_at: SystemTime::now(),
+    })
+}
+
+#[cfg(target_os = "macos")]
+fn read_tcp_stats(fd: std::os::fd::RawFd) -> std::io::Result<TunnelStats> {
+    let mut info: libc::tcp_connection_info = unsafe { std::mem::zeroed() };
+    getsockopt(fd, libc::TCP_CONNECTION_INFO, &mut info)?;
+    Ok(TunnelStats {
+        // macOS reports milliseconds
+        rtt_us: info.tcpi_srtt as u64 * 1000,
+        // and only counts retransmitted bytes, so we estimate the segments
+        retransmits: info.tcpi_txretransmitbytes / (info.tcpi_maxseg.max(1) as u64),
+        send_window: info.tcpi_snd_cwnd as u64,
+        sampled_at: SystemTime::now(),
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+#[cfg(unix)]
+fn read_tcp_stats(_fd: std::os::fd::RawFd) -> std::io::Result<TunnelStats> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+fn getsockopt<T>(fd: std::os::fd::RawFd, opt: libc::c_int, out: &mut T) -> std::io::Result<()> {
+    let mut len = std::mem::size_of::<T>() as libc::socklen_t;
+    let ret =
+        unsafe { libc::getsockopt(fd, libc::IPPROTO_TCP, opt, (out as *mut T).cast(), &mut len) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
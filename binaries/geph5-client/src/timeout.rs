@@ -0,0 +1,14 @@
+/// Awaits a future with the timeout configured in `ctx.init().<key>_timeout_ms`, turning a timeout into an error such as "handshake timed out after 15s".
+///
+/// This is for the timeouts that users may need to tune, such as how long an exit handshake may take on a slow network. Fixed internal timeouts stay as named constants next to where they are used.
+macro_rules! geph5_timeout {
+    ($ctx:expr, $key:ident, $fut:expr) => {{
+        let timeout =
+            std::time::Duration::from_millis(paste::paste! { $ctx.init().[<$key _timeout_ms>] });
+        geph5_rt::TimeoutExt::timeout($fut, timeout)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("{} timed out after {:?}", stringify!($key), timeout))
+    }};
+}
+
+pub(crate) use geph5_timeout;
@@ -0,0 +1,136 @@
+//! A system tray icon that shows the connection state, for desktop Linux users.
+use std::{
+    sync::{mpsc, Arc},
+    time::Duration,
+};
+
+use gtk::{glib, prelude::*};
+use tray_icon::{
+    menu::{Menu, MenuEvent, MenuItem},
+    Icon, TrayIcon, TrayIconBuilder,
+};
+
+use crate::{ConnInfo, ControlClient};
+
+/// Runs the tray icon, blocking the current thread on the GTK main loop.
+pub fn run_tray(control: ControlClient) -> anyhow::Result<()> {
+    gtk::init()?;
+
+    let menu = Menu::new();
+    let show_status = MenuItem::new("Show Status", true, None);
+    let disconnect = MenuItem::new("Disconnect", true, None);
+    menu.append_items(&[&show_status, &disconnect])?;
+
+    let tray = TrayIconBuilder::new()
+        .with_menu(Box::new(menu))
+        .with_icon(indicator_icon(false)?)
+        .with_tooltip("Geph: connecting")
+        .build()?;
+
+    // the control protocol is async, so it is spoken on the executor and never on the GTK main loop
+    let control = Arc::new(control);
+    let (status_send, status_recv) = mpsc::channel();
+    smolscale::spawn({
+        let control = control.clone();
+        async move {
+            loop {
+                let conn_info = control.conn_info().await;
+                if status_send.send(conn_info).is_err() {
+                    return;
+                }
+                smol::Timer::after(Duration::from_millis(500)).await;
+            }
+        }
+    })
+    .detach();
+
+    let mut conn_info = None;
+    glib::timeout_add_local(Duration::from_millis(100), move || {
+        while let Ok(result) = status_recv.try_recv() {
+            conn_info = match result {
+                Ok(info) => {
+                    update_tray(&tray, &info);
+                    Some(info)
+                }
+                Err(err) => {
+                    tracing::warn!(err = debug(err), "could not get conn info for tray");
+                    None
+                }
+            };
+        }
+
+        while let Ok(event) = MenuEvent::receiver().try_recv() {
+            if event.id == *show_status.id() {
+                show_status_dialog(&status_text(conn_info.as_ref()));
+            } else if event.id == *disconnect.id() {
+                let control = control.clone();
+                smolscale::spawn(async move {
+                    if let Err(err) = control.stop().await {
+                        tracing::warn!(err = debug(err), "could not disconnect from tray");
+                    }
+                })
+                .detach();
+            }
+        }
+        glib::ControlFlow::Continue
+    });
+
+    gtk::main();
+    Ok(())
+}
+
+fn update_tray(tray: &TrayIcon, conn_info: &ConnInfo) {
+    let connected = matches!(conn_info, ConnInfo::Connected(_));
+    match indicator_icon(connected) {
+        Ok(icon) => {
+            let _ = tray.set_icon(Some(icon));
+        }
+        Err(err) => tracing::warn!(err = debug(err), "could not create tray icon"),
+    }
+    let _ = tray.set_tooltip(Some(status_text(Some(conn_info))));
+}
+
+fn status_text(conn_info: Option<&ConnInfo>) -> String {
+    match conn_info {
+        Some(ConnInfo::Connected(info)) => format!(
+            "Geph: connected to {} / {}",
+            info.exit.country.alpha2(),
+            info.exit.city
+        ),
+        Some(ConnInfo::Connecting) => "Geph: connecting".to_string(),
+        None => "Geph: status unknown".to_string(),
+    }
+}
+
+fn show_status_dialog(text: &str) {
+    let dialog = gtk::MessageDialog::new(
+        None::<&gtk::Window>,
+        gtk::DialogFlags::empty(),
+        gtk::MessageType::Info,
+        gtk::ButtonsType::Ok,
+        text,
+    );
+    dialog.connect_response(|dialog, _| dialog.close());
+    dialog.show_all();
+}
+
+/// Draws a filled green (connected) or red (not connected) circle.
+fn indicator_icon(connected: bool) -> anyhow::Result<Icon> {
+    const SIZE: u32 = 32;
+    let color = if connected {
+        [0x2e, 0xcc, 0x40]
+    } else {
+        [0xff, 0x41, 0x36]
+    };
+    let center = (SIZE as f32 - 1.0) / 2.0;
+    let radius = SIZE as f32 / 2.0 - 1.0;
+    let mut rgba = Vec::with_capacity((SIZE * SIZE * 4) as usize);
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            let dist = ((x as f32 - center).powi(2) + (y as f32 - center).powi(2)).sqrt();
+            let alpha = if dist <= radius { 0xff } else { 0 };
+            rgba.extend_from_slice(&[color[0], color[1], color[2], alpha]);
+        }
+    }
+    Ok(Icon::from_rgba(rgba, SIZE, SIZE)?)
+}
@@ -1,46 +1,150 @@
 //! This module provides functionality for setting up a system-level VPN.
-#[cfg(target_os = "linux")]
+#[cfg(feature = "vpn")]
+mod icmp;
+#[cfg(all(feature = "vpn", target_os = "linux"))]
 mod linux;
-use bytes::Bytes;
-use crossbeam_queue::ArrayQueue;
-use dashmap::DashMap;
-use event_listener::Event;
-use ipstack_geph::{IpStack, IpStackConfig};
-#[cfg(target_os = "linux")]
+#[cfg(all(feature = "vpn", target_os = "linux"))]
 pub use linux::*;
 
-#[cfg(any(target_os = "android", target_os = "ios"))]
+#[cfg(all(feature = "vpn", any(target_os = "android", target_os = "ios")))]
 mod dummy;
 
-#[cfg(any(target_os = "android", target_os = "ios"))]
+#[cfg(all(feature = "vpn", any(target_os = "android", target_os = "ios")))]
 pub use dummy::*;
 
-use std::{net::Ipv4Addr, time::Instant};
-
-use anyctx::AnyCtx;
-use anyhow::Context;
-use futures_util::{AsyncReadExt, AsyncWriteExt};
-
-#[cfg(target_os = "windows")]
+#[cfg(all(feature = "vpn", target_os = "windows"))]
 mod windows;
-#[cfg(target_os = "windows")]
+#[cfg(all(feature = "vpn", target_os = "windows"))]
 pub use windows::*;
 
-use rand::Rng;
-use simple_dns::{Packet, QTYPE};
-use smol::future::FutureExt;
-
-#[cfg(target_os = "macos")]
+#[cfg(all(feature = "vpn", target_os = "macos"))]
 mod macos;
-#[cfg(target_os = "macos")]
+#[cfg(all(feature = "vpn", target_os = "macos"))]
 pub use macos::*;
 
-use crate::{client::CtxField, client_inner::open_conn, Config};
+// without the VPN mode, the rest of the client still gets to call into this module, which then does nothing
+#[cfg(not(feature = "vpn"))]
+mod stub;
+#[cfg(not(feature = "vpn"))]
+pub use stub::*;
+
+use ipnet::IpNet;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+use crate::client::CtxField;
+
+#[cfg(feature = "vpn")]
+use {
+    crate::{
+        client_inner::open_conn,
+        control_prot::{ConnInfo, CURRENT_CONN_INFO},
+        Config,
+    },
+    anyctx::AnyCtx,
+    anyhow::Context,
+    bytes::Bytes,
+    crossbeam_queue::ArrayQueue,
+    dashmap::DashMap,
+    event_listener::Event,
+    futures_util::{AsyncReadExt, AsyncWriteExt},
+    ipstack_geph::{stream::IpStackUdpStream, IpStack, IpStackConfig},
+    rand::Rng,
+    simple_dns::{Packet, QTYPE},
+    smol::future::FutureExt,
+    smol_timeout2::TimeoutExt,
+    std::{
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+        time::{Duration, Instant},
+    },
+};
+
+/// A per-app routing rule, deciding what happens to VPN traffic from processes with a given name.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AppRoute {
+    pub process_name: String,
+    pub action: AppAction,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AppAction {
+    Tunnel,
+    Direct,
+    Block,
+}
+
+/// Which destinations VPN traffic goes through the tunnel for, with everything else going through the default gateway as if the VPN were off.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SplitTunnel {
+    /// The networks to tunnel. If empty, every network is tunneled.
+    #[serde(default)]
+    pub include: Vec<IpNet>,
+    /// The networks never to tunnel, even if they are also included.
+    #[serde(default)]
+    pub exclude: Vec<IpNet>,
+}
+
+impl SplitTunnel {
+    /// Whether traffic to the given address should go through the tunnel.
+    pub fn tunnels(&self, dest: IpAddr) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|net| net.contains(&dest)))
+            && !self.exclude.iter().any(|net| net.contains(&dest))
+    }
+}
+
+/// A split-DNS rule, deciding where queries for names under a suffix get resolved. A suffix like `example.com` covers the name itself and all its subdomains, while `*.example.com` covers only the subdomains.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DnsRoute {
+    pub suffix: String,
+    pub resolver: DnsResolver,
+}
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsResolver {
+    /// The LAN's DNS server, reached without going through the tunnel.
+    Local,
+    /// The DNS server on the other side of the tunnel.
+    Tunnel,
+}
+
+impl DnsRoute {
+    /// How specifically this rule matches a name, or None if it does not match at all.
+    #[cfg(any(feature = "vpn", test))]
+    fn specificity(&self, name: &str) -> Option<usize> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let (suffix, wildcard) = match self.suffix.strip_prefix("*.") {
+            Some(suffix) => (suffix, true),
+            None => (self.suffix.trim_start_matches('.'), false),
+        };
+        let suffix = suffix.trim_end_matches('.').to_ascii_lowercase();
+        let is_subdomain = name
+            .strip_suffix(&suffix)
+            .is_some_and(|rest| rest.ends_with('.'));
+        (is_subdomain || (!wildcard && name == suffix)).then_some(suffix.len())
+    }
+}
+
+/// Picks the resolver for a name. The rule with the longest matching suffix wins, and names that no rule matches go through the tunnel.
+#[cfg(any(feature = "vpn", test))]
+fn dns_resolver_for(routes: &[DnsRoute], name: &str) -> DnsResolver {
+    routes
+        .iter()
+        .filter_map(|route| Some((route.specificity(name)?, route.resolver)))
+        .max_by_key(|(specificity, _)| *specificity)
+        .map(|(_, resolver)| resolver)
+        .unwrap_or(DnsResolver::Tunnel)
+}
+
+#[cfg(feature = "vpn")]
 static FAKE_DNS_FORWARD: CtxField<DashMap<String, Ipv4Addr>> = |_| DashMap::new();
 
+#[cfg(feature = "vpn")]
 static FAKE_DNS_BACKWARD: CtxField<DashMap<Ipv4Addr, String>> = |_| DashMap::new();
 
+#[cfg(feature = "vpn")]
 pub fn fake_dns_backtranslate(ctx: &AnyCtx<Config>, fake: Ipv4Addr) -> Option<String> {
     tracing::trace!(fake = debug(fake), "attempting to backtranslate");
     ctx.get(FAKE_DNS_BACKWARD)
@@ -48,6 +152,7 @@ pub fn fake_dns_backtranslate(ctx: &AnyCtx<Config>, fake: Ipv4Addr) -> Option<St
         .map(|entry| entry.clone())
 }
 
+#[cfg(feature = "vpn")]
 pub fn fake_dns_allocate(ctx: &AnyCtx<Config>, dns_name: &str) -> Ipv4Addr {
     *ctx.get(FAKE_DNS_FORWARD)
         .entry(dns_name.to_string())
@@ -69,6 +174,7 @@ pub fn fake_dns_allocate(ctx: &AnyCtx<Config>, dns_name: &str) -> Ipv4Addr {
 }
 
 /// Force a particular packet to be sent through VPN mode, regardless of whether VPN mode is on.
+#[cfg(feature = "vpn")]
 pub async fn send_vpn_packet(ctx: &AnyCtx<Config>, bts: Bytes) {
     tracing::trace!(
         len = bts.len(),
@@ -80,6 +186,7 @@ pub async fn send_vpn_packet(ctx: &AnyCtx<Config>, bts: Bytes) {
 }
 
 /// Receive a packet from VPN mode, regardless of whether VPN mode is on.
+#[cfg(feature = "vpn")]
 pub async fn recv_vpn_packet(ctx: &AnyCtx<Config>) -> Bytes {
     loop {
         let evt = ctx.get(VPN_EVENT).listen();
@@ -90,15 +197,24 @@ pub async fn recv_vpn_packet(ctx: &AnyCtx<Config>) -> Bytes {
     }
 }
 
+/// How long we wait for an answer to a DNS query that split DNS forwarded.
+#[cfg(feature = "vpn")]
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[cfg(feature = "vpn")]
 static VPN_EVENT: CtxField<Event> = |_| Event::new();
 
+#[cfg(feature = "vpn")]
 static VPN_CAPTURE: CtxField<ArrayQueue<(Bytes, Instant)>> = |_| ArrayQueue::new(100);
 
+#[cfg(feature = "vpn")]
 static VPN_INJECT: CtxField<ArrayQueue<Bytes>> = |_| ArrayQueue::new(100);
 
+#[cfg(feature = "vpn")]
 pub async fn vpn_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
     let (send_captured, recv_captured) = smol::channel::unbounded();
     let (send_injected, recv_injected) = smol::channel::unbounded();
+    let (send_to_stack, recv_to_stack) = smol::channel::unbounded();
 
     let ipstack = IpStack::new(
         #[cfg(target_os = "ios")]
@@ -109,9 +225,49 @@ pub async fn vpn_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
         },
         #[cfg(not(target_os = "ios"))]
         IpStackConfig::default(),
+        recv_to_stack,
+        send_injected.clone(),
+    );
+    let _icmp = smolscale::spawn(icmp::icmp_intercept_loop(
+        ctx.clone(),
         recv_captured,
+        send_to_stack,
         send_injected,
-    );
+    ));
+    #[cfg(not(target_os = "linux"))]
+    if ctx.init().vpn && !ctx.init().per_app_routing.is_empty() {
+        tracing::warn!("per-app routing is only supported on Linux, ignoring");
+    }
+    #[cfg(not(target_os = "linux"))]
+    if ctx.init().vpn && ctx.init().vpn_dns_leak_prevention {
+        tracing::warn!("DNS leak prevention is only supported on Linux, ignoring");
+    }
+    #[cfg(not(target_os = "linux"))]
+    if ctx.init().vpn && ctx.init().bridge_mode == crate::BridgeMode::ForceDirect {
+        tracing::warn!("path MTU discovery is only supported on Linux, keeping the default MTU");
+    }
+    #[cfg(not(any(target_os = "linux", all(target_os = "windows", feature = "windivert"))))]
+    if ctx.init().vpn && ctx.init().split_tunnel.is_some() {
+        tracing::warn!("split tunneling is only supported on Linux and Windows, ignoring");
+    }
+    #[cfg(not(target_os = "linux"))]
+    if ctx.init().vpn && !ctx.init().dns_routing.is_empty() {
+        tracing::warn!("split DNS is only supported on Linux, ignoring");
+    }
+    #[cfg(target_os = "linux")]
+    if ctx.init().vpn && ctx.init().local_dns.is_none() && has_local_dns_routes(ctx.init()) {
+        tracing::warn!(
+            "dns_routing has local rules but local_dns is not set, tunneling those names"
+        );
+    }
+    #[cfg(not(target_os = "linux"))]
+    if ctx.init().vpn && ctx.init().vpn_ipv6 {
+        tracing::warn!("tunneling IPv6 in VPN mode is only supported on Linux, ignoring");
+    }
+    // the probes go around the tunnel and are easy to recognize, so they are only for when we talk to exits directly anyway
+    #[cfg(target_os = "linux")]
+    let _pmtud = (ctx.init().vpn && ctx.init().bridge_mode == crate::BridgeMode::ForceDirect)
+        .then(|| smolscale::spawn(pmtud_loop(ctx.clone())));
     let _shuffle = if ctx.init().vpn {
         smolscale::spawn(packet_shuffle(ctx.clone(), send_captured, recv_injected))
     } else {
@@ -187,32 +343,17 @@ pub async fn vpn_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
 
                 let ctx = ctx.clone();
                 smolscale::spawn::<anyhow::Result<()>>(async move {
-                    if peer_addr.port() == 53 && ctx.init().spoof_dns {
+                    if peer_addr.port() == 53
+                        && cfg!(target_os = "linux")
+                        && ctx.init().local_dns.is_some()
+                        && has_local_dns_routes(ctx.init())
+                    {
+                        split_dns_loop(&ctx, &captured).await
+                    } else if peer_addr.port() == 53 && ctx.init().spoof_dns {
                         // fakedns handling
                         loop {
                             let pkt = captured.recv().await?;
-                            let pkt = Packet::parse(&pkt)?;
-                            tracing::trace!(pkt = debug(&pkt), "got DNS packet");
-                            let mut answers = vec![];
-                            for question in pkt.questions.iter() {
-                                if question.qtype == QTYPE::TYPE(simple_dns::TYPE::A) {
-                                    answers.push(simple_dns::ResourceRecord::new(
-                                        question.qname.clone(),
-                                        simple_dns::CLASS::IN,
-                                        1,
-                                        simple_dns::rdata::RData::A(
-                                            fake_dns_allocate(&ctx, &question.qname.to_string())
-                                                .into(),
-                                        ),
-                                    ));
-                                }
-                            }
-                            let mut response = pkt.into_reply();
-                            response.answers = answers;
-
-                            captured
-                                .send(&response.build_bytes_vec_compressed()?)
-                                .await?;
+                            captured.send(&spoof_dns_reply(&ctx, &pkt)?).await?;
                         }
                     } else {
                         let tunneled = open_conn(&ctx, "udp", &peer_addr.to_string()).await?;
@@ -251,3 +392,353 @@ pub async fn vpn_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
         }
     }
 }
+
+/// Answers a DNS query with fake addresses, which we translate back to the names when the connections come in.
+#[cfg(feature = "vpn")]
+fn spoof_dns_reply(ctx: &AnyCtx<Config>, pkt: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let pkt = Packet::parse(pkt)?;
+    tracing::trace!(pkt = debug(&pkt), "got DNS packet");
+    let mut answers = vec![];
+    for question in pkt.questions.iter() {
+        if question.qtype == QTYPE::TYPE(simple_dns::TYPE::A) {
+            answers.push(simple_dns::ResourceRecord::new(
+                question.qname.clone(),
+                simple_dns::CLASS::IN,
+                1,
+                simple_dns::rdata::RData::A(
+                    fake_dns_allocate(ctx, &question.qname.to_string()).into(),
+                ),
+            ));
+        }
+    }
+    let mut response = pkt.into_reply();
+    response.answers = answers;
+    Ok(response.build_bytes_vec_compressed()?)
+}
+
+/// Whether split DNS has anything to do, which is only the case if some names are to be resolved by the LAN's DNS server.
+#[cfg(feature = "vpn")]
+fn has_local_dns_routes(cfg: &Config) -> bool {
+    cfg.dns_routing
+        .iter()
+        .any(|route| route.resolver == DnsResolver::Local)
+}
+
+/// Answers the DNS queries in a captured UDP flow according to the split-DNS rules, sending each either to the LAN's DNS server or through the tunnel. Queries are answered concurrently, so that a slow resolver does not hold up the names that the other one resolves.
+#[cfg(feature = "vpn")]
+async fn split_dns_loop(ctx: &AnyCtx<Config>, captured: &IpStackUdpStream) -> anyhow::Result<()> {
+    let (send_response, recv_response) = smol::channel::unbounded();
+    let queries = async {
+        loop {
+            let query = captured.recv().await?;
+            let ctx = ctx.clone();
+            let send_response = send_response.clone();
+            smolscale::spawn(async move {
+                if let Some(response) = split_dns_exchange(&ctx, &query).await {
+                    let _ = send_response.send(response).await;
+                }
+            })
+            .detach();
+        }
+    };
+    let responses = async {
+        loop {
+            let response: Vec<u8> = recv_response.recv().await?;
+            captured.send(&response).await?;
+        }
+    };
+    queries.race(responses).await
+}
+
+/// Resolves one query according to the split-DNS rules, returning the response if there is one.
+#[cfg(feature = "vpn")]
+async fn split_dns_exchange(ctx: &AnyCtx<Config>, query: &[u8]) -> Option<Vec<u8>> {
+    let name = match Packet::parse(query) {
+        Ok(packet) => packet
+            .questions
+            .first()
+            .map(|question| question.qname.to_string())
+            .unwrap_or_default(),
+        Err(err) => {
+            tracing::debug!(err = debug(err), "dropping unparseable DNS query");
+            return None;
+        }
+    };
+    let resolver = dns_resolver_for(&ctx.init().dns_routing, &name);
+    tracing::trace!(name, resolver = debug(resolver), "routing DNS query");
+    let response = match (resolver, ctx.init().local_dns) {
+        (DnsResolver::Local, Some(local_dns)) => local_dns_exchange(local_dns, query).await,
+        _ if ctx.init().spoof_dns => spoof_dns_reply(ctx, query),
+        _ => tunnel_dns_exchange(ctx, query).await,
+    };
+    response
+        .inspect_err(|err| tracing::debug!(name, err = debug(err), "DNS query failed"))
+        .ok()
+}
+
+/// What path MTU discovery last found, for the metrics endpoint. Only the Linux TUN device is resized to fit, which covers every packet we tunnel in VPN mode there. Elsewhere, and outside VPN mode, what we send the exit are TCP streams, which the OS already segments to fit the path.
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct PathMtu {
+    /// The largest packet that made it to the exit unfragmented.
+    pub path_mtu: usize,
+    /// The MTU we gave the TUN device, so that tunneled packets fit into the path MTU.
+    pub tunnel_mtu: usize,
+}
+
+/// The result of the latest successful path MTU discovery, if any.
+pub static PATH_MTU: CtxField<Mutex<Option<PathMtu>>> = |_| Mutex::new(None);
+
+/// How often we rediscover the path MTU to the exit.
+#[cfg(all(feature = "vpn", target_os = "linux"))]
+const PMTUD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long we wait for the exit to answer a round of probes.
+#[cfg(all(feature = "vpn", target_os = "linux"))]
+const PMTUD_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What the tunnel adds to every packet: the outer TCP/IP headers, plus the framing of the obfuscation and encryption layers.
+#[cfg(all(feature = "vpn", target_os = "linux"))]
+const TUNNEL_OVERHEAD: usize = 80;
+
+/// Periodically discovers the path MTU to the exit with our own probes, and sizes the TUN device so that tunneled packets fit. We don't rely on the OS, since the ICMP messages its path MTU discovery needs are often filtered. Only exits that advertise `pmtud_echo` are probed, since the rest would see nothing but unexplained UDP packets.
+#[cfg(all(feature = "vpn", target_os = "linux"))]
+async fn pmtud_loop(ctx: AnyCtx<Config>) {
+    let mut current_mtu = None;
+    loop {
+        let exit_addr = match &*ctx.get(CURRENT_CONN_INFO).lock() {
+            ConnInfo::Connected(info) if info.exit.pmtud_echo => Some(info.exit.c2e_listen),
+            ConnInfo::Connected(_) => None,
+            ConnInfo::Connecting => None,
+        };
+        if let Some(exit_addr) = exit_addr {
+            match probe_path_mtu(exit_addr).await {
+                Ok(Some(path_mtu)) => {
+                    let mtu = path_mtu - TUNNEL_OVERHEAD;
+                    if current_mtu != Some(mtu) {
+                        tracing::info!(path_mtu, mtu, "setting TUN MTU from path MTU discovery");
+                        match vpn_set_mtu(mtu) {
+                            Ok(()) => {
+                                current_mtu = Some(mtu);
+                                *ctx.get(PATH_MTU).lock() = Some(PathMtu {
+                                    path_mtu,
+                                    tunnel_mtu: mtu,
+                                });
+                            }
+                            Err(err) => tracing::warn!(err = debug(err), "could not set TUN MTU"),
+                        }
+                    }
+                }
+                // the probes, or the answers, were lost even at the smallest size
+                Ok(None) => tracing::debug!("no PMTUD probes answered, leaving MTU alone"),
+                Err(err) => tracing::warn!(err = debug(err), "PMTUD probing failed"),
+            }
+            smol::Timer::after(PMTUD_INTERVAL).await;
+        } else {
+            // probe as soon as we are connected, rather than a whole interval after startup
+            smol::Timer::after(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+/// Sends unfragmentable UDP probes to the exit, with packet sizes from 1500 bytes stepping down by 10, and returns the largest size the exit answered.
+#[cfg(all(feature = "vpn", target_os = "linux"))]
+async fn probe_path_mtu(exit_addr: SocketAddr) -> anyhow::Result<Option<usize>> {
+    use geph5_misc_rpc::exit::PMTUD_MAGIC;
+    use std::os::fd::AsRawFd;
+
+    vpn_whitelist(exit_addr.ip());
+    let (bind_addr, header_len, min_size, level, opt, probe): (SocketAddr, _, _, _, _, _) =
+        if exit_addr.is_ipv4() {
+            (
+                (Ipv4Addr::UNSPECIFIED, 0).into(),
+                28,
+                576,
+                libc::IPPROTO_IP,
+                libc::IP_MTU_DISCOVER,
+                libc::IP_PMTUDISC_PROBE,
+            )
+        } else {
+            (
+                (Ipv6Addr::UNSPECIFIED, 0).into(),
+                48,
+                1280,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MTU_DISCOVER,
+                libc::IPV6_PMTUDISC_PROBE,
+            )
+        };
+    let socket = smol::net::UdpSocket::bind(bind_addr).await?;
+    // set the DF bit, without the kernel clamping our probes to the path MTU it has cached
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            opt,
+            (&probe as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    socket.connect(exit_addr).await?;
+    for size in (min_size..=1500).rev().step_by(10) {
+        let mut packet = vec![0u8; size - header_len];
+        packet[..PMTUD_MAGIC.len()].copy_from_slice(PMTUD_MAGIC);
+        packet[PMTUD_MAGIC.len()..][..2].copy_from_slice(&(size as u16).to_le_bytes());
+        // probes too big for the first hop fail right here, which is just another way of not getting through
+        let _ = socket.send(&packet).await;
+    }
+    let mut largest = None;
+    let collect = async {
+        let mut buf = [0u8; 64];
+        loop {
+            let n = socket.recv(&mut buf).await?;
+            if let Some(size) = buf[..n]
+                .strip_prefix(PMTUD_MAGIC.as_slice())
+                .and_then(|rest| Some(u16::from_le_bytes(rest.get(..2)?.try_into().ok()?)))
+            {
+                largest = largest.max(Some(size as usize));
+            }
+        }
+        #[allow(unreachable_code)]
+        anyhow::Ok(())
+    };
+    if let Some(res) = collect.timeout(PMTUD_TIMEOUT).await {
+        res?;
+    }
+    Ok(largest)
+}
+
+/// Sends a DNS query straight to a DNS server, bypassing the tunnel. Only this socket goes around the tunnel, rather than all traffic to the server, so that names resolved through the tunnel never leak to the same server.
+#[cfg(all(feature = "vpn", target_os = "linux"))]
+async fn local_dns_exchange(server: SocketAddr, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let bind_addr: SocketAddr = if server.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = bypass_udp_socket(bind_addr)?;
+    socket.connect(server).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0u8; 65536];
+    let n = socket
+        .recv(&mut buf)
+        .timeout(DNS_TIMEOUT)
+        .await
+        .context("timed out waiting for local DNS")??;
+    buf.truncate(n);
+    Ok(buf)
+}
+
+#[cfg(all(feature = "vpn", not(target_os = "linux")))]
+async fn local_dns_exchange(_server: SocketAddr, _query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    anyhow::bail!("split DNS is only supported on Linux")
+}
+
+/// Sends a DNS query to the DNS server on the other side of the tunnel.
+#[cfg(feature = "vpn")]
+async fn tunnel_dns_exchange(ctx: &AnyCtx<Config>, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let tunneled = open_conn(ctx, "udp", "1.1.1.1:53").await?;
+    let (mut read_tunneled, mut write_tunneled) = tunneled.split();
+    write_tunneled
+        .write_all(&(query.len() as u16).to_le_bytes())
+        .await?;
+    write_tunneled.write_all(query).await?;
+    write_tunneled.flush().await?;
+    async {
+        let mut len_buf = [0u8; 2];
+        read_tunneled.read_exact(&mut len_buf).await?;
+        let mut buf = vec![0u8; u16::from_le_bytes(len_buf) as usize];
+        read_tunneled.read_exact(&mut buf).await?;
+        anyhow::Ok(buf)
+    }
+    .timeout(DNS_TIMEOUT)
+    .await
+    .context("timed out waiting for tunneled DNS")?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_tunnel_include_and_exclude() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let net = |s: &str| s.parse::<IpNet>().unwrap();
+
+        // with nothing included, everything not excluded is tunneled
+        let everything = SplitTunnel::default();
+        assert!(everything.tunnels(ip("192.0.2.1")));
+        assert!(everything.tunnels(ip("2001:db8::1")));
+
+        let split = SplitTunnel {
+            include: vec![net("10.0.0.0/8"), net("2001:db8::/32")],
+            exclude: vec![net("10.1.0.0/16")],
+        };
+        assert!(split.tunnels(ip("10.2.3.4")));
+        assert!(split.tunnels(ip("2001:db8::1")));
+        assert!(!split.tunnels(ip("192.0.2.1")));
+        assert!(!split.tunnels(ip("2001:db9::1")));
+        // exclusions win over inclusions
+        assert!(!split.tunnels(ip("10.1.2.3")));
+
+        let exclude_only = SplitTunnel {
+            include: vec![],
+            exclude: vec![net("192.168.0.0/16")],
+        };
+        assert!(exclude_only.tunnels(ip("192.0.2.1")));
+        assert!(!exclude_only.tunnels(ip("192.168.1.1")));
+    }
+
+    fn route(suffix: &str, resolver: DnsResolver) -> DnsRoute {
+        DnsRoute {
+            suffix: suffix.into(),
+            resolver,
+        }
+    }
+
+    #[test]
+    fn dns_route_suffixes() {
+        let plain = route("example.com", DnsResolver::Local);
+        assert!(plain.specificity("example.com").is_some());
+        assert!(plain.specificity("www.example.com.").is_some());
+        assert!(plain.specificity("WWW.Example.COM").is_some());
+        // a suffix only matches whole labels
+        assert!(plain.specificity("badexample.com").is_none());
+        assert!(plain.specificity("example.com.evil").is_none());
+        assert!(plain.specificity("com").is_none());
+
+        let wildcard = route("*.example.com", DnsResolver::Local);
+        assert!(wildcard.specificity("example.com").is_none());
+        assert!(wildcard.specificity("a.b.example.com").is_some());
+    }
+
+    #[test]
+    fn most_specific_dns_route_wins() {
+        let routes = [
+            route("corp", DnsResolver::Local),
+            route("public.corp", DnsResolver::Tunnel),
+            route("*.internal.public.corp", DnsResolver::Local),
+        ];
+        assert_eq!(dns_resolver_for(&routes, "git.corp"), DnsResolver::Local);
+        assert_eq!(
+            dns_resolver_for(&routes, "www.public.corp"),
+            DnsResolver::Tunnel
+        );
+        assert_eq!(
+            dns_resolver_for(&routes, "db.internal.public.corp"),
+            DnsResolver::Local
+        );
+        assert_eq!(
+            dns_resolver_for(&routes, "internal.public.corp"),
+            DnsResolver::Tunnel
+        );
+        // names no rule covers go through the tunnel
+        assert_eq!(
+            dns_resolver_for(&routes, "example.com"),
+            DnsResolver::Tunnel
+        );
+        assert_eq!(dns_resolver_for(&[], "corp"), DnsResolver::Tunnel);
+    }
+}
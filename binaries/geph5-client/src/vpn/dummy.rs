@@ -17,3 +17,7 @@ pub(super) async fn packet_shuffle(
 pub fn vpn_whitelist(_addr: IpAddr) {
     // noop
 }
+
+pub fn vpn_bypass_network(_net: ipnet::IpNet) {
+    // noop
+}
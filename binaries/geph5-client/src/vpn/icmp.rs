@@ -0,0 +1,141 @@
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+use anyctx::AnyCtx;
+use bytes::Bytes;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use parking_lot::Mutex;
+use pnet_packet::{
+    ip::IpNextHeaderProtocols,
+    ipv4::{self, Ipv4Flags, Ipv4Packet, MutableIpv4Packet},
+};
+use smol::{
+    channel::{Receiver, Sender},
+    future::FutureExt,
+};
+
+use crate::{client_inner::open_conn, Config};
+
+/// How long a flow may go without an echo request or reply before its stream is closed.
+const ICMP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Takes ICMPv4 echo requests out of the captured packets, since the IP stack only handles TCP and UDP, and passes everything else on to it. Echo requests are tunneled to the exit, one `icmp` stream per destination, and the replies are injected back as if they came from the destination.
+pub(super) async fn icmp_intercept_loop(
+    ctx: AnyCtx<Config>,
+    recv_captured: Receiver<Bytes>,
+    send_to_stack: Sender<Bytes>,
+    send_injected: Sender<Bytes>,
+) -> anyhow::Result<()> {
+    let mut flows: HashMap<(Ipv4Addr, Ipv4Addr), Sender<Bytes>> = HashMap::new();
+    loop {
+        let pkt = recv_captured.recv().await?;
+        let Some((source, dest, request)) = parse_echo_request(&pkt) else {
+            send_to_stack.send(pkt).await?;
+            continue;
+        };
+        // flows whose streams closed, whether through idleness or failure, are opened afresh
+        flows.retain(|_, send| !send.is_closed());
+        let send = flows.entry((source, dest)).or_insert_with(|| {
+            let (send, recv) = smol::channel::bounded(100);
+            let flow = icmp_flow(ctx.clone(), source, dest, recv, send_injected.clone());
+            smolscale::spawn(async move {
+                if let Err(err) = flow.await {
+                    tracing::trace!(dest = display(dest), err = debug(err), "ICMP flow stopped");
+                }
+            })
+            .detach();
+            send
+        });
+        // like any ping, requests get dropped when the tunnel cannot keep up
+        let _ = send.try_send(request);
+    }
+}
+
+/// Tunnels the echo requests from one source to one destination, injecting the replies. The flow ends after [ICMP_IDLE_TIMEOUT] without traffic, so that every address ever pinged does not keep a stream open.
+async fn icmp_flow(
+    ctx: AnyCtx<Config>,
+    source: Ipv4Addr,
+    dest: Ipv4Addr,
+    requests: Receiver<Bytes>,
+    send_injected: Sender<Bytes>,
+) -> anyhow::Result<()> {
+    let tunneled = open_conn(&ctx, "icmp", &format!("{dest}:0")).await?;
+    let (mut read_tunneled, mut write_tunneled) = tunneled.split();
+    let last_active = Mutex::new(Instant::now());
+    let up_loop = async {
+        loop {
+            let request = requests.recv().await?;
+            *last_active.lock() = Instant::now();
+            write_tunneled
+                .write_all(&(request.len() as u16).to_be_bytes())
+                .await?;
+            write_tunneled.write_all(&request).await?;
+            write_tunneled.flush().await?;
+        }
+    };
+    let dn_loop = async {
+        loop {
+            let mut len_buf = [0u8; 2];
+            read_tunneled.read_exact(&mut len_buf).await?;
+            let mut reply = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+            read_tunneled.read_exact(&mut reply).await?;
+            *last_active.lock() = Instant::now();
+            send_injected.send(wrap_ipv4(dest, source, &reply)).await?;
+        }
+    };
+    let idle_loop = async {
+        loop {
+            let deadline = *last_active.lock() + ICMP_IDLE_TIMEOUT;
+            if Instant::now() >= deadline {
+                anyhow::bail!("idle for {ICMP_IDLE_TIMEOUT:?}");
+            }
+            smol::Timer::at(deadline).await;
+        }
+    };
+    up_loop.race(dn_loop).race(idle_loop).await
+}
+
+/// Returns the source, destination, and ICMP message of an unfragmented ICMPv4 echo request.
+fn parse_echo_request(pkt: &[u8]) -> Option<(Ipv4Addr, Ipv4Addr, Bytes)> {
+    let ip = Ipv4Packet::new(pkt)?;
+    if ip.get_version() != 4
+        || ip.get_next_level_protocol() != IpNextHeaderProtocols::Icmp
+        || ip.get_fragment_offset() != 0
+        || ip.get_flags() & Ipv4Flags::MoreFragments != 0
+    {
+        return None;
+    }
+    let start = ip.get_header_length() as usize * 4;
+    let end = (ip.get_total_length() as usize).min(pkt.len());
+    let message = pkt.get(start..end)?;
+    // type 8, code 0 is an echo request
+    if message.len() < 8 || message[0] != 8 || message[1] != 0 {
+        return None;
+    }
+    Some((
+        ip.get_source(),
+        ip.get_destination(),
+        Bytes::copy_from_slice(message),
+    ))
+}
+
+/// Wraps an ICMP message in an IPv4 header.
+fn wrap_ipv4(source: Ipv4Addr, dest: Ipv4Addr, message: &[u8]) -> Bytes {
+    let mut buf = vec![0u8; 20 + message.len()];
+    let mut ip = MutableIpv4Packet::new(&mut buf).expect("buffer fits the header");
+    ip.set_version(4);
+    ip.set_header_length(5);
+    ip.set_total_length((20 + message.len()) as u16);
+    ip.set_identification(rand::random());
+    ip.set_ttl(64);
+    ip.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+    ip.set_source(source);
+    ip.set_destination(dest);
+    ip.set_payload(message);
+    let checksum = ipv4::checksum(&ip.to_immutable());
+    ip.set_checksum(checksum);
+    buf.into()
+}
@@ -4,28 +4,63 @@ use bytes::Bytes;
 use dashmap::DashMap;
 use futures_util::{AsyncReadExt, AsyncWriteExt};
 
+use ipnet::IpNet;
 use itertools::Itertools;
 use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use smol::{
     channel::{Receiver, Sender},
     future::FutureExt as _,
 };
 use std::{
-    net::{IpAddr, Ipv4Addr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
     process::Command,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
 };
 
 use crate::{client_inner::open_conn, Config};
 
+use super::{AppAction, AppRoute, SplitTunnel};
+
 const FAKE_LOCAL_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(100, 64, 89, 64));
 
 pub fn vpn_whitelist(addr: IpAddr) {
-    WHITELIST.entry(addr).or_insert_with(|| {
-        tracing::warn!(addr = display(addr), "*** WHITELIST ***");
-        SingleWhitelister::new(addr)
+    vpn_bypass_network(addr.into());
+}
+
+/// Sends traffic to a whole network around the VPN, rather than through it.
+pub fn vpn_bypass_network(net: IpNet) {
+    WHITELIST.entry(net).or_insert_with(|| {
+        tracing::warn!(net = display(net), "*** WHITELIST ***");
+        SingleWhitelister::new(net)
     });
 }
 
+/// The firewall mark of traffic routed around the tunnel, whether marked by per-app routing or by us on our own sockets.
+const BYPASS_MARK: libc::c_int = 8965;
+
+/// Binds a UDP socket whose packets go around the tunnel. Unlike [vpn_whitelist], this leaves other traffic to the same destination in the tunnel.
+pub(super) fn bypass_udp_socket(bind_addr: SocketAddr) -> anyhow::Result<smol::net::UdpSocket> {
+    use std::os::fd::AsRawFd;
+
+    let socket = std::net::UdpSocket::bind(bind_addr)?;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            (&BYPASS_MARK as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(smol::net::UdpSocket::try_from(socket)?)
+}
+
 fn setup_routing() -> anyhow::Result<()> {
     let cmd = include_str!("linux_routing_setup.sh");
     let mut child = Command::new("sh").arg("-c").arg(cmd).spawn().unwrap();
@@ -42,6 +77,10 @@ fn setup_routing() -> anyhow::Result<()> {
 extern "C" fn teardown_routing() {
     tracing::debug!("teardown_routing starting!");
     WHITELIST.clear();
+    teardown_ipv6_routing();
+    teardown_app_routing();
+    teardown_dns_leak_prevention();
+    teardown_split_tunnel();
     let cmd = include_str!("linux_routing_setup.sh")
         .lines()
         .filter(|l| l.contains("-D") || l.contains("del") || l.contains("flush"))
@@ -62,10 +101,32 @@ pub(super) async fn packet_shuffle(
     let up_file = smol::Async::new(unsafe { std::fs::File::from_raw_fd(fd_num) })
         .context("cannot init up_file")?;
 
+    // with only some networks tunneled, DNS goes to the system resolver, which may well be outside of them
+    anyhow::ensure!(
+        !ctx.init().vpn_dns_leak_prevention
+            || ctx
+                .init()
+                .split_tunnel
+                .as_ref()
+                .is_none_or(|split_tunnel| split_tunnel.include.is_empty()),
+        "DNS leak prevention cannot be combined with including only some networks in the tunnel"
+    );
     // wait until we have a connection
     open_conn(&ctx, "", "").await?;
     setup_routing().unwrap();
     scopeguard::defer!(teardown_routing());
+    if ctx.init().vpn_ipv6 {
+        setup_ipv6_routing().context("could not set up IPv6 routing")?;
+    }
+    if !ctx.init().per_app_routing.is_empty() {
+        setup_app_routing().context("could not set up per-app routing")?;
+    }
+    if ctx.init().vpn_dns_leak_prevention {
+        setup_dns_leak_prevention().context("could not set up DNS leak prevention")?;
+    }
+    if let Some(split_tunnel) = &ctx.init().split_tunnel {
+        setup_split_tunnel(split_tunnel).context("could not set up split tunneling")?;
+    }
     let (mut read, mut write) = up_file.split();
     let inject = async {
         loop {
@@ -83,7 +144,266 @@ pub(super) async fn packet_shuffle(
             send_captured.send(Bytes::copy_from_slice(buf)).await?;
         }
     };
-    inject.race(capture).await
+    inject.race(capture).race(app_routing_loop(&ctx)).await
+}
+
+static IPV6_ROUTING_SET_UP: AtomicBool = AtomicBool::new(false);
+
+/// Routes IPv6 through the tunnel too, instead of blocking it outright.
+fn setup_ipv6_routing() -> anyhow::Result<()> {
+    let cmd = include_str!("linux_ipv6_setup.sh");
+    let mut child = Command::new("sh").arg("-c").arg(cmd).spawn()?;
+    child
+        .wait()
+        .context("IPv6 routing was not set up properly")?;
+    IPV6_ROUTING_SET_UP.store(true, Ordering::SeqCst);
+    anyhow::Ok(())
+}
+
+fn teardown_ipv6_routing() {
+    if !IPV6_ROUTING_SET_UP.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    let cmd = include_str!("linux_ipv6_setup.sh")
+        .lines()
+        .filter(|l| l.contains("-D") || l.contains("del") || l.contains("flush"))
+        .join("\n");
+    let mut child = Command::new("sh").arg("-c").arg(cmd).spawn().unwrap();
+    child
+        .wait()
+        .expect("IPv6 routing was not torn down properly");
+}
+
+/// The included networks that we replaced the catch-all rule sending everything into the tunnel with.
+static SPLIT_TUNNEL_INCLUDED: Lazy<Mutex<Vec<IpNet>>> = Lazy::new(Default::default);
+
+/// Narrows down what goes through the tunnel. Excluded networks are routed around it like whitelisted addresses, and if only some networks are included, the catch-all rule is swapped for one rule per included network. DNS is then no longer redirected into the tunnel either, but goes to the system resolver, through the tunnel only if the resolver is in an included network.
+fn setup_split_tunnel(split_tunnel: &SplitTunnel) -> anyhow::Result<()> {
+    for net in split_tunnel.exclude.iter() {
+        vpn_bypass_network(*net);
+    }
+    if split_tunnel.include.is_empty() {
+        return Ok(());
+    }
+    // otherwise every query would go to GEPH_DNS around the tunnel, unless that happens to be included
+    let cmd = include_str!("linux_routing_setup.sh")
+        .lines()
+        .filter(|l| l.contains("-D") && l.contains("DNAT"))
+        .join("\n");
+    let status = Command::new("sh").arg("-c").arg(cmd).status()?;
+    anyhow::ensure!(status.success(), "could not stop redirecting DNS");
+    let mut included = SPLIT_TUNNEL_INCLUDED.lock();
+    for net in split_tunnel.include.iter() {
+        ip_rule("add", *net)?;
+        included.push(*net);
+    }
+    for family in ["-4", "-6"] {
+        // the IPv6 catch-all only exists with vpn_ipv6, so this may well fail
+        let _ = Command::new("ip")
+            .args([
+                family, "rule", "del", "to", "all", "lookup", "8964", "pref", "2",
+            ])
+            .status();
+    }
+    Ok(())
+}
+
+fn teardown_split_tunnel() {
+    for net in SPLIT_TUNNEL_INCLUDED.lock().drain(..) {
+        if let Err(err) = ip_rule("del", net) {
+            tracing::warn!(
+                net = display(net),
+                err = debug(err),
+                "could not remove split tunnel rule"
+            );
+        }
+    }
+}
+
+/// Adds or deletes the rule sending traffic to a network into the tunnel.
+fn ip_rule(action: &str, net: IpNet) -> anyhow::Result<()> {
+    let status = Command::new("ip")
+        .args([
+            ip_family(net.addr()),
+            "rule",
+            action,
+            "to",
+            &net.to_string(),
+            "lookup",
+            "8964",
+            "pref",
+            "2",
+        ])
+        .status()?;
+    anyhow::ensure!(status.success(), "ip rule {action} failed with {status}");
+    Ok(())
+}
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// What /etc/resolv.conf was before we rewrote it, so that we can put it back.
+enum ResolvBackup {
+    File(Vec<u8>),
+    /// Often the case with systemd-resolved and NetworkManager, whose files we must not write through.
+    Symlink(PathBuf),
+}
+
+static RESOLV_BACKUP: Lazy<Mutex<Option<ResolvBackup>>> = Lazy::new(Default::default);
+
+/// Points the system resolver at the DNS server that the tunnel carries traffic to, and blocks DNS that would go around the tunnel.
+fn setup_dns_leak_prevention() -> anyhow::Result<()> {
+    let mut backup = RESOLV_BACKUP.lock();
+    if backup.is_none() {
+        *backup = Some(match std::fs::read_link(RESOLV_CONF) {
+            Ok(target) => ResolvBackup::Symlink(target),
+            Err(_) => ResolvBackup::File(std::fs::read(RESOLV_CONF)?),
+        });
+        // replace rather than write through, in case it is a symlink
+        let tmp_path = format!("{RESOLV_CONF}.geph5");
+        std::fs::write(
+            &tmp_path,
+            format!(
+                "# written by geph5-client, restored on exit\nnameserver {}\n",
+                std::env::var("GEPH_DNS")?
+            ),
+        )?;
+        std::fs::rename(&tmp_path, RESOLV_CONF)?;
+    }
+    let cmd = include_str!("linux_dns_leak_setup.sh");
+    let mut child = Command::new("sh").arg("-c").arg(cmd).spawn()?;
+    child
+        .wait()
+        .context("DNS leak prevention was not set up properly")?;
+    anyhow::Ok(())
+}
+
+fn teardown_dns_leak_prevention() {
+    let Some(backup) = RESOLV_BACKUP.lock().take() else {
+        return;
+    };
+    let cmd = include_str!("linux_dns_leak_setup.sh")
+        .lines()
+        .filter(|l| l.contains("-D"))
+        .join("\n");
+    let mut child = Command::new("sh").arg("-c").arg(cmd).spawn().unwrap();
+    child
+        .wait()
+        .expect("DNS leak prevention was not torn down properly");
+    let restored = match backup {
+        ResolvBackup::File(contents) => std::fs::write(RESOLV_CONF, contents),
+        ResolvBackup::Symlink(target) => std::fs::remove_file(RESOLV_CONF)
+            .and_then(|_| std::os::unix::fs::symlink(target, RESOLV_CONF)),
+    };
+    if let Err(err) = restored {
+        tracing::error!(err = debug(err), "could not restore {RESOLV_CONF}");
+    }
+}
+
+/// The cgroup v2 hierarchy under which processes with per-app routing rules are placed.
+const APP_CGROUP_ROOT: &str = "/sys/fs/cgroup/geph5";
+
+static APP_ROUTING_SET_UP: AtomicBool = AtomicBool::new(false);
+
+fn setup_app_routing() -> anyhow::Result<()> {
+    let cmd = include_str!("linux_app_routing_setup.sh");
+    // the script stops at the first command that fails, other than removing rules that may not be there
+    let status = Command::new("sh").arg("-c").arg(cmd).status()?;
+    // whatever did get set up must still be removed
+    APP_ROUTING_SET_UP.store(true, Ordering::SeqCst);
+    anyhow::ensure!(
+        status.success(),
+        "per-app routing setup failed with {status}"
+    );
+    anyhow::Ok(())
+}
+
+fn teardown_app_routing() {
+    if !APP_ROUTING_SET_UP.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    let cmd = include_str!("linux_app_routing_setup.sh")
+        .lines()
+        .filter(|l| l.contains("-D") || l.contains("del"))
+        .join("\n");
+    match Command::new("sh").arg("-c").arg(cmd).status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => tracing::warn!(status = display(status), "per-app routing teardown failed"),
+        Err(err) => tracing::warn!(err = debug(err), "could not tear down per-app routing"),
+    }
+    // cgroups can only be removed once empty, so we move everything back to the root first
+    for cgroup in ["direct", "block"] {
+        let cgroup = Path::new(APP_CGROUP_ROOT).join(cgroup);
+        if let Ok(procs) = std::fs::read_to_string(cgroup.join("cgroup.procs")) {
+            for pid in procs.lines() {
+                let _ = std::fs::write("/sys/fs/cgroup/cgroup.procs", pid);
+            }
+        }
+        let _ = std::fs::remove_dir(cgroup);
+    }
+    let _ = std::fs::remove_dir(APP_CGROUP_ROOT);
+}
+
+/// Periodically moves processes matching the per-app routing rules into the cgroups that the routing rules apply to, since processes start all the time. Child processes inherit the cgroup of their parent.
+async fn app_routing_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let routes = &ctx.init().per_app_routing;
+    if routes.is_empty() {
+        return smol::future::pending().await;
+    }
+    loop {
+        if let Err(err) = assign_app_cgroups(routes) {
+            tracing::warn!(err = debug(err), "could not assign processes to cgroups");
+        }
+        smol::Timer::after(Duration::from_secs(2)).await;
+    }
+}
+
+fn assign_app_cgroups(routes: &[AppRoute]) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir("/proc")? {
+        let entry = entry?;
+        let Ok(pid) = entry.file_name().to_string_lossy().parse::<u32>() else {
+            continue;
+        };
+        // comm is truncated to 15 bytes, so we also look at the executable
+        let comm = std::fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+        let exe = std::fs::read_link(entry.path().join("exe"))
+            .ok()
+            .and_then(|exe| Some(exe.file_name()?.to_string_lossy().into_owned()));
+        let Some(route) = routes.iter().find(|route| {
+            route.process_name == comm.trim() || Some(&route.process_name) == exe.as_ref()
+        }) else {
+            continue;
+        };
+        let cgroup = match route.action {
+            AppAction::Tunnel => continue,
+            AppAction::Direct => "direct",
+            AppAction::Block => "block",
+        };
+        let current = std::fs::read_to_string(entry.path().join("cgroup")).unwrap_or_default();
+        if current.trim_end().ends_with(&format!("/geph5/{cgroup}")) {
+            continue;
+        }
+        tracing::debug!(
+            pid,
+            process_name = display(&route.process_name),
+            cgroup,
+            "moving process to per-app routing cgroup"
+        );
+        // the process may well have exited in the meantime
+        let _ = std::fs::write(
+            Path::new(APP_CGROUP_ROOT).join(cgroup).join("cgroup.procs"),
+            pid.to_string(),
+        );
+    }
+    Ok(())
+}
+
+/// Sets the MTU of the TUN device.
+pub(super) fn vpn_set_mtu(mtu: usize) -> anyhow::Result<()> {
+    let status = Command::new("ip")
+        .args(["link", "set", "dev", "tun-geph", "mtu", &mtu.to_string()])
+        .status()?;
+    anyhow::ensure!(status.success(), "ip link set failed with {status}");
+    Ok(())
 }
 
 #[cfg(target_os = "linux")]
@@ -102,7 +422,7 @@ fn configure_tun_device() -> tun::platform::Device {
 }
 
 struct SingleWhitelister {
-    dest: IpAddr,
+    dest: IpNet,
 }
 
 impl Drop for SingleWhitelister {
@@ -111,26 +431,58 @@ impl Drop for SingleWhitelister {
         Command::new("sh")
             .arg("-c")
             .arg(format!(
-                "/usr/bin/env ip rule del to {} lookup main pref 1",
+                "/usr/bin/env ip {} rule del to {} lookup main pref 1",
+                ip_family(self.dest.addr()),
                 self.dest
             ))
             .status()
             .expect("cannot run iptables");
+        if self.dest.addr().is_ipv6() {
+            Command::new("sh")
+                .arg("-c")
+                .arg(format!(
+                    "/usr/bin/env ip6tables -D OUTPUT -d {} -j ACCEPT",
+                    self.dest
+                ))
+                .status()
+                .expect("cannot run ip6tables");
+        }
     }
 }
 
 impl SingleWhitelister {
-    fn new(dest: IpAddr) -> Self {
+    fn new(dest: IpNet) -> Self {
         Command::new("sh")
             .arg("-c")
             .arg(format!(
-                "/usr/bin/env ip rule add to {} lookup main pref 1",
+                "/usr/bin/env ip {} rule add to {} lookup main pref 1",
+                ip_family(dest.addr()),
                 dest
             ))
             .status()
             .expect("cannot run iptables");
+        // IPv6 is otherwise rejected outright unless it is tunneled, so IPv6 exits and bridges need a hole punched
+        if dest.addr().is_ipv6() {
+            Command::new("sh")
+                .arg("-c")
+                .arg(format!(
+                    "/usr/bin/env ip6tables -I OUTPUT -d {} -j ACCEPT",
+                    dest
+                ))
+                .status()
+                .expect("cannot run ip6tables");
+        }
         Self { dest }
     }
 }
 
-static WHITELIST: Lazy<DashMap<IpAddr, SingleWhitelister>> = Lazy::new(DashMap::new);
+/// The `ip` flag selecting the address family of the given address.
+fn ip_family(addr: IpAddr) -> &'static str {
+    if addr.is_ipv6() {
+        "-6"
+    } else {
+        "-4"
+    }
+}
+
+static WHITELIST: Lazy<DashMap<IpNet, SingleWhitelister>> = Lazy::new(DashMap::new);
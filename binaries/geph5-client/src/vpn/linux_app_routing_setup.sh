@@ -0,0 +1,15 @@
+set -e
+export PATH=$PATH:/usr/sbin/:/sbin/
+mkdir -p /sys/fs/cgroup/geph5/direct /sys/fs/cgroup/geph5/block
+
+# traffic from the direct cgroup is marked, then routed around the tunnel
+iptables -t mangle -D OUTPUT -m cgroup --path geph5/direct -j MARK --set-mark 8965 || true
+iptables -t mangle -A OUTPUT -m cgroup --path geph5/direct -j MARK --set-mark 8965
+iptables -t nat -D POSTROUTING -m mark --mark 8965 -j MASQUERADE || true
+iptables -t nat -A POSTROUTING -m mark --mark 8965 -j MASQUERADE
+ip rule del fwmark 8965 lookup main pref 1 || true
+ip rule add fwmark 8965 lookup main pref 1
+
+# traffic from the block cgroup goes nowhere
+iptables -D OUTPUT -m cgroup --path geph5/block -j REJECT || true
+iptables -A OUTPUT -m cgroup --path geph5/block -j REJECT
@@ -0,0 +1,7 @@
+export PATH=$PATH:/usr/sbin/:/sbin/
+
+# DNS may only leave through the tunnel, or as split DNS sends it to the LAN
+iptables -D OUTPUT -p udp --dport 53 ! -o tun-geph -m mark ! --mark 8965 -j REJECT
+iptables -A OUTPUT -p udp --dport 53 ! -o tun-geph -m mark ! --mark 8965 -j REJECT
+iptables -D OUTPUT -p tcp --dport 53 ! -o tun-geph -m mark ! --mark 8965 -j REJECT
+iptables -A OUTPUT -p tcp --dport 53 ! -o tun-geph -m mark ! --mark 8965 -j REJECT
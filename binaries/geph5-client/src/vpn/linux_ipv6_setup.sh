@@ -0,0 +1,12 @@
+export PATH=$PATH:/usr/sbin/:/sbin/
+ip -6 addr add fd00:6765:7068::1/64 dev tun-geph
+ip -6 route flush table 8964
+ip -6 route add default dev tun-geph table 8964
+
+ip -6 rule del table main suppress_prefixlength 0
+ip -6 rule add table main suppress_prefixlength 0
+ip -6 rule del to all lookup 8964 pref 2
+ip -6 rule add to all lookup 8964 pref 2
+
+# lift the blanket IPv6 block, since IPv6 now goes through the tunnel
+ip6tables -D OUTPUT  -j REJECT
\ No newline at end of file
@@ -6,10 +6,13 @@ ip rule del table main suppress_prefixlength 0
 ip rule add table main suppress_prefixlength 0
 ip rule del to all lookup 8964 pref 2
 ip rule add to all lookup 8964 pref 2
-iptables -t nat -D OUTPUT -p udp --dport 53 -j DNAT --to $GEPH_DNS
-iptables -t nat -D OUTPUT -p tcp --dport 53 -j DNAT --to $GEPH_DNS
-iptables -t nat -A OUTPUT -p udp --dport 53 -j DNAT --to $GEPH_DNS
-iptables -t nat -A OUTPUT -p tcp --dport 53 -j DNAT --to $GEPH_DNS
+# the client marks the few sockets it sends around the tunnel, such as those for split DNS
+ip rule del fwmark 8965 lookup main pref 1
+ip rule add fwmark 8965 lookup main pref 1
+iptables -t nat -D OUTPUT -p udp --dport 53 -m mark ! --mark 8965 -j DNAT --to $GEPH_DNS
+iptables -t nat -D OUTPUT -p tcp --dport 53 -m mark ! --mark 8965 -j DNAT --to $GEPH_DNS
+iptables -t nat -A OUTPUT -p udp --dport 53 -m mark ! --mark 8965 -j DNAT --to $GEPH_DNS
+iptables -t nat -A OUTPUT -p tcp --dport 53 -m mark ! --mark 8965 -j DNAT --to $GEPH_DNS
 
 # block ipv6 completely
 ip6tables -D OUTPUT -o lo -j ACCEPT
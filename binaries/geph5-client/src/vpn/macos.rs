@@ -17,3 +17,5 @@ pub(super) async fn packet_shuffle(
 }
 
 pub fn vpn_whitelist(addr: IpAddr) {}
+
+pub fn vpn_bypass_network(net: ipnet::IpNet) {}
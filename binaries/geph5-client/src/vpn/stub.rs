@@ -0,0 +1,33 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use anyctx::AnyCtx;
+use bytes::Bytes;
+
+use crate::Config;
+
+/// Without a VPN, there is nowhere for packets to go, so they are dropped.
+pub async fn send_vpn_packet(_ctx: &AnyCtx<Config>, _bts: Bytes) {}
+
+/// Without a VPN, no packets ever come out.
+pub async fn recv_vpn_packet(_ctx: &AnyCtx<Config>) -> Bytes {
+    futures_lite::future::pending().await
+}
+
+pub async fn vpn_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    if ctx.init().vpn {
+        anyhow::bail!("VPN mode was requested, but this client was built without VPN support")
+    }
+    futures_lite::future::pending().await
+}
+
+pub fn fake_dns_backtranslate(_ctx: &AnyCtx<Config>, _fake: Ipv4Addr) -> Option<String> {
+    None
+}
+
+pub fn vpn_whitelist(_addr: IpAddr) {
+    // noop
+}
+
+pub fn vpn_bypass_network(_net: ipnet::IpNet) {
+    // noop
+}
@@ -9,6 +9,7 @@ use anyhow::Context;
 use bytes::Bytes;
 
 use dashmap::DashSet;
+use ipnet::IpNet;
 
 use once_cell::sync::Lazy;
 use smol::channel::{Receiver, Sender};
@@ -42,7 +43,16 @@ fn up_shuffle(ctx: AnyCtx<Config>, send_captured: Sender<bytes::Bytes>) -> anyho
             let raw_pkt = handle.receive()?;
             let ip_pkt = pnet_packet::ipv4::Ipv4Packet::new(&raw_pkt)
                 .context("cannot parse packet as IPv4")?;
-            if WHITELIST.contains(&IpAddr::V4(ip_pkt.get_destination())) {
+            let dest = IpAddr::V4(ip_pkt.get_destination());
+            let tunneled = ctx
+                .init()
+                .split_tunnel
+                .as_ref()
+                .is_none_or(|split_tunnel| split_tunnel.tunnels(dest));
+            if !tunneled
+                || WHITELIST.contains(&dest)
+                || BYPASS_NETS.iter().any(|net| net.contains(&dest))
+            {
                 handle.inject(&raw_pkt, true)?;
                 anyhow::Ok(None)
             } else {
@@ -75,3 +85,10 @@ static WHITELIST: Lazy<DashSet<IpAddr>> = Lazy::new(DashSet::new);
 pub fn vpn_whitelist(addr: IpAddr) {
     WHITELIST.insert(addr);
 }
+
+static BYPASS_NETS: Lazy<DashSet<IpNet>> = Lazy::new(DashSet::new);
+
+/// Sends traffic to a whole network around the VPN, rather than through it.
+pub fn vpn_bypass_network(net: IpNet) {
+    BYPASS_NETS.insert(net);
+}
@@ -0,0 +1,34 @@
+//! What the client needs from the browser when built for it: storage on top of localStorage, and TCP connections through a WebSocket relay.
+pub mod database;
+mod ws;
+
+use once_cell::sync::OnceCell;
+use sillad::dialer::{DialerExt, DynDialer, FailingDialer};
+use wasm_bindgen::JsValue;
+
+use ws::WsDialer;
+
+/// The WebSocket relay to open TCP connections through, with `{addr}` standing for the destination.
+static WS_RELAY: OnceCell<String> = OnceCell::new();
+
+/// Sets the WebSocket relay, for as long as we run.
+pub fn set_ws_relay(relay: String) {
+    let _ = WS_RELAY.set(relay);
+}
+
+/// A dialer for a `host:port` destination, through the WebSocket relay. Without a relay, nothing can be reached.
+pub fn ws_dialer(dest_addr: &str) -> DynDialer {
+    match WS_RELAY.get() {
+        Some(relay) => WsDialer {
+            url: relay.replace("{addr}", dest_addr),
+            dest_addr: dest_addr.to_string(),
+        }
+        .dynamic(),
+        None => FailingDialer.dynamic(),
+    }
+}
+
+/// Turns an exception thrown by a browser API into an error.
+fn js_error(err: JsValue) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, format!("{err:?}"))
+}
@@ -0,0 +1,68 @@
+use anyctx::AnyCtx;
+use anyhow::Context;
+use event_listener::Event;
+use stdcode::StdcodeSerializeExt;
+use web_sys::Storage;
+
+use super::js_error;
+use crate::client::{Config, CtxField};
+
+/// The prefix of all our keys. The whole origin shares one localStorage, so this keeps clients with different caches or credentials apart, just like their separate database files do natively.
+static PREFIX: CtxField<String> = |ctx| {
+    let namespace = ctx
+        .init()
+        .cache
+        .as_ref()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| hex::encode(blake3::hash(&ctx.init().credentials.stdcode()).as_bytes()));
+    format!("geph5-persist-{namespace}/")
+};
+
+static EVENT: CtxField<Event> = |_| Event::new();
+
+fn local_storage() -> anyhow::Result<Storage> {
+    web_sys::window()
+        .context("not running in a window")?
+        .local_storage()
+        .map_err(js_error)?
+        .context("localStorage is not available")
+}
+
+fn storage_key(ctx: &AnyCtx<Config>, key: &str) -> String {
+    format!("{}{key}", ctx.get(PREFIX))
+}
+
+pub async fn db_write(ctx: &AnyCtx<Config>, key: &str, value: &[u8]) -> anyhow::Result<()> {
+    // localStorage only holds strings
+    local_storage()?
+        .set_item(&storage_key(ctx, key), &hex::encode(value))
+        .map_err(js_error)?;
+    ctx.get(EVENT).notify(usize::MAX);
+    Ok(())
+}
+
+pub async fn db_read(ctx: &AnyCtx<Config>, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    let value = local_storage()?
+        .get_item(&storage_key(ctx, key))
+        .map_err(js_error)?;
+    Ok(value.map(hex::decode).transpose()?)
+}
+
+pub async fn db_read_or_wait(ctx: &AnyCtx<Config>, key: &str) -> anyhow::Result<Vec<u8>> {
+    loop {
+        let event = ctx.get(EVENT).listen();
+        let result = db_read(ctx, key).await?;
+        match result {
+            Some(val) => return Ok(val),
+            None => event.await,
+        }
+    }
+}
+
+pub async fn db_remove(ctx: &AnyCtx<Config>, key: &str) -> anyhow::Result<()> {
+    local_storage()?
+        .remove_item(&storage_key(ctx, key))
+        .map_err(js_error)?;
+    ctx.get(EVENT).notify(usize::MAX);
+    Ok(())
+}
@@ -0,0 +1,156 @@
+use std::{
+    io::ErrorKind,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_channel::mpsc::{self, UnboundedReceiver};
+use futures_lite::{AsyncRead, AsyncWrite, StreamExt};
+use js_sys::{ArrayBuffer, Uint8Array};
+use send_wrapper::SendWrapper;
+use sillad::{dialer::Dialer, Pipe};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use web_sys::{BinaryType, MessageEvent, WebSocket};
+
+use super::js_error;
+
+/// Dials through a WebSocket relay, which opens a TCP connection to the destination for us, and then shuttles bytes between it and the WebSocket in binary messages.
+pub struct WsDialer {
+    /// The URL of the relay for this particular destination.
+    pub url: String,
+    pub dest_addr: String,
+}
+
+impl Dialer for WsDialer {
+    type P = WsPipe;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        geph5_rt::single_threaded(WsPipe::connect(&self.url, self.dest_addr.clone())).await
+    }
+}
+
+/// A connection through a WebSocket relay.
+pub struct WsPipe {
+    socket: SendWrapper<WebSocket>,
+    incoming: UnboundedReceiver<Vec<u8>>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    dest_addr: String,
+    // the socket calls into these for as long as it is open, so they have to live as long as we do
+    _handlers: SendWrapper<Vec<Closure<dyn FnMut(JsValue)>>>,
+}
+
+impl WsPipe {
+    async fn connect(url: &str, dest_addr: String) -> std::io::Result<Self> {
+        let socket = WebSocket::new(url).map_err(js_error)?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+
+        let (send_incoming, incoming) = mpsc::unbounded();
+        // whether the socket opened, or failed to
+        let (send_opened, mut opened) = mpsc::unbounded();
+        let on_message = Closure::<dyn FnMut(JsValue)>::new({
+            let send_incoming = send_incoming.clone();
+            move |event: JsValue| {
+                let data = event.unchecked_into::<MessageEvent>().data();
+                if let Ok(buffer) = data.dyn_into::<ArrayBuffer>() {
+                    let _ = send_incoming.unbounded_send(Uint8Array::new(&buffer).to_vec());
+                }
+            }
+        });
+        let on_open = Closure::<dyn FnMut(JsValue)>::new({
+            let send_opened = send_opened.clone();
+            move |_| {
+                let _ = send_opened.unbounded_send(true);
+            }
+        });
+        // an error is always followed by the socket closing, so closing is all we need to handle
+        let on_close = Closure::<dyn FnMut(JsValue)>::new(move |_| {
+            send_incoming.close_channel();
+            let _ = send_opened.unbounded_send(false);
+        });
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+        // made before waiting, so that the socket gets closed if we are cancelled
+        let pipe = Self {
+            socket: SendWrapper::new(socket),
+            incoming,
+            read_buf: vec![],
+            read_pos: 0,
+            dest_addr,
+            _handlers: SendWrapper::new(vec![on_message, on_open, on_close]),
+        };
+        if opened.next().await != Some(true) {
+            return Err(std::io::Error::new(
+                ErrorKind::ConnectionRefused,
+                "could not connect to the WebSocket relay",
+            ));
+        }
+        Ok(pipe)
+    }
+}
+
+impl Drop for WsPipe {
+    fn drop(&mut self) {
+        self.socket.set_onmessage(None);
+        self.socket.set_onopen(None);
+        self.socket.set_onclose(None);
+        let _ = self.socket.close();
+    }
+}
+
+impl AsyncRead for WsPipe {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        while self.read_pos >= self.read_buf.len() {
+            match std::task::ready!(self.incoming.poll_next(cx)) {
+                Some(message) => {
+                    self.read_buf = message;
+                    self.read_pos = 0;
+                }
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+        let n = buf.len().min(self.read_buf.len() - self.read_pos);
+        buf[..n].copy_from_slice(&self.read_buf[self.read_pos..][..n]);
+        self.read_pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for WsPipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if self.socket.ready_state() != WebSocket::OPEN {
+            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+        }
+        // the browser buffers whatever we send without ever pushing back, so the tunnel's own flow control is all that keeps this bounded
+        self.socket.send_with_u8_array(buf).map_err(js_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(self.socket.close().map_err(js_error))
+    }
+}
+
+impl Pipe for WsPipe {
+    fn protocol(&self) -> &str {
+        "ws"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        Some(&self.dest_addr)
+    }
+}
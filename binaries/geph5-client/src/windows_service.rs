@@ -0,0 +1,168 @@
+//! Integration with the Windows service control manager (SCM).
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use smol::future::FutureExt as _;
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+use winreg::{enums::HKEY_LOCAL_MACHINE, RegKey};
+
+use crate::{Client, Config};
+
+const SERVICE_NAME: &str = "geph5-client";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+const PARAMETERS_KEY: &str = r"SYSTEM\CurrentControlSet\Services\geph5-client\Parameters";
+
+/// What to do with the Windows service.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum ServiceAction {
+    /// Register the service with the SCM, remembering the given config file.
+    Install,
+    /// Stop and unregister the service.
+    Uninstall,
+    /// Start the installed service.
+    Start,
+    /// Stop the running service.
+    Stop,
+    /// Run as the service. This is what the SCM invokes; it is not meant to be used by hand.
+    Run,
+}
+
+/// Carries out a service action.
+pub fn service_command(action: ServiceAction, config: Option<&Path>) -> anyhow::Result<()> {
+    match action {
+        ServiceAction::Install => {
+            install(config.context("a config file is required to install the service")?)
+        }
+        ServiceAction::Uninstall => uninstall(),
+        ServiceAction::Start => {
+            let manager =
+                ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+            let service = manager.open_service(SERVICE_NAME, ServiceAccess::START)?;
+            service.start(&[] as &[&OsStr])?;
+            Ok(())
+        }
+        ServiceAction::Stop => {
+            let manager =
+                ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+            let service = manager.open_service(SERVICE_NAME, ServiceAccess::STOP)?;
+            service.stop()?;
+            Ok(())
+        }
+        ServiceAction::Run => {
+            service_dispatcher::start(SERVICE_NAME, ffi_service_main)?;
+            Ok(())
+        }
+    }
+}
+
+fn install(config: &Path) -> anyhow::Result<()> {
+    let config = config
+        .canonicalize()
+        .context("cannot resolve the config file path")?;
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+    let info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from("Geph5 client"),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments: vec![OsString::from("--service"), OsString::from("run")],
+        dependencies: vec![],
+        account_name: Some(OsString::from(r"NT AUTHORITY\LocalService")),
+        account_password: None,
+    };
+    manager.create_service(&info, ServiceAccess::QUERY_STATUS)?;
+    let (params, _) = RegKey::predef(HKEY_LOCAL_MACHINE).create_subkey(PARAMETERS_KEY)?;
+    params.set_value("ConfigPath", &config.to_string_lossy().to_string())?;
+    tracing::info!(config = debug(config), "installed the service");
+    Ok(())
+}
+
+fn uninstall() -> anyhow::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(
+        SERVICE_NAME,
+        ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+    )?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+    // the parameters key lives under the service's own key, so it goes away together with the service
+    service.delete()?;
+    tracing::info!("uninstalled the service");
+    Ok(())
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_args: Vec<OsString>) {
+    if let Err(err) = run_service() {
+        tracing::error!(err = debug(err), "service failed");
+    }
+}
+
+fn run_service() -> anyhow::Result<()> {
+    let (send_stop, recv_stop) = smol::channel::bounded(1);
+    let status_handle =
+        service_control_handler::register(SERVICE_NAME, move |event| match event {
+            ServiceControl::Stop => {
+                let _ = send_stop.try_send(());
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?;
+    let set_state = |state: ServiceState, exit_code: u32| {
+        status_handle.set_service_status(ServiceStatus {
+            service_type: SERVICE_TYPE,
+            current_state: state,
+            controls_accepted: if state == ServiceState::Running {
+                ServiceControlAccept::STOP
+            } else {
+                ServiceControlAccept::empty()
+            },
+            exit_code: ServiceExitCode::Win32(exit_code),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        })
+    };
+
+    let result = (|| {
+        let config_path: String = RegKey::predef(HKEY_LOCAL_MACHINE)
+            .open_subkey(PARAMETERS_KEY)?
+            .get_value("ConfigPath")?;
+        let config = load_config(&PathBuf::from(config_path))?;
+        set_state(ServiceState::Running, 0)?;
+        let client = Client::start(config);
+        smolscale::block_on(client.wait_until_dead().race(async {
+            let _ = recv_stop.recv().await;
+            tracing::info!("stop requested by the service control manager");
+            anyhow::Ok(())
+        }))
+    })();
+    set_state(ServiceState::Stopped, if result.is_ok() { 0 } else { 1 })?;
+    result
+}
+
+fn load_config(path: &Path) -> anyhow::Result<Config> {
+    let config: serde_json::Value = serde_yaml::from_slice(&std::fs::read(path)?)?;
+    Ok(serde_json::from_value(config)?)
+}
@@ -0,0 +1,352 @@
+//! A userspace WireGuard peer, so that stock WireGuard clients can tunnel through us. The protocol itself is boringtun's; we only move datagrams and packets around it. Since we learn where the client is from what it sends, the client must be the one configured with our endpoint.
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use boringtun::{
+    noise::{Tunn, TunnResult},
+    x25519::{PublicKey, StaticSecret},
+};
+use bytes::Bytes;
+use ipnet::IpNet;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use smol::{future::FutureExt as _, net::UdpSocket};
+
+use crate::{
+    client::Config,
+    vpn::{recv_vpn_packet, send_vpn_packet},
+};
+
+/// How often boringtun's timers are driven, which is how it knows to send keepalives and to rekey.
+const TIMER_TICK: Duration = Duration::from_millis(250);
+
+/// The most that WireGuard adds to a packet: the transport header, the tag, and up to 15 bytes of padding.
+const OVERHEAD: usize = 16 + 16 + 15;
+
+/// The length of a handshake initiation, the longest message that is not a packet.
+const HANDSHAKE_LEN: usize = 148;
+
+/// How to act as a WireGuard peer for a single stock WireGuard client.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WireguardConfig {
+    /// Our private key, in base64 as `wg genkey` prints it
+    pub private_key: String,
+    /// The public key of the WireGuard client, in base64
+    pub peer_public_key: String,
+    /// The preshared key, in base64, if the client is configured with one
+    #[serde(default)]
+    pub preshared_key: Option<String>,
+    /// The UDP address to listen on, such as `192.168.1.2:51820` to serve a phone on the same LAN
+    pub listen: SocketAddr,
+    /// The addresses the client may send packets from, and that packets are sent to it for, such as `10.8.0.2/32`
+    pub allowed_ips: Vec<String>,
+}
+
+/// Serves the configured WireGuard client, passing the packets it sends into the same virtual network stack that VPN mode uses, and the packets coming back out to it. Since VPN mode would take those packets for the system instead, this only works with VPN mode off.
+pub async fn wireguard_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let Some(config) = &ctx.init().wireguard else {
+        return smol::future::pending().await;
+    };
+    if ctx.init().vpn {
+        tracing::warn!("WireGuard does not work together with VPN mode, ignoring");
+        return smol::future::pending().await;
+    }
+    let peer = Peer::new(config)?;
+    let socket = UdpSocket::bind(config.listen).await?;
+    tracing::info!(
+        listen = display(config.listen),
+        "serving a WireGuard client"
+    );
+
+    // a single datagram that cannot be sent or received must not take the peer down with it
+    let up_loop = async {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let (n, src) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(err) => {
+                    tracing::debug!(err = debug(err), "could not receive WireGuard datagram");
+                    continue;
+                }
+            };
+            match peer.handle_datagram(&buf[..n], src) {
+                Ok(actions) => {
+                    for action in actions {
+                        match action {
+                            Action::ToPeer(msg) => send_datagram(&socket, &msg, src).await,
+                            Action::ToTunnel(packet) => send_vpn_packet(ctx, packet).await,
+                        }
+                    }
+                }
+                Err(err) => tracing::trace!(src = display(src), err = debug(err), "bad datagram"),
+            }
+        }
+    };
+    let down_loop = async {
+        loop {
+            let packet = recv_vpn_packet(ctx).await;
+            if let Some((msg, endpoint)) = peer.seal_packet(&packet) {
+                send_datagram(&socket, &msg, endpoint).await;
+            }
+        }
+    };
+    let timer_loop = async {
+        loop {
+            smol::Timer::after(TIMER_TICK).await;
+            if let Some((msg, endpoint)) = peer.update_timers() {
+                send_datagram(&socket, &msg, endpoint).await;
+            }
+        }
+    };
+    up_loop.race(down_loop).race(timer_loop).await
+}
+
+async fn send_datagram(socket: &UdpSocket, msg: &[u8], dest: SocketAddr) {
+    if let Err(err) = socket.send_to(msg, dest).await {
+        tracing::debug!(
+            dest = display(dest),
+            err = debug(err),
+            "could not send WireGuard datagram"
+        );
+    }
+}
+
+/// What to do after the peer sent us something.
+#[derive(Debug, PartialEq)]
+enum Action {
+    ToPeer(Vec<u8>),
+    ToTunnel(Bytes),
+}
+
+struct Peer {
+    tunn: Mutex<Tunn>,
+    allowed_ips: Vec<IpNet>,
+    /// Where the peer last sent an authenticated message from.
+    endpoint: Mutex<Option<SocketAddr>>,
+}
+
+impl Peer {
+    fn new(config: &WireguardConfig) -> anyhow::Result<Self> {
+        let key = |b64: &str| -> anyhow::Result<[u8; 32]> {
+            STANDARD
+                .decode(b64)?
+                .try_into()
+                .ok()
+                .context("WireGuard keys must be 32 bytes")
+        };
+        let tunn = Tunn::new(
+            StaticSecret::from(key(&config.private_key)?),
+            PublicKey::from(key(&config.peer_public_key)?),
+            config.preshared_key.as_deref().map(key).transpose()?,
+            None,
+            // boringtun keeps the low 8 bits of session indices for itself
+            rand::random::<u32>() >> 8,
+            None,
+        );
+        Ok(Self {
+            tunn: Mutex::new(tunn),
+            allowed_ips: config
+                .allowed_ips
+                .iter()
+                .map(|net| net.parse().context("invalid allowed IP"))
+                .collect::<anyhow::Result<_>>()?,
+            endpoint: Mutex::new(None),
+        })
+    }
+
+    fn allowed(&self, ip: IpAddr) -> bool {
+        self.allowed_ips.iter().any(|net| net.contains(&ip))
+    }
+
+    fn handle_datagram(&self, datagram: &[u8], src: SocketAddr) -> anyhow::Result<Vec<Action>> {
+        let mut tunn = self.tunn.lock();
+        let mut buf = vec![0u8; (datagram.len() + OVERHEAD).max(HANDSHAKE_LEN)];
+        let (packet, source) = match tunn.decapsulate(Some(src.ip()), datagram, &mut buf) {
+            TunnResult::WriteToNetwork(msg) => {
+                let mut actions = vec![Action::ToPeer(msg.to_vec())];
+                // packets that were waiting for the handshake go out now
+                let mut buf = vec![0u8; 65536];
+                while let TunnResult::WriteToNetwork(msg) = tunn.decapsulate(None, &[], &mut buf) {
+                    actions.push(Action::ToPeer(msg.to_vec()));
+                }
+                // replies go straight back to where the handshake came from, but we only move the endpoint once the peer sends on the session, since only then do we know it is really the peer
+                return Ok(actions);
+            }
+            TunnResult::WriteToTunnelV4(packet, source) => (packet, IpAddr::from(source)),
+            TunnResult::WriteToTunnelV6(packet, source) => (packet, IpAddr::from(source)),
+            TunnResult::Done => {
+                *self.endpoint.lock() = Some(src);
+                return Ok(vec![]);
+            }
+            TunnResult::Err(err) => anyhow::bail!("WireGuard error: {err:?}"),
+        };
+        *self.endpoint.lock() = Some(src);
+        anyhow::ensure!(
+            self.allowed(source),
+            "packet from {source}, which is not an allowed IP"
+        );
+        Ok(vec![Action::ToTunnel(Bytes::copy_from_slice(packet))])
+    }
+
+    /// Encrypts a packet for the peer, if it is for one of the allowed IPs and we know where the peer is. Without a session, this gives the handshake initiation instead, and the packet goes out once the handshake is done.
+    fn seal_packet(&self, packet: &[u8]) -> Option<(Vec<u8>, SocketAddr)> {
+        let dest = match packet.first()? >> 4 {
+            4 if packet.len() >= 20 => IpAddr::from(<[u8; 4]>::try_from(&packet[16..20]).ok()?),
+            6 if packet.len() >= 40 => IpAddr::from(<[u8; 16]>::try_from(&packet[24..40]).ok()?),
+            _ => return None,
+        };
+        if !self.allowed(dest) {
+            return None;
+        }
+        let endpoint = (*self.endpoint.lock())?;
+        let mut buf = vec![0u8; (packet.len() + OVERHEAD).max(HANDSHAKE_LEN)];
+        match self.tunn.lock().encapsulate(packet, &mut buf) {
+            TunnResult::WriteToNetwork(msg) => Some((msg.to_vec(), endpoint)),
+            _ => None,
+        }
+    }
+
+    /// Drives boringtun's timers, giving the keepalive or handshake they call for, if any.
+    fn update_timers(&self) -> Option<(Vec<u8>, SocketAddr)> {
+        let endpoint = (*self.endpoint.lock())?;
+        let mut buf = vec![0u8; HANDSHAKE_LEN];
+        match self.tunn.lock().update_timers(&mut buf) {
+            TunnResult::WriteToNetwork(msg) => Some((msg.to_vec(), endpoint)),
+            TunnResult::Err(err) => {
+                tracing::trace!(err = debug(err), "WireGuard timer error");
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER_ADDR: &str = "192.0.2.1:51820";
+
+    fn keypair(n: u8) -> (StaticSecret, PublicKey) {
+        let secret = StaticSecret::from([n; 32]);
+        let public = PublicKey::from(&secret);
+        (secret, public)
+    }
+
+    /// Our side, serving `10.8.0.2`, and a stock WireGuard client.
+    fn peers() -> (Peer, Tunn) {
+        let (our_secret, our_public) = keypair(1);
+        let (their_secret, their_public) = keypair(2);
+        let peer = Peer::new(&WireguardConfig {
+            private_key: STANDARD.encode(our_secret.to_bytes()),
+            peer_public_key: STANDARD.encode(their_public.as_bytes()),
+            preshared_key: None,
+            listen: "127.0.0.1:0".parse().unwrap(),
+            allowed_ips: vec!["10.8.0.2/32".into()],
+        })
+        .unwrap();
+        let client = Tunn::new(their_secret, our_public, None, None, 0, None);
+        (peer, client)
+    }
+
+    fn ipv4_packet(src: [u8; 4], dest: [u8; 4]) -> Vec<u8> {
+        let mut packet = vec![0u8; 28];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&28u16.to_be_bytes());
+        packet[9] = 17;
+        packet[12..16].copy_from_slice(&src);
+        packet[16..20].copy_from_slice(&dest);
+        packet
+    }
+
+    /// Has the client send a packet, handshaking first, and returns what each of its datagrams made us do.
+    fn client_sends(
+        peer: &Peer,
+        client: &mut Tunn,
+        packet: &[u8],
+    ) -> Vec<anyhow::Result<Vec<Action>>> {
+        let src = PEER_ADDR.parse().unwrap();
+        let mut buf = vec![0u8; 2048];
+        let TunnResult::WriteToNetwork(initiation) = client.encapsulate(packet, &mut buf) else {
+            panic!("client did not start a handshake");
+        };
+        let initiation = initiation.to_vec();
+        let actions = peer.handle_datagram(&initiation, src).unwrap();
+        let [Action::ToPeer(response)] = actions.as_slice() else {
+            panic!("we did not answer the handshake");
+        };
+        let mut datagrams = vec![];
+        if let TunnResult::WriteToNetwork(msg) = client.decapsulate(None, response, &mut buf) {
+            datagrams.push(msg.to_vec());
+        }
+        while let TunnResult::WriteToNetwork(msg) = client.decapsulate(None, &[], &mut buf) {
+            datagrams.push(msg.to_vec());
+        }
+        datagrams
+            .iter()
+            .map(|datagram| peer.handle_datagram(datagram, src))
+            .collect()
+    }
+
+    #[test]
+    fn stock_client_tunnels_through_us() {
+        let (peer, mut client) = peers();
+        let packet = ipv4_packet([10, 8, 0, 2], [1, 1, 1, 1]);
+        let tunneled: Vec<Action> = client_sends(&peer, &mut client, &packet)
+            .into_iter()
+            .flat_map(|actions| actions.unwrap())
+            .collect();
+        assert_eq!(tunneled, vec![Action::ToTunnel(Bytes::from(packet))]);
+
+        let reply = ipv4_packet([1, 1, 1, 1], [10, 8, 0, 2]);
+        let (sealed, endpoint) = peer.seal_packet(&reply).unwrap();
+        assert_eq!(endpoint, PEER_ADDR.parse().unwrap());
+        let mut buf = vec![0u8; 2048];
+        match client.decapsulate(None, &sealed, &mut buf) {
+            TunnResult::WriteToTunnelV4(opened, _) => assert_eq!(opened, reply.as_slice()),
+            _ => panic!("client could not open our reply"),
+        }
+    }
+
+    #[test]
+    fn only_allowed_ips_pass() {
+        let (peer, mut client) = peers();
+        let spoofed = ipv4_packet([10, 8, 0, 3], [1, 1, 1, 1]);
+        let results = client_sends(&peer, &mut client, &spoofed);
+        assert!(results.iter().any(|result| result.is_err()));
+        assert!(results
+            .iter()
+            .flatten()
+            .flatten()
+            .all(|action| !matches!(action, Action::ToTunnel(_))));
+        // the peer is known now, but not for this address
+        assert!(peer
+            .seal_packet(&ipv4_packet([1, 1, 1, 1], [10, 8, 0, 3]))
+            .is_none());
+    }
+
+    #[test]
+    fn strangers_are_ignored() {
+        let (peer, _) = peers();
+        let (stranger_secret, _) = keypair(3);
+        let mut stranger = Tunn::new(stranger_secret, keypair(1).1, None, None, 0, None);
+        let mut buf = vec![0u8; 2048];
+        let TunnResult::WriteToNetwork(initiation) =
+            stranger.encapsulate(&ipv4_packet([10, 8, 0, 2], [1, 1, 1, 1]), &mut buf)
+        else {
+            panic!("stranger did not start a handshake");
+        };
+        let src = PEER_ADDR.parse().unwrap();
+        assert!(peer.handle_datagram(initiation, src).is_err());
+        assert!(peer.handle_datagram(b"garbage", src).is_err());
+        // nothing authenticated came in, so we have nowhere to send to
+        assert!(peer
+            .seal_packet(&ipv4_packet([1, 1, 1, 1], [10, 8, 0, 2]))
+            .is_none());
+    }
+}
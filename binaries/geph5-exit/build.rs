@@ -0,0 +1,17 @@
+fn main() {
+    #[cfg(feature = "ebpf")]
+    compile_classifier();
+}
+
+/// Compiles the eBPF classifier, which needs clang, into an object that the exit embeds and loads at runtime.
+#[cfg(feature = "ebpf")]
+fn compile_classifier() {
+    const SOURCE: &str = "src/bpf/classify.bpf.c";
+    let object = std::path::Path::new(&std::env::var_os("OUT_DIR").unwrap()).join("classify.bpf.o");
+    libbpf_cargo::SkeletonBuilder::new()
+        .source(SOURCE)
+        .obj(&object)
+        .build()
+        .expect("cannot compile the eBPF classifier");
+    println!("cargo:rerun-if-changed={SOURCE}");
+}
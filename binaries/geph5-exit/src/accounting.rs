@@ -0,0 +1,210 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use geph5_broker_protocol::AccountLevel;
+use moka::future::Cache;
+use once_cell::sync::Lazy;
+use rusqlite::{params, Connection, OptionalExtension};
+use smol::lock::OnceCell;
+
+use crate::{ratelimit::RateLimiter, CONFIG_FILE};
+
+/// How often the bytes counted in memory are written to the database.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The usage database, opened on first use. It is only ever touched from blocking threads.
+static DATABASE: OnceCell<Mutex<Connection>> = OnceCell::new();
+
+/// The usage of recently active users, keyed by the hash of their connect token. Entries idle for long have long been flushed, so they can go.
+static USAGE: Lazy<Cache<[u8; 32], Arc<Usage>>> = Lazy::new(|| {
+    Cache::builder()
+        .time_to_idle(Duration::from_secs(3600))
+        .build()
+});
+
+/// How much one user has transferred in the current accounting period. The counters stay the same across periods, since the rate limiters of open connections keep counting into them.
+struct Usage {
+    /// The accounting period that the counters are for.
+    period: Mutex<u64>,
+    /// Everything transferred in the period, including what is already in the database.
+    total: Arc<AtomicU64>,
+    /// What has not yet been written to the database.
+    unflushed: Arc<AtomicU64>,
+}
+
+impl Usage {
+    /// Takes the bytes not yet written to the database, along with the period they belong to. If the given period is a new one, the usage starts over from zero.
+    fn take_unflushed(&self, current_period: u64) -> (u64, u64) {
+        let mut period = self.period.lock().unwrap();
+        let taken = (*period, self.unflushed.swap(0, Ordering::Relaxed));
+        if *period != current_period {
+            *period = current_period;
+            self.total.store(0, Ordering::Relaxed);
+        }
+        taken
+    }
+}
+
+/// The bytes a user may still transfer in the current accounting period. Every byte let through the rate limiter it wraps counts against the cap.
+pub struct DataCap {
+    level: AccountLevel,
+    usage: Arc<Usage>,
+}
+
+impl DataCap {
+    /// Looks up the usage of the user with the given connect token hash. This returns `None` when data caps are not configured.
+    pub async fn lookup(level: AccountLevel, token_hash: [u8; 32]) -> anyhow::Result<Option<Self>> {
+        let Some(db) = database().await? else {
+            return Ok(None);
+        };
+        let usage = USAGE
+            .try_get_with(token_hash, async {
+                let period = current_period();
+                let flushed: i64 = smol::unblock(move || {
+                    db.lock()
+                        .unwrap()
+                        .query_row(
+                            "SELECT bytes FROM usage WHERE user = ?1 AND period = ?2",
+                            params![hex::encode(token_hash), period as i64],
+                            |row| row.get(0),
+                        )
+                        .optional()
+                })
+                .await?
+                .unwrap_or_default();
+                anyhow::Ok(Arc::new(Usage {
+                    period: Mutex::new(period),
+                    total: Arc::new(AtomicU64::new(flushed as u64)),
+                    unflushed: Arc::new(AtomicU64::new(0)),
+                }))
+            })
+            .await
+            .map_err(|err| anyhow::anyhow!("cannot look up usage: {err}"))?;
+        // a new period starts from zero, once the old one's leftovers are flushed
+        if *usage.period.lock().unwrap() != current_period() {
+            flush_one(db, token_hash, &usage).await?;
+        }
+        Ok(Some(Self { level, usage }))
+    }
+
+    /// Whether the user has used up its cap for the period. Usage left over from a period that just ended does not count.
+    pub fn exceeded(&self) -> bool {
+        let config = CONFIG_FILE.wait();
+        let cap_mb = match self.level {
+            AccountLevel::Free => config.free_data_cap_mb,
+            AccountLevel::Plus => config.plus_data_cap_mb,
+        };
+        cap_mb.is_some_and(|cap_mb| {
+            *self.usage.period.lock().unwrap() == current_period()
+                && self.usage.total.load(Ordering::Relaxed) >= cap_mb.saturating_mul(1_000_000)
+        })
+    }
+
+    /// Makes the rate limiter count everything it lets through against the cap.
+    pub fn counting(&self, ratelimit: RateLimiter) -> RateLimiter {
+        ratelimit
+            .counting(self.usage.total.clone())
+            .counting(self.usage.unflushed.clone())
+    }
+}
+
+/// Periodically writes the bytes counted in memory to the database, and forgets the usage of periods long past.
+pub async fn accounting_loop() -> anyhow::Result<()> {
+    let Some(db) = database().await? else {
+        return smol::future::pending().await;
+    };
+    loop {
+        smol::Timer::after(FLUSH_INTERVAL).await;
+        for (token_hash, usage) in USAGE.iter() {
+            flush_one(db, *token_hash, &usage).await?;
+        }
+        let oldest = current_period() as i64 - 1;
+        smol::unblock(move || {
+            db.lock()
+                .unwrap()
+                .execute("DELETE FROM usage WHERE period < ?1", params![oldest])
+        })
+        .await?;
+    }
+}
+
+/// Writes what a user transferred to the database, which also starts a new period's usage over if one has begun.
+async fn flush_one(
+    db: &'static Mutex<Connection>,
+    token_hash: [u8; 32],
+    usage: &Usage,
+) -> anyhow::Result<()> {
+    let (period, bytes) = usage.take_unflushed(current_period());
+    if bytes == 0 {
+        return Ok(());
+    }
+    smol::unblock(move || {
+        db.lock().unwrap().execute(
+            "INSERT INTO usage (user, period, bytes) VALUES (?1, ?2, ?3)
+            ON CONFLICT(user, period) DO UPDATE SET bytes = bytes + excluded.bytes",
+            params![hex::encode(token_hash), period as i64, bytes as i64],
+        )
+    })
+    .await?;
+    Ok(())
+}
+
+/// The number of the current accounting period, counting from the Unix epoch.
+fn current_period() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    now / CONFIG_FILE.wait().data_cap_period_secs.max(1)
+}
+
+async fn database() -> anyhow::Result<Option<&'static Mutex<Connection>>> {
+    let Some(path) = &CONFIG_FILE.wait().data_cap_db_path else {
+        return Ok(None);
+    };
+    DATABASE
+        .get_or_try_init(|| {
+            smol::unblock(move || {
+                let conn = Connection::open(path)?;
+                conn.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS usage (
+                        user TEXT NOT NULL,
+                        period INTEGER NOT NULL,
+                        bytes INTEGER NOT NULL,
+                        PRIMARY KEY (user, period)
+                    );",
+                )?;
+                anyhow::Ok(Mutex::new(conn))
+            })
+        })
+        .await
+        .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage_starts_over_each_period() {
+        let usage = Usage {
+            period: Mutex::new(7),
+            total: Arc::new(AtomicU64::new(5000)),
+            unflushed: Arc::new(AtomicU64::new(300)),
+        };
+        assert_eq!(usage.take_unflushed(7), (7, 300));
+        assert_eq!(usage.total.load(Ordering::Relaxed), 5000);
+
+        // bytes counted at the end of a period are written to that period, not the next
+        usage.unflushed.fetch_add(200, Ordering::Relaxed);
+        assert_eq!(usage.take_unflushed(8), (7, 200));
+        assert_eq!(usage.total.load(Ordering::Relaxed), 0);
+        assert_eq!(*usage.period.lock().unwrap(), 8);
+        assert_eq!(usage.take_unflushed(8), (8, 0));
+    }
+}
@@ -0,0 +1,317 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use async_compat::CompatExt;
+use futures_util::{AsyncBufReadExt, AsyncWriteExt};
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, LetsEncrypt,
+    NewAccount, NewOrder, OrderStatus,
+};
+use rcgen::{CertificateParams, DistinguishedName, KeyPair};
+use sillad_quic::QuicCertReloader;
+use smol::{future::FutureExt, io::BufReader, net::TcpListener};
+use smol_timeout2::TimeoutExt;
+
+use crate::AcmeConfig;
+
+/// Certificates are renewed once they are this old. Let's Encrypt issues them for 90 days, so this leaves a month to retry in.
+const RENEW_AFTER: Duration = Duration::from_secs(60 * 86400);
+
+/// How often we check whether the certificate is due for renewal, which is also how soon a failed renewal is retried.
+const RENEW_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How long obtaining a certificate may take altogether.
+const ORDER_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The port the certificate authority connects to for HTTP-01 challenges.
+const CHALLENGE_PORT: u16 = 80;
+
+/// Where HTTP-01 challenge tokens are requested.
+const CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+fn cert_path(acme: &AcmeConfig) -> PathBuf {
+    acme.cache_dir.join("cert.pem")
+}
+
+fn key_path(acme: &AcmeConfig) -> PathBuf {
+    acme.cache_dir.join("key.pem")
+}
+
+fn account_path(acme: &AcmeConfig) -> PathBuf {
+    acme.cache_dir.join("account.json")
+}
+
+/// Gets the certificate chain and private key for the configured domain, in PEM format. The cached ones are used unless they are missing or due for renewal, in which case new ones are obtained, falling back to the cached ones if that fails.
+pub async fn acme_cert(acme: &AcmeConfig) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    if cert_age(acme).await.is_some_and(|age| age < RENEW_AFTER) {
+        return read_cached(acme).await;
+    }
+    match obtain_cert(acme).await {
+        Ok(cert) => Ok(cert),
+        Err(err) => {
+            let cached = read_cached(acme).await;
+            if cached.is_err() {
+                return Err(err);
+            }
+            tracing::warn!(
+                err = debug(err),
+                "could not renew the QUIC certificate, using the cached one for now"
+            );
+            cached
+        }
+    }
+}
+
+/// Renews the certificate once it is due, and has the listener present the new one, for as long as we run.
+pub async fn acme_renew_loop(acme: &AcmeConfig, reloader: QuicCertReloader) {
+    loop {
+        smol::Timer::after(RENEW_CHECK_INTERVAL).await;
+        if cert_age(acme).await.is_some_and(|age| age < RENEW_AFTER) {
+            continue;
+        }
+        let renewed = async {
+            let (cert_pem, key_pem) = obtain_cert(acme).await?;
+            reloader.reload(&cert_pem, &key_pem)
+        };
+        match renewed.await {
+            // clients pin the hash, so they need to hear about the new one
+            Ok(cert_hash) => tracing::info!(
+                domain = acme.domain,
+                cert_hash = hex::encode(cert_hash),
+                "renewed the QUIC certificate"
+            ),
+            Err(err) => tracing::warn!(
+                err = debug(err),
+                "could not renew the QUIC certificate, retrying later"
+            ),
+        }
+    }
+}
+
+/// How long ago the cached certificate was obtained, going by when it was written.
+async fn cert_age(acme: &AcmeConfig) -> Option<Duration> {
+    smol::fs::metadata(cert_path(acme))
+        .await
+        .ok()?
+        .modified()
+        .ok()?
+        .elapsed()
+        .ok()
+}
+
+async fn read_cached(acme: &AcmeConfig) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let cert_pem = smol::fs::read(cert_path(acme))
+        .await
+        .context("cannot read cached ACME certificate")?;
+    let key_pem = smol::fs::read(key_path(acme))
+        .await
+        .context("cannot read cached ACME private key")?;
+    Ok((cert_pem, key_pem))
+}
+
+/// Obtains a new certificate for the configured domain over HTTP-01, caching it along with its key.
+async fn obtain_cert(acme: &AcmeConfig) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    tracing::info!(domain = acme.domain, "obtaining a certificate over ACME");
+    smol::fs::create_dir_all(&acme.cache_dir)
+        .await
+        .context("cannot create the ACME cache directory")?;
+    let (cert_pem, key_pem) = order_cert(acme)
+        // instant-acme talks HTTP through hyper, which needs a tokio reactor
+        .compat()
+        .timeout(ORDER_TIMEOUT)
+        .await
+        .context("timed out obtaining a certificate")??;
+    // the key goes first, since the certificate's age is what says whether both are due for renewal
+    smol::fs::write(key_path(acme), &key_pem).await?;
+    smol::fs::write(cert_path(acme), &cert_pem).await?;
+    Ok((cert_pem, key_pem))
+}
+
+async fn order_cert(acme: &AcmeConfig) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let account = account(acme).await?;
+    let mut order = account
+        .new_order(&NewOrder {
+            identifiers: &[Identifier::Dns(acme.domain.clone())],
+        })
+        .await?;
+
+    let mut key_authorizations = HashMap::new();
+    let mut challenge_urls = vec![];
+    for authorization in order.authorizations().await? {
+        match authorization.status {
+            AuthorizationStatus::Pending => {}
+            AuthorizationStatus::Valid => continue,
+            status => anyhow::bail!("ACME authorization is {status:?}"),
+        }
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.r#type == ChallengeType::Http01)
+            .context("no HTTP-01 challenge offered")?;
+        key_authorizations.insert(
+            challenge.token.clone(),
+            order.key_authorization(challenge).as_str().to_string(),
+        );
+        challenge_urls.push(challenge.url.clone());
+    }
+
+    let finish = async {
+        for url in challenge_urls.iter() {
+            order.set_challenge_ready(url).await?;
+        }
+        let mut delay = Duration::from_millis(500);
+        loop {
+            smol::Timer::after(delay).await;
+            let state = order.refresh().await?;
+            match state.status {
+                OrderStatus::Ready => break,
+                OrderStatus::Invalid => anyhow::bail!("ACME order became invalid"),
+                _ => delay = (delay * 2).min(Duration::from_secs(10)),
+            }
+        }
+
+        let key_pair = KeyPair::generate()?;
+        let mut params = CertificateParams::new(vec![acme.domain.clone()])?;
+        params.distinguished_name = DistinguishedName::new();
+        let csr = params.serialize_request(&key_pair)?;
+        order.finalize(csr.der()).await?;
+        let cert_pem = loop {
+            if let Some(cert_pem) = order.certificate().await? {
+                break cert_pem;
+            }
+            smol::Timer::after(Duration::from_secs(1)).await;
+        };
+        anyhow::Ok((cert_pem.into_bytes(), key_pair.serialize_pem().into_bytes()))
+    };
+    // the challenge listener only lives as long as the order does
+    if key_authorizations.is_empty() {
+        finish.await
+    } else {
+        finish
+            .or(serve_challenges(Arc::new(key_authorizations)))
+            .await
+    }
+}
+
+/// Loads the cached ACME account, or registers a new one with the configured email address.
+async fn account(acme: &AcmeConfig) -> anyhow::Result<Account> {
+    if let Ok(credentials) = smol::fs::read(account_path(acme)).await {
+        let credentials: AccountCredentials =
+            serde_json::from_slice(&credentials).context("bad cached ACME account")?;
+        return Ok(Account::from_credentials(credentials).await?);
+    }
+    let (account, credentials) = Account::create(
+        &NewAccount {
+            contact: &[&format!("mailto:{}", acme.email)],
+            terms_of_service_agreed: true,
+            only_return_existing: false,
+        },
+        LetsEncrypt::Production.url(),
+        None,
+    )
+    .await?;
+    smol::fs::write(account_path(acme), serde_json::to_vec(&credentials)?).await?;
+    Ok(account)
+}
+
+/// Answers HTTP-01 challenges on port 80 until cancelled. Errors binding are returned, while those of individual requests are only logged.
+async fn serve_challenges(
+    key_authorizations: Arc<HashMap<String, String>>,
+) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    // an IPv6 socket also takes IPv4 connections, unless the host has no IPv6 at all
+    let listener =
+        match TcpListener::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, CHALLENGE_PORT))).await {
+            Ok(listener) => listener,
+            Err(_) => TcpListener::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, CHALLENGE_PORT)))
+                .await
+                .context("cannot listen on port 80 for ACME challenges")?,
+        };
+    loop {
+        let (conn, _) = listener.accept().await?;
+        let key_authorizations = key_authorizations.clone();
+        // the certificate authority validates from several places at once
+        smolscale::spawn(async move {
+            if let Err(err) = answer_challenge(conn, &key_authorizations)
+                .timeout(Duration::from_secs(10))
+                .await
+                .unwrap_or_else(|| Err(anyhow::anyhow!("timed out")))
+            {
+                tracing::debug!(err = debug(err), "ACME challenge connection failed");
+            }
+        })
+        .detach();
+    }
+}
+
+async fn answer_challenge(
+    conn: smol::net::TcpStream,
+    key_authorizations: &HashMap<String, String>,
+) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(conn.clone());
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = challenge_response(parts.next(), parts.next(), key_authorizations);
+    let mut conn = conn;
+    conn.write_all(
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+        .as_bytes(),
+    )
+    .await?;
+    conn.flush().await?;
+    Ok(())
+}
+
+/// Decides the answer to a request for an HTTP-01 challenge, as a status line and body.
+fn challenge_response<'a>(
+    method: Option<&str>,
+    path: Option<&str>,
+    key_authorizations: &'a HashMap<String, String>,
+) -> (&'static str, &'a str) {
+    let key_authorization = path
+        .and_then(|path| path.strip_prefix(CHALLENGE_PREFIX))
+        .and_then(|token| key_authorizations.get(token));
+    match (method, key_authorization) {
+        (Some("GET"), Some(key_authorization)) => ("200 OK", key_authorization),
+        _ => ("404 Not Found", "not found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_only_known_tokens() {
+        let key_authorizations =
+            HashMap::from([("token".to_string(), "token.thumbprint".to_string())]);
+        let respond =
+            |method, path| challenge_response(Some(method), Some(path), &key_authorizations);
+        assert_eq!(
+            respond("GET", "/.well-known/acme-challenge/token"),
+            ("200 OK", "token.thumbprint")
+        );
+        assert_eq!(
+            respond("GET", "/.well-known/acme-challenge/other").0,
+            "404 Not Found"
+        );
+        assert_eq!(respond("GET", "/token").0, "404 Not Found");
+        assert_eq!(
+            respond("POST", "/.well-known/acme-challenge/token").0,
+            "404 Not Found"
+        );
+        assert_eq!(
+            challenge_response(None, None, &key_authorizations).0,
+            "404 Not Found"
+        );
+    }
+}
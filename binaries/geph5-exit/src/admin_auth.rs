@@ -0,0 +1,127 @@
+use anyhow::Context;
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+
+/// The issuer that admin JWTs must carry.
+const ADMIN_ISSUER: &str = "geph5-admin";
+
+/// Checks the value of an `Authorization` header against the admin JWT secret. The token must be an HS256 JWT signed with the secret, issued by `geph5-admin`, and not yet expired.
+pub fn verify_admin_auth(authorization: Option<&str>, secret: &str) -> anyhow::Result<()> {
+    let token = authorization
+        .context("no authorization header")?
+        .strip_prefix("Bearer ")
+        .context("not a bearer token")?
+        .trim();
+    // only HS256 is accepted, whatever the token's header claims
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.set_issuer(&[ADMIN_ISSUER]);
+    validation.set_required_spec_claims(&["exp", "iss"]);
+    validation.leeway = 0;
+    decode::<serde_json::Value>(
+        token,
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &validation,
+    )
+    .context("invalid admin JWT")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+
+    const SECRET: &str = "correct horse battery staple";
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn token(alg: Algorithm, secret: &str, claims: serde_json::Value) -> String {
+        let token = encode(
+            &Header::new(alg),
+            &claims,
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap();
+        format!("Bearer {token}")
+    }
+
+    #[test]
+    fn accepts_valid_token() {
+        let auth = token(
+            Algorithm::HS256,
+            SECRET,
+            json!({"exp": now() + 60, "iss": ADMIN_ISSUER}),
+        );
+        verify_admin_auth(Some(&auth), SECRET).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_signature() {
+        let auth = token(
+            Algorithm::HS256,
+            "some other secret",
+            json!({"exp": now() + 60, "iss": ADMIN_ISSUER}),
+        );
+        assert!(verify_admin_auth(Some(&auth), SECRET).is_err());
+        // a valid token with its signature cut off
+        let auth = token(
+            Algorithm::HS256,
+            SECRET,
+            json!({"exp": now() + 60, "iss": ADMIN_ISSUER}),
+        );
+        let unsigned = &auth[..auth.rfind('.').unwrap() + 1];
+        assert!(verify_admin_auth(Some(unsigned), SECRET).is_err());
+    }
+
+    #[test]
+    fn rejects_other_algorithms() {
+        let auth = token(
+            Algorithm::HS512,
+            SECRET,
+            json!({"exp": now() + 60, "iss": ADMIN_ISSUER}),
+        );
+        assert!(verify_admin_auth(Some(&auth), SECRET).is_err());
+        // {"alg":"none","typ":"JWT"} with far-future claims and no signature
+        let none = "Bearer eyJhbGciOiJub25lIiwidHlwIjoiSldUIn0.eyJleHAiOjMyNTAzNjgwMDAwLCJpc3MiOiJnZXBoNS1hZG1pbiJ9.";
+        assert!(verify_admin_auth(Some(none), SECRET).is_err());
+    }
+
+    #[test]
+    fn rejects_expired_token() {
+        let auth = token(
+            Algorithm::HS256,
+            SECRET,
+            json!({"exp": now() - 1, "iss": ADMIN_ISSUER}),
+        );
+        assert!(verify_admin_auth(Some(&auth), SECRET).is_err());
+        let auth = token(Algorithm::HS256, SECRET, json!({"iss": ADMIN_ISSUER}));
+        assert!(verify_admin_auth(Some(&auth), SECRET).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_issuer() {
+        let auth = token(
+            Algorithm::HS256,
+            SECRET,
+            json!({"exp": now() + 60, "iss": "someone-else"}),
+        );
+        assert!(verify_admin_auth(Some(&auth), SECRET).is_err());
+        let auth = token(Algorithm::HS256, SECRET, json!({"exp": now() + 60}));
+        assert!(verify_admin_auth(Some(&auth), SECRET).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_or_malformed_header() {
+        assert!(verify_admin_auth(None, SECRET).is_err());
+        assert!(verify_admin_auth(Some("Basic YWRtaW46YWRtaW4="), SECRET).is_err());
+        assert!(verify_admin_auth(Some("Bearer not.a.jwt"), SECRET).is_err());
+    }
+}
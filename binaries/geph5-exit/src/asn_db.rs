@@ -0,0 +1,124 @@
+use std::{collections::BTreeMap, io::BufRead, net::IpAddr};
+
+use anyhow::Context;
+use flate2::read::GzDecoder;
+
+use crate::CONFIG_FILE;
+
+/// The iptoasn.com database covering both IPv4 and IPv6, downloaded when no local copy is configured.
+const COMBINED_DB_URL: &str = "https://iptoasn.com/data/ip2asn-combined.tsv.gz";
+
+/// A mapping from IP ranges to the ASN and country they are announced from, in the iptoasn.com format.
+///
+/// IPv4 and IPv6 ranges share one keyspace, with IPv4 addresses mapped into IPv6.
+pub struct AsnDb {
+    /// The end of each range, mapping to its start, ASN and country.
+    ranges: BTreeMap<u128, (u128, u32, String)>,
+}
+
+impl AsnDb {
+    /// Loads the database from the configured path, or downloads the combined database.
+    pub async fn load() -> anyhow::Result<Self> {
+        let bytes = if let Some(path) = &CONFIG_FILE.wait().asn_db_path {
+            smol::fs::read(path)
+                .await
+                .with_context(|| format!("cannot read ASN database at {}", path.display()))?
+        } else {
+            let response = reqwest::get(COMBINED_DB_URL).await?;
+            response.bytes().await?.to_vec()
+        };
+
+        // local copies may well have been decompressed already
+        let reader: Box<dyn BufRead> = if bytes.starts_with(&[0x1f, 0x8b]) {
+            Box::new(std::io::BufReader::new(GzDecoder::new(&bytes[..])))
+        } else {
+            Box::new(&bytes[..])
+        };
+        Self::parse(reader)
+    }
+
+    /// Parses a database in any of the iptoasn.com TSV formats: ip2asn-v4-u32, with ranges as integers, or ip2asn-v4, ip2asn-v6 and ip2asn-combined, with ranges as addresses.
+    pub fn parse(reader: impl BufRead) -> anyhow::Result<Self> {
+        let mut ranges = BTreeMap::new();
+        for line in reader.lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 4 {
+                continue;
+            }
+            let range_start = parse_bound(fields[0])?;
+            let range_end = parse_bound(fields[1])?;
+            let as_number: u32 = fields[2].parse()?;
+            let country_code = fields[3].to_string();
+            ranges.insert(range_end, (range_start, as_number, country_code));
+        }
+        Ok(Self { ranges })
+    }
+
+    /// Looks up the ASN and country of an IP address.
+    pub fn lookup(&self, ip: IpAddr) -> Option<(u32, &str)> {
+        let key = ip_key(ip);
+        let (_, (range_start, asn, country)) = self.ranges.range(key..).next()?;
+        if *range_start > key {
+            return None;
+        }
+        Some((*asn, country.as_str()))
+    }
+
+    /// The number of ranges in the database.
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+}
+
+fn parse_bound(field: &str) -> anyhow::Result<u128> {
+    if let Ok(ip) = field.parse::<u32>() {
+        return Ok(ip_key(IpAddr::V4(ip.into())));
+    }
+    let ip: IpAddr = field
+        .parse()
+        .with_context(|| format!("bad range bound {field:?} in ASN database"))?;
+    Ok(ip_key(ip))
+}
+
+fn ip_key(ip: IpAddr) -> u128 {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().to_bits(),
+        IpAddr::V6(ip) => ip.to_bits(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMBINED: &str = "1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET\n\
+        1.0.1.0\t1.0.3.255\t0\tNone\tNot routed\n\
+        2001:db8::\t2001:db8:ffff:ffff:ffff:ffff:ffff:ffff\t64496\tDE\tEXAMPLE\n";
+
+    #[test]
+    fn looks_up_both_families() {
+        let db = AsnDb::parse(COMBINED.as_bytes()).unwrap();
+        assert_eq!(db.len(), 3);
+        assert_eq!(db.lookup("1.0.0.7".parse().unwrap()), Some((13335, "US")));
+        assert_eq!(
+            db.lookup("2001:db8::1".parse().unwrap()),
+            Some((64496, "DE"))
+        );
+        // clients reaching a dual-stack socket over IPv4 show up mapped
+        assert_eq!(
+            db.lookup("::ffff:1.0.0.7".parse().unwrap()),
+            Some((13335, "US"))
+        );
+        // addresses in a gap between ranges belong to no ASN
+        assert_eq!(db.lookup("2001:db7::1".parse().unwrap()), None);
+        assert_eq!(db.lookup("9.9.9.9".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn reads_the_u32_format() {
+        let db = AsnDb::parse("16777216\t16777471\t13335\tUS\tCLOUDFLARENET\n".as_bytes()).unwrap();
+        assert_eq!(db.lookup("1.0.0.200".parse().unwrap()), Some((13335, "US")));
+        assert_eq!(db.lookup("1.0.1.0".parse().unwrap()), None);
+    }
+}
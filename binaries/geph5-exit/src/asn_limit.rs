@@ -0,0 +1,80 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use once_cell::sync::Lazy;
+
+use crate::CONFIG_FILE;
+
+/// How many client connections are currently open from each ASN.
+static ASN_CONNS: Lazy<Mutex<HashMap<u32, u32>>> = Lazy::new(Default::default);
+
+/// Counts one open connection from an ASN against its limit, until dropped.
+pub struct AsnConnGuard(u32);
+
+impl AsnConnGuard {
+    /// Admits a new connection from the given ASN, returning None if the ASN is already at its configured limit.
+    pub fn admit(asn: u32) -> Option<Self> {
+        Self::admit_with_limit(asn, CONFIG_FILE.wait().asn_rate_limits.get(&asn).copied())
+    }
+
+    fn admit_with_limit(asn: u32, limit: Option<u32>) -> Option<Self> {
+        let mut conns = ASN_CONNS.lock().unwrap();
+        let count = conns.entry(asn).or_default();
+        if limit.is_some_and(|limit| *count >= limit) {
+            return None;
+        }
+        *count += 1;
+        Some(Self(asn))
+    }
+}
+
+impl Drop for AsnConnGuard {
+    fn drop(&mut self) {
+        let mut conns = ASN_CONNS.lock().unwrap();
+        if let Some(count) = conns.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                conns.remove(&self.0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_conns(asn: u32) -> Option<u32> {
+        ASN_CONNS.lock().unwrap().get(&asn).copied()
+    }
+
+    #[test]
+    fn admits_up_to_the_limit() {
+        // each test uses its own ASN, since the counts are global
+        let asn = 64500;
+        let first = AsnConnGuard::admit_with_limit(asn, Some(2)).unwrap();
+        let second = AsnConnGuard::admit_with_limit(asn, Some(2)).unwrap();
+        assert!(AsnConnGuard::admit_with_limit(asn, Some(2)).is_none());
+        assert_eq!(open_conns(asn), Some(2));
+
+        // closing a connection frees up its slot
+        drop(first);
+        assert_eq!(open_conns(asn), Some(1));
+        let third = AsnConnGuard::admit_with_limit(asn, Some(2)).unwrap();
+        assert!(AsnConnGuard::admit_with_limit(asn, Some(2)).is_none());
+
+        drop(second);
+        drop(third);
+        assert_eq!(open_conns(asn), None);
+    }
+
+    #[test]
+    fn unlimited_asns_are_still_counted() {
+        let asn = 64501;
+        let guards: Vec<_> = (0..100)
+            .map(|_| AsnConnGuard::admit_with_limit(asn, None).unwrap())
+            .collect();
+        assert_eq!(open_conns(asn), Some(100));
+        drop(guards);
+        assert_eq!(open_conns(asn), None);
+    }
+}
@@ -0,0 +1,291 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use smol::channel::Sender;
+
+use crate::CONFIG_FILE;
+
+/// How many audit records may wait to be written before we start dropping them.
+const QUEUE_LEN: usize = 10000;
+
+/// Records waiting to be written to the audit log, or None if auditing is off.
+static AUDIT_QUEUE: Lazy<Option<Sender<AuditRecord>>> = Lazy::new(|| {
+    let path = CONFIG_FILE.wait().audit_log.clone()?;
+    let (send, recv) = smol::channel::bounded::<AuditRecord>(QUEUE_LEN);
+    // writing files blocks, so it gets a thread of its own
+    std::thread::spawn(move || {
+        let mut writer = RotatingWriter::new(path);
+        while let Ok(record) = recv.recv_blocking() {
+            if let Err(err) = writer.write(&record) {
+                tracing::warn!(err = debug(err), "could not write to the audit log");
+            }
+        }
+    });
+    Some(send)
+});
+
+/// One line of the audit log, describing one proxied stream.
+#[derive(Serialize)]
+struct AuditRecord {
+    /// When the stream started, in seconds since the Unix epoch
+    timestamp: u64,
+    /// The blake3 hash, in hex, of the client's connect token, so that streams can be tied to a user without keeping the credential
+    token_hash: Option<String>,
+    /// Where the client connected from, unless it came through a bridge
+    client_addr: Option<SocketAddr>,
+    /// The destination as the client asked for it
+    dest_host: String,
+    /// The address we actually connected to, once known
+    dest_addr: Option<SocketAddr>,
+    bytes: u64,
+    duration_secs: f64,
+}
+
+/// Audits one proxied stream, writing its record once dropped.
+pub struct StreamAudit {
+    timestamp: u64,
+    start: Instant,
+    token_hash: Option<String>,
+    client_addr: Option<SocketAddr>,
+    dest_host: String,
+    dest_addr: Mutex<Option<SocketAddr>>,
+    bytes: Arc<AtomicU64>,
+}
+
+impl StreamAudit {
+    /// Starts auditing a stream to the given destination, if the `audit_log` is configured.
+    pub fn start(
+        token_hash: Option<blake3::Hash>,
+        client_addr: Option<SocketAddr>,
+        dest_host: &str,
+    ) -> Option<Self> {
+        AUDIT_QUEUE.as_ref()?;
+        Some(Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            start: Instant::now(),
+            token_hash: token_hash.map(|hash| hash.to_hex().to_string()),
+            client_addr,
+            dest_host: dest_host.to_string(),
+            dest_addr: Mutex::new(None),
+            bytes: Default::default(),
+        })
+    }
+
+    /// The counter that the stream's rate limiter should add transferred bytes to.
+    pub fn counter(&self) -> Arc<AtomicU64> {
+        self.bytes.clone()
+    }
+
+    /// Records the address the stream actually went to.
+    pub fn set_dest_addr(&self, addr: SocketAddr) {
+        *self.dest_addr.lock().unwrap() = Some(addr);
+    }
+}
+
+impl Drop for StreamAudit {
+    fn drop(&mut self) {
+        let Some(queue) = AUDIT_QUEUE.as_ref() else {
+            return;
+        };
+        let record = AuditRecord {
+            timestamp: self.timestamp,
+            token_hash: self.token_hash.take(),
+            client_addr: self.client_addr,
+            dest_host: std::mem::take(&mut self.dest_host),
+            dest_addr: *self.dest_addr.lock().unwrap(),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            duration_secs: self.start.elapsed().as_secs_f64(),
+        };
+        if queue.try_send(record).is_err() {
+            tracing::warn!("audit log queue full, dropping a record");
+        }
+    }
+}
+
+/// Writes JSON lines to a file, which is renamed to end in the date at the end of every UTC day, with a fresh file taking its place.
+struct RotatingWriter {
+    path: PathBuf,
+    current: Option<(u64, BufWriter<File>)>,
+}
+
+impl RotatingWriter {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            current: None,
+        }
+    }
+
+    fn write(&mut self, record: &AuditRecord) -> anyhow::Result<()> {
+        self.write_at(record, SystemTime::now())
+    }
+
+    fn write_at(&mut self, record: &AuditRecord, now: SystemTime) -> anyhow::Result<()> {
+        let today = unix_day(now);
+        if let Some((day, writer)) = &mut self.current {
+            if *day != today {
+                writer.flush()?;
+                let day = *day;
+                self.current = None;
+                std::fs::rename(&self.path, dated_path(&self.path, day))?;
+            }
+        }
+        if self.current.is_none() {
+            // a file left over from before a restart belongs to the day it was last written
+            if let Ok(modified) = std::fs::metadata(&self.path).and_then(|meta| meta.modified()) {
+                let day = unix_day(modified);
+                if day != today {
+                    std::fs::rename(&self.path, dated_path(&self.path, day))?;
+                }
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            self.current = Some((today, BufWriter::new(file)));
+        }
+        let (_, writer) = self.current.as_mut().unwrap();
+        serde_json::to_writer(&mut *writer, record)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Days since the Unix epoch, in UTC.
+fn unix_day(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86400
+}
+
+/// The path a day's log is kept at once rotated, such as `audit.log.2024-07-01`.
+fn dated_path(path: &Path, day: u64) -> PathBuf {
+    let (year, month, day) = civil_from_days(day as i64);
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{year:04}-{month:02}-{day:02}"));
+    name.into()
+}
+
+/// Converts days since the Unix epoch into a year, month, and day, following Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn converts_date_edges() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        // 2000 is a leap year, 2100 is not
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11017), (2000, 3, 1));
+        assert_eq!(civil_from_days(47540), (2100, 2, 28));
+        assert_eq!(civil_from_days(47541), (2100, 3, 1));
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        assert_eq!(civil_from_days(20088), (2024, 12, 31));
+        assert_eq!(civil_from_days(20089), (2025, 1, 1));
+        assert_eq!(civil_from_days(24855), (2038, 1, 19));
+    }
+
+    #[test]
+    fn days_are_consecutive() {
+        let mut last = civil_from_days(0);
+        for days in 1..30000 {
+            let date = civil_from_days(days);
+            let (year, month, day) = date;
+            let next_day = (last.0, last.1, last.2 + 1);
+            let next_month = (last.0, last.1 + 1, 1);
+            let next_year = (last.0 + 1, 1, 1);
+            assert!(
+                date == next_day || date == next_month || date == next_year,
+                "{last:?} is followed by {date:?}"
+            );
+            let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+            if month == 3 && day == 1 {
+                assert_eq!(last, (year, 2, if leap { 29 } else { 28 }));
+            }
+            last = date;
+        }
+    }
+
+    #[test]
+    fn rotates_at_midnight_utc() {
+        let dir = std::env::temp_dir().join(format!("geph5-audit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+        let record = AuditRecord {
+            timestamp: 0,
+            token_hash: None,
+            client_addr: None,
+            dest_host: "example.com:443".into(),
+            dest_addr: None,
+            bytes: 0,
+            duration_secs: 0.0,
+        };
+        let new_years_eve = UNIX_EPOCH + Duration::from_secs(20088 * 86400 + 86399);
+        let mut writer = RotatingWriter::new(path.clone());
+        writer.write_at(&record, new_years_eve).unwrap();
+        writer.write_at(&record, new_years_eve).unwrap();
+        writer
+            .write_at(&record, new_years_eve + Duration::from_secs(1))
+            .unwrap();
+
+        let rotated = dir.join("audit.log.2024-12-31");
+        assert_eq!(
+            std::fs::read_to_string(&rotated).unwrap().lines().count(),
+            2
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+        // after a restart, a file last written on an earlier day is rotated to that day
+        drop(writer);
+        let new_years_day = new_years_eve + Duration::from_secs(1);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(new_years_day)
+            .unwrap();
+        let mut writer = RotatingWriter::new(path.clone());
+        writer
+            .write_at(&record, new_years_day + Duration::from_secs(86400))
+            .unwrap();
+        let rotated = dir.join("audit.log.2025-01-01");
+        assert_eq!(
+            std::fs::read_to_string(&rotated).unwrap().lines().count(),
+            1
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
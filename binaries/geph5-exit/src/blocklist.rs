@@ -0,0 +1,185 @@
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Context;
+use async_signal::{Signal, Signals};
+use futures_util::StreamExt;
+use ipnet::IpNet;
+use once_cell::sync::Lazy;
+
+use crate::CONFIG_FILE;
+
+/// The blocklist in force, replaced wholesale on every reload.
+static BLOCKLIST: Lazy<RwLock<Arc<Blocklist>>> = Lazy::new(Default::default);
+
+/// Checks whether the given destination, given as the host the client asked for, is blocked by the operator's blocklist.
+pub fn host_blocked(host: &str) -> bool {
+    BLOCKLIST.read().unwrap().blocks_host(host)
+}
+
+/// Checks whether the given destination address is blocked by the operator's blocklist.
+pub fn ip_blocked(ip: IpAddr) -> bool {
+    BLOCKLIST.read().unwrap().blocks_ip(ip)
+}
+
+/// Loads the configured blocklist, if any, so that it is in force before we accept any clients.
+pub fn load_blocklist() -> anyhow::Result<()> {
+    if let Some(path) = &CONFIG_FILE.wait().blocklist {
+        load(path)?;
+    }
+    Ok(())
+}
+
+/// Reloads the blocklist whenever we get SIGHUP. A blocklist that fails to reload leaves the old one in force.
+pub async fn blocklist_loop() -> anyhow::Result<()> {
+    let Some(path) = &CONFIG_FILE.wait().blocklist else {
+        return smol::future::pending().await;
+    };
+    let mut signals = Signals::new([Signal::Hup])?;
+    while let Some(signal) = signals.next().await {
+        signal?;
+        if let Err(err) = load(path) {
+            tracing::warn!(
+                err = debug(err),
+                "could not reload blocklist, keeping the old one"
+            );
+        }
+    }
+    smol::future::pending().await
+}
+
+fn load(path: &Path) -> anyhow::Result<()> {
+    let blocklist = Blocklist::parse(
+        &std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read blocklist at {}", path.display()))?,
+    )?;
+    tracing::info!(
+        ranges = blocklist.v4.len() + blocklist.v6.len(),
+        domains = blocklist.domains.len(),
+        "loaded blocklist"
+    );
+    *BLOCKLIST.write().unwrap() = Arc::new(blocklist);
+    Ok(())
+}
+
+/// Destinations we refuse to proxy to. IP ranges are kept sorted and merged, so that looking up an address takes a binary search.
+#[derive(Default)]
+struct Blocklist {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+    /// Blocked domains, each of which also blocks its subdomains.
+    domains: HashSet<String>,
+}
+
+impl Blocklist {
+    /// Parses a blocklist with one CIDR range, IP address, or domain per line. Blank lines and everything after a `#` are ignored.
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut v4 = vec![];
+        let mut v6 = vec![];
+        let mut domains = HashSet::new();
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let net = line
+                .parse::<IpNet>()
+                .or_else(|_| line.parse::<IpAddr>().map(IpNet::from));
+            match net {
+                Ok(IpNet::V4(net)) => {
+                    v4.push((u32::from(net.network()), u32::from(net.broadcast())))
+                }
+                Ok(IpNet::V6(net)) => {
+                    v6.push((u128::from(net.network()), u128::from(net.broadcast())))
+                }
+                Err(_) => {
+                    let domain = line.trim_start_matches('.').to_ascii_lowercase();
+                    anyhow::ensure!(
+                        !domain.is_empty() && !domain.contains(['/', ':', ' ']),
+                        "invalid blocklist entry on line {}: {line}",
+                        idx + 1
+                    );
+                    domains.insert(domain);
+                }
+            }
+        }
+        Ok(Self {
+            v4: merge_ranges(v4),
+            v6: merge_ranges(v6),
+            domains,
+        })
+    }
+
+    fn blocks_ip(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => in_ranges(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => in_ranges(&self.v4, u32::from(ip)),
+                None => in_ranges(&self.v6, u128::from(ip)),
+            },
+        }
+    }
+
+    fn blocks_host(&self, host: &str) -> bool {
+        if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse() {
+            return self.blocks_ip(ip);
+        }
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        // every suffix starting at a label boundary, from the whole name down to the TLD
+        std::iter::once(host.as_str())
+            .chain(host.match_indices('.').map(|(idx, _)| &host[idx + 1..]))
+            .any(|suffix| self.domains.contains(suffix))
+    }
+}
+
+/// Sorts inclusive ranges and merges the ones that overlap.
+fn merge_ranges<T: Ord + Copy>(mut ranges: Vec<(T, T)>) -> Vec<(T, T)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Checks whether a value falls in one of the sorted, disjoint, inclusive ranges.
+fn in_ranges<T: Ord + Copy>(ranges: &[(T, T)], value: T) -> bool {
+    let idx = ranges.partition_point(|(start, _)| *start <= value);
+    idx > 0 && value <= ranges[idx - 1].1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_ranges_and_subdomains() {
+        let blocklist = Blocklist::parse(
+            "# comment\n10.0.0.0/8\n10.1.0.0/16\n192.0.2.7\n2001:db8::/32\n.Example.com # trailing\n",
+        )
+        .unwrap();
+        assert_eq!(blocklist.v4.len(), 2);
+        assert!(blocklist.blocks_ip("10.200.3.4".parse().unwrap()));
+        assert!(blocklist.blocks_ip("192.0.2.7".parse().unwrap()));
+        assert!(!blocklist.blocks_ip("192.0.2.8".parse().unwrap()));
+        assert!(blocklist.blocks_ip("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(blocklist.blocks_ip("2001:db8::1".parse().unwrap()));
+        assert!(!blocklist.blocks_ip("2001:db9::1".parse().unwrap()));
+        assert!(blocklist.blocks_host("example.com"));
+        assert!(blocklist.blocks_host("www.EXAMPLE.com."));
+        assert!(!blocklist.blocks_host("notexample.com"));
+        assert!(blocklist.blocks_host("[2001:db8::5]"));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(Blocklist::parse("10.0.0.0/33").is_err());
+    }
+}
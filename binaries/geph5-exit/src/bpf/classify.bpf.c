@@ -0,0 +1,111 @@
+// Classifies proxied TCP streams by the first bytes of their payload, as it leaves the exit.
+//
+// Attached to the exit's cgroup on egress. Userspace marks the socket of a freshly dialed stream as pending in
+// stream_tags, right before writing the stream's first bytes to it. The first segment with a payload that leaves
+// that socket then gets its first CLASSIFY_LEN bytes classified, and the tag replaces the pending mark, for
+// userspace to read back. Every other socket is left alone, and no packet is ever dropped.
+
+#include <linux/bpf.h>
+#include <linux/if_ether.h>
+#include <linux/in.h>
+#include <linux/ip.h>
+#include <linux/ipv6.h>
+#include <linux/tcp.h>
+#include <bpf/bpf_endian.h>
+#include <bpf/bpf_helpers.h>
+
+#define CLASSIFY_LEN 20
+
+// These must match Protocol::from_tag in classify.rs.
+#define TAG_UNKNOWN 0
+#define TAG_HTTP 1
+#define TAG_TLS 2
+#define TAG_SSH 3
+#define TAG_BITTORRENT 4
+#define TAG_PENDING 0xff
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __uint(max_entries, 65536);
+    __type(key, __u64);
+    __type(value, __u8);
+} stream_tags SEC(".maps");
+
+static __always_inline int starts_with(const __u8 *buf, __u32 len, const char *prefix, __u32 prefix_len)
+{
+    if (len < prefix_len)
+        return 0;
+#pragma unroll
+    for (__u32 i = 0; i < CLASSIFY_LEN; i++) {
+        if (i >= prefix_len)
+            break;
+        if (buf[i] != (__u8)prefix[i])
+            return 0;
+    }
+    return 1;
+}
+
+#define STARTS_WITH(prefix) starts_with(buf, len, prefix, sizeof(prefix) - 1)
+
+static __always_inline __u8 classify(const __u8 *buf, __u32 len)
+{
+    if (len >= 3 && buf[0] == 0x16 && buf[1] == 0x03 && buf[2] <= 0x04)
+        return TAG_TLS;
+    if (STARTS_WITH("SSH-"))
+        return TAG_SSH;
+    if (STARTS_WITH("\x13"
+                    "BitTorrent protocol"))
+        return TAG_BITTORRENT;
+    if (STARTS_WITH("GET ") || STARTS_WITH("POST ") || STARTS_WITH("HEAD ") || STARTS_WITH("PUT ") ||
+        STARTS_WITH("DELETE ") || STARTS_WITH("OPTIONS ") || STARTS_WITH("PATCH ") || STARTS_WITH("TRACE ") ||
+        STARTS_WITH("CONNECT ") || STARTS_WITH("PRI * HTTP/2"))
+        return TAG_HTTP;
+    return TAG_UNKNOWN;
+}
+
+SEC("cgroup_skb/egress")
+int classify_egress(struct __sk_buff *skb)
+{
+    __u64 cookie = bpf_get_socket_cookie(skb);
+    __u8 *tag = bpf_map_lookup_elem(&stream_tags, &cookie);
+    if (!tag || *tag != TAG_PENDING)
+        return 1;
+
+    // cgroup skb programs see packets from the network header on
+    __u32 offset;
+    if (skb->protocol == bpf_htons(ETH_P_IP)) {
+        struct iphdr ip;
+        if (bpf_skb_load_bytes(skb, 0, &ip, sizeof(ip)) || ip.protocol != IPPROTO_TCP)
+            return 1;
+        offset = ip.ihl * 4;
+    } else if (skb->protocol == bpf_htons(ETH_P_IPV6)) {
+        // we never send IPv6 extension headers, so TCP comes right after
+        struct ipv6hdr ip6;
+        if (bpf_skb_load_bytes(skb, 0, &ip6, sizeof(ip6)) || ip6.nexthdr != IPPROTO_TCP)
+            return 1;
+        offset = sizeof(ip6);
+    } else {
+        return 1;
+    }
+    struct tcphdr tcp;
+    if (bpf_skb_load_bytes(skb, offset, &tcp, sizeof(tcp)))
+        return 1;
+    offset += tcp.doff * 4;
+    // segments without a payload, like the handshake, say nothing about the protocol
+    if (offset >= skb->len)
+        return 1;
+
+    __u32 len = skb->len - offset;
+    if (len > CLASSIFY_LEN)
+        len = CLASSIFY_LEN;
+    if (len < 1)
+        return 1;
+    __u8 buf[CLASSIFY_LEN] = {};
+    if (bpf_skb_load_bytes(skb, offset, buf, len))
+        return 1;
+    __u8 result = classify(buf, len);
+    bpf_map_update_elem(&stream_tags, &cookie, &result, BPF_EXIST);
+    return 1;
+}
+
+char LICENSE[] SEC("license") = "Dual MPL/GPL";
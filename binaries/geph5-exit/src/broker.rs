@@ -1,18 +1,68 @@
+use anyhow::Context;
 use async_trait::async_trait;
+use geph5_broker_protocol::{VersionRange, SUPPORTED_VERSIONS};
 use nanorpc::{JrpcRequest, JrpcResponse, RpcTransport};
 use reqwest::Method;
+use smol::lock::OnceCell;
+
+use crate::spki_pin::pinned_client;
 
 pub struct BrokerRpcTransport {
     url: String,
     client: reqwest::Client,
+    /// The negotiated API version, or None if the broker predates versioning.
+    version: OnceCell<Option<u32>>,
 }
 
 impl BrokerRpcTransport {
-    pub fn new(url: &str) -> Self {
-        Self {
+    /// Creates a transport to the broker at the given URL. If any SPKI hashes, in hex, are given, the broker's certificate chain must contain a matching key.
+    pub fn new(url: &str, spki_pins: &[String]) -> anyhow::Result<Self> {
+        let client = if spki_pins.is_empty() {
+            reqwest::Client::new()
+        } else {
+            let pins = spki_pins
+                .iter()
+                .map(|pin| {
+                    hex::decode(pin)?
+                        .try_into()
+                        .ok()
+                        .context("SPKI pin must be a 32-byte SHA-256 hash")
+                })
+                .collect::<anyhow::Result<Vec<[u8; 32]>>>()?;
+            pinned_client(pins)?
+        };
+        Ok(Self {
             url: url.to_string(),
-            client: reqwest::Client::new(),
-        }
+            client,
+            version: OnceCell::new(),
+        })
+    }
+
+    /// The URL to post requests to, negotiating the API version with the broker the first time.
+    async fn endpoint(&self) -> anyhow::Result<String> {
+        let version = self
+            .version
+            .get_or_try_init(|| async {
+                let resp = self
+                    .client
+                    .get(format!("{}/version", self.url.trim_end_matches('/')))
+                    .send()
+                    .await?;
+                if !resp.status().is_success() {
+                    return anyhow::Ok(None);
+                }
+                let theirs: VersionRange = serde_json::from_slice(&resp.bytes().await?)?;
+                let version = SUPPORTED_VERSIONS
+                    .negotiate(&theirs)
+                    .with_context(|| format!("no common API version with broker {theirs:?}"))?;
+                tracing::debug!(version, "negotiated broker API version");
+                anyhow::Ok(Some(version))
+            })
+            .await?;
+        Ok(match version {
+            Some(version) => VersionRange::versioned_url(&self.url, *version),
+            None => self.url.clone(),
+        })
     }
 }
 
@@ -23,7 +73,7 @@ impl RpcTransport for BrokerRpcTransport {
         tracing::debug!(method = req.method, "calling binder");
         let resp = self
             .client
-            .request(Method::POST, &self.url)
+            .request(Method::POST, self.endpoint().await?)
             .header("content-type", "application/json")
             .body(serde_json::to_vec(&req).unwrap())
             .send()
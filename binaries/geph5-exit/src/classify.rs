@@ -0,0 +1,226 @@
+use std::{collections::HashMap, fmt::Display, os::fd::RawFd, time::Duration};
+
+use futures_util::{AsyncWrite, AsyncWriteExt};
+use once_cell::sync::Lazy;
+#[cfg(feature = "ebpf")]
+use once_cell::sync::OnceCell;
+
+use crate::{ratelimit::RateLimiter, CONFIG_FILE};
+
+/// How many bytes at the start of a stream the classifier program looks at.
+pub const CLASSIFY_LEN: usize = 20;
+
+/// How long we wait for the first bytes of a stream to classify it, before taking it as [Protocol::Unknown].
+pub const SNIFF_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A coarse guess at the application protocol of a proxied stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Http,
+    Tls,
+    Ssh,
+    Bittorrent,
+    Unknown,
+}
+
+impl Protocol {
+    pub const ALL: &'static [Protocol] = &[
+        Protocol::Http,
+        Protocol::Tls,
+        Protocol::Ssh,
+        Protocol::Bittorrent,
+        Protocol::Unknown,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Http => "http",
+            Protocol::Tls => "tls",
+            Protocol::Ssh => "ssh",
+            Protocol::Bittorrent => "bittorrent",
+            Protocol::Unknown => "unknown",
+        }
+    }
+
+    /// The exit-wide rate limiter configured for this protocol, if any.
+    pub fn ratelimiter(&self) -> RateLimiter {
+        static LIMITERS: Lazy<HashMap<String, RateLimiter>> = Lazy::new(|| {
+            CONFIG_FILE
+                .wait()
+                .protocol_ratelimit
+                .iter()
+                .map(|(protocol, limit)| (protocol.clone(), RateLimiter::new(*limit, *limit)))
+                .collect()
+        });
+        LIMITERS
+            .get(self.as_str())
+            .cloned()
+            .unwrap_or_else(RateLimiter::unlimited)
+    }
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+/// The tag that marks a socket whose first bytes the classifier program should classify.
+#[cfg(feature = "ebpf")]
+const TAG_PENDING: u8 = 0xff;
+
+impl Protocol {
+    /// The protocol for a tag left by the classifier program in `src/bpf/classify.bpf.c`.
+    #[cfg(any(feature = "ebpf", test))]
+    fn from_tag(tag: u8) -> Self {
+        match tag {
+            1 => Protocol::Http,
+            2 => Protocol::Tls,
+            3 => Protocol::Ssh,
+            4 => Protocol::Bittorrent,
+            _ => Protocol::Unknown,
+        }
+    }
+}
+
+/// The map through which we ask the classifier program to classify a socket, and it answers.
+#[cfg(feature = "ebpf")]
+static STREAM_TAGS: OnceCell<libbpf_rs::MapHandle> = OnceCell::new();
+
+/// Loads the classifier program and attaches it to the egress of our cgroup, which takes Linux 5.7 or later. It stays attached for as long as we run.
+#[cfg(feature = "ebpf")]
+pub fn load_ebpf_classifier() -> anyhow::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    use anyhow::Context;
+
+    static BPF_OBJECT: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/classify.bpf.o"));
+
+    let mut object = libbpf_rs::ObjectBuilder::default()
+        .open_memory(BPF_OBJECT)?
+        .load()
+        .context("cannot load the classifier program")?;
+    let stream_tags = object
+        .maps()
+        .find(|map| map.name() == "stream_tags")
+        .context("classifier program has no stream_tags map")?;
+    let stream_tags = libbpf_rs::MapHandle::try_from(&stream_tags)?;
+    let cgroup = std::fs::File::open(own_cgroup()?).context("cannot open our cgroup")?;
+    let link = object
+        .progs_mut()
+        .find(|prog| prog.name() == "classify_egress")
+        .context("classifier program has no classify_egress")?
+        .attach_cgroup(cgroup.as_raw_fd())
+        .context("cannot attach the classifier program to our cgroup")?;
+    // detaching only happens when we exit
+    std::mem::forget(link);
+    std::mem::forget(object);
+    STREAM_TAGS
+        .set(stream_tags)
+        .ok()
+        .context("classifier program already loaded")?;
+    tracing::info!("loaded the eBPF classifier");
+    Ok(())
+}
+
+#[cfg(not(feature = "ebpf"))]
+pub fn load_ebpf_classifier() -> anyhow::Result<()> {
+    anyhow::bail!("ebpf_classifier needs an exit built with the ebpf feature")
+}
+
+/// The directory of our cgroup in the cgroup v2 hierarchy.
+#[cfg(feature = "ebpf")]
+fn own_cgroup() -> anyhow::Result<std::path::PathBuf> {
+    use anyhow::Context;
+
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup")?;
+    let path = cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .context("not in a cgroup v2 hierarchy")?;
+    Ok(std::path::Path::new("/sys/fs/cgroup").join(path.trim_start_matches('/')))
+}
+
+/// Writes the first bytes of a stream to its freshly dialed destination socket, returning the protocol that the classifier program made of them on their way out. Since nothing is in flight yet on a fresh connection, the kernel sends the bytes, and so runs the program on them, before the write returns.
+#[cfg(feature = "ebpf")]
+pub async fn write_classified(
+    dest: &mut (impl AsyncWrite + Unpin),
+    dest_fd: Option<RawFd>,
+    first_bytes: &[u8],
+) -> anyhow::Result<Protocol> {
+    use libbpf_rs::{MapCore, MapFlags};
+
+    let (Some(stream_tags), Some(dest_fd)) = (STREAM_TAGS.get(), dest_fd) else {
+        dest.write_all(first_bytes).await?;
+        return Ok(Protocol::Unknown);
+    };
+    let cookie = socket_cookie(dest_fd)?.to_ne_bytes();
+    stream_tags.update(&cookie, &[TAG_PENDING], MapFlags::ANY)?;
+    let written = dest.write_all(first_bytes).await;
+    let tag = stream_tags.lookup(&cookie, MapFlags::ANY);
+    // the entry may well be gone already, since the map evicts the least recently used ones
+    let _ = stream_tags.delete(&cookie);
+    written?;
+    Ok(match tag?.as_deref() {
+        Some(&[tag]) if tag != TAG_PENDING => Protocol::from_tag(tag),
+        _ => Protocol::Unknown,
+    })
+}
+
+#[cfg(not(feature = "ebpf"))]
+pub async fn write_classified(
+    dest: &mut (impl AsyncWrite + Unpin),
+    _dest_fd: Option<RawFd>,
+    first_bytes: &[u8],
+) -> anyhow::Result<Protocol> {
+    dest.write_all(first_bytes).await?;
+    Ok(Protocol::Unknown)
+}
+
+/// The cookie that identifies a socket to BPF programs.
+#[cfg(feature = "ebpf")]
+fn socket_cookie(fd: RawFd) -> std::io::Result<u64> {
+    let mut cookie = 0u64;
+    let mut len = std::mem::size_of::<u64>() as libc::socklen_t;
+    // SAFETY: the buffer and its length describe a valid u64
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_COOKIE,
+            &mut cookie as *mut u64 as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(cookie)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_match_the_classifier_program() {
+        let program = include_str!("bpf/classify.bpf.c");
+        for protocol in Protocol::ALL {
+            let name = protocol.as_str().to_uppercase();
+            let tag = program
+                .lines()
+                .find_map(|line| line.strip_prefix(&format!("#define TAG_{name} ")))
+                .unwrap_or_else(|| panic!("no tag for {name}"));
+            assert_eq!(Protocol::from_tag(tag.trim().parse().unwrap()), *protocol);
+        }
+        assert_eq!(Protocol::from_tag(0xfe), Protocol::Unknown);
+    }
+
+    #[test]
+    fn protocol_names_are_distinct() {
+        let mut names: Vec<_> = Protocol::ALL.iter().map(|p| p.as_str()).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), Protocol::ALL.len());
+    }
+}
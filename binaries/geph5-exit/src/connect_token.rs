@@ -0,0 +1,126 @@
+use anyhow::Context;
+use geph5_broker_protocol::AccountLevel;
+use mizaru2::{ClientToken, UnblindedSignature};
+use once_cell::sync::OnceCell;
+
+use crate::{revocation::is_revoked, CONFIG_FILE};
+
+/// The broker's Mizaru public keys for free and Plus connect tokens, if configured.
+static MIZARU_KEYS: OnceCell<Option<(mizaru2::PublicKey, mizaru2::PublicKey)>> = OnceCell::new();
+
+/// Loads the broker's Mizaru public keys from the config file, so that bad keys stop the exit at startup rather than at the first client.
+pub fn load_mizaru_keys() -> anyhow::Result<()> {
+    let parse = |hex_key: &str| -> anyhow::Result<mizaru2::PublicKey> {
+        Ok(mizaru2::PublicKey::from_bytes(
+            hex::decode(hex_key)?
+                .try_into()
+                .ok()
+                .context("Mizaru public keys must be 32 bytes")?,
+        ))
+    };
+    let keys = match &CONFIG_FILE.wait().broker {
+        Some(broker) => match (&broker.mizaru_free, &broker.mizaru_plus) {
+            (Some(free), Some(plus)) => Some((parse(free)?, parse(plus)?)),
+            _ => None,
+        },
+        None => None,
+    };
+    if keys.is_none() {
+        tracing::warn!(
+            "no Mizaru public keys configured, accepting connect tokens without verifying them"
+        );
+    }
+    let _ = MIZARU_KEYS.set(keys);
+    Ok(())
+}
+
+/// Checks that the broker really signed a connect token for the given account level, in the current epoch or an adjacent one, and has not revoked it since. Without configured Mizaru keys, every token that is not revoked passes.
+pub fn verify_connect_token(
+    level: AccountLevel,
+    token: ClientToken,
+    sig: &UnblindedSignature,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(!is_revoked(&token), "connect token revoked");
+    match MIZARU_KEYS.get().and_then(|keys| keys.as_ref()) {
+        Some(keys) => verify_with_keys(keys, level, token, sig),
+        None => Ok(()),
+    }
+}
+
+fn verify_with_keys(
+    (free, plus): &(mizaru2::PublicKey, mizaru2::PublicKey),
+    level: AccountLevel,
+    token: ClientToken,
+    sig: &UnblindedSignature,
+) -> anyhow::Result<()> {
+    // tokens are fetched ahead of time and used for a while, so ones from either side of an epoch boundary are fine
+    anyhow::ensure!(
+        sig.epoch.abs_diff(mizaru2::current_epoch()) <= 1,
+        "connect token from epoch {} is out of date",
+        sig.epoch
+    );
+    let key = match level {
+        AccountLevel::Free => free,
+        AccountLevel::Plus => plus,
+    };
+    key.blind_verify(token, sig)
+        .context("connect token not signed by the broker")
+}
+
+#[cfg(test)]
+mod tests {
+    use mizaru2::SecretKey;
+
+    use super::*;
+    use crate::revocation::revoke;
+
+    fn sign(key: &SecretKey, epoch: u16, token: ClientToken) -> UnblindedSignature {
+        let (blinded, secret) = token.blind(&key.get_subkey(epoch).public_key().unwrap());
+        key.blind_sign(epoch, &blinded)
+            .unblind(&secret, token)
+            .unwrap()
+    }
+
+    #[test]
+    fn rejects_revoked_tokens() {
+        let revoked = ClientToken::random();
+        let sig = UnblindedSignature {
+            epoch: mizaru2::current_epoch(),
+            used_key: vec![],
+            merkle_branch: vec![],
+            unblinded_sig: vec![],
+        };
+        // no Mizaru keys are loaded in tests, so only the revocation list stands in the way
+        verify_connect_token(AccountLevel::Free, revoked, &sig).unwrap();
+        revoke(&revoked);
+        assert!(verify_connect_token(AccountLevel::Free, revoked, &sig).is_err());
+        verify_connect_token(AccountLevel::Free, ClientToken::random(), &sig).unwrap();
+    }
+
+    #[test]
+    fn verifies_blind_signatures() {
+        let free = SecretKey::generate("test_free");
+        let plus = mizaru2::PublicKey::from_bytes([0; 32]);
+        let keys = (free.to_public_key(), plus);
+        let epoch = mizaru2::current_epoch();
+        let token = ClientToken::random();
+
+        let sig = sign(&free, epoch, token);
+        verify_with_keys(&keys, AccountLevel::Free, token, &sig).unwrap();
+        let sig = sign(&free, epoch - 1, token);
+        verify_with_keys(&keys, AccountLevel::Free, token, &sig).unwrap();
+
+        // signed by the free key, but presented as Plus
+        let sig = sign(&free, epoch, token);
+        assert!(verify_with_keys(&keys, AccountLevel::Plus, token, &sig).is_err());
+        // a signature over some other token
+        assert!(verify_with_keys(&keys, AccountLevel::Free, ClientToken::random(), &sig).is_err());
+        // a valid signature from long ago
+        let sig = sign(&free, epoch - 2, token);
+        assert!(verify_with_keys(&keys, AccountLevel::Free, token, &sig).is_err());
+        // a valid signature whose epoch was changed afterwards
+        let mut sig = sign(&free, epoch - 2, token);
+        sig.epoch = epoch;
+        assert!(verify_with_keys(&keys, AccountLevel::Free, token, &sig).is_err());
+    }
+}
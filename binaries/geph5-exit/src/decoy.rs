@@ -0,0 +1,26 @@
+use anyhow::Context;
+use futures_util::{io::copy, AsyncReadExt, AsyncWriteExt};
+use sillad::Pipe;
+use smol::{future::FutureExt as _, net::TcpStream};
+
+use crate::CONFIG_FILE;
+
+/// Hands a connection that did not start with the probe magic over to the decoy server, along with whatever we already read from it, so that whoever made it sees nothing but that server. Without a decoy configured, the connection is closed without a word.
+pub async fn pass_to_decoy(client: impl Pipe, read: Vec<u8>) -> anyhow::Result<()> {
+    let Some(decoy_url) = &CONFIG_FILE.wait().decoy_url else {
+        return Ok(());
+    };
+    let decoy_url = reqwest::Url::parse(decoy_url).context("invalid decoy URL")?;
+    let host = decoy_url.host_str().context("decoy URL has no host")?;
+    let port = decoy_url
+        .port_or_known_default()
+        .context("decoy URL has no port")?;
+    let mut decoy = TcpStream::connect((host, port)).await?;
+    decoy.write_all(&read).await?;
+    let (read_client, mut write_client) = client.split();
+    let (read_decoy, mut write_decoy) = decoy.split();
+    copy(read_client, &mut write_decoy)
+        .race(copy(read_decoy, &mut write_client))
+        .await?;
+    Ok(())
+}
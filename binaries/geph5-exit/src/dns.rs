@@ -0,0 +1,137 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::Context;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use smol::net::{TcpStream, UdpSocket};
+use smol_timeout2::TimeoutExt;
+
+use crate::{ratelimit::RateLimiter, CONFIG_FILE};
+
+/// How long the upstream resolver gets to answer a query.
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a DNS stream may sit idle between queries before we close it.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Serves a `dns` stream, which carries DNS queries framed as in DNS over TCP, with a big-endian two-byte length before each message. Every query is answered by our `dns_upstream`, so that clients do not have to trust whatever resolver their network hands them.
+pub async fn dns_stream(ratelimit: RateLimiter, stream: picomux::Stream) -> anyhow::Result<()> {
+    let (mut read_stream, mut write_stream) = stream.split();
+    loop {
+        let mut len_buf = [0u8; 2];
+        match read_stream
+            .read_exact(&mut len_buf)
+            .timeout(IDLE_TIMEOUT)
+            .await
+        {
+            Some(Ok(())) => {}
+            // the client closing the stream, or leaving it idle, is how DNS streams normally end
+            _ => return Ok(()),
+        }
+        let mut query = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+        read_stream.read_exact(&mut query).await?;
+        ratelimit.wait(query.len()).await;
+        let response = upstream_exchange(CONFIG_FILE.wait().dns_upstream, &query).await?;
+        ratelimit.wait(response.len()).await;
+        write_stream
+            .write_all(&(response.len() as u16).to_be_bytes())
+            .await?;
+        write_stream.write_all(&response).await?;
+        write_stream.flush().await?;
+    }
+}
+
+/// Asks the upstream resolver over UDP, asking again over TCP if the answer was too big for UDP and came back truncated.
+async fn upstream_exchange(upstream: SocketAddr, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let bind_addr: SocketAddr = if upstream.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(upstream).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0u8; 65536];
+    let n = socket
+        .recv(&mut buf)
+        .timeout(DNS_TIMEOUT)
+        .await
+        .context("timed out waiting for the upstream resolver")??;
+    buf.truncate(n);
+    if is_truncated(&buf) {
+        return upstream_tcp_exchange(upstream, query)
+            .timeout(DNS_TIMEOUT)
+            .await
+            .context("timed out waiting for the upstream resolver over TCP")?;
+    }
+    Ok(buf)
+}
+
+async fn upstream_tcp_exchange(upstream: SocketAddr, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut conn = TcpStream::connect(upstream).await?;
+    conn.write_all(&(query.len() as u16).to_be_bytes()).await?;
+    conn.write_all(query).await?;
+    let mut len_buf = [0u8; 2];
+    conn.read_exact(&mut len_buf).await?;
+    let mut response = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+    conn.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+/// Whether a DNS message has its TC bit set, meaning that the answer did not fit.
+fn is_truncated(message: &[u8]) -> bool {
+    message.get(2).is_some_and(|flags| flags & 0x02 != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn truncated_answers_are_asked_again_over_tcp() {
+        smolscale::block_on(async {
+            let udp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            let upstream = udp.local_addr().unwrap();
+            let tcp = TcpListener::bind(upstream).await.unwrap();
+            let query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+            let truncated = vec![0x12, 0x34, 0x83, 0x80, 0, 1, 0, 0, 0, 0, 0, 0];
+            let mut full = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+            full.extend_from_slice(&[0xaa; 1000]);
+
+            let server = {
+                let query = query.clone();
+                let full = full.clone();
+                async move {
+                    let mut buf = [0u8; 512];
+                    let (n, from) = udp.recv_from(&mut buf).await.unwrap();
+                    assert_eq!(&buf[..n], &query[..]);
+                    udp.send_to(&truncated, from).await.unwrap();
+
+                    let (mut conn, _) = tcp.accept().await.unwrap();
+                    let mut len_buf = [0u8; 2];
+                    conn.read_exact(&mut len_buf).await.unwrap();
+                    let mut asked = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+                    conn.read_exact(&mut asked).await.unwrap();
+                    assert_eq!(asked, query);
+                    conn.write_all(&(full.len() as u16).to_be_bytes())
+                        .await
+                        .unwrap();
+                    conn.write_all(&full).await.unwrap();
+                }
+            };
+            let (response, ()) = futures_util::join!(upstream_exchange(upstream, &query), server);
+            assert_eq!(response.unwrap(), full);
+        });
+    }
+
+    #[test]
+    fn truncation_bit() {
+        assert!(is_truncated(&[0, 0, 0x83, 0x80]));
+        assert!(!is_truncated(&[0, 0, 0x81, 0x80]));
+        assert!(!is_truncated(&[0, 0]));
+    }
+}
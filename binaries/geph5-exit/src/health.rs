@@ -0,0 +1,330 @@
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use async_signal::{Signal, Signals};
+use futures_util::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, StreamExt};
+use smol::{future::FutureExt, io::BufReader, net::TcpListener};
+use smol_timeout2::TimeoutExt;
+
+use crate::{admin_auth::verify_admin_auth, key_rotation::rotate_key, CONFIG_FILE};
+
+/// The largest request body we read, which is plenty for a key rotation request.
+const MAX_BODY_LEN: usize = 4096;
+
+/// Whether we are draining, i.e. waiting for existing connections to finish before shutting down.
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Whether the broker has been told to stop advertising us since draining started.
+static WITHDRAWN: AtomicBool = AtomicBool::new(false);
+
+/// How many proxied streams are currently open.
+static IN_FLIGHT_STREAMS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether we are draining, in which case we should stop advertising ourselves and accepting connections.
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+/// Waits until draining starts.
+pub async fn wait_draining() {
+    while !is_draining() {
+        smol::Timer::after(Duration::from_millis(200)).await;
+    }
+}
+
+/// Records that the broker has been sent a descriptor that has already expired.
+pub fn mark_withdrawn() {
+    WITHDRAWN.store(true, Ordering::SeqCst);
+}
+
+/// Counts one proxied stream as in flight, until dropped, so that draining can wait for it.
+pub struct InFlightGuard(());
+
+impl InFlightGuard {
+    pub fn new() -> Self {
+        IN_FLIGHT_STREAMS.fetch_add(1, Ordering::SeqCst);
+        Self(())
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT_STREAMS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Starts draining on SIGTERM or SIGINT. A second signal exits right away.
+pub async fn signal_loop() -> anyhow::Result<()> {
+    let mut signals = Signals::new([Signal::Term, Signal::Int])?;
+    while let Some(signal) = signals.next().await {
+        let signal = signal?;
+        if is_draining() {
+            tracing::warn!(
+                signal = debug(signal),
+                "signaled again while draining, exiting now"
+            );
+            std::process::exit(1);
+        }
+        start_draining(&format!("received {signal:?}"));
+    }
+    smol::future::pending().await
+}
+
+/// Serves the health-check endpoints for load balancers, if configured. `GET /health` returns 200 until draining starts, and 503 afterwards. `POST /drain` starts draining, just like SIGTERM, but only with a valid admin JWT, so it is disabled unless `admin_jwt_secret` is set. It is not a GET, so that prefetchers, link checkers and retries cannot start a drain. `POST /rotate-key` replaces our signing key with the one in the JSON [RotationRequest](crate::key_rotation::RotationRequest) it carries, and needs an admin JWT just the same.
+pub async fn health_loop() -> anyhow::Result<()> {
+    let Some(health_addr) = CONFIG_FILE.wait().health_addr else {
+        return smol::future::pending().await;
+    };
+    let listener = TcpListener::bind(health_addr).await?;
+    tracing::info!(health_addr = display(health_addr), "serving health checks");
+    loop {
+        let (conn, remote) = listener.accept().await?;
+        smolscale::spawn(async move {
+            if let Err(err) = handle_health(conn, remote)
+                .timeout(Duration::from_secs(10))
+                .await
+                .unwrap_or_else(|| Err(anyhow::anyhow!("timed out")))
+            {
+                tracing::debug!(err = debug(err), "health check connection failed");
+            }
+        })
+        .detach();
+    }
+}
+
+async fn handle_health(conn: smol::net::TcpStream, remote: SocketAddr) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(conn.clone());
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next(), parts.next());
+    let mut authorization = None;
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            } else if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse()?;
+            }
+        }
+    }
+    anyhow::ensure!(content_length <= MAX_BODY_LEN, "request body too long");
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+    let (status, response) = respond(
+        method,
+        path,
+        &body,
+        authorization.as_deref(),
+        CONFIG_FILE.wait().admin_jwt_secret.as_deref(),
+        remote,
+    );
+    let mut conn = conn;
+    conn.write_all(
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{response}",
+            response.len()
+        )
+        .as_bytes(),
+    )
+    .await?;
+    conn.flush().await?;
+    Ok(())
+}
+
+/// Picks the status and body of the answer to a request for the given path.
+fn respond(
+    method: Option<&str>,
+    path: Option<&str>,
+    body: &[u8],
+    authorization: Option<&str>,
+    secret: Option<&str>,
+    remote: SocketAddr,
+) -> (&'static str, &'static str) {
+    match (method, path) {
+        (Some("GET"), Some("/health")) => {
+            if is_draining() {
+                ("503 Service Unavailable", "draining")
+            } else {
+                ("200 OK", "ok")
+            }
+        }
+        (Some("POST"), Some("/drain")) if !admin_authorized(secret, authorization, remote) => {
+            ("401 Unauthorized", "unauthorized")
+        }
+        (Some("POST"), Some("/drain")) => {
+            start_draining(&format!("drain requested by {remote}"));
+            ("503 Service Unavailable", "draining")
+        }
+        (Some(_), Some("/drain")) => ("405 Method Not Allowed", "use POST"),
+        (Some("POST"), Some("/rotate-key")) if !admin_authorized(secret, authorization, remote) => {
+            ("401 Unauthorized", "unauthorized")
+        }
+        (Some("POST"), Some("/rotate-key")) => match rotate_key(body) {
+            Ok(()) => ("200 OK", "rotated"),
+            Err(err) => {
+                tracing::warn!(
+                    remote = display(remote),
+                    err = debug(err),
+                    "rejected key rotation"
+                );
+                ("400 Bad Request", "rejected")
+            }
+        },
+        (Some(_), Some("/rotate-key")) => ("405 Method Not Allowed", "use POST"),
+        _ => ("404 Not Found", "not found"),
+    }
+}
+
+/// Whether a request to an admin endpoint is allowed. Without an `admin_jwt_secret`, nobody is.
+fn admin_authorized(secret: Option<&str>, authorization: Option<&str>, remote: SocketAddr) -> bool {
+    let Some(secret) = secret else {
+        tracing::warn!(
+            remote = display(remote),
+            "rejected admin request, since no admin_jwt_secret is configured"
+        );
+        return false;
+    };
+    match verify_admin_auth(authorization, secret) {
+        Ok(()) => true,
+        Err(err) => {
+            tracing::warn!(
+                remote = display(remote),
+                err = debug(err),
+                "rejected admin request"
+            );
+            false
+        }
+    }
+}
+
+/// Starts draining: we stop accepting connections, sessions and new streams on existing sessions, and withdraw from the broker, then exit once every in-flight stream has finished, or `drain_timeout_secs` later at the latest.
+pub fn start_draining(reason: &str) {
+    if DRAINING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let drain_timeout = Duration::from_secs(CONFIG_FILE.wait().drain_timeout_secs);
+    tracing::warn!(
+        reason,
+        drain_timeout = debug(drain_timeout),
+        "draining, will exit once in-flight streams finish or after the timeout"
+    );
+    smolscale::spawn(async move {
+        let finished = async {
+            loop {
+                smol::Timer::after(Duration::from_secs(1)).await;
+                let withdrawn =
+                    WITHDRAWN.load(Ordering::SeqCst) || CONFIG_FILE.wait().broker.is_none();
+                if withdrawn && IN_FLIGHT_STREAMS.load(Ordering::SeqCst) == 0 {
+                    tracing::warn!("all in-flight streams finished, exiting");
+                    break;
+                }
+            }
+        };
+        let timeout = async {
+            smol::Timer::after(drain_timeout).await;
+            tracing::warn!(
+                in_flight = IN_FLIGHT_STREAMS.load(Ordering::SeqCst),
+                "drain timeout reached, exiting"
+            );
+        };
+        finished.race(timeout).await;
+        std::process::exit(0);
+    })
+    .detach();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REMOTE: SocketAddr = SocketAddr::V4(std::net::SocketAddrV4::new(
+        std::net::Ipv4Addr::new(203, 0, 113, 1),
+        1234,
+    ));
+
+    #[test]
+    fn drain_needs_admin_secret() {
+        // without a secret, not even a well-formed token gets through
+        let (status, _) = respond(
+            Some("POST"),
+            Some("/drain"),
+            b"",
+            Some("Bearer a.b.c"),
+            None,
+            REMOTE,
+        );
+        assert_eq!(status, "401 Unauthorized");
+        let (status, _) = respond(
+            Some("POST"),
+            Some("/drain"),
+            b"",
+            None,
+            Some("secret"),
+            REMOTE,
+        );
+        assert_eq!(status, "401 Unauthorized");
+        assert!(!is_draining());
+        let (status, _) = respond(Some("GET"), Some("/health"), b"", None, None, REMOTE);
+        assert_eq!(status, "200 OK");
+    }
+
+    #[test]
+    fn drain_needs_post() {
+        // even with a valid token, a GET must not start a drain
+        let secret = "secret";
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &serde_json::json!({"iss": "geph5-admin", "exp": u32::MAX}),
+            &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap();
+        let authorization = format!("Bearer {token}");
+        for method in ["GET", "HEAD", "PUT"] {
+            let (status, _) = respond(
+                Some(method),
+                Some("/drain"),
+                b"",
+                Some(&authorization),
+                Some(secret),
+                REMOTE,
+            );
+            assert_eq!(status, "405 Method Not Allowed");
+        }
+        assert!(!is_draining());
+    }
+
+    #[test]
+    fn rotate_key_needs_admin_secret() {
+        // turned away before the body is even looked at
+        for secret in [None, Some("secret")] {
+            let (status, _) = respond(
+                Some("POST"),
+                Some("/rotate-key"),
+                b"{}",
+                Some("Bearer a.b.c"),
+                secret,
+                REMOTE,
+            );
+            assert_eq!(status, "401 Unauthorized");
+        }
+        let (status, _) = respond(Some("GET"), Some("/rotate-key"), b"", None, None, REMOTE);
+        assert_eq!(status, "405 Method Not Allowed");
+    }
+
+    #[test]
+    fn unknown_paths_not_found() {
+        let (status, _) = respond(Some("POST"), Some("/health/drain"), b"", None, None, REMOTE);
+        assert_eq!(status, "404 Not Found");
+        let (status, _) = respond(None, None, b"", None, None, REMOTE);
+        assert_eq!(status, "404 Not Found");
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::atomic::{AtomicU16, Ordering},
+    time::Duration,
+};
+
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use smol::{future::FutureExt as _, net::UdpSocket};
+use smol_timeout2::TimeoutExt;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::ratelimit::RateLimiter;
+
+/// How long an ICMP stream may go without echo requests before we close it.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long the TCP fallback waits for the destination to answer a connection attempt.
+const TCP_ECHO_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Serves an `icmp` stream, which carries ICMPv4 echo requests to one destination, each a whole ICMP message with a big-endian two-byte length before it. The replies come back the same way.
+///
+/// Echoes are really sent to the destination when we can open an ICMP socket. That takes either the `CAP_NET_RAW` capability, for a raw socket, or our group being within the `net.ipv4.ping_group_range` sysctl, for an unprivileged ping socket. Otherwise, we answer an echo request ourselves whenever the destination accepts or refuses a TCP connection on port 80, which at least tells the client that it is up and roughly how far away.
+pub async fn icmp_stream(
+    ratelimit: RateLimiter,
+    dest: IpAddr,
+    stream: picomux::Stream,
+) -> anyhow::Result<()> {
+    let IpAddr::V4(dest) = dest else {
+        anyhow::bail!("only ICMPv4 echo is supported")
+    };
+    let (mut read_stream, mut write_stream) = stream.split();
+    let (socket, raw) = match icmp_socket(dest).await {
+        Ok(socket) => socket,
+        Err(err) => {
+            tracing::debug!(
+                err = debug(err),
+                "no ICMP socket, falling back to TCP echo; grant CAP_NET_RAW for real pings"
+            );
+            while let Some(request) = read_request(&mut read_stream).await? {
+                ratelimit.wait(request.len()).await;
+                if let Some(reply) = tcp_echo(dest, &request).await {
+                    write_reply(&mut write_stream, &reply).await?;
+                }
+            }
+            return Ok(());
+        }
+    };
+    // ping sockets replace the identifier with their own, so we put back the one the client used
+    let client_ident = AtomicU16::new(0);
+    let up_loop = async {
+        while let Some(request) = read_request(&mut read_stream).await? {
+            ratelimit.wait(request.len()).await;
+            client_ident.store(
+                u16::from_be_bytes([request[4], request[5]]),
+                Ordering::Relaxed,
+            );
+            socket.send(&request).await?;
+        }
+        anyhow::Ok(())
+    };
+    let dn_loop = async {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let n = socket.recv(&mut buf).await?;
+            // raw sockets give us the IP header too
+            let header_len = if raw {
+                ((buf[0] & 0x0f) as usize) * 4
+            } else {
+                0
+            };
+            let Some(reply) = buf.get_mut(header_len..n) else {
+                continue;
+            };
+            if reply.len() < 8 || reply[0] != 0 {
+                continue;
+            }
+            let ident = client_ident.load(Ordering::Relaxed).to_be_bytes();
+            if raw && reply[4..6] != ident {
+                // a raw socket sees every echo reply from the destination, including to others' pings
+                continue;
+            }
+            reply[4..6].copy_from_slice(&ident);
+            set_checksum(reply);
+            ratelimit.wait(reply.len()).await;
+            write_reply(&mut write_stream, reply).await?;
+        }
+    };
+    up_loop.race(dn_loop).await
+}
+
+/// Reads the next echo request from the client, skipping anything else, or returns None once the client closes the stream or leaves it idle, which is how ICMP streams normally end.
+async fn read_request(
+    read_stream: &mut (impl AsyncRead + Unpin),
+) -> anyhow::Result<Option<Vec<u8>>> {
+    loop {
+        let mut len_buf = [0u8; 2];
+        match read_stream
+            .read_exact(&mut len_buf)
+            .timeout(IDLE_TIMEOUT)
+            .await
+        {
+            Some(Ok(())) => {}
+            _ => return Ok(None),
+        }
+        let mut request = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+        read_stream.read_exact(&mut request).await?;
+        if request.len() >= 8 && request[0] == 8 && request[1] == 0 {
+            return Ok(Some(request));
+        }
+    }
+}
+
+/// Opens an ICMP socket connected to the destination, returning whether it is a raw socket rather than a ping socket.
+async fn icmp_socket(dest: Ipv4Addr) -> anyhow::Result<(UdpSocket, bool)> {
+    let (socket, raw) = match Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4)) {
+        Ok(socket) => (socket, true),
+        Err(_) => (
+            Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4))?,
+            false,
+        ),
+    };
+    // an ICMP socket sends and receives datagrams just like a UDP one, and the port is ignored
+    let socket = UdpSocket::try_from(std::net::UdpSocket::from(socket))?;
+    socket.connect(SocketAddr::from((dest, 0))).await?;
+    Ok((socket, raw))
+}
+
+/// Answers an echo request ourselves if the destination answers a TCP connection attempt at all.
+async fn tcp_echo(dest: Ipv4Addr, request: &[u8]) -> Option<Vec<u8>> {
+    match smol::net::TcpStream::connect((dest, 80))
+        .timeout(TCP_ECHO_TIMEOUT)
+        .await?
+    {
+        Ok(_) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {}
+        Err(_) => return None,
+    }
+    let mut reply = request.to_vec();
+    reply[0] = 0;
+    set_checksum(&mut reply);
+    Some(reply)
+}
+
+async fn write_reply(
+    write_stream: &mut (impl AsyncWrite + Unpin),
+    reply: &[u8],
+) -> anyhow::Result<()> {
+    write_stream
+        .write_all(&(reply.len() as u16).to_be_bytes())
+        .await?;
+    write_stream.write_all(reply).await?;
+    write_stream.flush().await?;
+    Ok(())
+}
+
+/// Fills in the checksum of an ICMP message.
+fn set_checksum(message: &mut [u8]) {
+    message[2..4].copy_from_slice(&[0, 0]);
+    let mut sum: u32 = message
+        .chunks(2)
+        .map(|chunk| u16::from_be_bytes([chunk[0], chunk.get(1).copied().unwrap_or(0)]) as u32)
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    message[2..4].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum() {
+        let mut message = [8, 0, 0xaa, 0xbb, 0, 1, 0, 1];
+        set_checksum(&mut message);
+        assert_eq!(message[2..4], [0xf7, 0xfd]);
+    }
+}
@@ -0,0 +1,35 @@
+use std::{net::IpAddr, num::NonZeroU32, sync::Arc, time::Duration};
+
+use governor::{DefaultDirectRateLimiter, Quota};
+use moka::future::Cache;
+use once_cell::sync::Lazy;
+
+use crate::CONFIG_FILE;
+
+/// A token bucket of new connections for each client IP address. A bucket left idle long enough to refill completely is no different from a fresh one, so it gets evicted then.
+static IP_BUCKETS: Lazy<Cache<IpAddr, Arc<DefaultDirectRateLimiter>>> = Lazy::new(|| {
+    let config = CONFIG_FILE.wait();
+    let refill_secs =
+        config.burst as u64 / config.max_conn_per_ip_per_second.unwrap_or(1).max(1) as u64;
+    Cache::builder()
+        .time_to_idle(Duration::from_secs(refill_secs + 1))
+        .build()
+});
+
+/// Takes a token from the bucket of the given IP address, returning false if a new connection from it would exceed `max_conn_per_ip_per_second`.
+pub async fn admit_ip(ip: IpAddr) -> bool {
+    let config = CONFIG_FILE.wait();
+    let Some(rate) = config.max_conn_per_ip_per_second.and_then(NonZeroU32::new) else {
+        return true;
+    };
+    let burst = NonZeroU32::new(config.burst).unwrap_or(rate);
+    IP_BUCKETS
+        .get_with(ip, async move {
+            Arc::new(governor::RateLimiter::direct(
+                Quota::per_second(rate).allow_burst(burst),
+            ))
+        })
+        .await
+        .check()
+        .is_ok()
+}
@@ -0,0 +1,276 @@
+use std::{
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier};
+use geph5_broker_protocol::{ExitKeyRotation, Signed, DOMAIN_EXIT_KEY_ROTATION};
+use geph5_misc_rpc::exit::{exit_x25519_public, exit_x25519_secret};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{CONFIG_FILE, SIGNING_SECRET};
+
+/// What the current key signs, followed by the new public key, to authorize its replacement.
+const ROTATION_CONTEXT: &[u8] = b"geph5-exit-key-rotation";
+
+/// A request to replace our signing key, as POSTed to `/rotate-key`. The new secret key is sealed to the current key, so that it never crosses the network in the clear.
+#[derive(Serialize, Deserialize)]
+pub struct RotationRequest {
+    /// An ephemeral X25519 public key, in hex. Its Diffie-Hellman with the current key, taken as an X25519 key, keys the sealing.
+    ephemeral_pk: String,
+    /// The new ed25519 secret key, in hex, sealed with ChaCha20-Poly1305
+    sealed_secret: String,
+    /// The signature, in hex, of the current key over `geph5-exit-key-rotation` followed by the new public key
+    signature: String,
+}
+
+impl RotationRequest {
+    /// Makes a request to replace the given current key with the given new one.
+    pub fn seal(current: &SigningKey, new_key: &SigningKey) -> Self {
+        let ephemeral = x25519_dalek::EphemeralSecret::random_from_rng(rand::thread_rng());
+        let ephemeral_pk = x25519_dalek::PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(&exit_x25519_public(&current.verifying_key()));
+        let sealed = sealing_cipher(shared.as_bytes())
+            .encrypt(&[0; 12].into(), new_key.to_bytes().as_slice())
+            .expect("encryption cannot fail");
+        let message = [ROTATION_CONTEXT, new_key.verifying_key().as_bytes()].concat();
+        Self {
+            ephemeral_pk: hex::encode(ephemeral_pk.as_bytes()),
+            sealed_secret: hex::encode(sealed),
+            signature: hex::encode(current.sign(&message).to_bytes()),
+        }
+    }
+}
+
+/// The cipher that seals the new secret key in a [RotationRequest]. Every request has its own ephemeral key, so a zero nonce is never reused.
+fn sealing_cipher(shared_secret: &[u8; 32]) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(&blake3::derive_key("geph5-exit-key-rotation", shared_secret).into())
+}
+
+struct SigningKeys {
+    current: SigningKey,
+    /// The key we rotated away from, and when, which we keep answering to for a while.
+    retired: Option<(SigningKey, Instant)>,
+    /// Whether the broker has acknowledged that the retired key is retired.
+    announced: bool,
+}
+
+impl SigningKeys {
+    /// Returns the new key in the request, if it was sealed to the current key, and the current key signed off on it.
+    fn check_rotation(&self, request: &RotationRequest) -> anyhow::Result<SigningKey> {
+        let ephemeral_pk: [u8; 32] = hex::decode(&request.ephemeral_pk)?
+            .try_into()
+            .ok()
+            .context("ephemeral keys must be 32 bytes")?;
+        let shared = exit_x25519_secret(&self.current)
+            .diffie_hellman(&x25519_dalek::PublicKey::from(ephemeral_pk));
+        let new_secret = sealing_cipher(shared.as_bytes())
+            .decrypt(
+                &[0; 12].into(),
+                hex::decode(&request.sealed_secret)?.as_slice(),
+            )
+            .ok()
+            .context("new key not sealed to the current key")?;
+        let new_key = SigningKey::from_bytes(
+            &new_secret
+                .try_into()
+                .ok()
+                .context("signing keys must be 32 bytes")?,
+        );
+        let signature = Signature::from_slice(&hex::decode(&request.signature)?)?;
+        anyhow::ensure!(
+            new_key.verifying_key() != self.current.verifying_key(),
+            "new key is the same as the current key"
+        );
+        let message = [ROTATION_CONTEXT, new_key.verifying_key().as_bytes()].concat();
+        self.current
+            .verifying_key()
+            .verify(&message, &signature)
+            .context("rotation not signed by the current key")?;
+        Ok(new_key)
+    }
+
+    /// Makes the given key the current one, retiring the old one.
+    fn replace_current(&mut self, new_key: SigningKey) {
+        let old_key = std::mem::replace(&mut self.current, new_key);
+        self.retired = Some((old_key, Instant::now()));
+        self.announced = false;
+    }
+
+    /// The notice telling the broker about our last rotation, signed by the retired key, unless the broker already has it.
+    fn rotation_notice(&self) -> Option<Signed<ExitKeyRotation>> {
+        if self.announced {
+            return None;
+        }
+        let (retired, _) = self.retired.as_ref()?;
+        Some(Signed::new(
+            ExitKeyRotation {
+                new_pubkey: self.current.verifying_key(),
+            },
+            DOMAIN_EXIT_KEY_ROTATION,
+            retired,
+        ))
+    }
+}
+
+static SIGNING_KEYS: Lazy<RwLock<SigningKeys>> = Lazy::new(|| {
+    RwLock::new(SigningKeys {
+        current: SIGNING_SECRET.clone(),
+        retired: None,
+        announced: true,
+    })
+});
+
+/// The key that new exit descriptors are signed with.
+pub fn current_key() -> SigningKey {
+    SIGNING_KEYS.read().unwrap().current.clone()
+}
+
+/// Every key that clients may still know us by, current key first. Right after a rotation, clients that fetched the exit list earlier still expect the retired key, so we keep handshaking with it and advertising it to the broker until `key_rotation_overlap_secs` have passed.
+pub fn live_keys() -> Vec<SigningKey> {
+    let keys = SIGNING_KEYS.read().unwrap();
+    let overlap = Duration::from_secs(CONFIG_FILE.wait().key_rotation_overlap_secs);
+    std::iter::once(keys.current.clone())
+        .chain(
+            keys.retired
+                .as_ref()
+                .filter(|(_, retired_at)| retired_at.elapsed() < overlap)
+                .map(|(key, _)| key.clone()),
+        )
+        .collect()
+}
+
+/// The notice for the broker that we retired our previous key, if the broker has not acknowledged it yet.
+pub fn rotation_notice() -> Option<Signed<ExitKeyRotation>> {
+    SIGNING_KEYS.read().unwrap().rotation_notice()
+}
+
+/// Records that the broker acknowledged the given notice, unless we rotated again since it was made.
+pub fn mark_rotation_announced(notice: &Signed<ExitKeyRotation>) {
+    let mut keys = SIGNING_KEYS.write().unwrap();
+    if keys.current.verifying_key() == notice.inner.new_pubkey {
+        keys.announced = true;
+    }
+}
+
+/// Replaces the current key with the one in the given JSON [RotationRequest], if the current key signed off on it. The new key is saved to `signing_secret` so that it survives restarts. Sessions that are already established are unaffected, since the key is only used during the handshake.
+pub fn rotate_key(request: &[u8]) -> anyhow::Result<()> {
+    let request: RotationRequest =
+        serde_json::from_slice(request).context("cannot parse key rotation request")?;
+    let mut keys = SIGNING_KEYS.write().unwrap();
+    let new_key = keys.check_rotation(&request)?;
+
+    let secret_path = &CONFIG_FILE.wait().signing_secret;
+    let tmp_path = secret_path.with_extension("tmp");
+    std::fs::write(&tmp_path, new_key.to_bytes())?;
+    std::fs::rename(&tmp_path, secret_path)?;
+
+    tracing::warn!(
+        old_pubkey = hex::encode(keys.current.verifying_key().as_bytes()),
+        new_pubkey = hex::encode(new_key.verifying_key().as_bytes()),
+        "rotated signing key"
+    );
+    keys.replace_current(new_key);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> SigningKeys {
+        SigningKeys {
+            current: SigningKey::from_bytes(&[1; 32]),
+            retired: None,
+            announced: true,
+        }
+    }
+
+    #[test]
+    fn rotates_to_sealed_and_signed_key() {
+        let mut keys = keys();
+        let old_key = keys.current.clone();
+        let new_key = SigningKey::from_bytes(&[2; 32]);
+        let request = RotationRequest::seal(&old_key, &new_key);
+        // the new secret is nowhere in the request
+        let encoded = serde_json::to_string(&request).unwrap();
+        assert!(!encoded.contains(&hex::encode(new_key.to_bytes())));
+
+        let checked = keys.check_rotation(&request).unwrap();
+        assert_eq!(checked.to_bytes(), new_key.to_bytes());
+        keys.replace_current(checked);
+        assert_eq!(keys.current.to_bytes(), new_key.to_bytes());
+        assert_eq!(
+            keys.retired.as_ref().unwrap().0.to_bytes(),
+            old_key.to_bytes()
+        );
+    }
+
+    #[test]
+    fn rejects_rotation_signed_by_another_key() {
+        let keys = keys();
+        let new_key = SigningKey::from_bytes(&[2; 32]);
+        let mut request = RotationRequest::seal(&keys.current, &new_key);
+        // a new key vouching for itself is not enough
+        let message = [ROTATION_CONTEXT, new_key.verifying_key().as_bytes()].concat();
+        request.signature = hex::encode(new_key.sign(&message).to_bytes());
+        assert!(keys.check_rotation(&request).is_err());
+        let stranger = SigningKey::from_bytes(&[3; 32]);
+        request.signature = hex::encode(stranger.sign(&message).to_bytes());
+        assert!(keys.check_rotation(&request).is_err());
+    }
+
+    #[test]
+    fn rejects_rotation_sealed_to_another_key() {
+        let keys = keys();
+        let stranger = SigningKey::from_bytes(&[3; 32]);
+        let request = RotationRequest::seal(&stranger, &SigningKey::from_bytes(&[2; 32]));
+        assert!(keys.check_rotation(&request).is_err());
+    }
+
+    #[test]
+    fn rejects_rotation_to_same_key() {
+        let keys = keys();
+        let current = keys.current.clone();
+        assert!(keys
+            .check_rotation(&RotationRequest::seal(&current, &current))
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_request() {
+        let keys = keys();
+        let new_key = SigningKey::from_bytes(&[2; 32]);
+        let mut bad = RotationRequest::seal(&keys.current, &new_key);
+        bad.sealed_secret.truncate(10);
+        assert!(keys.check_rotation(&bad).is_err());
+        let mut bad = RotationRequest::seal(&keys.current, &new_key);
+        bad.ephemeral_pk = "00".into();
+        assert!(keys.check_rotation(&bad).is_err());
+        let mut bad = RotationRequest::seal(&keys.current, &new_key);
+        bad.signature = "zz".into();
+        assert!(keys.check_rotation(&bad).is_err());
+    }
+
+    #[test]
+    fn announces_rotation_with_retired_key() {
+        let mut keys = keys();
+        assert!(keys.rotation_notice().is_none());
+        let old_key = keys.current.clone();
+        let new_key = SigningKey::from_bytes(&[2; 32]);
+        keys.replace_current(new_key.clone());
+        let notice = keys.rotation_notice().unwrap();
+        assert_eq!(notice.pubkey, old_key.verifying_key());
+        let rotation = notice
+            .verify(DOMAIN_EXIT_KEY_ROTATION, |pk| {
+                pk == &old_key.verifying_key()
+            })
+            .unwrap();
+        assert_eq!(rotation.new_pubkey, new_key.verifying_key());
+        keys.announced = true;
+        assert!(keys.rotation_notice().is_none());
+    }
+}
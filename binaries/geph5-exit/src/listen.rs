@@ -1,28 +1,39 @@
 use anyhow::Context;
-use ed25519_dalek::{Signer, VerifyingKey};
-use flate2::read::GzDecoder;
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
 use futures_util::{AsyncReadExt, TryFutureExt};
 use geph5_broker_protocol::{
     AccountLevel, BrokerClient, ExitDescriptor, Mac, Signed, DOMAIN_EXIT_DESCRIPTOR,
 };
 use geph5_misc_rpc::{
     bridge::B2eMetadata,
-    exit::{ClientCryptHello, ClientExitCryptPipe, ClientHello, ExitHello, ExitHelloInner},
-    read_prepend_length, write_prepend_length,
+    exit::{
+        exit_x25519_secret, read_screened_hello, resumed_shared_secret, resumption_secret,
+        ClientCryptHello, ClientExitCryptPipe, ClientHello, ExitHello, ExitHelloInner, Keepalive,
+        ScreenedHello, EXT_OBFUSCATE_FRAMES,
+    },
+    obfs::ObfuscatedPipe,
+    write_prepend_length,
 };
+use isocountry::CountryCode;
 use mizaru2::{ClientToken, UnblindedSignature};
 use moka::future::Cache;
+use once_cell::sync::OnceCell;
 use picomux::{LivenessConfig, PicoMux};
 
-use sillad::{listener::Listener, tcp::TcpListener, EitherPipe, Pipe};
+use sillad::{
+    listener::{EitherListener, Listener, ListenerExt},
+    tcp::TcpListener,
+    unix::UnixListener,
+    EitherPipe, Pipe,
+};
+use sillad_quic::QuicListener;
 use smol::future::FutureExt as _;
+use smol_timeout2::TimeoutExt;
 use std::{
-    collections::BTreeMap,
-    io::BufRead,
     net::{IpAddr, SocketAddr},
     str::FromStr,
     sync::atomic::Ordering,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 use stdcode::StdcodeSerializeExt;
 use tachyonix::Sender;
@@ -31,17 +42,53 @@ use x25519_dalek::{EphemeralSecret, PublicKey};
 mod b2e_process;
 
 use crate::{
+    accounting::{accounting_loop, DataCap},
+    acme::{acme_cert, acme_renew_loop},
+    asn_db::AsnDb,
+    asn_limit::AsnConnGuard,
+    audit::StreamAudit,
+    blocklist::{blocklist_loop, load_blocklist},
     broker::BrokerRpcTransport,
+    connect_token::{load_mizaru_keys, verify_connect_token},
+    decoy::pass_to_decoy,
+    health::{health_loop, is_draining, mark_withdrawn, signal_loop, wait_draining, InFlightGuard},
+    ip_limit::admit_ip,
+    key_rotation::{current_key, live_keys, mark_rotation_announced, rotation_notice},
+    metrics::{prometheus_loop, ConnectionMetrics, BROKER_HEARTBEATS, HANDSHAKE_LATENCY, LOAD},
+    pmtud::pmtud_echo_loop,
     proxy::proxy_stream,
-    ratelimit::{get_load, get_ratelimiter, RateLimiter, TOTAL_BYTE_COUNT},
-    CONFIG_FILE, SIGNING_SECRET,
+    proxy_protocol,
+    ratelimit::{get_load, get_ratelimiter, get_stream_ratelimiter, RateLimiter, TOTAL_BYTE_COUNT},
+    replay::{is_replay, is_stale},
+    revocation::revocation_loop,
+    session_ticket::{issue_ticket, redeem_ticket},
+    tenant::{take_tenant_stats, TenantGuard},
+    CONFIG_FILE,
 };
 
 pub async fn listen_main() -> anyhow::Result<()> {
+    load_mizaru_keys()?;
+    load_blocklist()?;
     let c2e = c2e_loop();
     let b2e = b2e_loop();
     let broker = broker_loop();
-    c2e.race(broker).race(b2e).await
+    let health = health_loop();
+    let prometheus = prometheus_loop();
+    let revocation = revocation_loop();
+    let accounting = accounting_loop();
+    let pmtud = pmtud_echo_loop();
+    let signal = signal_loop();
+    let blocklist = blocklist_loop();
+    c2e.race(broker)
+        .race(b2e)
+        .race(health)
+        .race(prometheus)
+        .race(revocation)
+        .race(accounting)
+        .race(pmtud)
+        .race(signal)
+        .race(blocklist)
+        .await
 }
 
 #[tracing::instrument]
@@ -59,7 +106,7 @@ async fn broker_loop() -> anyhow::Result<()> {
             .trim(),
         )?
     };
-    let my_pubkey: VerifyingKey = (&*SIGNING_SECRET).into();
+    let my_pubkey: VerifyingKey = current_key().verifying_key();
     tracing::info!(
         c2e_direct = format!(
             "{}:{}/{}",
@@ -77,11 +124,15 @@ async fn broker_loop() -> anyhow::Result<()> {
     );
     match &CONFIG_FILE.wait().broker {
         Some(broker) => {
-            let transport = BrokerRpcTransport::new(&broker.url);
+            let transport = BrokerRpcTransport::new(&broker.url, &broker.spki_pins)?;
             let client = BrokerClient(transport);
             let mut last_byte_count = TOTAL_BYTE_COUNT.load(Ordering::Relaxed);
+            let mut withdrawn = false;
             loop {
                 let upload = async {
+                    if is_draining() && withdrawn {
+                        return anyhow::Ok(());
+                    }
                     let byte_count = TOTAL_BYTE_COUNT.load(Ordering::Relaxed);
                     let diff = byte_count.saturating_sub(last_byte_count);
                     last_byte_count = byte_count;
@@ -89,10 +140,26 @@ async fn broker_loop() -> anyhow::Result<()> {
                     client
                         .incr_stat(format!("{server_name}.throughput"), diff as _)
                         .await?;
+                    // recomputed on every heartbeat, so that connections opened since the last tick count
                     let load = get_load();
+                    LOAD.set(load as f64);
                     client
                         .set_stat(format!("{server_name}.load"), load as _)
                         .await?;
+                    for (tenant_id, bytes, conns) in take_tenant_stats() {
+                        client
+                            .incr_stat(
+                                format!("{server_name}.tenant.{tenant_id}.throughput"),
+                                bytes as _,
+                            )
+                            .await?;
+                        client
+                            .set_stat(
+                                format!("{server_name}.tenant.{tenant_id}.connections"),
+                                conns as _,
+                            )
+                            .await?;
+                    }
 
                     let descriptor = ExitDescriptor {
                         c2e_listen: CONFIG_FILE
@@ -106,24 +173,66 @@ async fn broker_loop() -> anyhow::Result<()> {
                         country: CONFIG_FILE.wait().country,
                         city: CONFIG_FILE.wait().city.clone(),
                         load,
-                        expiry: SystemTime::now()
-                            .duration_since(SystemTime::UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs()
-                            + 60,
+                        probe_magic: CONFIG_FILE.wait().probe_magic
+                            || CONFIG_FILE.wait().probe_resistant,
+                        pmtud_echo: CONFIG_FILE.wait().pmtud_echo,
+                        // when draining, a descriptor that has already expired makes the broker stop sending clients here right away
+                        expiry: if is_draining() {
+                            0
+                        } else {
+                            SystemTime::now()
+                                .duration_since(SystemTime::UNIX_EPOCH)
+                                .unwrap()
+                                .as_secs()
+                                + 60
+                        },
                     };
-                    let to_upload = Mac::new(
-                        Signed::new(descriptor, DOMAIN_EXIT_DESCRIPTOR, &SIGNING_SECRET),
-                        blake3::hash(broker.auth_token.as_bytes()).as_bytes(),
-                    );
-                    client
-                        .insert_exit(to_upload)
-                        .await?
-                        .map_err(|e| anyhow::anyhow!(e.0))?;
+                    // brokers that predate key rotation do not know this call, which should not keep us from registering
+                    if let Some(notice) = rotation_notice() {
+                        let to_send = Mac::new(
+                            notice.clone(),
+                            blake3::hash(broker.auth_token.as_bytes()).as_bytes(),
+                        );
+                        match client.retire_exit_key(to_send).await {
+                            Ok(Ok(())) => mark_rotation_announced(&notice),
+                            Ok(Err(err)) => {
+                                tracing::warn!(
+                                    err = display(err),
+                                    "broker refused our key rotation"
+                                )
+                            }
+                            Err(err) => {
+                                tracing::debug!(
+                                    err = debug(err),
+                                    "cannot tell the broker about our key rotation"
+                                )
+                            }
+                        }
+                    }
+                    // while rotating keys, the broker keeps listing us under the old key too
+                    for key in live_keys() {
+                        let to_upload = Mac::new(
+                            Signed::new(descriptor.clone(), DOMAIN_EXIT_DESCRIPTOR, &key),
+                            blake3::hash(broker.auth_token.as_bytes()).as_bytes(),
+                        );
+                        client
+                            .insert_exit(to_upload)
+                            .await?
+                            .map_err(|e| anyhow::anyhow!(e))?;
+                    }
+                    if is_draining() {
+                        tracing::warn!("withdrew from the broker");
+                        withdrawn = true;
+                        mark_withdrawn();
+                    }
                     anyhow::Ok(())
                 };
-                if let Err(err) = upload.await {
-                    tracing::warn!(err = debug(err), "failed to upload descriptor")
+                match upload.await {
+                    Ok(()) => BROKER_HEARTBEATS.with_label_values(&["success"]).inc(),
+                    Err(err) => {
+                        BROKER_HEARTBEATS.with_label_values(&["failure"]).inc();
+                        tracing::warn!(err = debug(err), "failed to upload descriptor")
+                    }
                 }
                 smol::Timer::after(Duration::from_secs_f64(fastrand::f64() * 5.0)).await;
             }
@@ -135,12 +244,64 @@ async fn broker_loop() -> anyhow::Result<()> {
     }
 }
 
+/// Binds the QUIC listener for client connections, if one is configured. With ACME, the certificate is obtained first if need be, and renewed in the background.
+async fn quic_listener() -> anyhow::Result<Option<QuicListener>> {
+    let cfg = CONFIG_FILE.wait();
+    let Some(quic_listen) = cfg.quic_listen else {
+        return Ok(None);
+    };
+    let listener = if let Some(acme) = &cfg.acme {
+        let (cert_pem, key_pem) = acme_cert(acme)
+            .await
+            .context("cannot get a QUIC certificate over ACME")?;
+        let listener = QuicListener::bind(quic_listen, &cert_pem, &key_pem)?;
+        smolscale::spawn(acme_renew_loop(acme, listener.cert_reloader())).detach();
+        listener
+    } else {
+        let (Some(cert_path), Some(key_path)) = (&cfg.quic_cert, &cfg.quic_key) else {
+            anyhow::bail!("quic_listen needs both quic_cert and quic_key");
+        };
+        let cert_pem = std::fs::read(cert_path).context("cannot read QUIC certificate")?;
+        let key_pem = std::fs::read(key_path).context("cannot read QUIC private key")?;
+        QuicListener::bind(quic_listen, &cert_pem, &key_pem)?
+    };
+    tracing::info!(
+        quic_listen = display(quic_listen),
+        cert_hash = hex::encode(listener.cert_hash()),
+        "also listening over QUIC"
+    );
+    Ok(Some(listener))
+}
+
 async fn c2e_loop() -> anyhow::Result<()> {
-    let mut listener = TcpListener::bind(CONFIG_FILE.wait().c2e_listen).await?;
-    let ip_to_asn = get_ip_to_asn_map().await?;
+    let tcp_listener = TcpListener::bind(CONFIG_FILE.wait().c2e_listen).await?;
+    let listener = if let Some(path) = &CONFIG_FILE.wait().c2e_listen_unix {
+        tracing::info!(path = debug(path), "also listening on a Unix domain socket");
+        EitherListener::Left(tcp_listener.join(UnixListener::bind(path).await?))
+    } else {
+        EitherListener::Right(tcp_listener)
+    };
+    let mut listener = if let Some(quic_listener) = quic_listener().await? {
+        EitherListener::Left(listener.join(quic_listener))
+    } else {
+        EitherListener::Right(listener)
+    };
+    let ip_to_asn = AsnDb::load().await?;
+    let ip_to_asn = IP_TO_ASN.get_or_init(|| ip_to_asn);
     tracing::info!(len = ip_to_asn.len(), "loaded ASN mapping");
     loop {
-        let c2e_raw = match listener.accept().await {
+        let accepted = async { Some(listener.accept().await) }
+            .race(async {
+                wait_draining().await;
+                None
+            })
+            .await;
+        let Some(accepted) = accepted else {
+            tracing::warn!("draining, no longer accepting client connections");
+            drop(listener);
+            return smol::future::pending().await;
+        };
+        let c2e_raw = match accepted {
             Ok(conn) => conn,
             Err(err) => {
                 tracing::error!(err = debug(err), "error accepting");
@@ -148,28 +309,75 @@ async fn c2e_loop() -> anyhow::Result<()> {
             }
         };
 
-        let test_addr = async {
-            let remote_addr: SocketAddr = c2e_raw.remote_addr().unwrap().parse()?;
-            if let SocketAddr::V4(remote_addr) = remote_addr {
-                let (_, (asn, country)) = ip_to_asn
-                    .range(remote_addr.ip().to_bits()..)
-                    .next()
-                    .context("ASN lookup failed")?;
-                tracing::debug!(asn, country, remote_addr = display(remote_addr), "got ASN");
-                if CONFIG_FILE.wait().country_blacklist.contains(country) {
-                    anyhow::bail!("rejected connection from blacklisted country")
-                }
+        smolscale::spawn(async move {
+            admit_client(c2e_raw, ip_to_asn)
+                .map_err(|e| tracing::warn!("client died suddenly with {e}"))
+                .await
+        })
+        .detach()
+    }
+}
+
+/// Vets a new client connection by where it comes from, then serves it. Behind a load balancer speaking the PROXY protocol, that is where the header says, not the load balancer.
+async fn admit_client(mut c2e_raw: impl Pipe, ip_to_asn: &AsnDb) -> anyhow::Result<()> {
+    // connections over the Unix socket have no IP address to test
+    let mut remote_addr: Option<SocketAddr> =
+        c2e_raw.remote_addr().and_then(|addr| addr.parse().ok());
+    // only our own load balancers get to say where a connection is from
+    let from_load_balancer = remote_addr.is_some_and(|addr| {
+        CONFIG_FILE
+            .wait()
+            .proxy_protocol_sources
+            .contains(&addr.ip().to_canonical())
+    });
+    if CONFIG_FILE.wait().proxy_protocol && c2e_raw.protocol() == "tcp" && from_load_balancer {
+        remote_addr = Some(
+            proxy_protocol::read_header(&mut c2e_raw)
+                .timeout(Duration::from_secs(10))
+                .await
+                .context("timed out reading PROXY protocol header")?
+                .context("bad PROXY protocol header")?,
+        );
+    }
+    if let Some(remote_addr) = remote_addr {
+        if !admit_ip(remote_addr.ip()).await {
+            tracing::debug!(
+                remote_addr = display(remote_addr),
+                "dropped connection from IP over its connection rate limit"
+            );
+            return Ok(());
+        }
+    }
+    let mut remote_asn = None;
+    let test_addr = async {
+        if let Some(remote_addr) = remote_addr {
+            let (asn, country) = ip_to_asn
+                .lookup(remote_addr.ip())
+                .context("ASN lookup failed")?;
+            tracing::debug!(asn, country, remote_addr = display(remote_addr), "got ASN");
+            remote_asn = Some(asn);
+            if CONFIG_FILE.wait().country_blacklist.contains(country) {
+                anyhow::bail!("rejected connection from blacklisted country")
             }
-            anyhow::Ok(())
-        };
-        if let Err(err) = test_addr.await {
-            tracing::warn!(err = debug(err), "addr testing failed");
         }
-        smolscale::spawn(
-            handle_client(c2e_raw).map_err(|e| tracing::warn!("client died suddenly with {e}")),
-        )
-        .detach()
+        anyhow::Ok(())
+    };
+    if let Err(err) = test_addr.await {
+        tracing::warn!(err = debug(err), "addr testing failed");
     }
+    let _asn_guard = match remote_asn.map(AsnConnGuard::admit) {
+        Some(None) => {
+            tracing::warn!(
+                asn = remote_asn,
+                remote_addr = debug(remote_addr),
+                "rejected connection from ASN over its connection limit"
+            );
+            return Ok(());
+        }
+        Some(guard) => guard,
+        None => None,
+    };
+    handle_client(c2e_raw, remote_addr).await
 }
 
 async fn b2e_loop() -> anyhow::Result<()> {
@@ -178,7 +386,18 @@ async fn b2e_loop() -> anyhow::Result<()> {
         .time_to_idle(Duration::from_secs(86400))
         .build();
     loop {
-        let b2e_raw = match listener.accept().await {
+        let accepted = async { Some(listener.accept().await) }
+            .race(async {
+                wait_draining().await;
+                None
+            })
+            .await;
+        let Some(accepted) = accepted else {
+            tracing::warn!("draining, no longer accepting bridge connections");
+            drop(listener);
+            return smol::future::pending().await;
+        };
+        let b2e_raw = match accepted {
             Ok(conn) => conn,
             Err(err) => {
                 tracing::error!(err = debug(err), "error accepting");
@@ -195,6 +414,10 @@ async fn b2e_loop() -> anyhow::Result<()> {
         smolscale::spawn::<anyhow::Result<()>>(async move {
             loop {
                 let lala = b2e_mux.accept().await?;
+                // each of these is a new client connection relayed by the bridge
+                if is_draining() {
+                    continue;
+                }
                 let b2e_metadata: B2eMetadata = stdcode::deserialize(lala.metadata())?;
                 tracing::debug!(
                     metadata = debug(&b2e_metadata),
@@ -218,16 +441,100 @@ async fn b2e_loop() -> anyhow::Result<()> {
     }
 }
 
-async fn handle_client(mut client: impl Pipe) -> anyhow::Result<()> {
+/// Serves a client, whose address is only known when it connects to us directly rather than through a bridge.
+async fn handle_client(
+    mut client: impl Pipe,
+    client_addr: Option<SocketAddr>,
+) -> anyhow::Result<()> {
+    let start = Instant::now();
     // execute the authentication
-    let client_hello: ClientHello = stdcode::deserialize(&read_prepend_length(&mut client).await?)?;
+    let keys = live_keys();
+    let pubkeys: Vec<VerifyingKey> = keys.iter().map(|key| key.verifying_key()).collect();
+    let screened =
+        read_screened_hello(&mut client, &pubkeys, CONFIG_FILE.wait().probe_resistant).await?;
+    let screened = match screened {
+        // whoever replays a magic they saw on the wire is probing us
+        ScreenedHello::Hello(_, Some((_, magic)))
+            if is_replay(&blake3::derive_key("geph5-probe-magic-replay", &magic)) =>
+        {
+            ScreenedHello::Probe(magic.to_vec())
+        }
+        screened => screened,
+    };
+    let (client_hello, signing_key) = match screened {
+        // clients that send no magic can only be answered with the current key
+        ScreenedHello::Hello(hello, magic) => (
+            ClientHello::decode(&hello)?,
+            &keys[magic.map(|(key_index, _)| key_index).unwrap_or_default()],
+        ),
+        ScreenedHello::Probe(read) if CONFIG_FILE.wait().probe_resistant => {
+            tracing::debug!(
+                client_addr = debug(client_addr),
+                "passing suspected probe to the decoy"
+            );
+            return pass_to_decoy(client, read).await;
+        }
+        ScreenedHello::Probe(_) => anyhow::bail!("client sent a bad or replayed probe magic"),
+    };
+    for ext in client_hello.unknown_extensions() {
+        tracing::debug!(ext, "ignoring unknown client hello extension");
+    }
+    // the connect token is checked before anything else, so that clients without a valid one cost us as little as possible
+    let resumption = client_hello.session_resumption()?;
+    let mut ticket_secret = None;
+    let credentials = if CONFIG_FILE.wait().broker.is_some() {
+        // a session ticket stands in for a connect token that we already verified
+        match resumption.as_ref().and_then(|r| r.presented.as_ref()) {
+            Some(presented) => match redeem_ticket(presented, &client_hello.nonce()) {
+                Ok((level, token, secret)) => {
+                    ticket_secret = Some(secret);
+                    Some((level, token))
+                }
+                Err(err) => {
+                    reject_client(&mut client, signing_key, &client_hello, format!("{err:#}"))
+                        .await?;
+                    anyhow::bail!("rejected client with a bad session ticket: {err:#}");
+                }
+            },
+            None => {
+                let (level, token, sig): (AccountLevel, ClientToken, UnblindedSignature) =
+                    stdcode::deserialize(&client_hello.credentials)
+                        .context("cannot deserialize credentials")?;
+                if let Err(err) = verify_connect_token(level, token, &sig) {
+                    reject_client(&mut client, signing_key, &client_hello, format!("{err:#}"))
+                        .await?;
+                    anyhow::bail!("rejected client with a bad connect token: {err:#}");
+                }
+                Some((level, token))
+            }
+        }
+    } else {
+        None
+    };
+    // clients that predate keepalive negotiation get our own settings
+    let keepalive = Keepalive {
+        interval_secs: CONFIG_FILE.wait().keepalive_interval_secs,
+        timeout_secs: CONFIG_FILE.wait().keepalive_timeout_secs,
+    };
+    let keepalive = match client_hello.keepalive()? {
+        Some(theirs) => keepalive.negotiate(theirs.clamp(
+            CONFIG_FILE.wait().min_keepalive_interval_secs,
+            CONFIG_FILE.wait().max_keepalive_timeout_secs,
+        )),
+        None => keepalive,
+    };
 
+    let obfuscate = client_hello.extensions.contains_key(EXT_OBFUSCATE_FRAMES);
     let keys: Option<([u8; 32], [u8; 32])>;
+    let session_secret: [u8; 32];
+    let timestamp_secret: Vec<u8>;
     let exit_hello_inner: ExitHelloInner = match client_hello.crypt_hello {
         ClientCryptHello::SharedSecretChallenge(key) => {
             let real_ss = client.shared_secret().context("no shared secret")?;
             let mac = blake3::keyed_hash(&key, real_ss);
             keys = None;
+            session_secret = resumption_secret(real_ss);
+            timestamp_secret = real_ss.to_vec();
             ExitHelloInner::SharedSecretResponse(mac)
         }
         ClientCryptHello::X25519(their_epk) => {
@@ -237,68 +544,198 @@ async fn handle_client(mut client: impl Pipe) -> anyhow::Result<()> {
             let read_key = blake3::derive_key("c2e", shared_secret.as_bytes());
             let write_key = blake3::derive_key("e2c", shared_secret.as_bytes());
             keys = Some((read_key, write_key));
-            ExitHelloInner::X25519(my_epk)
+            session_secret = resumption_secret(shared_secret.as_bytes());
+            timestamp_secret = exit_x25519_secret(signing_key)
+                .diffie_hellman(&their_epk)
+                .as_bytes()
+                .to_vec();
+            if obfuscate {
+                ExitHelloInner::X25519Obfuscated(my_epk)
+            } else {
+                ExitHelloInner::X25519(my_epk)
+            }
+        }
+        // the ticket already proved who the client is, so there is no key exchange to do
+        ClientCryptHello::Resume(client_nonce) => {
+            let Some(secret) = ticket_secret else {
+                let reason = "cannot resume a session without a session ticket".to_string();
+                reject_client(&mut client, signing_key, &client_hello, reason).await?;
+                anyhow::bail!("client tried to resume without a session ticket");
+            };
+            let exit_nonce = rand::random();
+            let shared_secret = resumed_shared_secret(&secret, &client_nonce, &exit_nonce);
+            let read_key = blake3::derive_key("c2e", &shared_secret);
+            let write_key = blake3::derive_key("e2c", &shared_secret);
+            keys = Some((read_key, write_key));
+            session_secret = resumption_secret(&shared_secret);
+            timestamp_secret = secret.to_vec();
+            ExitHelloInner::Resumed {
+                nonce: exit_nonce,
+                obfuscated: obfuscate,
+            }
         }
     };
 
-    let ratelimit = if CONFIG_FILE.wait().broker.is_some() {
-        let (level, token, _sig): (AccountLevel, ClientToken, UnblindedSignature) =
-            stdcode::deserialize(&client_hello.credentials)
-                .context("cannot deserialize credentials")?;
-        get_ratelimiter(level, token).await
+    let mut reject = None;
+    let replayed = is_replay(&client_hello.nonce());
+    if replayed {
+        reject = Some("replayed client hello".to_string());
+    }
+    // connections accepted just before draining started, or arriving through a bridge, must not become new sessions
+    if is_draining() {
+        reject = Some("exit is shutting down".to_string());
+    }
+    match client_hello.timestamp()? {
+        Some(timestamp) if !timestamp.verify(&timestamp_secret, &client_hello.crypt_hello) => {
+            reject = Some("client hello timestamp failed validation".to_string());
+        }
+        Some(timestamp) if is_stale(timestamp.secs) => {
+            reject = Some("stale client hello, check the system clock".to_string());
+        }
+        Some(_) => {}
+        None if CONFIG_FILE.wait().require_hello_timestamp => {
+            reject = Some("client hello has no timestamp, please upgrade".to_string());
+        }
+        // hellos from older clients carry no timestamp, so only the nonce protects them
+        None => {}
+    }
+    let token_hash = credentials.map(|(_, token)| blake3::hash(&token.stdcode()));
+    let (mut ratelimit, level, data_cap) = if let Some((level, token)) = credentials {
+        // accounting trouble should not take the whole exit down, so it fails open
+        let data_cap = DataCap::lookup(level, *blake3::hash(&token.stdcode()).as_bytes())
+            .await
+            .unwrap_or_else(|err| {
+                tracing::warn!(err = debug(err), "cannot look up data usage");
+                None
+            });
+        let mut ratelimit = get_ratelimiter(level, token).await;
+        if let Some(data_cap) = &data_cap {
+            if data_cap.exceeded() {
+                reject = Some("data cap exceeded".to_string());
+            }
+            ratelimit = data_cap.counting(ratelimit);
+        }
+        (ratelimit, Some(level), data_cap)
     } else {
-        RateLimiter::unlimited()
+        (RateLimiter::unlimited(), None, None)
+    };
+    // clients of a tenant share the tenant's quotas, on top of their own
+    let tenant_guard = match client_hello.tenant_claim()? {
+        Some(claim) => match TenantGuard::admit(&claim, &client_hello.crypt_hello) {
+            Ok(guard) => {
+                ratelimit = ratelimit.combine(&guard.limiter());
+                Some(guard)
+            }
+            Err(err) => {
+                tracing::debug!(
+                    tenant_id = claim.tenant_id,
+                    err = debug(&err),
+                    "rejected tenant claim"
+                );
+                reject = Some(format!("tenant {}: {err}", claim.tenant_id));
+                None
+            }
+        },
+        None => None,
+    };
+    let exit_hello_inner = match (&reject, credentials) {
+        (Some(_), _) if replayed => ExitHelloInner::ReplayDetected,
+        (Some(reason), _) => ExitHelloInner::Reject(reason.clone()),
+        (None, Some((level, token))) if resumption.is_some() => ExitHelloInner::Resumable {
+            inner: Box::new(exit_hello_inner),
+            ticket: issue_ticket(level, token, session_secret),
+        },
+        (None, _) => exit_hello_inner,
     };
-
-    // TODO authenticate against broker's public key
 
     let exit_hello = ExitHello {
         inner: exit_hello_inner.clone(),
-        signature: SIGNING_SECRET.sign(&(client_hello, exit_hello_inner).stdcode()),
+        signature: signing_key.sign(&(client_hello, exit_hello_inner).stdcode()),
     };
     write_prepend_length(&exit_hello.stdcode(), &mut client).await?;
+    HANDSHAKE_LATENCY.observe(start.elapsed().as_secs_f64());
+    if let Some(reason) = reject {
+        anyhow::bail!("rejected client: {reason}");
+    }
+    // only clients that made it through the handshake count towards our load, so that idle connections cannot make us look full
+    let conn_metrics = ConnectionMetrics::new(tenant_guard.as_ref().map(|guard| guard.tenant_id()));
+    if let Some(guard) = &tenant_guard {
+        tracing::debug!(tenant_id = guard.tenant_id(), "admitted tenant client");
+    }
 
-    let client = if let Some((read_key, write_key)) = keys {
-        EitherPipe::Left(ClientExitCryptPipe::new(client, read_key, write_key))
-    } else {
-        EitherPipe::Right(client)
+    let client = match keys {
+        Some((read_key, write_key)) if obfuscate => EitherPipe::Left(EitherPipe::Left(
+            ObfuscatedPipe::new(ClientExitCryptPipe::new(client, read_key, write_key)),
+        )),
+        Some((read_key, write_key)) => EitherPipe::Left(EitherPipe::Right(
+            ClientExitCryptPipe::new(client, read_key, write_key),
+        )),
+        None => EitherPipe::Right(client),
     };
 
     let (client_read, client_write) = client.split();
-    let mux = PicoMux::new(client_read, client_write);
+    let mut mux = PicoMux::new(client_read, client_write);
+    mux.set_liveness(LivenessConfig {
+        ping_interval: Duration::from_secs(keepalive.interval_secs),
+        timeout: Duration::from_secs(keepalive.timeout_secs),
+    });
     loop {
         let stream = mux.accept().await?;
         let metadata = String::from_utf8_lossy(stream.metadata()).to_string();
-        smolscale::spawn(
-            proxy_stream(ratelimit.clone(), stream)
-                .map_err(|e| tracing::trace!(metadata = display(metadata), "stream died with {e}")),
-        )
+        // the sessions we already have get to finish their streams, but start no new ones
+        if is_draining() {
+            tracing::debug!(
+                metadata = display(metadata),
+                "refused stream while draining"
+            );
+            continue;
+        }
+        // picomux has no way to refuse a single stream with a reason, so we end the session instead, and the client's next handshake is turned away with a signed "data cap exceeded"
+        if data_cap
+            .as_ref()
+            .is_some_and(|data_cap| data_cap.exceeded())
+        {
+            anyhow::bail!("client went over its data cap, refused stream {metadata}");
+        }
+        let in_flight = InFlightGuard::new();
+        let mut ratelimit = ratelimit
+            .combine(&get_stream_ratelimiter(level))
+            .counting(conn_metrics.counter());
+        let audit = StreamAudit::start(token_hash, client_addr, &metadata);
+        if let Some(audit) = &audit {
+            ratelimit = ratelimit.counting(audit.counter());
+        }
+        smolscale::spawn(async move {
+            let _in_flight = in_flight;
+            proxy_stream(ratelimit, client_addr, audit.as_ref(), stream)
+                .map_err(|e| tracing::trace!(metadata = display(metadata), "stream died with {e}"))
+                .await
+        })
         .detach();
     }
 }
 
-async fn get_ip_to_asn_map() -> anyhow::Result<BTreeMap<u32, (u32, String)>> {
-    let url = "https://iptoasn.com/data/ip2asn-v4-u32.tsv.gz";
-    let response = reqwest::get(url).await?;
-    let bytes = response.bytes().await?;
-
-    let decoder = GzDecoder::new(&bytes[..]);
-    let reader = std::io::BufReader::new(decoder);
-
-    let mut map = BTreeMap::new();
-
-    for line in reader.lines() {
-        let line = line?;
-        let fields: Vec<&str> = line.split('\t').collect();
-
-        if fields.len() >= 4 {
-            let range_end: u32 = fields[1].parse()?;
-            let as_number: u32 = fields[2].parse()?;
-            let country_code = fields[3].to_string();
+/// Turns a client away with a signed rejection, without doing the key exchange first.
+async fn reject_client(
+    client: &mut impl Pipe,
+    signing_key: &SigningKey,
+    client_hello: &ClientHello,
+    reason: String,
+) -> anyhow::Result<()> {
+    let inner = ExitHelloInner::Reject(reason);
+    let exit_hello = ExitHello {
+        inner: inner.clone(),
+        signature: signing_key.sign(&(client_hello, inner).stdcode()),
+    };
+    write_prepend_length(&exit_hello.stdcode(), client).await?;
+    Ok(())
+}
 
-            map.insert(range_end, (as_number, country_code));
-        }
-    }
+/// The mapping from IP ranges to their ASN and country, loaded when we start accepting clients.
+static IP_TO_ASN: OnceCell<AsnDb> = OnceCell::new();
 
-    Ok(map)
+/// Looks up the country an IP address is registered in, if the ASN mapping is loaded.
+pub fn ip_country(ip: IpAddr) -> Option<CountryCode> {
+    let (_, country) = IP_TO_ASN.get()?.lookup(ip)?;
+    CountryCode::for_alpha2(country).ok()
 }
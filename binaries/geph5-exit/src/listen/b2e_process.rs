@@ -1,6 +1,5 @@
 use std::io::ErrorKind;
 
-use async_trait::async_trait;
 use geph5_misc_rpc::bridge::{B2eMetadata, ObfsProtocol};
 use sillad_sosistab3::{listener::SosistabListener, Cookie};
 use tachyonix::Receiver;
@@ -22,13 +21,13 @@ pub async fn b2e_process(
 async fn b2e_inner(mut listener: impl sillad::listener::Listener) -> anyhow::Result<()> {
     loop {
         let client = listener.accept().await?;
-        smolscale::spawn(handle_client(client)).detach();
+        // clients behind a bridge have no address of their own to go by
+        smolscale::spawn(handle_client(client, None)).detach();
     }
 }
 
 struct ReceiverListener(Receiver<picomux::Stream>);
 
-#[async_trait]
 impl sillad::listener::Listener for ReceiverListener {
     type P = picomux::Stream;
     async fn accept(&mut self) -> std::io::Result<Self::P> {
@@ -1,3 +1,4 @@
+use anyhow::Context as _;
 use clap::Parser;
 use ed25519_dalek::SigningKey;
 use isocountry::CountryCode;
@@ -6,18 +7,43 @@ use once_cell::sync::{Lazy, OnceCell};
 use rand::Rng;
 use serde::Deserialize;
 use std::{
+    collections::HashMap,
     net::{IpAddr, SocketAddr},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt as _};
 
+mod accounting;
+mod acme;
+mod admin_auth;
 mod allow;
+mod asn_db;
+mod asn_limit;
+mod audit;
+mod blocklist;
 mod broker;
+mod classify;
+mod connect_token;
+mod decoy;
+mod dns;
+mod health;
+mod icmp;
+mod ip_limit;
+mod key_rotation;
 mod listen;
+mod metrics;
+mod mirror;
+mod pmtud;
 mod proxy;
+mod proxy_protocol;
 mod ratelimit;
+mod replay;
+mod revocation;
+mod session_ticket;
+mod spki_pin;
+mod tenant;
 
-use crate::ratelimit::update_load_loop;
+use crate::{classify::Protocol, ratelimit::update_load_loop};
 
 // #[cfg(not(target_env = "msvc"))]
 // #[global_allocator]
@@ -33,6 +59,20 @@ struct ConfigFile {
     broker: Option<BrokerConfig>,
 
     c2e_listen: SocketAddr,
+    /// Also accept client connections on this Unix domain socket, for clients on the same machine
+    #[serde(default)]
+    c2e_listen_unix: Option<PathBuf>,
+    /// Also accept client connections over QUIC on this UDP address, which must differ from `c2e_listen` when `pmtud_echo` is on
+    #[serde(default)]
+    quic_listen: Option<SocketAddr>,
+    /// The PEM certificate chain and private key to present over QUIC, unless `acme` is set. Clients pin the hash of the certificate, which is logged at startup, and check nothing else, so a self-signed certificate does
+    #[serde(default)]
+    quic_cert: Option<PathBuf>,
+    #[serde(default)]
+    quic_key: Option<PathBuf>,
+    /// Obtain the QUIC certificate from Let's Encrypt instead, and renew it without restarting. Each renewal changes the hash clients pin, and logs the new one
+    #[serde(default)]
+    acme: Option<AcmeConfig>,
     b2e_listen: SocketAddr,
     ip_addr: Option<IpAddr>,
 
@@ -42,6 +82,22 @@ struct ConfigFile {
     #[serde(default = "default_country_blacklist")]
     country_blacklist: Vec<String>,
 
+    /// The maximum number of concurrent client connections from each listed ASN
+    #[serde(default)]
+    asn_rate_limits: HashMap<u32, u32>,
+
+    /// The most new client connections to accept from a single IP address per second, over the long run. Unlimited if unset.
+    #[serde(default)]
+    max_conn_per_ip_per_second: Option<u32>,
+
+    /// How many connections a single IP address may open in a burst before `max_conn_per_ip_per_second` kicks in. Zero means the same as the per-second limit.
+    #[serde(default)]
+    burst: u32,
+
+    /// A local copy of an iptoasn.com database, optionally gzipped, used instead of downloading ip2asn-combined. Only ip2asn-combined and ip2asn-v6 cover IPv6 clients
+    #[serde(default)]
+    asn_db_path: Option<PathBuf>,
+
     #[serde(default = "default_free_ratelimit")]
     free_ratelimit: u32,
 
@@ -50,6 +106,209 @@ struct ConfigFile {
 
     #[serde(default = "default_total_ratelimit")]
     total_ratelimit: u32,
+
+    /// How many authenticated client connections we can comfortably serve at once. Reaching it counts as full load.
+    #[serde(default = "default_connection_capacity")]
+    connection_capacity: u32,
+
+    /// If set, caps every single stream at this many KB/s, on top of the per-account limits
+    #[serde(default)]
+    stream_ratelimit: Option<u32>,
+
+    /// If set, caps every single stream of a free account at this many KB/s instead of `stream_ratelimit`
+    #[serde(default)]
+    free_stream_ratelimit: Option<u32>,
+
+    /// Where to keep the SQLite database that counts how much each user transfers. Data caps are not enforced without it. Users are told apart by their connect tokens, which are unlinkable to each other, so usage starts over whenever a client moves on to a new token.
+    #[serde(default)]
+    data_cap_db_path: Option<PathBuf>,
+
+    /// How many MB a free account may transfer in each accounting period. Unlimited if unset.
+    #[serde(default)]
+    free_data_cap_mb: Option<u64>,
+
+    /// How many MB a Plus account may transfer in each accounting period. Unlimited if unset.
+    #[serde(default)]
+    plus_data_cap_mb: Option<u64>,
+
+    /// How long each accounting period lasts, after which everyone's usage starts over from zero
+    #[serde(default = "default_data_cap_period_secs")]
+    data_cap_period_secs: u64,
+
+    #[serde(default)]
+    egress_prefer_ipv6: bool,
+
+    /// The DNS server that answers the queries clients send over `dns` streams
+    #[serde(default = "default_dns_upstream")]
+    dns_upstream: SocketAddr,
+
+    /// Local IP addresses to send traffic from, keyed by the country of the destination
+    #[serde(default)]
+    egress_bindings: HashMap<CountryCode, IpAddr>,
+
+    /// The local IP address to send traffic from when no egress binding matches
+    #[serde(default)]
+    default_egress: Option<IpAddr>,
+
+    /// Classify proxied TCP streams by the first bytes they send, with an eBPF program attached to our cgroup. Needs Linux 5.7 or later, an exit built with the `ebpf` feature, and the privileges to load BPF programs
+    #[serde(default)]
+    ebpf_classifier: bool,
+
+    /// Exit-wide limits in KB/s on streams classified as each protocol: http, tls, ssh, bittorrent or unknown. Only used with ebpf_classifier.
+    #[serde(default)]
+    protocol_ratelimit: HashMap<String, u32>,
+
+    #[serde(default)]
+    proxy_protocol_emit: bool,
+
+    /// Expect a PROXY protocol v1 or v2 header at the start of every TCP client connection from one of `proxy_protocol_sources`, and go by the client address in it rather than the load balancer's
+    #[serde(default)]
+    proxy_protocol: bool,
+
+    /// The IP addresses of the load balancers that put PROXY protocol headers before client connections. Connections from anywhere else are taken to come straight from clients.
+    #[serde(default)]
+    proxy_protocol_sources: Vec<IpAddr>,
+
+    /// A file of destinations that clients may not reach, with one CIDR range, IP address, or domain per line. Domains also block their subdomains. The file is reread on SIGHUP.
+    #[serde(default)]
+    blocklist: Option<PathBuf>,
+
+    /// Where to send a best-effort copy of all proxied TCP traffic, over UDP, for passive analysis
+    #[serde(default)]
+    traffic_mirror: Option<SocketAddr>,
+
+    /// Where to log every proxied stream, one JSON object per line, with the destination, the bytes transferred, and a hash of the client's connect token. The file is renamed to end in the date at the end of every UTC day.
+    #[serde(default)]
+    audit_log: Option<PathBuf>,
+
+    /// Answer path MTU discovery probes from clients, over UDP on the same port as `c2e_listen`. The probes and their answers are plainly recognizable, so this needs `probe_magic` and cannot be combined with `probe_resistant`, and clients only send them when connecting directly.
+    #[serde(default)]
+    pmtud_echo: bool,
+
+    #[serde(default)]
+    health_addr: Option<SocketAddr>,
+
+    /// Serve Prometheus metrics at `/metrics` on this address
+    #[serde(default)]
+    prometheus_listen: Option<SocketAddr>,
+
+    /// How long draining, whether started over `/drain` or by SIGTERM or SIGINT, waits for in-flight streams before exiting anyway
+    #[serde(default = "default_drain_timeout_secs")]
+    drain_timeout_secs: u64,
+
+    /// How long after a key rotation over `/rotate-key` we keep accepting clients that know us by the old key, and keep advertising it to the broker. The broker stops accepting the old key after its own `exit_key_overlap_secs`.
+    #[serde(default = "default_key_rotation_overlap_secs")]
+    key_rotation_overlap_secs: u64,
+
+    /// Tell clients, through our exit descriptor, that we understand the probe magic, so that clients that know about it start their connections with it. The broker must be new enough to know about the probe magic too, or it cannot check our descriptor's signature and will not list us.
+    #[serde(default)]
+    probe_magic: bool,
+
+    /// Only reveal ourselves to clients that start with a valid probe magic for our key, and treat every other connection as an active probe. Implies `probe_magic`. Clients too old to know about the probe magic never send it, so roll this out by turning on `probe_magic` first, and only turn this on once such clients are no longer in use, since they will be treated as probes.
+    #[serde(default)]
+    probe_resistant: bool,
+
+    /// The HTTPS server, such as `https://example.com`, that connections treated as probes are passed to, so that the exit looks like that server. Probes are disconnected silently if unset.
+    #[serde(default)]
+    decoy_url: Option<String>,
+
+    /// How often to ping each client to keep idle tunnels from being dropped by NAT boxes along the way. Clients that ask for more frequent pings get them.
+    #[serde(default = "default_keepalive_interval_secs")]
+    keepalive_interval_secs: u64,
+
+    /// How long to wait for a client to answer a keepalive ping before giving up the tunnel as dead. Clients that ask for more patience get it.
+    #[serde(default = "default_keepalive_timeout_secs")]
+    keepalive_timeout_secs: u64,
+
+    /// The most often a client may have us ping it, whatever it asks for
+    #[serde(default = "default_min_keepalive_interval_secs")]
+    min_keepalive_interval_secs: u64,
+
+    /// The longest a client may have us wait for its pongs, whatever it asks for
+    #[serde(default = "default_max_keepalive_timeout_secs")]
+    max_keepalive_timeout_secs: u64,
+
+    /// How long client hello nonces are remembered, to reject replayed handshakes
+    #[serde(default = "default_replay_window_secs")]
+    replay_window_secs: u64,
+
+    /// Reject client hellos without a timestamp. Hellos from clients that predate timestamps are only checked against remembered nonces, so a hello recorded longer than `replay_window_secs` ago can be replayed with its timestamp stripped, until this is turned on.
+    #[serde(default)]
+    require_hello_timestamp: bool,
+
+    /// How long a session ticket lets a client reconnect without presenting its connect token again, or doing a key exchange. Tickets never last longer than `replay_window_secs`.
+    #[serde(default = "default_session_ticket_lifetime_secs")]
+    session_ticket_lifetime_secs: u64,
+
+    /// The secret that admin endpoints such as `/drain` and `/rotate-key` require an HS256 JWT to be signed with. Admin endpoints are disabled if unset.
+    #[serde(default)]
+    admin_jwt_secret: Option<String>,
+
+    /// Log levels for particular modules, keyed by target as in `RUST_LOG`, e.g. `geph5_exit::listen: trace`. They take precedence over `RUST_LOG`.
+    #[serde(default)]
+    log_filters: HashMap<String, String>,
+
+    /// Organizations sharing this exit, each with its own resource quotas, keyed by tenant ID
+    #[serde(default)]
+    tenants: HashMap<String, TenantConfig>,
+}
+
+impl ConfigFile {
+    /// Rejects settings that would make no sense at runtime.
+    fn validate(&self) -> anyhow::Result<()> {
+        for (name, limit) in [
+            ("free_ratelimit", Some(self.free_ratelimit)),
+            ("plus_ratelimit", Some(self.plus_ratelimit)),
+            ("total_ratelimit", Some(self.total_ratelimit)),
+            ("stream_ratelimit", self.stream_ratelimit),
+            ("free_stream_ratelimit", self.free_stream_ratelimit),
+        ] {
+            anyhow::ensure!(limit != Some(0), "{name} must be at least 1 KB/s");
+        }
+        for (protocol, limit) in &self.protocol_ratelimit {
+            anyhow::ensure!(
+                Protocol::ALL.iter().any(|known| known.as_str() == protocol),
+                "protocol_ratelimit has an unknown protocol {protocol}"
+            );
+            anyhow::ensure!(
+                *limit != 0,
+                "protocol_ratelimit for {protocol} must be at least 1 KB/s"
+            );
+        }
+        anyhow::ensure!(
+            self.acme.is_none() || (self.quic_cert.is_none() && self.quic_key.is_none()),
+            "acme obtains the QUIC certificate itself, so it cannot go with quic_cert and quic_key"
+        );
+        anyhow::ensure!(
+            self.quic_listen.is_none()
+                || self.acme.is_some()
+                || (self.quic_cert.is_some() && self.quic_key.is_some()),
+            "quic_listen needs either acme or both quic_cert and quic_key"
+        );
+        anyhow::ensure!(
+            !self.proxy_protocol || !self.proxy_protocol_sources.is_empty(),
+            "proxy_protocol needs the addresses of the load balancers in proxy_protocol_sources"
+        );
+        anyhow::ensure!(
+            !(self.pmtud_echo && self.probe_resistant),
+            "pmtud_echo answers anyone who asks, which gives away a probe_resistant exit"
+        );
+        anyhow::ensure!(
+            !self.pmtud_echo || self.probe_magic,
+            "pmtud_echo is only advertised together with probe_magic"
+        );
+        anyhow::ensure!(
+            self.min_keepalive_interval_secs > 0
+                && self.keepalive_interval_secs >= self.min_keepalive_interval_secs,
+            "keepalive_interval_secs must be at least min_keepalive_interval_secs, which must be at least 1"
+        );
+        anyhow::ensure!(
+            self.keepalive_timeout_secs > 0
+                && self.keepalive_timeout_secs <= self.max_keepalive_timeout_secs,
+            "keepalive_timeout_secs must be between 1 and max_keepalive_timeout_secs"
+        );
+        Ok(())
+    }
 }
 
 fn default_free_ratelimit() -> u32 {
@@ -64,14 +323,90 @@ fn default_total_ratelimit() -> u32 {
     125000
 }
 
+fn default_connection_capacity() -> u32 {
+    5000
+}
+
+fn default_dns_upstream() -> SocketAddr {
+    "1.1.1.1:53".parse().unwrap()
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    300
+}
+
+fn default_key_rotation_overlap_secs() -> u64 {
+    600
+}
+
+fn default_replay_window_secs() -> u64 {
+    3600
+}
+
+fn default_session_ticket_lifetime_secs() -> u64 {
+    900
+}
+
+fn default_keepalive_interval_secs() -> u64 {
+    1800
+}
+
+fn default_keepalive_timeout_secs() -> u64 {
+    30
+}
+
+fn default_min_keepalive_interval_secs() -> u64 {
+    10
+}
+
+fn default_max_keepalive_timeout_secs() -> u64 {
+    300
+}
+
+fn default_data_cap_period_secs() -> u64 {
+    30 * 86400
+}
+
 fn default_country_blacklist() -> Vec<String> {
     vec!["CN".to_string(), "IR".to_string()]
 }
 
+#[derive(Deserialize)]
+struct TenantConfig {
+    /// The ed25519 public keys, in hex, that may sign claims of membership in this tenant
+    allowed_pubkeys: Vec<String>,
+    /// The most client connections the tenant may have open at once, or 0 for no limit
+    max_connections: u32,
+    /// The bandwidth shared by all of the tenant's connections, or 0 for no limit
+    bandwidth_cap_kbps: u32,
+}
+
+#[derive(Deserialize)]
+struct AcmeConfig {
+    /// The contact address for the Let's Encrypt account
+    email: String,
+    /// The domain to obtain the certificate for, which must resolve to this exit, since the challenge is answered on port 80
+    domain: String,
+    /// Where the account, certificate, and private key are kept between runs
+    cache_dir: PathBuf,
+}
+
 #[derive(Deserialize)]
 struct BrokerConfig {
     url: String,
     auth_token: String,
+    /// The broker's master public key, in hex, which must sign the revocation list. Revocations are not enforced without it.
+    #[serde(default)]
+    master_pk: Option<String>,
+    /// The broker's Mizaru public key for free connect tokens, in hex. Connect tokens are only verified if both Mizaru keys are given.
+    #[serde(default)]
+    mizaru_free: Option<String>,
+    /// The broker's Mizaru public key for Plus connect tokens, in hex.
+    #[serde(default)]
+    mizaru_plus: Option<String>,
+    /// SHA-256 hashes, in hex, of DER-encoded SubjectPublicKeyInfos. If any are given, the broker's certificate chain must contain one of these keys.
+    #[serde(default)]
+    spki_pins: Vec<String>,
 }
 
 static SIGNING_SECRET: Lazy<SigningKey> = Lazy::new(|| {
@@ -92,28 +427,59 @@ static SIGNING_SECRET: Lazy<SigningKey> = Lazy::new(|| {
     }
 });
 
+fn read_signing_key(path: &Path) -> anyhow::Result<SigningKey> {
+    Ok(SigningKey::from_bytes(
+        &std::fs::read(path)
+            .with_context(|| format!("cannot read {}", path.display()))?
+            .try_into()
+            .ok()
+            .context("signing keys must be 32 bytes")?,
+    ))
+}
+
 /// Run the Geph5 broker.
 #[derive(Parser)]
 struct CliArgs {
     /// path to a YAML-based config file
     #[arg(short, long)]
     config: PathBuf,
+    /// Instead of running, print the body of a `/rotate-key` request that replaces our current signing key with the 32-byte ed25519 secret key in this file
+    #[arg(long)]
+    seal_key_rotation: Option<PathBuf>,
 }
 
 fn main() -> anyhow::Result<()> {
     std::thread::spawn(update_load_loop);
+    let args = CliArgs::parse();
+    let config: ConfigFile = serde_yaml::from_slice(&std::fs::read(args.config)?)?;
+    config.validate()?;
+    if let Some(new_secret) = args.seal_key_rotation {
+        let request = key_rotation::RotationRequest::seal(
+            &read_signing_key(&config.signing_secret)?,
+            &read_signing_key(&new_secret)?,
+        );
+        println!("{}", serde_json::to_string(&request)?);
+        return Ok(());
+    }
+    let mut filter = tracing_subscriber::EnvFilter::builder()
+        .with_default_directive("geph5_exit=debug".parse()?)
+        .from_env_lossy();
+    for (target, level) in config.log_filters.iter() {
+        filter = filter.add_directive(
+            format!("{target}={level}")
+                .parse()
+                .with_context(|| format!("invalid log filter {target}: {level}"))?,
+        );
+    }
     tracing_subscriber::registry()
         .with(tracing_subscriber::fmt::layer().compact())
-        .with(
-            tracing_subscriber::EnvFilter::builder()
-                .with_default_directive("geph5_exit=debug".parse()?)
-                .from_env_lossy(),
-        )
+        .with(filter)
         .init();
     tracing::info!("**** START GEPH EXIT ****");
-    let args = CliArgs::parse();
-    let config: ConfigFile = serde_yaml::from_slice(&std::fs::read(args.config)?)?;
 
+    if config.ebpf_classifier {
+        classify::load_ebpf_classifier().context("cannot start the eBPF classifier")?;
+    }
     CONFIG_FILE.set(config).ok().unwrap();
 
     smol::future::block_on(smolscale::spawn(listen_main()))
@@ -0,0 +1,186 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures_util::{AsyncBufReadExt, AsyncWriteExt};
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, register_gauge, register_histogram, register_histogram_vec,
+    register_int_counter_vec, register_int_gauge_vec, Encoder, Gauge, Histogram, HistogramVec,
+    IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use smol::{io::BufReader, net::TcpListener};
+use smol_timeout2::TimeoutExt;
+
+use crate::CONFIG_FILE;
+
+/// How many authenticated client connections are currently open, by `tenant`, which is empty for clients outside any tenant.
+pub static ACTIVE_CONNECTIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "geph5_exit_active_connections",
+        "Authenticated client connections currently open, by tenant",
+        &["tenant"]
+    )
+    .unwrap()
+});
+
+/// How many authenticated client connections are currently open across all tenants, for working out our load.
+static TOTAL_ACTIVE_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// How many authenticated client connections are currently open, whatever their tenant.
+pub fn active_connections() -> usize {
+    TOTAL_ACTIVE_CONNECTIONS.load(Ordering::Relaxed)
+}
+
+/// How many bytes each client connection forwarded in total, observed when it closes, by `tenant` as in [ACTIVE_CONNECTIONS].
+pub static CONNECTION_BYTES: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "geph5_exit_connection_bytes",
+        "Bytes forwarded over each client connection, by tenant",
+        &["tenant"],
+        exponential_buckets(1024.0, 4.0, 12).unwrap()
+    )
+    .unwrap()
+});
+
+/// How long it takes from accepting a client connection until our hello is sent.
+pub static HANDSHAKE_LATENCY: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "geph5_exit_handshake_latency_seconds",
+        "Time from accepting a client connection to sending the exit hello",
+        exponential_buckets(0.001, 2.0, 14).unwrap()
+    )
+    .unwrap()
+});
+
+/// How many heartbeats to the broker succeeded or failed, by `result`.
+pub static BROKER_HEARTBEATS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "geph5_exit_broker_heartbeats_total",
+        "Descriptor uploads to the broker, by result",
+        &["result"]
+    )
+    .unwrap()
+});
+
+/// The load we advertise to the broker, from 0 to 1.
+pub static LOAD: Lazy<Gauge> =
+    Lazy::new(|| register_gauge!("geph5_exit_load", "Current load, from 0 to 1").unwrap());
+
+/// Counts one authenticated client connection as open, until dropped, when the bytes it forwarded are observed.
+pub struct ConnectionMetrics {
+    bytes: Arc<AtomicU64>,
+    tenant: String,
+}
+
+impl ConnectionMetrics {
+    /// Starts counting a connection, under its tenant if it has one.
+    pub fn new(tenant: Option<&str>) -> Self {
+        let tenant = tenant.unwrap_or_default().to_string();
+        ACTIVE_CONNECTIONS.with_label_values(&[&tenant]).inc();
+        TOTAL_ACTIVE_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        Self {
+            bytes: Default::default(),
+            tenant,
+        }
+    }
+
+    /// The counter that the connection's rate limiter should add forwarded bytes to.
+    pub fn counter(&self) -> Arc<AtomicU64> {
+        self.bytes.clone()
+    }
+}
+
+impl Drop for ConnectionMetrics {
+    fn drop(&mut self) {
+        ACTIVE_CONNECTIONS.with_label_values(&[&self.tenant]).dec();
+        TOTAL_ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+        CONNECTION_BYTES
+            .with_label_values(&[&self.tenant])
+            .observe(self.bytes.load(Ordering::Relaxed) as f64);
+    }
+}
+
+/// Serves every metric in the Prometheus text format at `GET /metrics`, if `prometheus_listen` is configured.
+pub async fn prometheus_loop() -> anyhow::Result<()> {
+    let Some(listen) = CONFIG_FILE.wait().prometheus_listen else {
+        return smol::future::pending().await;
+    };
+    let listener = TcpListener::bind(listen).await?;
+    tracing::info!(listen = display(listen), "serving Prometheus metrics");
+    loop {
+        let (conn, remote) = listener.accept().await?;
+        smolscale::spawn(async move {
+            if let Err(err) = handle_scrape(conn)
+                .timeout(Duration::from_secs(10))
+                .await
+                .unwrap_or_else(|| Err(anyhow::anyhow!("timed out")))
+            {
+                tracing::debug!(
+                    remote = display(remote),
+                    err = debug(err),
+                    "metrics connection failed"
+                );
+            }
+        })
+        .detach();
+    }
+}
+
+async fn handle_scrape(conn: smol::net::TcpStream) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(conn.clone());
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let encoder = TextEncoder::new();
+            let mut body = vec![];
+            encoder.encode(&prometheus::gather(), &mut body)?;
+            ("200 OK", encoder.format_type().to_string(), body)
+        }
+        _ => ("404 Not Found", "text/plain".into(), b"not found".to_vec()),
+    };
+    let mut conn = conn;
+    conn.write_all(
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .as_bytes(),
+    )
+    .await?;
+    conn.write_all(&body).await?;
+    conn.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_are_counted_by_tenant() {
+        // a tenant of its own, since the metrics are global
+        let tenant = "metrics-test";
+        let active = || ACTIVE_CONNECTIONS.with_label_values(&[tenant]).get();
+        let metrics = ConnectionMetrics::new(Some(tenant));
+        metrics.counter().fetch_add(5000, Ordering::Relaxed);
+        assert_eq!(active(), 1);
+        drop(metrics);
+        assert_eq!(active(), 0);
+        let bytes = CONNECTION_BYTES.with_label_values(&[tenant]);
+        assert_eq!(bytes.get_sample_count(), 1);
+        assert_eq!(bytes.get_sample_sum(), 5000.0);
+    }
+}
@@ -0,0 +1,182 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::AsyncRead;
+use once_cell::sync::Lazy;
+use smol::{channel::Sender, net::UdpSocket};
+
+use crate::CONFIG_FILE;
+
+/// How many mirrored datagrams may wait to be sent before we start dropping them.
+const QUEUE_LEN: usize = 1000;
+
+/// The most payload we put in one mirrored datagram, so that the header still fits.
+const MAX_PAYLOAD: usize = 65000;
+
+/// Datagrams waiting to be sent to the traffic mirror, or None if mirroring is off.
+static MIRROR_QUEUE: Lazy<Option<Sender<Vec<u8>>>> = Lazy::new(|| {
+    let dest = CONFIG_FILE.wait().traffic_mirror?;
+    let (send, recv) = smol::channel::bounded::<Vec<u8>>(QUEUE_LEN);
+    smolscale::spawn(async move {
+        let bind_addr: SocketAddr = if dest.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        tracing::info!(dest = display(dest), "mirroring traffic");
+        while let Ok(datagram) = recv.recv().await {
+            if let Err(err) = socket.send_to(&datagram, dest).await {
+                tracing::debug!(err = debug(err), "could not send mirrored traffic");
+            }
+        }
+        anyhow::Ok(())
+    })
+    .detach();
+    Some(send)
+});
+
+/// Which way mirrored traffic was going.
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum Direction {
+    /// From the client to the destination.
+    Up = 0,
+    /// From the destination to the client.
+    Down = 1,
+}
+
+/// Mirrors the traffic of one proxied session. Every datagram starts with a 17-byte header: the session ID (8 bytes), the direction (1 byte), and the time in microseconds since the Unix epoch (8 bytes), all big-endian. The payload follows.
+#[derive(Clone, Copy)]
+pub struct Mirror {
+    session_id: u64,
+}
+
+impl Mirror {
+    /// Starts mirroring a new session, if the `traffic_mirror` is configured.
+    pub fn new() -> Option<Self> {
+        MIRROR_QUEUE.as_ref()?;
+        Some(Self {
+            session_id: rand::random(),
+        })
+    }
+
+    /// Queues a copy of some traffic to be mirrored. This never waits; if the queue is full, the copy is dropped.
+    pub fn record(&self, direction: Direction, data: &[u8]) {
+        let Some(queue) = MIRROR_QUEUE.as_ref() else {
+            return;
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        for datagram in self.datagrams(direction, timestamp, data) {
+            if queue.try_send(datagram).is_err() {
+                tracing::trace!("traffic mirror queue full, dropping");
+                return;
+            }
+        }
+    }
+
+    /// Splits some traffic into mirrored datagrams, each with the header.
+    fn datagrams<'a>(
+        &self,
+        direction: Direction,
+        timestamp: u64,
+        data: &'a [u8],
+    ) -> impl Iterator<Item = Vec<u8>> + 'a {
+        let session_id = self.session_id;
+        data.chunks(MAX_PAYLOAD).map(move |chunk| {
+            let mut datagram = Vec::with_capacity(17 + chunk.len());
+            datagram.extend_from_slice(&session_id.to_be_bytes());
+            datagram.push(direction as u8);
+            datagram.extend_from_slice(&timestamp.to_be_bytes());
+            datagram.extend_from_slice(chunk);
+            datagram
+        })
+    }
+
+    /// Wraps a reader so that everything read from it gets mirrored.
+    pub fn reader<R: AsyncRead + Unpin>(
+        mirror: Option<Self>,
+        direction: Direction,
+        inner: R,
+    ) -> MirrorReader<R> {
+        MirrorReader {
+            inner,
+            mirror,
+            direction,
+        }
+    }
+}
+
+/// A reader that mirrors everything read through it.
+pub struct MirrorReader<R> {
+    inner: R,
+    mirror: Option<Mirror>,
+    direction: Direction,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for MirrorReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let (Some(mirror), Poll::Ready(Ok(n))) = (&self.mirror, &res) {
+            mirror.record(self.direction, &buf[..*n]);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::AsyncReadExt as _;
+
+    use super::*;
+
+    #[test]
+    fn datagram_header_layout() {
+        let mirror = Mirror {
+            session_id: 0x0102030405060708,
+        };
+        let datagrams: Vec<_> = mirror
+            .datagrams(Direction::Down, 0x1112131415161718, b"hello")
+            .collect();
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(&datagrams[0][..8], &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(datagrams[0][8], 1);
+        assert_eq!(
+            &datagrams[0][9..17],
+            &[0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18]
+        );
+        assert_eq!(&datagrams[0][17..], b"hello");
+    }
+
+    #[test]
+    fn big_reads_are_split() {
+        let mirror = Mirror { session_id: 1 };
+        let data = vec![0xab; MAX_PAYLOAD * 2 + 1];
+        let datagrams: Vec<_> = mirror.datagrams(Direction::Up, 2, &data).collect();
+        let payload_lens: Vec<usize> = datagrams.iter().map(|d| d.len() - 17).collect();
+        assert_eq!(payload_lens, vec![MAX_PAYLOAD, MAX_PAYLOAD, 1]);
+        // every piece carries the same header, so the collector can stitch them back together
+        assert!(datagrams.iter().all(|d| d[..17] == datagrams[0][..17]));
+        assert_eq!(datagrams[0][8], 0);
+        assert_eq!(mirror.datagrams(Direction::Up, 2, &[]).count(), 0);
+    }
+
+    #[test]
+    fn reader_passes_data_through() {
+        let mut reader = Mirror::reader(None, Direction::Up, &b"hello"[..]);
+        let mut buf = vec![];
+        smol::future::block_on(reader.read_to_end(&mut buf)).unwrap();
+        assert_eq!(buf, b"hello");
+    }
+}
@@ -0,0 +1,27 @@
+use geph5_misc_rpc::exit::PMTUD_MAGIC;
+use smol::net::UdpSocket;
+
+use crate::CONFIG_FILE;
+
+/// Answers path MTU discovery probes, which clients send to the same port as our c2e listener, but over UDP. Since the probes are sent unfragmented, each answer tells the client that a packet of that size made it all the way here.
+pub async fn pmtud_echo_loop() -> anyhow::Result<()> {
+    if !CONFIG_FILE.wait().pmtud_echo {
+        return smol::future::pending().await;
+    }
+    let socket = UdpSocket::bind(CONFIG_FILE.wait().c2e_listen).await?;
+    let mut buf = [0u8; 65536];
+    loop {
+        let (n, client) = socket.recv_from(&mut buf).await?;
+        let probe = &buf[..n];
+        let Some(size) = probe
+            .strip_prefix(PMTUD_MAGIC.as_slice())
+            .and_then(|rest| rest.get(..2))
+        else {
+            continue;
+        };
+        let reply = [PMTUD_MAGIC.as_slice(), size].concat();
+        if let Err(err) = socket.send_to(&reply, client).await {
+            tracing::debug!(err = debug(err), "could not answer PMTUD probe");
+        }
+    }
+}
@@ -1,6 +1,9 @@
 use std::{
-    net::SocketAddr,
-    sync::LazyLock,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock,
+    },
     time::{Duration, Instant},
 };
 
@@ -9,90 +12,198 @@ use anyhow::Context;
 use futures_util::{io::BufReader, AsyncReadExt, AsyncWriteExt};
 use moka::future::Cache;
 
-use sillad::{dialer::Dialer, tcp::HappyEyeballsTcpDialer};
+use sillad::{
+    dialer::{Dialer, DialerExt, DynDialer},
+    tcp::{BoundTcpDialer, HappyEyeballsTcpDialer, TcpDialer},
+    Pipe,
+};
 use smol::{future::FutureExt as _, net::UdpSocket};
 
-use crate::{allow::proxy_allowed, ratelimit::RateLimiter};
+use crate::{
+    allow::proxy_allowed,
+    audit::StreamAudit,
+    blocklist::{host_blocked, ip_blocked},
+    classify::{write_classified, CLASSIFY_LEN, SNIFF_TIMEOUT},
+    dns::dns_stream,
+    icmp::icmp_stream,
+    listen::ip_country,
+    mirror::{Direction, Mirror},
+    proxy_protocol,
+    ratelimit::RateLimiter,
+    CONFIG_FILE,
+};
 
 use smol_timeout2::TimeoutExt;
 
+/// How long a UDP mapping, between one client stream and one destination, lasts without packets going either way.
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
 #[tracing::instrument(skip_all)]
-pub async fn proxy_stream(ratelimit: RateLimiter, stream: picomux::Stream) -> anyhow::Result<()> {
-    let dest_host = String::from_utf8_lossy(stream.metadata());
+pub async fn proxy_stream(
+    ratelimit: RateLimiter,
+    client_addr: Option<SocketAddr>,
+    audit: Option<&StreamAudit>,
+    stream: picomux::Stream,
+) -> anyhow::Result<()> {
+    let dest_host = String::from_utf8_lossy(stream.metadata()).into_owned();
     let (protocol, dest_host): (&str, &str) = if dest_host.contains('$') {
         dest_host.split_once('$').unwrap()
     } else {
         ("tcp", &dest_host)
     };
-    let dest_addrs = dns_resolve(dest_host)
+    if protocol == "dns" {
+        return dns_stream(ratelimit, stream).await;
+    }
+    let bare_host = dest_host
+        .rsplit_once(':')
+        .map(|(host, _)| host)
+        .unwrap_or(dest_host);
+    if host_blocked(bare_host) {
+        anyhow::bail!("{} is blocklisted", dest_host);
+    }
+    let mut dest_addrs = dns_resolve(dest_host)
         .await
         .context("failed to resolve DNS")?;
+    if CONFIG_FILE.wait().egress_prefer_ipv6 {
+        // happy-eyeballs staggers later addresses by 250 ms, so putting IPv6 first is enough
+        dest_addrs.sort_by_key(|addr| addr.is_ipv4());
+    }
     if !dest_addrs.iter().all(|addr| proxy_allowed(*addr)) {
         anyhow::bail!("Proxying to {} is not allowed", dest_host);
     }
+    if dest_addrs.iter().any(|addr| ip_blocked(addr.ip())) {
+        anyhow::bail!("{} resolves to a blocklisted address", dest_host);
+    }
     match protocol {
         "tcp" => {
             let start = Instant::now();
-            let dest_tcp = HappyEyeballsTcpDialer(dest_addrs)
-                .dial()
-                .await
-                .context("failed to dial")?;
+            let (mut read_stream, mut write_stream) = stream.split();
+            // for the eBPF classifier, we read the first bytes the client sends while dialing, so that the wait overlaps with the dial, and then write them in one go, so that they make up the first segment it sees. With server-speaks-first protocols, the client sends nothing until the server has spoken, so their streams start only once the sniff times out, or the dial is done if that takes longer.
+            let sniff = async {
+                let mut first_bytes = vec![0u8; CLASSIFY_LEN];
+                if !CONFIG_FILE.wait().ebpf_classifier {
+                    first_bytes.clear();
+                    return anyhow::Ok(first_bytes);
+                }
+                let n = read_stream
+                    .read(&mut first_bytes)
+                    .timeout(SNIFF_TIMEOUT)
+                    .await
+                    .transpose()?
+                    .unwrap_or_default();
+                first_bytes.truncate(n);
+                anyhow::Ok(first_bytes)
+            };
+            let dial = async {
+                egress_dialer(&dest_addrs)?
+                    .dial()
+                    .await
+                    .context("failed to dial")
+            };
+            let (first_bytes, dest_tcp) = futures_util::future::try_join(sniff, dial).await?;
             tracing::trace!(
                 protocol,
                 dest_host = display(dest_host),
                 latency = debug(start.elapsed()),
                 "TCP established resolved"
             );
-            let (read_stream, mut write_stream) = stream.split();
+            let dest_addr: SocketAddr = dest_tcp
+                .remote_addr()
+                .context("no remote addr for destination")?
+                .parse()?;
+            if let Some(audit) = audit {
+                audit.set_dest_addr(dest_addr);
+            }
+            let dest_fd = dest_tcp.raw_fd();
             let (read_dest, mut write_dest) = dest_tcp.split();
+            if CONFIG_FILE.wait().proxy_protocol_emit {
+                write_dest
+                    .write_all(&proxy_protocol::encode_v2(client_addr, dest_addr))
+                    .await?;
+            }
+            let mirror = Mirror::new();
+            if let Some(mirror) = mirror {
+                mirror.record(Direction::Up, &first_bytes);
+            }
+            ratelimit.wait(first_bytes.len()).await;
+            let ratelimit = if CONFIG_FILE.wait().ebpf_classifier {
+                let app_protocol = write_classified(&mut write_dest, dest_fd, &first_bytes).await?;
+                tracing::trace!(
+                    dest_host = display(dest_host),
+                    app_protocol = display(app_protocol),
+                    "classified stream"
+                );
+                ratelimit.combine(&app_protocol.ratelimiter())
+            } else {
+                write_dest.write_all(&first_bytes).await?;
+                ratelimit
+            };
             smol::future::race(
-                ratelimit.io_copy(read_stream, &mut write_dest),
-                ratelimit.io_copy(read_dest, &mut write_stream),
+                ratelimit.io_copy(
+                    Mirror::reader(mirror, Direction::Up, read_stream),
+                    &mut write_dest,
+                ),
+                ratelimit.io_copy(
+                    Mirror::reader(mirror, Direction::Down, read_dest),
+                    &mut write_stream,
+                ),
             )
             .await?;
             Ok(())
         }
         "udp" => {
-            let udp_socket: UdpSocket = UdpSocket::bind("0.0.0.0:0")
-                .await
-                .context("UDP bind failed")?;
+            // IPv4 unless the operator prefers IPv6, but either will do
+            let prefer_ipv6 = CONFIG_FILE.wait().egress_prefer_ipv6;
             let addr = *dest_addrs
                 .iter()
-                .find(|s| s.is_ipv4())
-                .context("UDP only supports ipv4 for now")?;
+                .find(|addr| addr.is_ipv6() == prefer_ipv6)
+                .or_else(|| dest_addrs.first())
+                .context("no addresses to send UDP to")?;
+            let bind_ip = egress_ip(addr).unwrap_or(if addr.is_ipv4() {
+                IpAddr::from([0, 0, 0, 0])
+            } else {
+                IpAddr::from([0u16; 8])
+            });
+            anyhow::ensure!(
+                bind_ip.is_ipv4() == addr.is_ipv4(),
+                "egress IP for {addr} is of the wrong address family"
+            );
+            let udp_socket: UdpSocket = UdpSocket::bind((bind_ip, 0))
+                .await
+                .context("UDP bind failed")?;
             if addr.port() == 443 {
                 anyhow::bail!("special-case banning QUIC to improve traffic management")
             }
             udp_socket.connect(addr).await?;
+            if let Some(audit) = audit {
+                audit.set_dest_addr(addr);
+            }
             let (read_stream, mut write_stream) = stream.split();
+            // the mapping lives as long as packets go either way, so that one-way flows like a muted VoIP call are not cut off
+            let start = Instant::now();
+            let last_active = AtomicU64::new(0);
+            let mark_active =
+                || last_active.store(start.elapsed().as_millis() as u64, Ordering::Relaxed);
             let up_loop = async {
                 let mut read_stream = BufReader::new(read_stream);
                 let mut len_buf = [0; 2];
                 loop {
-                    read_stream
-                        .read_exact(&mut len_buf)
-                        .timeout(Duration::from_secs(60))
-                        .await
-                        .context("timeout")??;
+                    read_stream.read_exact(&mut len_buf).await?;
                     let mut packet_buf = vec![0; u16::from_le_bytes(len_buf) as usize];
-                    read_stream
-                        .read_exact(&mut packet_buf)
-                        .timeout(Duration::from_secs(60))
-                        .await
-                        .context("timeout")??;
+                    read_stream.read_exact(&mut packet_buf).await?;
+                    mark_active();
                     ratelimit.wait(packet_buf.len()).await;
                     udp_socket.send(&packet_buf).await?;
                 }
             };
             let dn_loop = async {
-                let mut buf = [0u8; 8192];
+                // the largest datagram the length prefix can carry
+                let mut buf = vec![0u8; 2 + u16::MAX as usize];
                 loop {
                     // Receive data into the buffer starting from the third byte
-                    let len = udp_socket
-                        .recv(&mut buf[2..])
-                        .timeout(Duration::from_secs(60))
-                        .await
-                        .context("timeout")??;
+                    let len = udp_socket.recv(&mut buf[2..]).await?;
+                    mark_active();
+                    // a datagram bigger than the rate limit's burst is let through a burst at a time
                     ratelimit.wait(len).await;
 
                     // Store the length of the data in the first two bytes
@@ -104,7 +215,27 @@ pub async fn proxy_stream(ratelimit: RateLimiter, stream: picomux::Stream) -> an
                     write_stream.write_all(&buf[..len + 2]).await?;
                 }
             };
-            up_loop.race(dn_loop).await
+            let idle_loop = async {
+                loop {
+                    let idle_since =
+                        start + Duration::from_millis(last_active.load(Ordering::Relaxed));
+                    if idle_since.elapsed() >= UDP_IDLE_TIMEOUT {
+                        anyhow::bail!("UDP mapping idle for too long");
+                    }
+                    smol::Timer::at(idle_since + UDP_IDLE_TIMEOUT).await;
+                }
+            };
+            up_loop.race(dn_loop).race(idle_loop).await
+        }
+        "icmp" => {
+            let addr = dest_addrs
+                .iter()
+                .find(|addr| addr.is_ipv4())
+                .context("no IPv4 address to ping")?;
+            if let Some(audit) = audit {
+                audit.set_dest_addr(*addr);
+            }
+            icmp_stream(ratelimit, addr.ip(), stream).await
         }
         prot => {
             anyhow::bail!("unknown protocol {prot}")
@@ -112,6 +243,48 @@ pub async fn proxy_stream(ratelimit: RateLimiter, stream: picomux::Stream) -> an
     }
 }
 
+/// Picks the local IP address that traffic to the given destination must leave from, if the operator bound any.
+fn egress_ip(dest_addr: SocketAddr) -> Option<IpAddr> {
+    let config = CONFIG_FILE.wait();
+    ip_country(dest_addr.ip())
+        .and_then(|country| config.egress_bindings.get(&country).copied())
+        .or(config.default_egress)
+}
+
+/// Like [HappyEyeballsTcpDialer], but dials each address from its egress IP. Addresses of a different family than their egress IP are skipped, since we cannot reach them from there.
+fn egress_dialer(dest_addrs: &[SocketAddr]) -> anyhow::Result<DynDialer> {
+    let config = CONFIG_FILE.wait();
+    if config.egress_bindings.is_empty() && config.default_egress.is_none() {
+        return Ok(HappyEyeballsTcpDialer(dest_addrs.to_vec()).dynamic());
+    }
+    dest_addrs
+        .iter()
+        .filter_map(|dest_addr| match egress_ip(*dest_addr) {
+            Some(bind_ip) if bind_ip.is_ipv4() == dest_addr.is_ipv4() => Some(
+                BoundTcpDialer {
+                    bind_ip,
+                    dest_addr: *dest_addr,
+                }
+                .dynamic(),
+            ),
+            Some(_) => None,
+            None => Some(
+                TcpDialer {
+                    dest_addr: *dest_addr,
+                }
+                .dynamic(),
+            ),
+        })
+        .enumerate()
+        .map(|(idx, dialer)| {
+            dialer
+                .delay(Duration::from_millis(250 * idx as u64))
+                .dynamic()
+        })
+        .reduce(|a, b| a.race(b).dynamic())
+        .context("no destination address reachable from the egress IPs")
+}
+
 async fn dns_resolve(name: &str) -> anyhow::Result<Vec<SocketAddr>> {
     static CACHE: LazyLock<Cache<String, Vec<SocketAddr>>> = LazyLock::new(|| {
         Cache::builder()
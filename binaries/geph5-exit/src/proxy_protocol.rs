@@ -0,0 +1,182 @@
+use std::net::{IpAddr, SocketAddr};
+
+use futures_util::{AsyncRead, AsyncReadExt};
+use ppp::{v1, v2};
+
+/// The fixed signature at the start of every PROXY protocol v2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Encodes a PROXY protocol v2 header for a TCP connection from `src` to `dst`. If the source is not known, a LOCAL header carrying no addresses is produced instead.
+pub fn encode_v2(src: Option<SocketAddr>, dst: SocketAddr) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    let Some(src) = src else {
+        // version 2, LOCAL command, unspecified family, no address block
+        header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        return header;
+    };
+    // version 2, PROXY command
+    header.push(0x21);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            // TCP over IPv4
+            header.push(0x11);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&src_ip.octets());
+            header.extend_from_slice(&dst_ip.octets());
+        }
+        (src_ip, dst_ip) => {
+            // TCP over IPv6, with any IPv4 address mapped into IPv6
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            header.push(0x21);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&to_v6(src_ip).octets());
+            header.extend_from_slice(&to_v6(dst_ip).octets());
+        }
+    }
+    header.extend_from_slice(&src.port().to_be_bytes());
+    header.extend_from_slice(&dst.port().to_be_bytes());
+    header
+}
+
+/// The longest a PROXY protocol v1 header may be, including the CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// Reads the PROXY protocol v1 or v2 header that a load balancer puts before everything else on a connection, returning the address of the real client. Headers that carry no client address, such as v1 `UNKNOWN` or v2 `LOCAL` ones, are rejected, since a connection that reaches us is always from some client. Exactly the header is read, so the rest of the connection is left untouched.
+pub async fn read_header(conn: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<SocketAddr> {
+    let mut start = [0u8; 6];
+    conn.read_exact(&mut start).await?;
+    if &start == b"PROXY " {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            anyhow::ensure!(line.len() < V1_MAX_LEN, "PROXY v1 header too long");
+            let mut byte = [0u8; 1];
+            conn.read_exact(&mut byte).await?;
+            line.push(byte[0]);
+        }
+        let header = v1::Header::try_from(std::str::from_utf8(&line)?)
+            .map_err(|err| anyhow::anyhow!("bad PROXY v1 header: {err:?}"))?;
+        return match header.addresses {
+            v1::Addresses::Tcp4(addrs) => Ok(SocketAddr::new(
+                addrs.source_address.into(),
+                addrs.source_port,
+            )),
+            v1::Addresses::Tcp6(addrs) => Ok(SocketAddr::new(
+                addrs.source_address.into(),
+                addrs.source_port,
+            )),
+            v1::Addresses::Unknown => anyhow::bail!("PROXY v1 header has no client address"),
+        };
+    }
+    anyhow::ensure!(start == V2_SIGNATURE[..6], "no PROXY protocol header");
+    let mut header = start.to_vec();
+    header.resize(16, 0);
+    conn.read_exact(&mut header[6..]).await?;
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+    header.resize(16 + len, 0);
+    conn.read_exact(&mut header[16..]).await?;
+    let header = v2::Header::try_from(&header[..])
+        .map_err(|err| anyhow::anyhow!("bad PROXY v2 header: {err:?}"))?;
+    // LOCAL headers, as for the load balancer's own health checks, are not for client connections
+    anyhow::ensure!(
+        matches!(header.command, v2::Command::Proxy),
+        "PROXY v2 header is not for a proxied connection"
+    );
+    match header.addresses {
+        v2::Addresses::IPv4(addrs) => Ok(SocketAddr::new(
+            addrs.source_address.into(),
+            addrs.source_port,
+        )),
+        v2::Addresses::IPv6(addrs) => Ok(SocketAddr::new(
+            addrs.source_address.into(),
+            addrs.source_port,
+        )),
+        _ => anyhow::bail!("PROXY v2 header has no client IP address"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v2_roundtrip() {
+        let src: SocketAddr = "192.0.2.1:56324".parse().unwrap();
+        let dst: SocketAddr = "198.51.100.1:443".parse().unwrap();
+        let mut header = &encode_v2(Some(src), dst)[..];
+        let parsed = smol::future::block_on(read_header(&mut header)).unwrap();
+        assert_eq!(parsed, src);
+        assert!(header.is_empty());
+
+        let src: SocketAddr = "[2001:db8::1]:56324".parse().unwrap();
+        let mut header = &encode_v2(Some(src), dst)[..];
+        let parsed = smol::future::block_on(read_header(&mut header)).unwrap();
+        assert_eq!(parsed, src);
+    }
+
+    #[test]
+    fn v2_encodes_both_ends() {
+        let decode = |src: Option<&str>, dst: &str| {
+            let header = encode_v2(src.map(|src| src.parse().unwrap()), dst.parse().unwrap());
+            let parsed = v2::Header::try_from(&header[..]).unwrap();
+            (parsed.command, parsed.addresses)
+        };
+
+        let (command, addresses) = decode(Some("192.0.2.1:56324"), "198.51.100.1:443");
+        assert!(matches!(command, v2::Command::Proxy));
+        let v2::Addresses::IPv4(addrs) = addresses else {
+            panic!("expected IPv4 addresses, got {addresses:?}");
+        };
+        assert_eq!(addrs.source_address, std::net::Ipv4Addr::new(192, 0, 2, 1));
+        assert_eq!(
+            addrs.destination_address,
+            std::net::Ipv4Addr::new(198, 51, 100, 1)
+        );
+        assert_eq!((addrs.source_port, addrs.destination_port), (56324, 443));
+
+        // mixed families go over IPv6, with the IPv4 end mapped
+        let (_, addresses) = decode(Some("[2001:db8::1]:56324"), "198.51.100.1:443");
+        let v2::Addresses::IPv6(addrs) = addresses else {
+            panic!("expected IPv6 addresses, got {addresses:?}");
+        };
+        assert_eq!(
+            addrs.source_address,
+            "2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap()
+        );
+        assert_eq!(
+            addrs.destination_address,
+            "::ffff:198.51.100.1".parse::<std::net::Ipv6Addr>().unwrap()
+        );
+        assert_eq!((addrs.source_port, addrs.destination_port), (56324, 443));
+
+        let (command, addresses) = decode(None, "198.51.100.1:443");
+        assert!(matches!(command, v2::Command::Local));
+        assert!(matches!(addresses, v2::Addresses::Unspecified));
+    }
+
+    #[test]
+    fn v1_leaves_rest_unread() {
+        let mut conn = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nhello"[..];
+        let parsed = smol::future::block_on(read_header(&mut conn)).unwrap();
+        assert_eq!(parsed, "192.0.2.1:56324".parse().unwrap());
+        assert_eq!(conn, b"hello");
+    }
+
+    #[test]
+    fn rejects_addressless_headers() {
+        let mut conn = &b"PROXY UNKNOWN\r\n"[..];
+        assert!(smol::future::block_on(read_header(&mut conn)).is_err());
+
+        let dst: SocketAddr = "198.51.100.1:443".parse().unwrap();
+        let mut header = &encode_v2(None, dst)[..];
+        assert!(smol::future::block_on(read_header(&mut header)).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_header() {
+        let mut conn = &b"\x00\x01\x02\x03\x04\x05\x06\x07"[..];
+        assert!(smol::future::block_on(read_header(&mut conn)).is_err());
+    }
+}
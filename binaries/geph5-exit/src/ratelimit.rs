@@ -10,14 +10,17 @@ use std::{
 use atomic_float::AtomicF32;
 use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use geph5_broker_protocol::AccountLevel;
-use governor::{DefaultDirectRateLimiter, Quota};
+use governor::{DefaultDirectRateLimiter, InsufficientCapacity, Quota};
 use mizaru2::ClientToken;
 use moka::future::Cache;
 use once_cell::sync::Lazy;
 use stdcode::StdcodeSerializeExt;
 use sysinfo::System;
 
-use crate::CONFIG_FILE;
+use crate::{
+    metrics::{active_connections, LOAD},
+    CONFIG_FILE,
+};
 
 static FREE_RL_CACHE: Lazy<Cache<blake3::Hash, RateLimiter>> = Lazy::new(|| {
     Cache::builder()
@@ -35,11 +38,19 @@ static CPU_USAGE: Lazy<AtomicF32> = Lazy::new(|| AtomicF32::new(0.0));
 static CURRENT_SPEED: Lazy<AtomicF32> = Lazy::new(|| AtomicF32::new(0.0));
 
 pub fn get_load() -> f32 {
+    let config = CONFIG_FILE.wait();
+    compute_load(
+        CPU_USAGE.load(Ordering::Relaxed),
+        CURRENT_SPEED.load(Ordering::Relaxed) / (config.total_ratelimit as f32 * 1000.0),
+        active_connections() as f32 / config.connection_capacity.max(1) as f32,
+    )
+}
+
+/// Combines CPU usage, egress speed, and open connections, each as a fraction of what we can handle, into a load between 0 and 1.
+fn compute_load(cpu: f32, speed: f32, connections: f32) -> f32 {
     // we weigh CPU usage lower until it's really close to massively overloading
-    let cpu = CPU_USAGE.load(Ordering::Relaxed).powi(4);
-    let speed = CURRENT_SPEED.load(Ordering::Relaxed)
-        / (CONFIG_FILE.wait().total_ratelimit as f32 * 1000.0);
-    cpu.max(speed)
+    let cpu = cpu.powi(4);
+    cpu.max(speed).max(connections).clamp(0.0, 1.0)
 }
 
 pub static TOTAL_BYTE_COUNT: Lazy<AtomicU64> = Lazy::new(|| AtomicU64::new(0));
@@ -67,6 +78,7 @@ pub fn update_load_loop() {
 
             last_count_time = Instant::now();
             last_byte_count = new_byte_count;
+            LOAD.set(get_load() as f64);
 
             std::thread::sleep(Duration::from_millis(100));
         }
@@ -99,10 +111,24 @@ pub async fn get_ratelimiter(level: AccountLevel, token: ClientToken) -> RateLim
     }
 }
 
+/// Gets a fresh rate limiter for a single stream of a client with the given account level, which is unknown when the exit runs without a broker.
+pub fn get_stream_ratelimiter(level: Option<AccountLevel>) -> RateLimiter {
+    let config = CONFIG_FILE.wait();
+    let limit = match level {
+        Some(AccountLevel::Free) => config.free_stream_ratelimit.or(config.stream_ratelimit),
+        _ => config.stream_ratelimit,
+    };
+    match limit {
+        Some(limit) => RateLimiter::new(limit, limit),
+        None => RateLimiter::unlimited(),
+    }
+}
+
 /// A generic rate limiter.
 #[derive(Clone)]
 pub struct RateLimiter {
-    inner: Option<Arc<DefaultDirectRateLimiter>>,
+    inner: Vec<Arc<DefaultDirectRateLimiter>>,
+    counters: Vec<Arc<AtomicU64>>,
 }
 
 impl RateLimiter {
@@ -112,33 +138,77 @@ impl RateLimiter {
         let burst_size = NonZeroU32::new(burst_kb * 1024).unwrap();
         let inner = governor::RateLimiter::direct(Quota::per_second(limit).allow_burst(burst_size));
         Self {
-            inner: Some(Arc::new(inner)),
+            inner: vec![Arc::new(inner)],
+            counters: vec![],
         }
     }
 
     /// Creates a new unlimited ratelimit.
     pub fn unlimited() -> Self {
-        Self { inner: None }
+        Self {
+            inner: vec![],
+            counters: vec![],
+        }
+    }
+
+    /// Combines two rate limiters into one that waits for both.
+    pub fn combine(&self, other: &RateLimiter) -> Self {
+        Self {
+            inner: self
+                .inner
+                .iter()
+                .chain(other.inner.iter())
+                .cloned()
+                .collect(),
+            counters: self
+                .counters
+                .iter()
+                .chain(other.counters.iter())
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Also adds all the bytes let through to the given counter.
+    pub fn counting(mut self, counter: Arc<AtomicU64>) -> Self {
+        self.counters.push(counter);
+        self
     }
 
     /// Waits until the given number of bytes can be let through.
     pub async fn wait(&self, bytes: usize) {
         TOTAL_BYTE_COUNT.fetch_add(bytes as _, Ordering::Relaxed);
+        for counter in self.counters.iter() {
+            counter.fetch_add(bytes as _, Ordering::Relaxed);
+        }
         if bytes == 0 {
             return;
         }
         let multiplier = (1.0 / (1.0 - get_load().min(0.999)) - 1.0) / 2.0;
 
         let bytes = bytes as f32 * (multiplier.max(1.0));
-        if let Some(inner) = &self.inner {
+        self.wait_scaled(bytes as u32).await
+    }
+
+    /// Waits until the given number of bytes can be let through every inner limiter, without regard to load.
+    async fn wait_scaled(&self, bytes: u32) {
+        for inner in self.inner.iter() {
+            let mut remaining = bytes;
+            let mut max_chunk = u32::MAX;
             let mut delay: f32 = 0.05;
-            while inner
-                .check_n((bytes as u32).try_into().unwrap())
-                .unwrap()
-                .is_err()
-            {
-                smol::Timer::after(Duration::from_secs_f32(delay)).await;
-                delay += rand::random::<f32>() * 0.05;
+            while let Some(chunk) = NonZeroU32::new(remaining.min(max_chunk)) {
+                match inner.check_n(chunk) {
+                    Ok(Ok(())) => {
+                        remaining -= chunk.get();
+                        delay = 0.05;
+                    }
+                    Ok(Err(_)) => {
+                        smol::Timer::after(Duration::from_secs_f32(delay)).await;
+                        delay += rand::random::<f32>() * 0.05;
+                    }
+                    // more than the burst size can never go through at once, so it goes through a burst at a time
+                    Err(InsufficientCapacity(burst)) => max_chunk = burst,
+                }
             }
         }
     }
@@ -167,3 +237,41 @@ impl RateLimiter {
         Ok(total_bytes)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{compute_load, RateLimiter};
+
+    #[test]
+    fn load_increases_with_connections() {
+        let mut last = compute_load(0.0, 0.0, 0.0);
+        for conns in [0.1, 0.3, 0.6, 0.9] {
+            let load = compute_load(0.0, 0.0, conns);
+            assert!(load > last);
+            last = load;
+        }
+        assert_eq!(compute_load(0.0, 0.0, 5.0), 1.0);
+    }
+
+    #[test]
+    fn load_follows_busiest_resource() {
+        assert_eq!(compute_load(0.5, 0.2, 0.7), 0.7);
+        assert_eq!(compute_load(1.0, 0.2, 0.1), 1.0);
+        assert_eq!(compute_load(0.0, 0.4, 0.1), 0.4);
+    }
+
+    #[test]
+    fn wait_beyond_burst() {
+        // 1 KB of burst, and about 100 KB/s
+        let limiter = RateLimiter::new(99, 1);
+        smol::future::block_on(limiter.wait_scaled(8192));
+        smol::future::block_on(limiter.wait_scaled(1));
+    }
+
+    #[test]
+    fn wait_largest_datagram() {
+        // UDP datagrams cannot be split up, so a whole one is charged at once
+        let limiter = RateLimiter::new(5000, 16);
+        smol::future::block_on(limiter.wait_scaled(u16::MAX as u32));
+    }
+}
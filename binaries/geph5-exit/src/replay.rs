@@ -0,0 +1,185 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+
+use crate::CONFIG_FILE;
+
+/// How many connections per hour the filter is sized for.
+const CONNS_PER_HOUR: f64 = 1_000_000.0;
+
+/// The false positive rate the filter is sized for, at that many connections.
+const FALSE_POSITIVE_RATE: f64 = 0.0001;
+
+/// The nonces of recently seen client hellos. There are two generations, each covering one replay window, so every nonce is remembered for at least one whole window.
+static SEEN_NONCES: Lazy<Mutex<ReplayFilter>> = Lazy::new(|| {
+    Mutex::new(ReplayFilter::new(Duration::from_secs(
+        CONFIG_FILE.wait().replay_window_secs,
+    )))
+});
+
+/// How far the timestamp of a client hello may be from our clock, in either direction, before we reject the hello as stale.
+const MAX_HELLO_SKEW: Duration = Duration::from_secs(30);
+
+/// Checks whether a client hello timestamp, in seconds since the Unix epoch, is too far from the present. A hello can only be replayed while it is fresh, so the replay window need not be longer than this.
+pub fn is_stale(timestamp: u64) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    now.abs_diff(timestamp) > MAX_HELLO_SKEW.as_secs()
+}
+
+/// Records the nonce of a client hello, returning whether it was already seen within the replay window.
+pub fn is_replay(nonce: &[u8; 32]) -> bool {
+    SEEN_NONCES.lock().unwrap().check_and_insert(nonce)
+}
+
+struct ReplayFilter {
+    window: Duration,
+    rotated: Instant,
+    current: BloomFilter,
+    previous: BloomFilter,
+}
+
+impl ReplayFilter {
+    fn new(window: Duration) -> Self {
+        let expected = (CONNS_PER_HOUR * window.as_secs_f64() / 3600.0).max(1000.0);
+        // every nonce is looked up in both generations, so each gets half the false positives
+        let rate = FALSE_POSITIVE_RATE / 2.0;
+        Self {
+            window,
+            rotated: Instant::now(),
+            current: BloomFilter::new(expected, rate),
+            previous: BloomFilter::new(expected, rate),
+        }
+    }
+
+    fn check_and_insert(&mut self, nonce: &[u8; 32]) -> bool {
+        if self.rotated.elapsed() >= self.window {
+            std::mem::swap(&mut self.current, &mut self.previous);
+            self.current.clear();
+            self.rotated = Instant::now();
+        }
+        let seen = self.current.contains(nonce) || self.previous.contains(nonce);
+        self.current.insert(nonce);
+        seen
+    }
+}
+
+/// A plain bloom filter over 32-byte nonces.
+struct BloomFilter {
+    bits: Vec<u64>,
+    num_hashes: u64,
+    /// Nonces are chosen by clients, so we hash them with a secret key, lest somebody craft nonces that fill up exactly the bits other clients' nonces need.
+    key: [u8; 32],
+}
+
+impl BloomFilter {
+    /// Creates a filter with the optimal size and number of hashes for the given number of items and false positive rate.
+    fn new(expected_items: f64, false_positive_rate: f64) -> Self {
+        let num_hashes = (-false_positive_rate.log2()).round().max(1.0);
+        // the hash count had to be rounded, so the bits are worked out for the count we actually use
+        let num_bits = (-num_hashes * expected_items
+            / (1.0 - false_positive_rate.powf(1.0 / num_hashes)).ln())
+        .ceil();
+        Self {
+            bits: vec![0; (num_bits as usize).div_ceil(64)],
+            num_hashes: num_hashes as u64,
+            key: rand::random(),
+        }
+    }
+
+    fn insert(&mut self, item: &[u8; 32]) {
+        for idx in self.indices(item) {
+            self.bits[idx / 64] |= 1 << (idx % 64);
+        }
+    }
+
+    fn contains(&self, item: &[u8; 32]) -> bool {
+        self.indices(item)
+            .all(|idx| self.bits[idx / 64] & (1 << (idx % 64)) != 0)
+    }
+
+    fn clear(&mut self) {
+        self.bits.fill(0);
+        self.key = rand::random();
+    }
+
+    /// The bit indices for an item, by double hashing.
+    fn indices(&self, item: &[u8; 32]) -> impl Iterator<Item = usize> {
+        let hash = blake3::keyed_hash(&self.key, item);
+        let hash = hash.as_bytes();
+        let h1 = u64::from_le_bytes(hash[..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(hash[8..16].try_into().unwrap());
+        let num_bits = self.bits.len() as u64 * 64;
+        (0..self.num_hashes).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bloom_filter_has_no_false_negatives() {
+        let mut filter = BloomFilter::new(10_000.0, FALSE_POSITIVE_RATE);
+        let items: Vec<[u8; 32]> = (0..10_000).map(|_| rand::random()).collect();
+        for item in &items {
+            filter.insert(item);
+        }
+        assert!(items.iter().all(|item| filter.contains(item)));
+    }
+
+    #[test]
+    fn bloom_filter_false_positive_rate() {
+        let expected = 10_000;
+        let rate = 0.01;
+        let mut filter = BloomFilter::new(expected as f64, rate);
+        for _ in 0..expected {
+            filter.insert(&rand::random());
+        }
+        let trials = 100_000;
+        let false_positives = (0..trials)
+            .filter(|_| filter.contains(&rand::random()))
+            .count();
+        // about 1000 expected, so twice that is far outside the noise
+        assert!(
+            (false_positives as f64) < 2.0 * rate * trials as f64,
+            "{false_positives} false positives in {trials}"
+        );
+    }
+
+    #[test]
+    fn replay_filter_is_sized_for_production_load() {
+        // at the end of an hour-long window, both generations hold an hour's worth of connections, and a nonce is looked up in both
+        let filter = ReplayFilter::new(Duration::from_secs(3600));
+        for generation in [&filter.current, &filter.previous] {
+            let bits = (generation.bits.len() * 64) as f64;
+            let hashes = generation.num_hashes as f64;
+            let rate = (1.0 - (-hashes * CONNS_PER_HOUR / bits).exp()).powf(hashes);
+            assert!(
+                2.0 * rate < FALSE_POSITIVE_RATE,
+                "{rate} false positive rate with {bits} bits and {hashes} hashes"
+            );
+        }
+    }
+
+    #[test]
+    fn replay_filter_remembers_for_a_whole_window() {
+        let window = Duration::from_secs(60);
+        let mut filter = ReplayFilter::new(window);
+        assert!(!filter.check_and_insert(&[1; 32]));
+        assert!(!filter.check_and_insert(&[2; 32]));
+        assert!(filter.check_and_insert(&[2; 32]));
+        // one window later, both are still remembered in the previous generation
+        filter.rotated -= window;
+        assert!(filter.check_and_insert(&[1; 32]));
+        // two windows later, only the nonce seen again in between is
+        filter.rotated -= window;
+        assert!(!filter.check_and_insert(&[2; 32]));
+        assert!(filter.check_and_insert(&[1; 32]));
+    }
+}
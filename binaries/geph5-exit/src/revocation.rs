@@ -0,0 +1,90 @@
+use std::{
+    sync::RwLock,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use ed25519_dalek::VerifyingKey;
+use geph5_broker_protocol::{BrokerClient, RevocationList, DOMAIN_REVOCATION_LIST};
+use mizaru2::ClientToken;
+use once_cell::sync::Lazy;
+
+use crate::{broker::BrokerRpcTransport, CONFIG_FILE};
+
+/// How often we refetch the revocation list from the broker.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// The last revocation list we accepted.
+static REVOCATIONS: Lazy<RwLock<RevocationList>> = Lazy::new(Default::default);
+
+/// Checks whether a connect token has been revoked by the broker.
+pub fn is_revoked(token: &ClientToken) -> bool {
+    REVOCATIONS.read().unwrap().is_revoked(token)
+}
+
+/// Adds a connect token to the revocation list, as if the broker had revoked it.
+#[cfg(test)]
+pub fn revoke(token: &ClientToken) {
+    REVOCATIONS
+        .write()
+        .unwrap()
+        .revoked_tokens
+        .push(RevocationList::token_hash(token));
+}
+
+/// Periodically fetches the revocation list from the broker. This does nothing unless the broker's master public key is configured, since we cannot trust the list otherwise.
+pub async fn revocation_loop() -> anyhow::Result<()> {
+    let Some(broker) = &CONFIG_FILE.wait().broker else {
+        return smol::future::pending().await;
+    };
+    let Some(master_pk) = &broker.master_pk else {
+        tracing::warn!("no broker master public key configured, not enforcing revocations");
+        return smol::future::pending().await;
+    };
+    let master_pk = VerifyingKey::from_bytes(
+        &hex::decode(master_pk)?
+            .try_into()
+            .ok()
+            .context("broker master public key must be 32 bytes")?,
+    )?;
+    let client = BrokerClient(BrokerRpcTransport::new(&broker.url, &broker.spki_pins)?);
+    loop {
+        let fallible = async {
+            let list = client
+                .get_revocation_list()
+                .await?
+                .map_err(|e| anyhow::anyhow!("broker refused revocation list: {e}"))?
+                .verify(DOMAIN_REVOCATION_LIST, |pk| pk == &master_pk)?;
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_secs();
+            anyhow::ensure!(list.expiry > now, "revocation list already expired");
+            tracing::debug!(
+                count = list.revoked_tokens.len(),
+                "refreshed revocation list"
+            );
+            *REVOCATIONS.write().unwrap() = list;
+            anyhow::Ok(())
+        };
+        // on failure, we keep enforcing the last list we got, since revocations are rarely undone
+        if let Err(err) = fallible.await {
+            tracing::warn!(err = debug(err), "could not refresh revocation list");
+        }
+        smol::Timer::after(REFRESH_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enforces_the_accepted_list() {
+        let revoked = ClientToken::random();
+        let fine = ClientToken::random();
+        assert!(!is_revoked(&revoked));
+        revoke(&revoked);
+        assert!(is_revoked(&revoked));
+        assert!(!is_revoked(&fine));
+    }
+}
@@ -0,0 +1,252 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use bytes::Bytes;
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit};
+use geph5_broker_protocol::AccountLevel;
+use geph5_misc_rpc::exit::PresentedTicket;
+use mizaru2::ClientToken;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use stdcode::StdcodeSerializeExt;
+
+use crate::{replay::is_replay, revocation::is_revoked, CONFIG_FILE};
+
+/// The key that session tickets are encrypted with. It never leaves this process, so tickets stop working when the exit restarts, which only costs clients a full handshake.
+static TICKET_KEY: Lazy<ChaCha20Poly1305> =
+    Lazy::new(|| ChaCha20Poly1305::new(&rand::random::<[u8; 32]>().into()));
+
+/// What a session ticket says, once decrypted.
+#[derive(Serialize, Deserialize)]
+struct TicketInner {
+    /// A random value identifying the ticket, so that it can only be redeemed once.
+    id: [u8; 32],
+    level: AccountLevel,
+    token: ClientToken,
+    /// The secret that the client must prove knowledge of to redeem the ticket.
+    resumption_secret: [u8; 32],
+    /// When the ticket stops working, in seconds since the Unix epoch.
+    expiry: u64,
+}
+
+/// Issues a ticket that lets the client of a session with an already verified connect token resume it once, without presenting the token again.
+pub fn issue_ticket(level: AccountLevel, token: ClientToken, resumption_secret: [u8; 32]) -> Bytes {
+    // tickets must not outlive the replay window, where their IDs are remembered once redeemed
+    let lifetime = CONFIG_FILE
+        .wait()
+        .session_ticket_lifetime_secs
+        .min(CONFIG_FILE.wait().replay_window_secs);
+    seal_ticket(level, token, resumption_secret, now() + lifetime)
+}
+
+fn seal_ticket(
+    level: AccountLevel,
+    token: ClientToken,
+    resumption_secret: [u8; 32],
+    expiry: u64,
+) -> Bytes {
+    let inner = TicketInner {
+        id: rand::random(),
+        level,
+        token,
+        resumption_secret,
+        expiry,
+    };
+    let nonce: [u8; 12] = rand::random();
+    let ciphertext = TICKET_KEY
+        .encrypt(&nonce.into(), inner.stdcode().as_slice())
+        .expect("encryption cannot fail");
+    [nonce.as_slice(), &ciphertext].concat().into()
+}
+
+/// Redeems a ticket presented in the hello with the given nonce, returning the account level and connect token that were verified when it was issued, and the resumption secret that a resumed session derives its keys from.
+pub fn redeem_ticket(
+    presented: &PresentedTicket,
+    nonce: &[u8; 32],
+) -> anyhow::Result<(AccountLevel, ClientToken, [u8; 32])> {
+    open_ticket(presented, nonce, now(), is_replay)
+}
+
+/// Checks a presented ticket as of the given time, with `seen` recording its ID and telling whether it was recorded before.
+fn open_ticket(
+    presented: &PresentedTicket,
+    nonce: &[u8; 32],
+    now: u64,
+    seen: impl FnOnce(&[u8; 32]) -> bool,
+) -> anyhow::Result<(AccountLevel, ClientToken, [u8; 32])> {
+    anyhow::ensure!(presented.ticket.len() > 12, "session ticket too short");
+    let (ticket_nonce, ciphertext) = presented.ticket.split_at(12);
+    let plaintext = TICKET_KEY
+        .decrypt(ticket_nonce.into(), ciphertext)
+        .ok()
+        .context("session ticket not issued by us, or by us before a restart")?;
+    let inner: TicketInner = stdcode::deserialize(&plaintext)?;
+    anyhow::ensure!(inner.expiry > now, "session ticket expired");
+    anyhow::ensure!(
+        blake3::keyed_hash(&inner.resumption_secret, nonce) == presented.proof,
+        "wrong proof of session ticket possession"
+    );
+    // the token may have been revoked since we issued the ticket
+    anyhow::ensure!(!is_revoked(&inner.token), "connect token revoked");
+    anyhow::ensure!(!seen(&inner.id), "session ticket already redeemed");
+    Ok((inner.level, inner.token, inner.resumption_secret))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::revocation::revoke;
+
+    const SECRET: [u8; 32] = [7; 32];
+    const NONCE: [u8; 32] = [9; 32];
+
+    fn ticket(expiry: u64) -> Bytes {
+        seal_ticket(AccountLevel::Plus, ClientToken::random(), SECRET, expiry)
+    }
+
+    fn never_seen(_: &[u8; 32]) -> bool {
+        false
+    }
+
+    #[test]
+    fn redeems_a_fresh_ticket() {
+        let presented = PresentedTicket::new(ticket(now() + 60), &SECRET, &NONCE);
+        let (level, _, secret) = open_ticket(&presented, &NONCE, now(), never_seen).unwrap();
+        assert_eq!(level, AccountLevel::Plus);
+        assert_eq!(secret, SECRET);
+    }
+
+    #[test]
+    fn rejects_expired_ticket() {
+        let expiry = now() + 60;
+        let presented = PresentedTicket::new(ticket(expiry), &SECRET, &NONCE);
+        assert!(open_ticket(&presented, &NONCE, expiry - 1, never_seen).is_ok());
+        assert!(open_ticket(&presented, &NONCE, expiry, never_seen).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_proof() {
+        let ticket = ticket(now() + 60);
+        // somebody who saw the ticket, but not the session's secret
+        let presented = PresentedTicket::new(ticket.clone(), &[8; 32], &NONCE);
+        assert!(open_ticket(&presented, &NONCE, now(), never_seen).is_err());
+        // a proof lifted from another hello
+        let presented = PresentedTicket::new(ticket, &SECRET, &[10; 32]);
+        assert!(open_ticket(&presented, &NONCE, now(), never_seen).is_err());
+    }
+
+    #[test]
+    fn redeems_only_once() {
+        let mut seen = HashSet::new();
+        let presented = PresentedTicket::new(ticket(now() + 60), &SECRET, &NONCE);
+        assert!(open_ticket(&presented, &NONCE, now(), |id| !seen.insert(*id)).is_ok());
+        assert!(open_ticket(&presented, &NONCE, now(), |id| !seen.insert(*id)).is_err());
+    }
+
+    #[test]
+    fn rejects_revoked_token() {
+        let token = ClientToken::random();
+        let ticket = seal_ticket(AccountLevel::Plus, token, SECRET, now() + 60);
+        let presented = PresentedTicket::new(ticket, &SECRET, &NONCE);
+        assert!(open_ticket(&presented, &NONCE, now(), never_seen).is_ok());
+        revoke(&token);
+        assert!(open_ticket(&presented, &NONCE, now(), never_seen).is_err());
+    }
+
+    /// Times the cryptography of both sides of a handshake, which is all that resuming changes: the round trips are the same either way. Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "timing-dependent benchmark"]
+    fn resuming_halves_handshake_work() {
+        use std::time::Instant;
+
+        use ed25519_dalek::{Signer, SigningKey};
+        use geph5_misc_rpc::exit::{
+            exit_x25519_public, exit_x25519_secret, resumed_shared_secret, resumption_secret,
+        };
+
+        const ROUNDS: u32 = 2000;
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let pubkey = signing_key.verifying_key();
+
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            // client: ephemeral key, and the timestamp secret
+            let client_esk = x25519_dalek::StaticSecret::random_from_rng(rand::thread_rng());
+            let client_epk = x25519_dalek::PublicKey::from(&client_esk);
+            let client_timestamp = client_esk.diffie_hellman(&exit_x25519_public(&pubkey));
+            // exit: its own ephemeral key, the session secret, and the timestamp secret
+            let exit_esk = x25519_dalek::EphemeralSecret::random_from_rng(rand::thread_rng());
+            let exit_epk = x25519_dalek::PublicKey::from(&exit_esk);
+            let exit_shared = exit_esk.diffie_hellman(&client_epk);
+            let exit_timestamp = exit_x25519_secret(&signing_key).diffie_hellman(&client_epk);
+            assert_eq!(client_timestamp.as_bytes(), exit_timestamp.as_bytes());
+            let signature = signing_key.sign(exit_epk.as_bytes());
+            let ticket = seal_ticket(
+                AccountLevel::Plus,
+                ClientToken::random(),
+                resumption_secret(exit_shared.as_bytes()),
+                now() + 60,
+            );
+            // client: checks the exit hello and finishes the key exchange
+            pubkey
+                .verify_strict(exit_epk.as_bytes(), &signature)
+                .unwrap();
+            let client_shared = client_esk.diffie_hellman(&exit_epk);
+            assert_eq!(client_shared.as_bytes(), exit_shared.as_bytes());
+            std::hint::black_box(ticket);
+        }
+        let full = start.elapsed();
+
+        let ticket = ticket(now() + 60);
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            // client: proves it holds the ticket
+            let client_nonce: [u8; 32] = rand::random();
+            let presented = PresentedTicket::new(ticket.clone(), &SECRET, &client_nonce);
+            // exit: opens the ticket and answers with its own nonce
+            let (_, token, secret) =
+                open_ticket(&presented, &client_nonce, now(), never_seen).unwrap();
+            let exit_nonce: [u8; 32] = rand::random();
+            let exit_shared = resumed_shared_secret(&secret, &client_nonce, &exit_nonce);
+            let signature = signing_key.sign(&exit_nonce);
+            let next_ticket = seal_ticket(
+                AccountLevel::Plus,
+                token,
+                resumption_secret(&exit_shared),
+                now() + 60,
+            );
+            // client: checks the exit hello
+            pubkey.verify_strict(&exit_nonce, &signature).unwrap();
+            let client_shared = resumed_shared_secret(&SECRET, &client_nonce, &exit_nonce);
+            assert_eq!(client_shared, exit_shared);
+            std::hint::black_box(next_ticket);
+        }
+        let resumed = start.elapsed();
+
+        eprintln!(
+            "per handshake: full {:?}, resumed {:?}",
+            full / ROUNDS,
+            resumed / ROUNDS
+        );
+        assert!(resumed * 2 < full, "full {full:?}, resumed {resumed:?}");
+    }
+
+    #[test]
+    fn rejects_tampered_ticket() {
+        let mut tampered = ticket(now() + 60).to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+        let presented = PresentedTicket::new(tampered.into(), &SECRET, &NONCE);
+        assert!(open_ticket(&presented, &NONCE, now(), never_seen).is_err());
+        let presented = PresentedTicket::new(Bytes::from_static(&[0; 12]), &SECRET, &NONCE);
+        assert!(open_ticket(&presented, &NONCE, now(), never_seen).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+use std::sync::Arc;
+
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+
+/// Builds an HTTPS client that, on top of the usual WebPKI checks against the bundled Mozilla roots, only accepts certificate chains in which some certificate has a SubjectPublicKeyInfo whose SHA-256 hash is among `pins`.
+pub fn pinned_client(pins: Vec<[u8; 32]>) -> anyhow::Result<reqwest::Client> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let inner =
+        WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build()?;
+    let tls = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(SpkiPinVerifier { inner, pins }))
+        .with_no_client_auth();
+    Ok(reqwest::Client::builder()
+        .use_preconfigured_tls(tls)
+        .build()?)
+}
+
+#[derive(Debug)]
+struct SpkiPinVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for SpkiPinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        let chain: Vec<(&[u8], &[u8])> = std::iter::once(end_entity)
+            .chain(intermediates)
+            .filter_map(|cert| spki_and_issuer(cert))
+            .collect();
+        let mut spkis: Vec<Vec<u8>> = chain.iter().map(|(spki, _)| spki.to_vec()).collect();
+        // the root is not sent, but the chain has already been verified up to one of ours, so we can look it up by the last issuer
+        if let Some((_, issuer)) = chain.last() {
+            spkis.extend(
+                webpki_roots::TLS_SERVER_ROOTS
+                    .iter()
+                    .filter(|root| root.subject.as_ref() == *issuer)
+                    .map(|root| der_sequence(root.subject_public_key_info.as_ref())),
+            );
+        }
+        if spkis
+            .iter()
+            .any(|spki| self.pins.contains(&Sha256::digest(spki).into()))
+        {
+            Ok(verified)
+        } else {
+            tracing::warn!("broker certificate chain matches none of the pinned SPKI hashes");
+            Err(rustls::Error::General(
+                "no certificate in the chain matches a pinned SPKI hash".into(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Extracts the full DER SubjectPublicKeyInfo of an X.509 certificate, together with the contents of its issuer name.
+fn spki_and_issuer(cert: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (_, cert, _) = der_tlv(cert)?;
+    let (_, tbs, _) = der_tlv(cert)?;
+    let (first, _, rest) = der_tlv(tbs)?;
+    // the version is optional, and otherwise the serial number comes first
+    let rest = if first == 0xa0 {
+        der_tlv(rest)?.2
+    } else {
+        rest
+    };
+    let (_, _, rest) = der_tlv(rest)?; // signature algorithm
+    let (_, issuer, rest) = der_tlv(rest)?;
+    let (_, _, rest) = der_tlv(rest)?; // validity
+    let (_, _, rest) = der_tlv(rest)?; // subject
+    let (_, _, after) = der_tlv(rest)?;
+    Some((&rest[..rest.len() - after.len()], issuer))
+}
+
+/// Splits off one DER element, returning its tag, its contents, and whatever follows it.
+fn der_tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *input.first()?;
+    let first_len = *input.get(1)?;
+    let (len, header) = if first_len < 0x80 {
+        (first_len as usize, 2)
+    } else {
+        let n = (first_len & 0x7f) as usize;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = input
+            .get(2..2 + n)?
+            .iter()
+            .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+        (len, 2 + n)
+    };
+    let contents = input.get(header..header.checked_add(len)?)?;
+    Some((tag, contents, &input[header + len..]))
+}
+
+/// Wraps contents in a DER SEQUENCE.
+fn der_sequence(contents: &[u8]) -> Vec<u8> {
+    let len = contents.len();
+    let mut out = vec![0x30];
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|b| *b == 0)
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(contents);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A self-signed RSA-2048 certificate, whose lengths need the long DER form.
+    const RSA_V3: &[u8] = include_bytes!("../testdata/rsa_v3.der");
+    /// A self-signed P-256 certificate without the optional version field.
+    const EC_V1: &[u8] = include_bytes!("../testdata/ec_v1.der");
+
+    fn spki_hash(cert: &[u8]) -> String {
+        let (spki, _) = spki_and_issuer(cert).unwrap();
+        hex::encode(Sha256::digest(spki))
+    }
+
+    #[test]
+    fn hashes_match_openssl() {
+        // openssl x509 -inform der -pubkey -noout | openssl pkey -pubin -outform der | sha256sum
+        assert_eq!(
+            spki_hash(RSA_V3),
+            "9174ec866c3fb4fba99d0ae2e98dc9c8c9c5b43139ef5a6d05a73e493c2fb4f2"
+        );
+        assert_eq!(
+            spki_hash(EC_V1),
+            "8ccbf4dd53d9cf3bf647346f835bb2adf699862ba879d1715ae515ba865c55e4"
+        );
+    }
+
+    #[test]
+    fn finds_the_issuer() {
+        for cert in [RSA_V3, EC_V1] {
+            let (_, issuer) = spki_and_issuer(cert).unwrap();
+            assert!(issuer.ends_with(b"broker.example.com"));
+        }
+    }
+
+    #[test]
+    fn rewraps_root_keys() {
+        for root in webpki_roots::TLS_SERVER_ROOTS {
+            let contents = root.subject_public_key_info.as_ref();
+            let wrapped = der_sequence(contents);
+            assert_eq!(der_tlv(&wrapped), Some((0x30, contents, &[][..])));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        for cert in [RSA_V3, EC_V1] {
+            for len in 0..cert.len() {
+                assert!(spki_and_issuer(&cert[..len]).is_none());
+            }
+        }
+        assert!(spki_and_issuer(b"not a certificate").is_none());
+        // more length bytes than we accept, and a length that overflows
+        assert!(der_tlv(&[0x30, 0x85, 0, 0, 0, 0, 1]).is_none());
+        assert!(der_tlv(&[0x30, 0x80]).is_none());
+        assert!(der_tlv(&[0x30, 0x84, 0xff, 0xff, 0xff, 0xff]).is_none());
+    }
+}
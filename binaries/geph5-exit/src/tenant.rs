@@ -0,0 +1,109 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Context;
+use geph5_misc_rpc::exit::{ClientCryptHello, TenantClaim};
+use once_cell::sync::Lazy;
+
+use crate::{ratelimit::RateLimiter, CONFIG_FILE};
+
+/// The live state of a tenant, shared by all of its connections.
+struct TenantState {
+    conns: AtomicU32,
+    bytes: Arc<AtomicU64>,
+    limiter: RateLimiter,
+}
+
+static TENANTS: Lazy<HashMap<String, TenantState>> = Lazy::new(|| {
+    CONFIG_FILE
+        .wait()
+        .tenants
+        .iter()
+        .map(|(id, cfg)| {
+            let limiter = if cfg.bandwidth_cap_kbps == 0 {
+                RateLimiter::unlimited()
+            } else {
+                // kbps is in kilobits, but the limiter counts kilobytes
+                let limit_kb = (cfg.bandwidth_cap_kbps / 8).max(1);
+                RateLimiter::new(limit_kb, limit_kb)
+            };
+            (
+                id.clone(),
+                TenantState {
+                    conns: AtomicU32::new(0),
+                    bytes: Default::default(),
+                    limiter,
+                },
+            )
+        })
+        .collect()
+});
+
+/// Counts one open connection against its tenant's quota, until dropped.
+pub struct TenantGuard {
+    tenant_id: &'static str,
+    state: &'static TenantState,
+}
+
+impl TenantGuard {
+    /// Admits a connection claiming membership in a tenant, checking the claim and the tenant's connection limit.
+    pub fn admit(claim: &TenantClaim, crypt_hello: &ClientCryptHello) -> anyhow::Result<Self> {
+        let (tenant_id, state) = TENANTS
+            .get_key_value(&claim.tenant_id)
+            .context("unknown tenant")?;
+        let cfg = &CONFIG_FILE.wait().tenants[tenant_id];
+        let pubkey = hex::encode(claim.pubkey.as_bytes());
+        anyhow::ensure!(
+            cfg.allowed_pubkeys
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(&pubkey)),
+            "key not allowed for tenant"
+        );
+        claim.verify(crypt_hello)?;
+        state
+            .conns
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |conns| {
+                (cfg.max_connections == 0 || conns < cfg.max_connections).then_some(conns + 1)
+            })
+            .ok()
+            .context("tenant over its connection limit")?;
+        Ok(Self { tenant_id, state })
+    }
+
+    pub fn tenant_id(&self) -> &str {
+        self.tenant_id
+    }
+
+    /// The rate limiter shared by all of the tenant's connections, which also counts their traffic.
+    pub fn limiter(&self) -> RateLimiter {
+        self.state
+            .limiter
+            .clone()
+            .counting(self.state.bytes.clone())
+    }
+}
+
+impl Drop for TenantGuard {
+    fn drop(&mut self) {
+        self.state.conns.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// For every tenant, takes the bytes transferred since the last call, and gets the number of open connections.
+pub fn take_tenant_stats() -> Vec<(&'static str, u64, u32)> {
+    TENANTS
+        .iter()
+        .map(|(id, state)| {
+            (
+                id.as_str(),
+                state.bytes.swap(0, Ordering::Relaxed),
+                state.conns.load(Ordering::Relaxed),
+            )
+        })
+        .collect()
+}
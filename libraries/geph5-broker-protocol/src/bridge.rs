@@ -1,4 +1,4 @@
-use std::net::SocketAddr;
+use std::{collections::BTreeMap, net::SocketAddr};
 
 use serde::{Deserialize, Serialize};
 
@@ -9,3 +9,17 @@ pub struct BridgeDescriptor {
     pub pool: String,
     pub expiry: u64,
 }
+
+/// The health of bridges, as actively probed by the broker, keyed by their control address.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct BridgeStatus {
+    pub bridges: BTreeMap<SocketAddr, BridgeHealth>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BridgeHealth {
+    pub reachable: bool,
+    pub latency_ms: Option<u32>,
+    /// When the bridge was last probed, in seconds since the Unix epoch.
+    pub last_checked: u64,
+}
@@ -24,6 +24,19 @@ pub struct ExitDescriptor {
     pub load: f32,
     /// When does this descriptor expire?
     pub expiry: u64,
+    /// Whether the exit understands the probe magic that clients may send before their client hello, and so whether to send it. Left out when false, so that descriptors without it are encoded, and thus signed, exactly like before it existed. Older clients cannot check signatures over descriptors with it, so the [get_exits](crate::BrokerProtocol::get_exits) and [get_free_exits](crate::BrokerProtocol::get_free_exits) that they call leave it out.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub probe_magic: bool,
+    /// Whether the exit answers path MTU discovery probes on the UDP port of `c2e_listen`. The probes are plainly recognizable, so clients only send them when connecting directly. Only advertised together with `probe_magic`, so that skipping that field never shifts this one into its place in the signed encoding.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pmtud_echo: bool,
+}
+
+/// An exit's notice that it has moved on to a new signing key, signed by the key it retired.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExitKeyRotation {
+    /// The key that the exit signs its descriptors with from now on
+    pub new_pubkey: VerifyingKey,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -35,6 +48,15 @@ pub struct ExitList {
 }
 
 impl ExitList {
+    /// Leaves out whatever older clients cannot check the signature over.
+    pub fn for_older_clients(mut self) -> Self {
+        for (_, exit) in self.all_exits.iter_mut() {
+            exit.probe_magic = false;
+            exit.pmtud_echo = false;
+        }
+        self
+    }
+
     /// A convenience method to find the overall expiry time of the exit list.
     pub fn expiry(&self) -> SystemTime {
         UNIX_EPOCH
@@ -47,3 +69,60 @@ impl ExitList {
             )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use stdcode::StdcodeSerializeExt;
+
+    use super::*;
+
+    #[test]
+    fn probe_magic_encodes_like_before_when_unset() {
+        #[derive(Serialize)]
+        struct LegacyExitDescriptor {
+            c2e_listen: SocketAddr,
+            b2e_listen: SocketAddr,
+            country: CountryCode,
+            city: String,
+            load: f32,
+            expiry: u64,
+        }
+
+        let mut exit = ExitDescriptor {
+            c2e_listen: "192.0.2.1:1".parse().unwrap(),
+            b2e_listen: "192.0.2.1:2".parse().unwrap(),
+            country: CountryCode::CAN,
+            city: "Toronto".into(),
+            load: 0.5,
+            expiry: 1_700_000_000,
+            probe_magic: false,
+            pmtud_echo: false,
+        };
+        let legacy = LegacyExitDescriptor {
+            c2e_listen: exit.c2e_listen,
+            b2e_listen: exit.b2e_listen,
+            country: exit.country,
+            city: exit.city.clone(),
+            load: exit.load,
+            expiry: exit.expiry,
+        };
+        assert_eq!(exit.stdcode(), legacy.stdcode());
+        assert_eq!(
+            serde_json::to_value(&exit).unwrap(),
+            serde_json::to_value(&legacy).unwrap()
+        );
+
+        exit.probe_magic = true;
+        exit.pmtud_echo = true;
+        assert_ne!(exit.stdcode(), legacy.stdcode());
+        let list = ExitList {
+            all_exits: vec![(
+                ed25519_dalek::SigningKey::from_bytes(&[1; 32]).verifying_key(),
+                exit,
+            )],
+            city_names: HashMap::new(),
+        }
+        .for_older_clients();
+        assert_eq!(list.all_exits[0].1.stdcode(), legacy.stdcode());
+    }
+}
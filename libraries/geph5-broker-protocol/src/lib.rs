@@ -16,7 +16,11 @@ mod mac;
 pub use mac::*;
 mod bridge;
 pub use bridge::*;
+mod revocation;
+pub use revocation::*;
+mod version;
 use thiserror::Error;
+pub use version::*;
 
 #[nanorpc_derive]
 #[async_trait]
@@ -32,19 +36,32 @@ pub trait BrokerProtocol {
         blind_token: BlindedClientToken,
     ) -> Result<BlindedSignature, AuthError>;
 
-    async fn get_exits(&self) -> Result<Signed<ExitList>, GenericError>;
-    async fn get_free_exits(&self) -> Result<Signed<ExitList>, GenericError>;
+    async fn get_exits(&self) -> Result<MultiSigned<ExitList>, BrokerFault>;
+    async fn get_free_exits(&self) -> Result<MultiSigned<ExitList>, BrokerFault>;
+    /// Like [BrokerProtocol::get_exits], but with the descriptor fields that older clients cannot check the signature over, such as [ExitDescriptor::probe_magic].
+    async fn get_exits_v2(&self) -> Result<MultiSigned<ExitList>, BrokerFault>;
+    /// Like [BrokerProtocol::get_free_exits], but with the descriptor fields that older clients cannot check the signature over.
+    async fn get_free_exits_v2(&self) -> Result<MultiSigned<ExitList>, BrokerFault>;
     async fn get_routes(
         &self,
         token: ClientToken,
         sig: UnblindedSignature,
         exit_b2e: SocketAddr,
-    ) -> Result<RouteDescriptor, GenericError>;
-    async fn insert_exit(
+    ) -> Result<RouteDescriptor, BrokerFault>;
+    async fn get_bridge_status(
         &self,
-        descriptor: Mac<Signed<ExitDescriptor>>,
-    ) -> Result<(), GenericError>;
-    async fn insert_bridge(&self, descriptor: Mac<BridgeDescriptor>) -> Result<(), GenericError>;
+        token: ClientToken,
+        sig: UnblindedSignature,
+    ) -> Result<BridgeStatus, BrokerFault>;
+    async fn get_revocation_list(&self) -> Result<Signed<RevocationList>, BrokerFault>;
+    async fn insert_exit(&self, descriptor: Mac<Signed<ExitDescriptor>>)
+        -> Result<(), BrokerFault>;
+    /// Tells the broker that an exit has retired the key that signed the notice. The broker keeps accepting descriptors signed by the retired key for a while, so that clients that fetched the exit list earlier can still reach the exit, and turns them away after that.
+    async fn retire_exit_key(
+        &self,
+        notice: Mac<Signed<ExitKeyRotation>>,
+    ) -> Result<(), BrokerFault>;
+    async fn insert_bridge(&self, descriptor: Mac<BridgeDescriptor>) -> Result<(), BrokerFault>;
 
     async fn incr_stat(&self, stat: String, value: i32);
 
@@ -89,18 +106,141 @@ impl Default for Credential {
 
 pub const DOMAIN_EXIT_DESCRIPTOR: &str = "exit-descriptor";
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-#[serde(transparent)]
-pub struct GenericError(pub String);
+pub const DOMAIN_EXIT_KEY_ROTATION: &str = "exit-key-rotation";
 
-impl Display for GenericError {
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// An error returned by the broker, typed so that clients can react to it programmatically. On the wire it is a string, like the untyped errors of older brokers, so that older clients can still show it. The string starts with a machine-readable code in brackets, such as `[rate_limited:30]`, followed by the message; clients go by the code alone, and strings without a known code, such as errors from older brokers, read as [BrokerFault::InternalError].
+pub enum BrokerFault {
+    RateLimited { retry_after_secs: u64 },
+    InvalidCredential,
+    NoExitsAvailable,
+    InvalidSignature,
+    InternalError(String),
+}
+
+impl Display for BrokerFault {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.0.fmt(f)
+        match self {
+            BrokerFault::RateLimited { retry_after_secs } => {
+                write!(f, "rate limited, retry after {retry_after_secs}s")
+            }
+            BrokerFault::InvalidCredential => write!(f, "invalid credential"),
+            BrokerFault::NoExitsAvailable => write!(f, "no exits available"),
+            BrokerFault::InvalidSignature => write!(f, "invalid signature"),
+            BrokerFault::InternalError(msg) => msg.fmt(f),
+        }
+    }
+}
+
+impl BrokerFault {
+    /// The code that identifies the kind of error on the wire.
+    fn code(&self) -> String {
+        match self {
+            BrokerFault::RateLimited { retry_after_secs } => {
+                format!("rate_limited:{retry_after_secs}")
+            }
+            BrokerFault::InvalidCredential => "invalid_credential".into(),
+            BrokerFault::NoExitsAvailable => "no_exits_available".into(),
+            BrokerFault::InvalidSignature => "invalid_signature".into(),
+            BrokerFault::InternalError(_) => "internal_error".into(),
+        }
+    }
+
+    fn from_wire(wire: String) -> Self {
+        let Some((code, msg)) = wire
+            .strip_prefix('[')
+            .and_then(|rest| rest.split_once("] "))
+        else {
+            return BrokerFault::InternalError(wire);
+        };
+        match code.split_once(':') {
+            Some(("rate_limited", secs)) => match secs.parse() {
+                Ok(retry_after_secs) => BrokerFault::RateLimited { retry_after_secs },
+                Err(_) => BrokerFault::InternalError(wire),
+            },
+            None if code == "invalid_credential" => BrokerFault::InvalidCredential,
+            None if code == "no_exits_available" => BrokerFault::NoExitsAvailable,
+            None if code == "invalid_signature" => BrokerFault::InvalidSignature,
+            None if code == "internal_error" => BrokerFault::InternalError(msg.into()),
+            _ => BrokerFault::InternalError(wire),
+        }
+    }
+}
+
+impl Serialize for BrokerFault {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("[{}] {}", self.code(), self))
+    }
+}
+
+impl<'de> Deserialize<'de> for BrokerFault {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::from_wire)
     }
 }
 
-impl<T: Into<anyhow::Error>> From<T> for GenericError {
+impl<T: Into<anyhow::Error>> From<T> for BrokerFault {
     fn from(value: T) -> Self {
-        Self(value.into().to_string())
+        Self::InternalError(value.into().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broker_fault_is_a_string_on_the_wire() {
+        for fault in [
+            BrokerFault::RateLimited {
+                retry_after_secs: 30,
+            },
+            BrokerFault::InvalidCredential,
+            BrokerFault::NoExitsAvailable,
+            BrokerFault::InvalidSignature,
+            BrokerFault::InternalError("database is down".into()),
+        ] {
+            let json = serde_json::to_string(&fault).unwrap();
+            let wire: String = serde_json::from_str(&json).unwrap();
+            assert!(wire.ends_with(&fault.to_string()));
+            assert_eq!(serde_json::from_str::<BrokerFault>(&json).unwrap(), fault);
+        }
+        assert_eq!(
+            serde_json::to_string(&BrokerFault::RateLimited {
+                retry_after_secs: 30
+            })
+            .unwrap(),
+            "\"[rate_limited:30] rate limited, retry after 30s\""
+        );
+    }
+
+    #[test]
+    fn broker_fault_goes_by_the_code() {
+        // the message may be reworded without changing what the client makes of it
+        assert_eq!(
+            serde_json::from_str::<BrokerFault>("\"[rate_limited:5] slow down\"").unwrap(),
+            BrokerFault::RateLimited {
+                retry_after_secs: 5
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<BrokerFault>("\"[invalid_credential] who are you\"").unwrap(),
+            BrokerFault::InvalidCredential
+        );
+        // what older brokers send
+        assert_eq!(
+            serde_json::from_str::<BrokerFault>("\"cannot connect to database\"").unwrap(),
+            BrokerFault::InternalError("cannot connect to database".into())
+        );
+        for garbled in [
+            "[rate_limited:soon] wait",
+            "[no_such_code] hi",
+            "[invalid_credential]",
+        ] {
+            assert_eq!(
+                BrokerFault::from_wire(garbled.into()),
+                BrokerFault::InternalError(garbled.into())
+            );
+        }
     }
 }
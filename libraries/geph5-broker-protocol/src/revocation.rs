@@ -0,0 +1,28 @@
+use mizaru2::ClientToken;
+use serde::{Deserialize, Serialize};
+use serde_with::{hex::Hex, serde_as};
+use stdcode::StdcodeSerializeExt;
+
+pub const DOMAIN_REVOCATION_LIST: &str = "revocation-list";
+
+/// A list of connect tokens that exits must no longer accept, for example because the account they were issued to was banned for abuse. Tokens are identified by their [RevocationList::token_hash].
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RevocationList {
+    #[serde_as(as = "Vec<Hex>")]
+    pub revoked_tokens: Vec<[u8; 32]>,
+    /// When this list should no longer be trusted to be complete, in seconds since the Unix epoch.
+    pub expiry: u64,
+}
+
+impl RevocationList {
+    /// The BLAKE3 hash that identifies a connect token in the list.
+    pub fn token_hash(token: &ClientToken) -> [u8; 32] {
+        *blake3::hash(&token.stdcode()).as_bytes()
+    }
+
+    /// Checks whether a connect token is revoked.
+    pub fn is_revoked(&self, token: &ClientToken) -> bool {
+        self.revoked_tokens.contains(&Self::token_hash(token))
+    }
+}
@@ -1,12 +1,21 @@
 use std::net::SocketAddr;
 
+use isocountry::CountryCode;
 use serde::{Deserialize, Serialize};
+use serde_with::{hex::Hex, serde_as};
 
+#[serde_as]
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "snake_case")]
 /// This fully describes a route to a particular exit.
 pub enum RouteDescriptor {
     Tcp(SocketAddr),
+    /// A QUIC connection to a server presenting the certificate with this hash. Before dialing, the client checks that UDP gets through to the server at all, and fails immediately if it doesn't, so this should be raced against or fall back to a TCP route. Then QUIC is used whenever UDP is not blocked.
+    Quic {
+        addr: SocketAddr,
+        #[serde_as(as = "Hex")]
+        cert_hash: [u8; 32],
+    },
     Sosistab3 {
         cookie: String,
         lower: Box<RouteDescriptor>,
@@ -21,7 +30,73 @@ pub enum RouteDescriptor {
         milliseconds: u32,
         lower: Box<RouteDescriptor>,
     },
+    /// Uses `then_` if the condition holds when dialing, and `else_` otherwise.
+    ConditionalRoute {
+        condition: RouteCondition,
+        then_: Box<RouteDescriptor>,
+        else_: Box<RouteDescriptor>,
+    },
+    /// Spreads new connections across `routes` according to `strategy`, rather than having every connection use the same one.
+    LoadBalance {
+        strategy: LoadBalanceStrategy,
+        routes: Vec<RouteDescriptor>,
+    },
 
     #[serde(untagged)]
     Other(serde_json::Value),
 }
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+/// How a [RouteDescriptor::LoadBalance] picks the route for each new connection.
+pub enum LoadBalanceStrategy {
+    /// Each route in turn.
+    RoundRobin,
+    /// The route with the fewest connections currently open through it.
+    LeastConnections,
+    /// A uniformly random route.
+    Random,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+/// A condition about the client's network environment, evaluated by the client at dial time.
+pub enum RouteCondition {
+    /// Whether outgoing TCP connections to this port on the `else_` route's servers fail.
+    IfPortBlocked(u16),
+    /// Whether the client is in this country.
+    IfCountry(CountryCode),
+    /// Whether it is currently one of these days of the week, in the client's local time.
+    IfDayOfWeek { days: Vec<Weekday> },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quic_cert_hash_is_hex() {
+        let route = RouteDescriptor::Quic {
+            addr: "1.2.3.4:443".parse().unwrap(),
+            cert_hash: [0xab; 32],
+        };
+        let json = serde_json::to_value(&route).unwrap();
+        assert_eq!(json["quic"]["cert_hash"], "ab".repeat(32));
+        let RouteDescriptor::Quic { cert_hash, .. } = serde_json::from_value(json).unwrap() else {
+            panic!("did not roundtrip to a QUIC route")
+        };
+        assert_eq!(cert_hash, [0xab; 32]);
+    }
+}
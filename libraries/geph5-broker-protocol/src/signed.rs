@@ -47,6 +47,109 @@ impl<T: Serialize> Signed<T> {
     }
 }
 
+/// A piece of data signed by several ed25519 keys. On the wire, it looks like a [Signed] by the first key with the rest of the signatures alongside, so that clients that only know about [Signed] can still check the first signature, and a [Signed] from an older broker reads as a [MultiSigned] with one signature.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(
+    from = "MultiSignedWire<T>",
+    bound(deserialize = "T: Deserialize<'de>")
+)]
+pub struct MultiSigned<T> {
+    pub inner: T,
+
+    pub signatures: Vec<(VerifyingKey, Signature)>,
+}
+
+#[derive(Deserialize)]
+struct MultiSignedWire<T> {
+    inner: T,
+
+    signature: Signature,
+    pubkey: VerifyingKey,
+    #[serde(default)]
+    signatures: Vec<(VerifyingKey, Signature)>,
+}
+
+impl<T> From<MultiSignedWire<T>> for MultiSigned<T> {
+    fn from(wire: MultiSignedWire<T>) -> Self {
+        let signatures = if wire.signatures.is_empty() {
+            vec![(wire.pubkey, wire.signature)]
+        } else {
+            wire.signatures
+        };
+        MultiSigned {
+            inner: wire.inner,
+            signatures,
+        }
+    }
+}
+
+impl<T: Serialize> Serialize for MultiSigned<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct MultiSignedWireRef<'a, T> {
+            inner: &'a T,
+
+            signature: &'a Signature,
+            pubkey: &'a VerifyingKey,
+            signatures: &'a [(VerifyingKey, Signature)],
+        }
+
+        let (pubkey, signature) = self
+            .signatures
+            .first()
+            .ok_or_else(|| serde::ser::Error::custom("no signatures"))?;
+        MultiSignedWireRef {
+            inner: &self.inner,
+            signature,
+            pubkey,
+            signatures: &self.signatures,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<T: Serialize> MultiSigned<T> {
+    /// Creates a new MultiSigned instance, which represents a piece of data signed by several ed25519 keys.
+    pub fn new(inner: T, domain: &str, seckeys: &[&SigningKey]) -> Self {
+        let to_sign =
+            blake3::keyed_hash(blake3::hash(domain.as_bytes()).as_bytes(), &inner.stdcode());
+        let signatures = seckeys
+            .iter()
+            .map(|sk| (sk.verifying_key(), sk.sign(to_sign.as_bytes())))
+            .collect();
+        MultiSigned { inner, signatures }
+    }
+
+    /// Verifies the signed document, returning what's inside only if at least `threshold` distinct keys out of the `trusted` keys have validly signed it.
+    pub fn verify(
+        self,
+        domain: &str,
+        trusted: &[VerifyingKey],
+        threshold: usize,
+    ) -> Result<T, VerifyError> {
+        let to_sign = blake3::keyed_hash(
+            blake3::hash(domain.as_bytes()).as_bytes(),
+            &self.inner.stdcode(),
+        );
+        let mut valid_signers: Vec<&VerifyingKey> = vec![];
+        for (pubkey, signature) in self.signatures.iter() {
+            if !trusted.contains(pubkey) || valid_signers.contains(&pubkey) {
+                continue;
+            }
+            if pubkey.verify_strict(to_sign.as_bytes(), signature).is_ok() {
+                valid_signers.push(pubkey);
+            }
+        }
+        if valid_signers.len() < threshold {
+            return Err(VerifyError::NotEnoughSignatures {
+                valid: valid_signers.len(),
+                threshold,
+            });
+        }
+        Ok(self.inner)
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum VerifyError {
     #[error("Invalid public key")]
@@ -54,4 +157,51 @@ pub enum VerifyError {
 
     #[error("Invalid signature")]
     InvalidSignature,
+
+    #[error("Only {valid} valid signatures, but {threshold} are required")]
+    NotEnoughSignatures { valid: usize, threshold: usize },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multisigned_threshold() {
+        let keys: Vec<SigningKey> = (0..3u8).map(|i| SigningKey::from_bytes(&[i; 32])).collect();
+        let trusted: Vec<VerifyingKey> = keys.iter().map(|k| k.verifying_key()).collect();
+
+        let signed = MultiSigned::new(42u64, "test", &[&keys[0], &keys[1]]);
+        assert_eq!(signed.clone().verify("test", &trusted, 2).unwrap(), 42);
+        assert!(signed.clone().verify("test", &trusted, 3).is_err());
+        assert!(signed.clone().verify("other", &trusted, 1).is_err());
+        assert!(signed.verify("test", &trusted[2..], 1).is_err());
+
+        // the same key signing twice only counts once
+        let doubled = MultiSigned::new(42u64, "test", &[&keys[0], &keys[0]]);
+        assert!(doubled.verify("test", &trusted, 2).is_err());
+    }
+
+    #[test]
+    fn test_multisigned_reads_as_signed() {
+        let keys: Vec<SigningKey> = (0..2u8).map(|i| SigningKey::from_bytes(&[i; 32])).collect();
+        let trusted: Vec<VerifyingKey> = keys.iter().map(|k| k.verifying_key()).collect();
+
+        // older clients only see the first signature
+        let multi = MultiSigned::new(42u64, "test", &[&keys[0], &keys[1]]);
+        let json = serde_json::to_string(&multi).unwrap();
+        let single: Signed<u64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(single.pubkey, trusted[0]);
+        assert_eq!(single.verify("test", |pk| pk == &trusted[0]).unwrap(), 42);
+        let multi: MultiSigned<u64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(multi.verify("test", &trusted, 2).unwrap(), 42);
+
+        // and what older brokers send has exactly one
+        let single = Signed::new(42u64, "test", &keys[0]);
+        let json = serde_json::to_string(&single).unwrap();
+        let multi: MultiSigned<u64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(multi.signatures.len(), 1);
+        assert_eq!(multi.clone().verify("test", &trusted, 1).unwrap(), 42);
+        assert!(multi.verify("test", &trusted, 2).is_err());
+    }
 }
@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+
+/// A range of broker HTTP API versions, as served by the broker at `GET /version`. Each version `n` is served under the `/v{n}/` prefix.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct VersionRange {
+    pub min_version: u32,
+    pub max_version: u32,
+}
+
+/// The broker HTTP API versions that this crate speaks.
+pub const SUPPORTED_VERSIONS: VersionRange = VersionRange {
+    min_version: 1,
+    max_version: 2,
+};
+
+impl VersionRange {
+    /// Picks the highest version that both ranges contain, if there is one.
+    pub fn negotiate(&self, other: &VersionRange) -> Option<u32> {
+        let highest = self.max_version.min(other.max_version);
+        (highest >= self.min_version.max(other.min_version)).then_some(highest)
+    }
+
+    /// The URL that version `version` of the API at `base_url` is served at.
+    pub fn versioned_url(base_url: &str, version: u32) -> String {
+        format!("{}/v{version}/", base_url.trim_end_matches('/'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_picks_highest_common() {
+        let old = VersionRange {
+            min_version: 1,
+            max_version: 1,
+        };
+        assert_eq!(SUPPORTED_VERSIONS.negotiate(&old), Some(1));
+        assert_eq!(SUPPORTED_VERSIONS.negotiate(&SUPPORTED_VERSIONS), Some(2));
+        let future = VersionRange {
+            min_version: 3,
+            max_version: 4,
+        };
+        assert_eq!(SUPPORTED_VERSIONS.negotiate(&future), None);
+    }
+
+    #[test]
+    fn versioned_url() {
+        assert_eq!(
+            VersionRange::versioned_url("https://broker.example.com/", 2),
+            "https://broker.example.com/v2/"
+        );
+        assert_eq!(
+            VersionRange::versioned_url("https://broker.example.com", 1),
+            "https://broker.example.com/v1/"
+        );
+    }
+}
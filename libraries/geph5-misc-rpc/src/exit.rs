@@ -1,4 +1,4 @@
-use std::pin::Pin;
+use std::{collections::HashMap, pin::Pin};
 
 use anyhow::Context;
 
@@ -6,15 +6,48 @@ use async_task::Task;
 use bipe::{BipeReader, BipeWriter};
 use bytes::Bytes;
 use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
 use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use pin_project::pin_project;
 use serde::{Deserialize, Serialize};
 use sillad::Pipe;
+use stdcode::StdcodeSerializeExt;
 
 use tap::Tap;
+use web_time::{SystemTime, UNIX_EPOCH};
 
 use crate::{read_prepend_length, write_prepend_length};
 
+/// Extension requesting compression of the tunnel. Reserved, not yet negotiated.
+pub const EXT_COMPRESSION: &str = "compression";
+/// Extension carrying a traffic priority hint. Reserved, not yet negotiated.
+pub const EXT_PRIORITY: &str = "priority";
+/// Extension asking for a session ticket, and presenting the ticket of a previous session if the client has one, as a stdcode-encoded [SessionResumption]. Exits that issue tickets answer with [ExitHelloInner::Resumable].
+pub const EXT_SESSION_RESUMPTION: &str = "session_resumption";
+
+/// Extension claiming membership in a tenant of a multi-tenant exit, carrying a stdcode-encoded [TenantClaim].
+pub const EXT_TENANT: &str = "tenant";
+
+/// Extension asking the exit to wrap the encrypted tunnel in an [ObfuscatedPipe](crate::obfs::ObfuscatedPipe), with no value. Exits that agree answer with [ExitHelloInner::X25519Obfuscated].
+pub const EXT_OBFUSCATE_FRAMES: &str = "obfuscate_frames";
+
+/// Extension carrying when the hello was made, as a stdcode-encoded [HelloTimestamp]. Exits reject hellos that are too old, so they only have to remember nonces for a bounded time to catch replays.
+pub const EXT_TIMESTAMP: &str = "timestamp";
+
+/// Extension carrying the [Keepalive] the client uses for its side of the tunnel, stdcode-encoded, so that the exit can keep the tunnel alive at a similar pace from its side.
+pub const EXT_KEEPALIVE: &str = "keepalive";
+
+/// All the [ClientHello] extension keys that have been registered. Unknown keys should be ignored by the receiver.
+pub const KNOWN_EXTENSIONS: &[&str] = &[
+    EXT_COMPRESSION,
+    EXT_PRIORITY,
+    EXT_SESSION_RESUMPTION,
+    EXT_TENANT,
+    EXT_OBFUSCATE_FRAMES,
+    EXT_TIMESTAMP,
+    EXT_KEEPALIVE,
+];
+
 /// ClientHello represents the initial message sent by the client to
 /// the exit node to negotiate the authentication/encryption system
 /// to use.
@@ -24,6 +57,313 @@ pub struct ClientHello {
     pub credentials: Bytes,
     // The initial cryptographic hello message
     pub crypt_hello: ClientCryptHello,
+    // Optional features, keyed by names from KNOWN_EXTENSIONS. Omitted from the wire when empty. Old exits ignore them when present, and sign their hello as if they were absent, as legacy_signed_value describes.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub extensions: HashMap<String, Vec<u8>>,
+}
+
+impl ClientHello {
+    /// The random value in the crypt hello, which is fresh for every handshake, and so identifies replays.
+    pub fn nonce(&self) -> [u8; 32] {
+        self.crypt_hello.nonce()
+    }
+
+    /// Decodes a ClientHello, accepting hellos from older clients that do not send extensions at all.
+    pub fn decode(bts: &[u8]) -> anyhow::Result<Self> {
+        #[derive(Deserialize)]
+        struct LegacyClientHello {
+            credentials: Bytes,
+            crypt_hello: ClientCryptHello,
+        }
+
+        if let Ok(hello) = stdcode::deserialize(bts) {
+            return Ok(hello);
+        }
+        let legacy: LegacyClientHello = stdcode::deserialize(bts)?;
+        Ok(Self {
+            credentials: legacy.credentials,
+            crypt_hello: legacy.crypt_hello,
+            extensions: HashMap::new(),
+        })
+    }
+
+    /// What an exit that predates extensions signs its [ExitHello] over. Such exits decode the hello without its extensions, and so sign it as if it had none.
+    pub fn legacy_signed_value(&self, inner: &ExitHelloInner) -> Vec<u8> {
+        ((&self.credentials, &self.crypt_hello), inner).stdcode()
+    }
+
+    /// Decodes the tenant claim, if the client made one.
+    pub fn tenant_claim(&self) -> anyhow::Result<Option<TenantClaim>> {
+        self.extensions
+            .get(EXT_TENANT)
+            .map(|bts| stdcode::deserialize(bts).context("cannot deserialize tenant claim"))
+            .transpose()
+    }
+
+    /// Decodes the timestamp, if the client sent one.
+    pub fn timestamp(&self) -> anyhow::Result<Option<HelloTimestamp>> {
+        self.extensions
+            .get(EXT_TIMESTAMP)
+            .map(|bts| stdcode::deserialize(bts).context("cannot deserialize timestamp"))
+            .transpose()
+    }
+
+    /// Decodes the session resumption request, if the client made one.
+    pub fn session_resumption(&self) -> anyhow::Result<Option<SessionResumption>> {
+        self.extensions
+            .get(EXT_SESSION_RESUMPTION)
+            .map(|bts| stdcode::deserialize(bts).context("cannot deserialize session resumption"))
+            .transpose()
+    }
+
+    /// Decodes the keepalive settings, if the client sent them.
+    pub fn keepalive(&self) -> anyhow::Result<Option<Keepalive>> {
+        self.extensions
+            .get(EXT_KEEPALIVE)
+            .map(|bts| stdcode::deserialize(bts).context("cannot deserialize keepalive"))
+            .transpose()
+    }
+
+    /// Iterates over the extensions that are not in [KNOWN_EXTENSIONS].
+    pub fn unknown_extensions(&self) -> impl Iterator<Item = &str> {
+        self.extensions
+            .keys()
+            .map(|k| k.as_str())
+            .filter(|k| !KNOWN_EXTENSIONS.contains(k))
+    }
+}
+
+/// How often one side of a tunnel pings the other to keep NAT boxes and stateful firewalls along the way from dropping it while it is idle, and how long it waits for the pong before giving the tunnel up as dead.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keepalive {
+    pub interval_secs: u64,
+    pub timeout_secs: u64,
+}
+
+impl Keepalive {
+    /// Agrees on the keepalive for a tunnel, given the settings of both sides: pings as often as either side wants, and waits for pongs as long as either side would.
+    pub fn negotiate(self, other: Self) -> Self {
+        Self {
+            interval_secs: self.interval_secs.min(other.interval_secs),
+            timeout_secs: self.timeout_secs.max(other.timeout_secs),
+        }
+    }
+
+    /// Bounds settings that came from the other side, so that it cannot have us ping it over and over, or keep its tunnel around long after it has gone.
+    pub fn clamp(self, min_interval_secs: u64, max_timeout_secs: u64) -> Self {
+        Self {
+            interval_secs: self.interval_secs.max(min_interval_secs),
+            timeout_secs: self.timeout_secs.min(max_timeout_secs),
+        }
+    }
+}
+
+/// How long a probe magic is good for. Exits also accept magics from the periods right before and after the current one, to allow for clock skew.
+pub const PROBE_MAGIC_PERIOD_SECS: u64 = 60;
+
+/// When a hello was made, in seconds since the Unix epoch, with a MAC binding it to the crypt hello. Only the client that made the hello knows the key, so a recorded hello cannot be replayed with a fresh timestamp once the exit has forgotten its nonce.
+///
+/// The key is what the client shares with the exit before the handshake: the shared secret of the underlying pipe for [ClientCryptHello::SharedSecretChallenge], for [ClientCryptHello::X25519], the Diffie-Hellman of the client's ephemeral key with the exit's long-term key, taken as an X25519 key by [exit_x25519_public] and [exit_x25519_secret], and for [ClientCryptHello::Resume], the [resumption_secret] of the ticket being presented.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HelloTimestamp {
+    pub secs: u64,
+    pub mac: blake3::Hash,
+}
+
+impl HelloTimestamp {
+    /// Stamps the hello with the given crypt hello.
+    pub fn new(secs: u64, secret: &[u8], crypt_hello: &ClientCryptHello) -> Self {
+        Self {
+            secs,
+            mac: Self::mac(secs, secret, crypt_hello),
+        }
+    }
+
+    /// Checks that the timestamp was made for the given crypt hello by someone who knows the secret.
+    pub fn verify(&self, secret: &[u8], crypt_hello: &ClientCryptHello) -> bool {
+        // blake3 hashes compare in constant time
+        self.mac == Self::mac(self.secs, secret, crypt_hello)
+    }
+
+    fn mac(secs: u64, secret: &[u8], crypt_hello: &ClientCryptHello) -> blake3::Hash {
+        let key = blake3::derive_key("geph5-hello-timestamp", secret);
+        blake3::keyed_hash(&key, &(crypt_hello.nonce(), secs).stdcode())
+    }
+}
+
+/// The exit's long-term key as an X25519 public key, for keying a [HelloTimestamp].
+pub fn exit_x25519_public(exit_pubkey: &VerifyingKey) -> x25519_dalek::PublicKey {
+    x25519_dalek::PublicKey::from(exit_pubkey.to_montgomery().to_bytes())
+}
+
+/// The exit's long-term key as an X25519 secret key, matching [exit_x25519_public].
+pub fn exit_x25519_secret(signing_key: &SigningKey) -> x25519_dalek::StaticSecret {
+    x25519_dalek::StaticSecret::from(signing_key.to_scalar_bytes())
+}
+
+/// The bytes a client sends right before its length-prefixed [ClientHello], to exits that say in their descriptor that they understand them, so that a probe-resistant exit can tell it apart from an active probe. The first half is a random nonce and the second a MAC over the nonce and the current period, keyed with the exit's public key, which only those who got the exit from the broker know. Every connection thus starts differently, and a magic seen on the wire stops working after a few minutes, during which the exit remembers it to catch replays. Unlike the length prefix of any hello, a magic never starts with a zero byte, so exits can still tell when a client sent none.
+pub fn probe_magic(exit_pubkey: &VerifyingKey, mut nonce: [u8; 16], unix_secs: u64) -> [u8; 32] {
+    nonce[0] |= 0x80;
+    let mut magic = [0u8; 32];
+    magic[..16].copy_from_slice(&nonce);
+    magic[16..].copy_from_slice(&probe_magic_tag(
+        exit_pubkey,
+        &nonce,
+        unix_secs / PROBE_MAGIC_PERIOD_SECS,
+    ));
+    magic
+}
+
+/// Checks that a [probe_magic] was made for the given exit key within a period of the given time.
+pub fn check_probe_magic(exit_pubkey: &VerifyingKey, magic: &[u8; 32], unix_secs: u64) -> bool {
+    let period = unix_secs / PROBE_MAGIC_PERIOD_SECS;
+    [period.saturating_sub(1), period, period + 1]
+        .into_iter()
+        .any(|period| {
+            let tag = probe_magic_tag(exit_pubkey, &magic[..16], period);
+            // compared in constant time, so that the tag cannot be guessed a byte at a time
+            tag.iter()
+                .zip(&magic[16..])
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+        })
+}
+
+fn probe_magic_tag(exit_pubkey: &VerifyingKey, nonce: &[u8], period: u64) -> [u8; 16] {
+    let key = blake3::derive_key("geph5-probe-magic", exit_pubkey.as_bytes());
+    let mac = blake3::Hasher::new_keyed(&key)
+        .update(nonce)
+        .update(&period.to_le_bytes())
+        .finalize();
+    let mut tag = [0u8; 16];
+    tag.copy_from_slice(&mac.as_bytes()[..16]);
+    tag
+}
+
+/// What an exit read at the start of a client connection.
+pub enum ScreenedHello {
+    /// The encoded [ClientHello] of a genuine client, together with the index of the exit key whose magic it sent and the magic itself, if it sent any. The exit should still check that the magic is not a replay.
+    Hello(Vec<u8>, Option<(usize, [u8; 32])>),
+    /// Everything read from something that is not a genuine client, so that it can be passed on elsewhere.
+    Probe(Vec<u8>),
+}
+
+/// Reads the [probe_magic] for one of the given exit keys, followed by a length-prefixed [ClientHello]. An exit has more than one key while it is rotating to a new one. Hellos without any magic, from clients that predate it or that did not know that this exit understands it, are treated as probes if `require_magic` is set.
+pub async fn read_screened_hello<R: AsyncRead + Unpin>(
+    mut input: R,
+    exit_pubkeys: &[VerifyingKey],
+    require_magic: bool,
+) -> std::io::Result<ScreenedHello> {
+    let mut first = [0u8; 1];
+    input.read_exact(&mut first).await?;
+    if first[0] == 0 {
+        if require_magic {
+            return Ok(ScreenedHello::Probe(first.to_vec()));
+        }
+        let mut len_buf = [0u8; 4];
+        input.read_exact(&mut len_buf[1..]).await?;
+        let mut hello = vec![0u8; u32::from_be_bytes(len_buf) as usize];
+        input.read_exact(&mut hello).await?;
+        return Ok(ScreenedHello::Hello(hello, None));
+    }
+    let mut magic = [0u8; 32];
+    magic[0] = first[0];
+    input.read_exact(&mut magic[1..]).await?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let Some(key_index) = exit_pubkeys
+        .iter()
+        .position(|pubkey| check_probe_magic(pubkey, &magic, now))
+    else {
+        return Ok(ScreenedHello::Probe(magic.to_vec()));
+    };
+    Ok(ScreenedHello::Hello(
+        read_prepend_length(input).await?,
+        Some((key_index, magic)),
+    ))
+}
+
+/// A request for a session ticket, which lets the next connection to the same exit skip presenting a connect token, and the key exchange too with [ClientCryptHello::Resume].
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SessionResumption {
+    /// The ticket of a previous session, if we have one. The credentials of the hello are then left empty.
+    pub presented: Option<PresentedTicket>,
+}
+
+/// A session ticket presented for resumption, together with proof that we were the client of the session it was issued in, so that a ticket seen on the wire is of no use to anybody else.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PresentedTicket {
+    /// The ticket, opaque to the client.
+    pub ticket: Bytes,
+    /// The nonce of this hello, keyed-hashed with the [resumption_secret] of the session the ticket was issued in.
+    pub proof: blake3::Hash,
+}
+
+impl PresentedTicket {
+    /// Presents a ticket in the hello with the given nonce.
+    pub fn new(ticket: Bytes, resumption_secret: &[u8; 32], nonce: &[u8; 32]) -> Self {
+        Self {
+            ticket,
+            proof: blake3::keyed_hash(resumption_secret, nonce),
+        }
+    }
+}
+
+/// The secret that proves possession of a session ticket, derived from the secret that the session shared between client and exit.
+pub fn resumption_secret(shared_secret: &[u8]) -> [u8; 32] {
+    blake3::derive_key("geph5-session-resumption", shared_secret)
+}
+
+/// The secret shared by a session resumed with [ClientCryptHello::Resume], which takes the place of the Diffie-Hellman output of a full handshake. Both nonces are fresh, so every resumed session gets its own keys, but anybody who later learns the resumption secret can derive them too: unlike a full handshake, resuming gives no forward secrecy beyond the session the ticket came from.
+pub fn resumed_shared_secret(
+    resumption_secret: &[u8; 32],
+    client_nonce: &[u8; 32],
+    exit_nonce: &[u8; 32],
+) -> [u8; 32] {
+    blake3::derive_key(
+        "geph5-resumed-session",
+        &[resumption_secret.as_slice(), client_nonce, exit_nonce].concat(),
+    )
+}
+
+/// A claim that the client may use the resources of a tenant of the exit, proven by signing the crypt hello of this very handshake with one of the tenant's keys, so that the claim cannot be replayed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TenantClaim {
+    pub tenant_id: String,
+    pub pubkey: VerifyingKey,
+    pub signature: Signature,
+}
+
+impl TenantClaim {
+    /// Creates a claim for the given tenant, bound to the given crypt hello.
+    pub fn new(tenant_id: String, crypt_hello: &ClientCryptHello, seckey: &SigningKey) -> Self {
+        let signature = seckey.sign(Self::to_sign(&tenant_id, crypt_hello).as_bytes());
+        Self {
+            tenant_id,
+            pubkey: seckey.verifying_key(),
+            signature,
+        }
+    }
+
+    /// Checks that the claim was made for the given crypt hello. Whether the key is actually one of the tenant's is up to the caller.
+    pub fn verify(&self, crypt_hello: &ClientCryptHello) -> anyhow::Result<()> {
+        self.pubkey
+            .verify_strict(
+                Self::to_sign(&self.tenant_id, crypt_hello).as_bytes(),
+                &self.signature,
+            )
+            .ok()
+            .context("invalid tenant claim signature")
+    }
+
+    fn to_sign(tenant_id: &str, crypt_hello: &ClientCryptHello) -> blake3::Hash {
+        blake3::keyed_hash(
+            blake3::hash(b"tenant-claim").as_bytes(),
+            &(tenant_id, crypt_hello).stdcode(),
+        )
+    }
 }
 
 /// ClientCryptHello is an enum representing the possible
@@ -34,6 +374,19 @@ pub enum ClientCryptHello {
     SharedSecretChallenge([u8; 32]),
     /// An X25519 public key to be used to add a layer of encryption
     X25519(x25519_dalek::PublicKey),
+    /// A fresh random nonce, for resuming a session with the ticket presented in [EXT_SESSION_RESUMPTION] without a key exchange. The keys then come from [resumed_shared_secret]. Only sent to exits that gave us a ticket, which all understand this.
+    Resume([u8; 32]),
+}
+
+impl ClientCryptHello {
+    /// The random value in the crypt hello, which is fresh for every handshake.
+    pub fn nonce(&self) -> [u8; 32] {
+        match self {
+            ClientCryptHello::SharedSecretChallenge(challenge) => *challenge,
+            ClientCryptHello::X25519(epk) => epk.to_bytes(),
+            ClientCryptHello::Resume(nonce) => *nonce,
+        }
+    }
 }
 
 /// ExitHello represents the response of the exit node to the initial
@@ -58,8 +411,32 @@ pub enum ExitHelloInner {
     SharedSecretResponse(blake3::Hash),
     /// An X25519 public key to be used in the key exchange process
     X25519(x25519_dalek::PublicKey),
+    /// Rejects the request because the same client hello was seen recently, and so might be replayed
+    ReplayDetected,
+    /// Like [ExitHelloInner::X25519], but also agreeing to the client's request to obfuscate frames, so that both sides wrap the encrypted pipe in an [ObfuscatedPipe](crate::obfs::ObfuscatedPipe)
+    X25519Obfuscated(x25519_dalek::PublicKey),
+    /// Any of the other successful responses, together with a ticket for resuming this session on the next connection, sent only to clients that asked for one with [EXT_SESSION_RESUMPTION]
+    Resumable {
+        inner: Box<ExitHelloInner>,
+        ticket: Bytes,
+    },
+    /// Accepts a [ClientCryptHello::Resume], with the exit's own fresh nonce for [resumed_shared_secret], and whether frames are obfuscated just like [ExitHelloInner::X25519Obfuscated] says
+    Resumed { nonce: [u8; 32], obfuscated: bool },
+}
+
+impl ExitHelloInner {
+    /// Separates the session ticket, if any, from the rest of the response.
+    pub fn take_ticket(self) -> (Self, Option<Bytes>) {
+        match self {
+            Self::Resumable { inner, ticket } => (*inner, Some(ticket)),
+            other => (other, None),
+        }
+    }
 }
 
+/// Path MTU discovery probes, sent over UDP to the port of the exit's c2e listener, start with this magic, followed by the 2-byte little-endian size of the whole IP packet. The exit answers each probe with just the magic and the size, so that the answers are small enough to always get through.
+pub const PMTUD_MAGIC: &[u8; 12] = b"geph5-pmtud\0";
+
 /// ClientExitCryptPipe is a sillad::Pipe implementation representing an end-to-end encrypted connection between the client and the exit.
 #[pin_project]
 pub struct ClientExitCryptPipe {
@@ -71,6 +448,8 @@ pub struct ClientExitCryptPipe {
     _write_task: Task<()>,
 
     addr: Option<String>,
+    #[cfg(unix)]
+    raw_fd: Option<std::os::fd::RawFd>,
 }
 
 impl AsyncRead for ClientExitCryptPipe {
@@ -111,11 +490,13 @@ impl ClientExitCryptPipe {
     /// Creates a new pipe, given read and write keys
     pub fn new(pipe: impl Pipe, read_key: [u8; 32], write_key: [u8; 32]) -> Self {
         let addr = pipe.remote_addr().map(|s| s.to_string());
+        #[cfg(unix)]
+        let raw_fd = pipe.raw_fd();
         let (mut pipe_read, mut pipe_write) = pipe.split();
         let (mut write_incoming, read_incoming) = bipe::bipe(32768);
         let (write_outgoing, mut read_outgoing) = bipe::bipe(32768);
 
-        let _read_task = smolscale::spawn(async move {
+        let _read_task = geph5_rt::spawn(async move {
             let read_aead = ChaCha20Poly1305::new_from_slice(&read_key).unwrap();
             let fallible = async {
                 for read_nonce in 0u64.. {
@@ -135,7 +516,7 @@ impl ClientExitCryptPipe {
             }
         });
 
-        let _write_task = smolscale::spawn(async move {
+        let _write_task = geph5_rt::spawn(async move {
             let fallible = async {
                 let write_aead = ChaCha20Poly1305::new_from_slice(&write_key).unwrap();
                 let mut buf = [0; 8192];
@@ -159,6 +540,8 @@ impl ClientExitCryptPipe {
             _write_task,
 
             addr,
+            #[cfg(unix)]
+            raw_fd,
         }
     }
 }
@@ -171,4 +554,334 @@ impl Pipe for ClientExitCryptPipe {
     fn remote_addr(&self) -> Option<&str> {
         self.addr.as_deref()
     }
+
+    // the underlying pipe lives in the read and write tasks, which drop it once the connection fails, so the descriptor may be closed before we are
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        self.raw_fd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_hello_extensions_roundtrip() {
+        let hello = ClientHello {
+            credentials: Bytes::from_static(b"creds"),
+            crypt_hello: ClientCryptHello::SharedSecretChallenge([1; 32]),
+            extensions: [
+                (EXT_PRIORITY.to_string(), vec![3]),
+                ("bogus".to_string(), vec![]),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        let decoded = ClientHello::decode(&hello.stdcode()).unwrap();
+        assert_eq!(decoded.extensions, hello.extensions);
+        assert_eq!(
+            decoded.unknown_extensions().collect::<Vec<_>>(),
+            vec!["bogus"]
+        );
+    }
+
+    #[test]
+    fn legacy_exits_sign_hellos_without_extensions() {
+        // how exits decoded hellos before extensions existed
+        #[derive(Serialize, Deserialize)]
+        struct LegacyClientHello {
+            credentials: Bytes,
+            crypt_hello: ClientCryptHello,
+        }
+
+        let crypt_hello = ClientCryptHello::X25519(x25519_dalek::PublicKey::from([2; 32]));
+        let hello = ClientHello {
+            credentials: Bytes::from_static(b"creds"),
+            extensions: [
+                (
+                    EXT_TIMESTAMP.to_string(),
+                    HelloTimestamp::new(1_700_000_000, b"secret", &crypt_hello).stdcode(),
+                ),
+                (
+                    EXT_KEEPALIVE.to_string(),
+                    Keepalive {
+                        interval_secs: 30,
+                        timeout_secs: 90,
+                    }
+                    .stdcode(),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            crypt_hello,
+        };
+        let legacy: LegacyClientHello = stdcode::deserialize(&hello.stdcode()).unwrap();
+        assert_eq!(legacy.credentials, hello.credentials);
+        assert_eq!(legacy.crypt_hello.nonce(), hello.nonce());
+
+        let inner = ExitHelloInner::X25519(x25519_dalek::PublicKey::from([3; 32]));
+        assert_eq!(
+            hello.legacy_signed_value(&inner),
+            (&legacy, &inner).stdcode()
+        );
+        assert_ne!(
+            hello.legacy_signed_value(&inner),
+            (&hello, &inner).stdcode()
+        );
+    }
+
+    #[test]
+    fn session_ticket_proof_bound_to_secret_and_nonce() {
+        let secret = resumption_secret(b"shared");
+        let presented = PresentedTicket::new(Bytes::from_static(b"ticket"), &secret, &[1; 32]);
+        assert_eq!(presented.proof, blake3::keyed_hash(&secret, &[1; 32]));
+        assert_ne!(presented.proof, blake3::keyed_hash(&secret, &[2; 32]));
+        assert_ne!(
+            presented.proof,
+            blake3::keyed_hash(&resumption_secret(b"other"), &[1; 32])
+        );
+
+        let hello = ClientHello {
+            credentials: Bytes::new(),
+            crypt_hello: ClientCryptHello::SharedSecretChallenge([1; 32]),
+            extensions: [(
+                EXT_SESSION_RESUMPTION.to_string(),
+                SessionResumption {
+                    presented: Some(presented),
+                }
+                .stdcode(),
+            )]
+            .into_iter()
+            .collect(),
+        };
+        let decoded = ClientHello::decode(&hello.stdcode()).unwrap();
+        let resumption = decoded.session_resumption().unwrap().unwrap();
+        assert_eq!(resumption.presented.unwrap().ticket, &b"ticket"[..]);
+    }
+
+    #[test]
+    fn resumed_sessions_get_fresh_secrets() {
+        let secret = resumption_secret(b"shared");
+        let shared = resumed_shared_secret(&secret, &[1; 32], &[2; 32]);
+        assert_eq!(shared, resumed_shared_secret(&secret, &[1; 32], &[2; 32]));
+        // a fresh nonce from either side gives different keys
+        assert_ne!(shared, resumed_shared_secret(&secret, &[3; 32], &[2; 32]));
+        assert_ne!(shared, resumed_shared_secret(&secret, &[1; 32], &[3; 32]));
+        assert_ne!(
+            shared,
+            resumed_shared_secret(&resumption_secret(b"other"), &[1; 32], &[2; 32])
+        );
+        // the next ticket's secret is not the one this session was resumed with
+        assert_ne!(resumption_secret(&shared), secret);
+        assert_eq!(ClientCryptHello::Resume([1; 32]).nonce(), [1; 32]);
+    }
+
+    #[test]
+    fn tenant_claim_bound_to_crypt_hello() {
+        let seckey = SigningKey::from_bytes(&[7; 32]);
+        let crypt_hello = ClientCryptHello::SharedSecretChallenge([1; 32]);
+        let claim = TenantClaim::new("acme".into(), &crypt_hello, &seckey);
+        let hello = ClientHello {
+            credentials: Bytes::new(),
+            crypt_hello,
+            extensions: [(EXT_TENANT.to_string(), claim.stdcode())]
+                .into_iter()
+                .collect(),
+        };
+        let decoded = ClientHello::decode(&hello.stdcode())
+            .unwrap()
+            .tenant_claim()
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded.tenant_id, "acme");
+        assert!(decoded.verify(&hello.crypt_hello).is_ok());
+        assert!(decoded
+            .verify(&ClientCryptHello::SharedSecretChallenge([2; 32]))
+            .is_err());
+    }
+
+    #[test]
+    fn client_hello_timestamp_roundtrip() {
+        let mut hello = ClientHello {
+            credentials: Bytes::new(),
+            crypt_hello: ClientCryptHello::SharedSecretChallenge([1; 32]),
+            extensions: HashMap::new(),
+        };
+        assert_eq!(hello.timestamp().unwrap(), None);
+        let timestamp = HelloTimestamp::new(1_700_000_000, b"secret", &hello.crypt_hello);
+        hello
+            .extensions
+            .insert(EXT_TIMESTAMP.to_string(), timestamp.stdcode());
+        let decoded = ClientHello::decode(&hello.stdcode()).unwrap();
+        assert_eq!(decoded.timestamp().unwrap(), Some(timestamp));
+        assert_eq!(decoded.unknown_extensions().count(), 0);
+    }
+
+    #[test]
+    fn hello_timestamp_is_bound() {
+        let crypt_hello = ClientCryptHello::SharedSecretChallenge([1; 32]);
+        let timestamp = HelloTimestamp::new(1_700_000_000, b"secret", &crypt_hello);
+        assert!(timestamp.verify(b"secret", &crypt_hello));
+        assert!(!timestamp.verify(b"other secret", &crypt_hello));
+        assert!(!timestamp.verify(b"secret", &ClientCryptHello::SharedSecretChallenge([2; 32])));
+        // a replayer cannot move the timestamp forward
+        let moved = HelloTimestamp {
+            secs: timestamp.secs + 3600,
+            ..timestamp
+        };
+        assert!(!moved.verify(b"secret", &crypt_hello));
+    }
+
+    #[test]
+    fn exit_x25519_keys_agree() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let client_esk = x25519_dalek::StaticSecret::from([9; 32]);
+        let client_epk = x25519_dalek::PublicKey::from(&client_esk);
+        let client_side =
+            client_esk.diffie_hellman(&exit_x25519_public(&signing_key.verifying_key()));
+        let exit_side = exit_x25519_secret(&signing_key).diffie_hellman(&client_epk);
+        assert_eq!(client_side.as_bytes(), exit_side.as_bytes());
+        assert_ne!(client_side.as_bytes(), &[0; 32]);
+    }
+
+    #[test]
+    fn keepalive_negotiation() {
+        let ours = Keepalive {
+            interval_secs: 120,
+            timeout_secs: 10,
+        };
+        let hello = ClientHello {
+            credentials: Bytes::new(),
+            crypt_hello: ClientCryptHello::SharedSecretChallenge([1; 32]),
+            extensions: [(EXT_KEEPALIVE.to_string(), ours.stdcode())]
+                .into_iter()
+                .collect(),
+        };
+        let theirs = ClientHello::decode(&hello.stdcode())
+            .unwrap()
+            .keepalive()
+            .unwrap()
+            .unwrap();
+        assert_eq!(theirs, ours);
+        let agreed = theirs.negotiate(Keepalive {
+            interval_secs: 1800,
+            timeout_secs: 30,
+        });
+        assert_eq!(
+            agreed,
+            Keepalive {
+                interval_secs: 120,
+                timeout_secs: 30,
+            }
+        );
+        let greedy = Keepalive {
+            interval_secs: 0,
+            timeout_secs: u64::MAX,
+        };
+        assert_eq!(
+            greedy.clamp(10, 300),
+            Keepalive {
+                interval_secs: 10,
+                timeout_secs: 300,
+            }
+        );
+    }
+
+    #[test]
+    fn probe_magic_per_connection() {
+        let pubkey = SigningKey::from_bytes(&[7; 32]).verifying_key();
+        let other = SigningKey::from_bytes(&[6; 32]).verifying_key();
+        let now = 1_700_000_000;
+        let magic = probe_magic(&pubkey, [1; 16], now);
+        assert_ne!(magic[0], 0);
+        assert!(check_probe_magic(&pubkey, &magic, now));
+        assert!(!check_probe_magic(&other, &magic, now));
+        // fresh nonces make for unrelated magics
+        assert_ne!(probe_magic(&pubkey, [2; 16], now)[16..], magic[16..]);
+        // a period of skew either way is fine, more is not
+        let period = PROBE_MAGIC_PERIOD_SECS;
+        assert!(check_probe_magic(&pubkey, &magic, now + period));
+        assert!(check_probe_magic(&pubkey, &magic, now - period));
+        assert!(!check_probe_magic(&pubkey, &magic, now + 3 * period));
+        assert!(!check_probe_magic(&pubkey, &magic, now - 3 * period));
+        // neither half can be swapped out
+        let mut tampered = magic;
+        tampered[5] ^= 1;
+        assert!(!check_probe_magic(&pubkey, &tampered, now));
+        let mut tampered = magic;
+        tampered[20] ^= 1;
+        assert!(!check_probe_magic(&pubkey, &tampered, now));
+    }
+
+    #[test]
+    fn screened_hello_needs_magic() {
+        let old_pubkey = SigningKey::from_bytes(&[6; 32]).verifying_key();
+        let pubkey = SigningKey::from_bytes(&[7; 32]).verifying_key();
+        let pubkeys = [old_pubkey, pubkey];
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let magic = probe_magic(&pubkey, [1; 16], now);
+        let with_magic = magic
+            .to_vec()
+            .tap_mut(|v| v.extend_from_slice(&[0, 0, 0, 2, 4, 2]));
+        let with_stale_magic = probe_magic(&pubkey, [1; 16], now - 600)
+            .to_vec()
+            .tap_mut(|v| v.extend_from_slice(&[0, 0, 0, 2, 4, 2]));
+        let without_magic = [0, 0, 0, 2, 4, 2];
+        let tls = [0x16; 40];
+        smolscale::block_on(async move {
+            for require_magic in [false, true] {
+                assert!(matches!(
+                    read_screened_hello(&with_magic[..], &pubkeys, require_magic).await.unwrap(),
+                    ScreenedHello::Hello(hello, Some((1, sent))) if hello == [4, 2] && sent == magic
+                ));
+                assert!(matches!(
+                    read_screened_hello(&with_magic[..], &pubkeys[..1], require_magic)
+                        .await
+                        .unwrap(),
+                    ScreenedHello::Probe(_)
+                ));
+                assert!(matches!(
+                    read_screened_hello(&with_stale_magic[..], &pubkeys, require_magic)
+                        .await
+                        .unwrap(),
+                    ScreenedHello::Probe(_)
+                ));
+                assert!(matches!(
+                    read_screened_hello(&tls[..], &pubkeys, require_magic).await.unwrap(),
+                    ScreenedHello::Probe(read) if read == tls[..32]
+                ));
+            }
+            assert!(matches!(
+                read_screened_hello(&without_magic[..], &pubkeys, false).await.unwrap(),
+                ScreenedHello::Hello(hello, None) if hello == [4, 2]
+            ));
+            assert!(matches!(
+                read_screened_hello(&without_magic[..], &pubkeys, true).await.unwrap(),
+                ScreenedHello::Probe(read) if read == [0]
+            ));
+        });
+    }
+
+    #[test]
+    fn client_hello_without_extensions_is_legacy() {
+        #[derive(Serialize)]
+        struct LegacyClientHello {
+            credentials: Bytes,
+            crypt_hello: ClientCryptHello,
+        }
+
+        let legacy = LegacyClientHello {
+            credentials: Bytes::from_static(b"creds"),
+            crypt_hello: ClientCryptHello::SharedSecretChallenge([1; 32]),
+        }
+        .stdcode();
+        let hello = ClientHello::decode(&legacy).unwrap();
+        assert!(hello.extensions.is_empty());
+        assert_eq!(hello.credentials, Bytes::from_static(b"creds"));
+        assert_eq!(hello.stdcode(), legacy);
+    }
 }
@@ -2,6 +2,7 @@ use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub mod bridge;
 pub mod exit;
+pub mod obfs;
 
 /// A helper function to write a length-prepended value into an AsyncWrite.
 pub async fn write_prepend_length<W: AsyncWrite + Unpin>(
@@ -0,0 +1,177 @@
+use std::pin::Pin;
+
+use async_task::Task;
+use bipe::{BipeReader, BipeWriter};
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use pin_project::pin_project;
+use sillad::Pipe;
+
+/// The size of every cell an [ObfuscatedPipe] writes to the underlying pipe.
+pub const CELL_SIZE: usize = 1400;
+
+/// The most payload a single cell carries, after its 2-byte length.
+const CELL_PAYLOAD: usize = CELL_SIZE - 2;
+
+/// ObfuscatedPipe is a sillad::Pipe wrapper that hides the sizes of the writes made to it. Whatever gets written is merged or split into fixed-size cells, each carrying a little-endian 2-byte payload length, then the payload, then zero padding, so the underlying pipe only ever sees writes of [CELL_SIZE] bytes.
+#[pin_project]
+pub struct ObfuscatedPipe {
+    #[pin]
+    read_incoming: BipeReader,
+    _read_task: Task<()>,
+    #[pin]
+    write_outgoing: BipeWriter,
+    _write_task: Task<()>,
+
+    addr: Option<String>,
+    #[cfg(unix)]
+    raw_fd: Option<std::os::fd::RawFd>,
+}
+
+impl AsyncRead for ObfuscatedPipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().read_incoming.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ObfuscatedPipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().write_outgoing.poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().write_outgoing.poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().write_outgoing.poll_close(cx)
+    }
+}
+
+impl ObfuscatedPipe {
+    /// Wraps a pipe whose other end is also wrapped in an ObfuscatedPipe.
+    pub fn new(pipe: impl Pipe) -> Self {
+        let addr = pipe.remote_addr().map(|s| s.to_string());
+        #[cfg(unix)]
+        let raw_fd = pipe.raw_fd();
+        let (mut pipe_read, mut pipe_write) = pipe.split();
+        let (mut write_incoming, read_incoming) = bipe::bipe(32768);
+        let (write_outgoing, mut read_outgoing) = bipe::bipe(32768);
+
+        let _read_task = geph5_rt::spawn(async move {
+            let fallible = async {
+                let mut cell = [0; CELL_SIZE];
+                loop {
+                    pipe_read.read_exact(&mut cell).await?;
+                    let len = u16::from_le_bytes([cell[0], cell[1]]) as usize;
+                    anyhow::ensure!(len <= CELL_PAYLOAD, "cell payload too long");
+                    write_incoming.write_all(&cell[2..][..len]).await?;
+                }
+                #[allow(unreachable_code)]
+                anyhow::Ok(())
+            };
+            if let Err(_err) = fallible.await {
+                // todo handle error
+            }
+        });
+
+        let _write_task = geph5_rt::spawn(async move {
+            let fallible = async {
+                let mut cell = [0; CELL_SIZE];
+                loop {
+                    // whatever has piled up since the last cell goes into the next one, up to its capacity
+                    let n = read_outgoing.read(&mut cell[2..]).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    cell[..2].copy_from_slice(&(n as u16).to_le_bytes());
+                    cell[2 + n..].fill(0);
+                    pipe_write.write_all(&cell).await?;
+                    pipe_write.flush().await?;
+                }
+                anyhow::Ok(())
+            };
+            if let Err(_err) = fallible.await {
+                // todo handle error
+            }
+        });
+        Self {
+            read_incoming,
+            _read_task,
+            write_outgoing,
+            _write_task,
+
+            addr,
+            #[cfg(unix)]
+            raw_fd,
+        }
+    }
+}
+
+impl Pipe for ObfuscatedPipe {
+    fn protocol(&self) -> &str {
+        "client-exit-obfs"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.addr.as_deref()
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        self.raw_fd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sillad::{
+        dialer::Dialer,
+        listener::Listener,
+        tcp::{TcpDialer, TcpListener},
+    };
+
+    use super::*;
+
+    #[test]
+    fn obfuscated_roundtrip_in_fixed_cells() {
+        smolscale::block_on(async {
+            let mut listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let dest_addr = listener.local_addr().await;
+            let client = TcpDialer { dest_addr }.dial().await.unwrap();
+            let mut raw_server = listener.accept().await.unwrap();
+            let mut client = ObfuscatedPipe::new(client);
+
+            // a small write still takes up a whole cell on the wire
+            client.write_all(b"hello").await.unwrap();
+            let mut cell = [0; CELL_SIZE];
+            raw_server.read_exact(&mut cell).await.unwrap();
+            assert_eq!(u16::from_le_bytes([cell[0], cell[1]]), 5);
+            assert_eq!(&cell[2..7], b"hello");
+            assert!(cell[7..].iter().all(|b| *b == 0));
+
+            // a large write gets split across cells, and comes out whole on the other side
+            let mut server = ObfuscatedPipe::new(raw_server);
+            let big: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+            client.write_all(&big).await.unwrap();
+            let mut received = vec![0; big.len()];
+            server.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, big);
+        });
+    }
+}
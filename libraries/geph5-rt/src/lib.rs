@@ -0,0 +1,21 @@
+//! Spawning, timers and timeouts that work the same natively, where they run on smolscale and async-io, and in the browser, where they run on the JavaScript event loop.
+
+pub use async_task::Task;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use async_io::Timer;
+#[cfg(not(target_arch = "wasm32"))]
+pub use smol_timeout2::TimeoutExt;
+#[cfg(not(target_arch = "wasm32"))]
+pub use smolscale::spawn;
+
+/// Lets a future be used where `Send` is required even if it holds on to the browser's APIs, none of which are `Send`. Natively, it must already be `Send`, and is returned as it is.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn single_threaded<F: std::future::Future + Send>(future: F) -> F {
+    future
+}
+
+#[cfg(target_arch = "wasm32")]
+mod wasm;
+#[cfg(target_arch = "wasm32")]
+pub use wasm::*;
@@ -0,0 +1,125 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use async_task::{Runnable, Task};
+use futures_core::Stream;
+use gloo_timers::future::TimeoutFuture;
+use pin_project::pin_project;
+use send_wrapper::SendWrapper;
+use web_time::Instant;
+
+/// Spawns a future onto the browser's event loop. As natively, it is cancelled once the returned task is dropped, unless the task is detached.
+pub fn spawn<T: 'static>(future: impl Future<Output = T> + 'static) -> Task<T> {
+    let (runnable, task) = async_task::spawn_local(future, |runnable: Runnable| {
+        wasm_bindgen_futures::spawn_local(async move {
+            runnable.run();
+        });
+    });
+    runnable.schedule();
+    task
+}
+
+/// Lets a future be used where `Send` is required even if it holds on to the browser's APIs, none of which are `Send`. This is sound because the browser runs us on a single thread, so the future never actually moves.
+pub fn single_threaded<F: Future>(future: F) -> SendWrapper<F> {
+    SendWrapper::new(future)
+}
+
+/// A timer on top of `setTimeout`, with the parts of the API of async-io's that we use. It fires once, unless it was made with [Timer::interval], in which case it is also a stream that fires periodically.
+pub struct Timer {
+    deadline: Instant,
+    period: Option<Duration>,
+    // the browser is single-threaded, so the timer never actually leaves the thread it was made on
+    inner: SendWrapper<TimeoutFuture>,
+}
+
+impl Timer {
+    /// Fires after the given duration.
+    pub fn after(duration: Duration) -> Self {
+        Self::at(Instant::now() + duration)
+    }
+
+    /// Fires at the given instant.
+    pub fn at(deadline: Instant) -> Self {
+        Self {
+            deadline,
+            period: None,
+            inner: SendWrapper::new(timeout_until(deadline)),
+        }
+    }
+
+    /// Fires every `period`, starting one period from now.
+    pub fn interval(period: Duration) -> Self {
+        Self {
+            period: Some(period),
+            ..Self::after(period)
+        }
+    }
+}
+
+fn timeout_until(deadline: Instant) -> TimeoutFuture {
+    let millis = deadline
+        .saturating_duration_since(Instant::now())
+        .as_millis()
+        .min(u32::MAX as u128);
+    TimeoutFuture::new(millis as u32)
+}
+
+impl Future for Timer {
+    type Output = Instant;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let deadline = self.deadline;
+        Pin::new(&mut *self.inner).poll(cx).map(|()| deadline)
+    }
+}
+
+impl Stream for Timer {
+    type Item = Instant;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let Some(period) = self.period else {
+            return Poll::Pending;
+        };
+        let fired = std::task::ready!(self.as_mut().poll(cx));
+        self.deadline = fired + period;
+        self.inner = SendWrapper::new(timeout_until(self.deadline));
+        Poll::Ready(Some(fired))
+    }
+}
+
+/// Adds a timeout to any future, like smol-timeout2 does natively.
+pub trait TimeoutExt: Future + Sized {
+    /// Resolves to `None` if the future does not finish within the given duration.
+    fn timeout(self, after: Duration) -> Timeout<Self> {
+        Timeout {
+            inner: self,
+            timer: Timer::after(after),
+        }
+    }
+}
+
+impl<F: Future> TimeoutExt for F {}
+
+/// The future returned by [TimeoutExt::timeout].
+#[pin_project]
+pub struct Timeout<F> {
+    #[pin]
+    inner: F,
+    timer: Timer,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Option<F::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        if let Poll::Ready(output) = this.inner.poll(cx) {
+            return Poll::Ready(Some(output));
+        }
+        Pin::new(this.timer).poll(cx).map(|_| None)
+    }
+}
@@ -2,13 +2,11 @@ use blind_rsa_signatures as brs;
 use brs::reexports::rsa::pkcs1::EncodeRsaPublicKey as _;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::{
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
-    time::SystemTime,
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
 };
+use web_time::{SystemTime, UNIX_EPOCH};
 
 const KEY_COUNT: usize = 65536;
 const KEY_BITS: usize = 2048;
@@ -16,7 +14,7 @@ const KEY_BITS: usize = 2048;
 /// Obtains the current epoch.
 pub fn current_epoch() -> u16 {
     (SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
+        .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_secs()
         / 86400) as u16
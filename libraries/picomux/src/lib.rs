@@ -12,7 +12,7 @@ use std::{
         Arc,
     },
     task::Poll,
-    time::{Duration, Instant},
+    time::Duration,
 };
 
 use ahash::AHasher;
@@ -29,13 +29,14 @@ use futures_util::{
     future::Shared, io::BufReader, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, FutureExt,
 };
 
-use async_io::Timer;
+use geph5_rt::TimeoutExt;
+use geph5_rt::Timer;
 use parking_lot::Mutex;
 use pin_project::pin_project;
 use rand::Rng;
-use smol_timeout2::TimeoutExt;
 use tachyonix::{Receiver, Sender, TrySendError};
 use tap::Tap;
+use web_time::Instant;
 
 use crate::frame::{Header, PingInfo};
 
@@ -67,6 +68,7 @@ pub struct PicoMux {
     liveness: LivenessConfig,
 
     last_ping: Arc<Mutex<Option<Duration>>>,
+    ping_waiters: Arc<Mutex<Vec<oneshot::Sender<Duration>>>>,
 }
 
 impl PicoMux {
@@ -81,7 +83,8 @@ impl PicoMux {
         let liveness = LivenessConfig::default();
         send_liveness.try_send(liveness).unwrap();
         let last_ping = Arc::new(Mutex::new(None));
-        let task = smolscale::spawn(
+        let ping_waiters = Arc::new(Mutex::new(vec![]));
+        let task = geph5_rt::spawn(
             picomux_inner(
                 read,
                 write,
@@ -89,6 +92,7 @@ impl PicoMux {
                 recv_open_req,
                 recv_liveness,
                 last_ping.clone(),
+                ping_waiters.clone(),
             )
             .map(Arc::new),
         )
@@ -102,6 +106,7 @@ impl PicoMux {
             liveness,
 
             last_ping,
+            ping_waiters,
         }
     }
 
@@ -140,6 +145,22 @@ impl PicoMux {
         *self.last_ping.lock()
     }
 
+    /// Sends a ping right away, returning the round-trip time. Since the ping is queued behind any data we are sending, this measures the latency that the data actually sees.
+    pub async fn ping(&self) -> std::io::Result<Duration> {
+        let (send, recv) = oneshot::channel();
+        self.ping_waiters.lock().push(send);
+        let _ = self.send_liveness.try_send(self.liveness);
+        async {
+            if let Ok(val) = recv.await {
+                Ok(val)
+            } else {
+                futures_util::future::pending().await
+            }
+        }
+        .race(self.wait_error())
+        .await
+    }
+
     /// Opens a new stream to the peer, putting the given metadata in the stream.
     pub async fn open(&self, metadata: &[u8]) -> std::io::Result<Stream> {
         {
@@ -189,6 +210,7 @@ async fn picomux_inner(
     mut recv_open_req: Receiver<(Bytes, oneshot::Sender<Stream>)>,
     mut recv_liveness: Receiver<LivenessConfig>,
     last_ping: Arc<Mutex<Option<Duration>>>,
+    ping_waiters: Arc<Mutex<Vec<oneshot::Sender<Duration>>>>,
 ) -> Result<Infallible, std::io::Error> {
     let mut inner_read = BufReader::with_capacity(100_000, read);
 
@@ -236,7 +258,7 @@ async fn picomux_inner(
 
         let send_more = SharedSemaphore::new(false, INIT_WINDOW);
         // jelly bean movers
-        smolscale::spawn::<anyhow::Result<()>>({
+        geph5_rt::spawn::<anyhow::Result<()>>({
             let send_outgoing = send_outgoing.clone();
 
             async move {
@@ -298,7 +320,7 @@ async fn picomux_inner(
         })
         .detach();
 
-        smolscale::spawn::<anyhow::Result<()>>({
+        geph5_rt::spawn::<anyhow::Result<()>>({
             let send_more = send_more.clone();
             let send_outgoing = send_outgoing.clone();
             async move {
@@ -319,7 +341,7 @@ async fn picomux_inner(
                     }
                 };
                 scopeguard::defer!({
-                    smolscale::spawn(closer).detach();
+                    geph5_rt::spawn(closer).detach();
                 });
                 let mut buf = [0u8; MSS];
                 loop {
@@ -417,6 +439,9 @@ async fn picomux_inner(
                 }
                 tracing::debug!(latency = debug(start.elapsed()), "PONG received");
                 last_ping.lock().replace(start.elapsed());
+                for waiter in ping_waiters.lock().drain(..) {
+                    let _ = waiter.send(start.elapsed());
+                }
             } else {
                 return futures_util::future::pending().await;
             }
@@ -671,4 +696,18 @@ mod tests {
             a_proc.race(b_proc).await
         })
     }
+
+    #[test]
+    fn test_picomux_ping() {
+        smolscale::block_on(async move {
+            let (picomux_a, _picomux_b) = setup_picomux_pair().await;
+            // pings made while one is in flight are answered together
+            let (first, second) = futures_util::join!(picomux_a.ping(), picomux_a.ping());
+            let rtt = first.unwrap();
+            second.unwrap();
+            assert!(rtt < Duration::from_secs(5));
+            assert!(picomux_a.last_latency().is_some());
+            picomux_a.ping().await.unwrap();
+        })
+    }
 }
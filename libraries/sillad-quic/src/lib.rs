@@ -0,0 +1,449 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::{
+    future::{select, Either},
+    AsyncRead, AsyncWrite,
+};
+use pin_project::pin_project;
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    Connection, Endpoint, RecvStream, SendStream, TransportConfig,
+};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring::default_provider, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    CertificateError, DigitallySignedStruct, SignatureScheme,
+};
+use sillad::{dialer::Dialer, listener::Listener, Pipe};
+
+/// The ALPN protocol both sides insist on, so that we never talk to some other QUIC service by mistake.
+const ALPN: &[u8] = b"geph5";
+
+/// The server name we send. Servers are authenticated by certificate pinning, so it does not matter.
+const SERVER_NAME: &str = "geph5";
+
+/// How often an idle connection is kept alive, well within the default idle timeout.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The hash that a [QuicDialer] pins the server's certificate to, computed from its DER encoding.
+pub fn cert_hash(cert_der: &[u8]) -> [u8; 32] {
+    *blake3::hash(cert_der).as_bytes()
+}
+
+/// A QuicDialer dials a QUIC server, authenticated by the hash of its certificate, and opens a single bidirectional stream on a fresh connection.
+pub struct QuicDialer {
+    pub dest_addr: SocketAddr,
+    pub cert_hash: [u8; 32],
+}
+
+impl Dialer for QuicDialer {
+    type P = QuicPipe;
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let bind_addr: SocketAddr = if self.dest_addr.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let mut endpoint = Endpoint::client(bind_addr)?;
+        endpoint.set_default_client_config(client_config(self.cert_hash)?);
+        let conn = endpoint
+            .connect(self.dest_addr, SERVER_NAME)
+            .map_err(std::io::Error::other)?
+            .await?;
+        let (send, recv) = conn.open_bi().await?;
+        Ok(QuicPipe {
+            send,
+            recv,
+            _conn: conn,
+            _endpoint: Some(endpoint),
+            remote_addr: self.dest_addr.to_string(),
+        })
+    }
+}
+
+fn client_config(cert_hash: [u8; 32]) -> std::io::Result<quinn::ClientConfig> {
+    let provider = Arc::new(default_provider());
+    let mut crypto = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(std::io::Error::other)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier {
+            cert_hash,
+            provider,
+        }))
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = quinn::ClientConfig::new(Arc::new(
+        QuicClientConfig::try_from(crypto).map_err(std::io::Error::other)?,
+    ));
+    let mut transport = TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+    config.transport_config(Arc::new(transport));
+    Ok(config)
+}
+
+/// Accepts exactly the certificate with the given hash, rather than anything signed by a certificate authority.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    cert_hash: [u8; 32],
+    provider: Arc<rustls::crypto::CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if cert_hash(end_entity) == self.cert_hash {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// A QuicListener accepts QUIC connections, producing a pipe for the first bidirectional stream of each.
+pub struct QuicListener {
+    endpoint: Endpoint,
+    cert: Arc<ReloadableCert>,
+    recv_pipe: async_channel::Receiver<QuicPipe>,
+    send_pipe: async_channel::Sender<QuicPipe>,
+}
+
+impl QuicListener {
+    /// Listens on the given UDP address, presenting a certificate chain and private key in PEM format. Clients pin the [cert_hash] of the first certificate.
+    pub fn bind(addr: SocketAddr, cert_pem: &[u8], key_pem: &[u8]) -> anyhow::Result<Self> {
+        let cert = Arc::new(ReloadableCert(RwLock::new(certified_key(
+            cert_pem, key_pem,
+        )?)));
+        let mut crypto = rustls::ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_no_client_auth()
+            .with_cert_resolver(cert.clone());
+        crypto.alpn_protocols = vec![ALPN.to_vec()];
+        let config =
+            quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
+        let endpoint = Endpoint::server(config, addr)?;
+        let (send_pipe, recv_pipe) = async_channel::bounded(100);
+        Ok(Self {
+            endpoint,
+            cert,
+            recv_pipe,
+            send_pipe,
+        })
+    }
+
+    /// The hash of our certificate, which clients need to know to connect.
+    pub fn cert_hash(&self) -> [u8; 32] {
+        self.cert.0.read().unwrap().1
+    }
+
+    /// Gets a handle for replacing our certificate while we keep listening.
+    pub fn cert_reloader(&self) -> QuicCertReloader {
+        QuicCertReloader {
+            cert: self.cert.clone(),
+        }
+    }
+}
+
+/// Replaces the certificate of a running [QuicListener], such as after a renewal. New connections get the new certificate, while established ones are unaffected.
+#[derive(Clone, Debug)]
+pub struct QuicCertReloader {
+    cert: Arc<ReloadableCert>,
+}
+
+impl QuicCertReloader {
+    /// Starts presenting a new certificate chain and private key in PEM format, returning the [cert_hash] that clients must now pin. If they cannot be loaded, the old certificate stays.
+    pub fn reload(&self, cert_pem: &[u8], key_pem: &[u8]) -> anyhow::Result<[u8; 32]> {
+        let loaded = certified_key(cert_pem, key_pem)?;
+        let cert_hash = loaded.1;
+        *self.cert.0.write().unwrap() = loaded;
+        Ok(cert_hash)
+    }
+}
+
+/// Presents whichever certificate was loaded last, along with the hash of its first certificate.
+#[derive(Debug)]
+struct ReloadableCert(RwLock<(Arc<CertifiedKey>, [u8; 32])>);
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.read().unwrap().0.clone())
+    }
+}
+
+/// Loads a certificate chain and private key in PEM format, along with the [cert_hash] of the first certificate.
+fn certified_key(cert_pem: &[u8], key_pem: &[u8]) -> anyhow::Result<(Arc<CertifiedKey>, [u8; 32])> {
+    let chain = rustls_pemfile::certs(&mut &cert_pem[..]).collect::<Result<Vec<_>, _>>()?;
+    let cert_hash = cert_hash(
+        chain
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("no certificate found"))?,
+    );
+    let key: PrivateKeyDer = rustls_pemfile::private_key(&mut &key_pem[..])?
+        .ok_or_else(|| anyhow::anyhow!("no private key found"))?;
+    let key = default_provider().key_provider.load_private_key(key)?;
+    Ok((Arc::new(CertifiedKey::new(chain, key)), cert_hash))
+}
+
+impl Listener for QuicListener {
+    type P = QuicPipe;
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        loop {
+            // handshakes happen in the background, so that a slow client cannot hold up everybody else
+            let incoming = select(
+                Box::pin(self.endpoint.accept()),
+                Box::pin(self.recv_pipe.recv()),
+            )
+            .await;
+            match incoming {
+                Either::Left((Some(incoming), _)) => {
+                    let send_pipe = self.send_pipe.clone();
+                    smolscale::spawn(async move {
+                        let fallible = async {
+                            let conn = incoming.await?;
+                            let remote_addr = conn.remote_address().to_string();
+                            let (send, recv) = conn.accept_bi().await?;
+                            anyhow::Ok(QuicPipe {
+                                send,
+                                recv,
+                                _conn: conn,
+                                _endpoint: None,
+                                remote_addr,
+                            })
+                        };
+                        match fallible.await {
+                            Ok(pipe) => {
+                                let _ = send_pipe.send(pipe).await;
+                            }
+                            Err(err) => {
+                                tracing::debug!(err = debug(err), "QUIC handshake failed")
+                            }
+                        }
+                    })
+                    .detach();
+                }
+                Either::Left((None, _)) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        "QUIC endpoint closed",
+                    ))
+                }
+                Either::Right((pipe, _)) => {
+                    return pipe.map_err(|_| std::io::ErrorKind::BrokenPipe.into())
+                }
+            }
+        }
+    }
+}
+
+/// A single bidirectional stream of a QUIC connection, which lives as long as the pipe.
+#[pin_project]
+pub struct QuicPipe {
+    #[pin]
+    send: SendStream,
+    #[pin]
+    recv: RecvStream,
+    _conn: Connection,
+    /// Dialed connections have their own endpoint, which has to outlive them.
+    _endpoint: Option<Endpoint>,
+    remote_addr: String,
+}
+
+impl AsyncRead for QuicPipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        AsyncRead::poll_read(self.project().recv, cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicPipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        AsyncWrite::poll_write(self.project().send, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        AsyncWrite::poll_flush(self.project().send, cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        AsyncWrite::poll_close(self.project().send, cx)
+    }
+}
+
+impl Pipe for QuicPipe {
+    fn protocol(&self) -> &str {
+        "quic"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        Some(&self.remote_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    const CERT_PEM: &[u8] = include_bytes!("../testdata/cert.pem");
+    const KEY_PEM: &[u8] = include_bytes!("../testdata/key.pem");
+    const CERT2_PEM: &[u8] = include_bytes!("../testdata/cert2.pem");
+    const KEY2_PEM: &[u8] = include_bytes!("../testdata/key2.pem");
+
+    fn pem_cert_hash(cert_pem: &[u8]) -> [u8; 32] {
+        let cert = rustls_pemfile::certs(&mut &cert_pem[..])
+            .next()
+            .unwrap()
+            .unwrap();
+        cert_hash(&cert)
+    }
+
+    fn test_cert_hash() -> [u8; 32] {
+        pem_cert_hash(CERT_PEM)
+    }
+
+    /// Dials the listener pinning the given hash, returning whether the handshake succeeded.
+    async fn handshake(listener: &mut QuicListener, cert_hash: [u8; 32]) -> bool {
+        let dialer = QuicDialer {
+            dest_addr: listener.endpoint.local_addr().unwrap(),
+            cert_hash,
+        };
+        let client = async {
+            let mut client = dialer.dial().await?;
+            client.write_all(b"hello").await?;
+            std::io::Result::Ok(client)
+        };
+        let accept = async {
+            let _ = listener.accept().await;
+            futures_util::future::pending::<()>().await
+        };
+        match select(Box::pin(client), Box::pin(accept)).await {
+            Either::Left((result, _)) => result.is_ok(),
+            Either::Right(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn quic_roundtrip() {
+        smolscale::block_on(async {
+            let mut listener =
+                QuicListener::bind("127.0.0.1:0".parse().unwrap(), CERT_PEM, KEY_PEM).unwrap();
+            let dialer = QuicDialer {
+                dest_addr: listener.endpoint.local_addr().unwrap(),
+                cert_hash: test_cert_hash(),
+            };
+            // the server only learns of a stream once something is sent on it
+            let client = async {
+                let mut client = dialer.dial().await?;
+                client.write_all(b"hello").await?;
+                std::io::Result::Ok(client)
+            };
+            let (_client, mut server) = futures_util::future::try_join(client, listener.accept())
+                .await
+                .unwrap();
+            let mut buf = [0u8; 5];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        });
+    }
+
+    #[test]
+    fn wrong_cert_rejected() {
+        smolscale::block_on(async {
+            let mut listener =
+                QuicListener::bind("127.0.0.1:0".parse().unwrap(), CERT_PEM, KEY_PEM).unwrap();
+            let dialer = QuicDialer {
+                dest_addr: listener.endpoint.local_addr().unwrap(),
+                cert_hash: [0; 32],
+            };
+            // the handshake only goes anywhere while the server is accepting
+            let accept = async {
+                let _ = listener.accept().await;
+                futures_util::future::pending::<()>().await
+            };
+            let raced = select(Box::pin(dialer.dial()), Box::pin(accept)).await;
+            match raced {
+                Either::Left((result, _)) => assert!(result.is_err()),
+                Either::Right(_) => unreachable!(),
+            };
+        });
+    }
+
+    #[test]
+    fn reloaded_cert_is_presented() {
+        smolscale::block_on(async {
+            let mut listener =
+                QuicListener::bind("127.0.0.1:0".parse().unwrap(), CERT_PEM, KEY_PEM).unwrap();
+            let reloader = listener.cert_reloader();
+            assert!(handshake(&mut listener, test_cert_hash()).await);
+
+            // a broken certificate is refused, and the old one stays
+            assert!(reloader.reload(b"", KEY2_PEM).is_err());
+            assert_eq!(listener.cert_hash(), test_cert_hash());
+
+            let new_hash = reloader.reload(CERT2_PEM, KEY2_PEM).unwrap();
+            assert_eq!(new_hash, pem_cert_hash(CERT2_PEM));
+            assert_eq!(listener.cert_hash(), new_hash);
+            assert!(handshake(&mut listener, new_hash).await);
+            assert!(!handshake(&mut listener, test_cert_hash()).await);
+        });
+    }
+}
@@ -1,13 +1,10 @@
-use std::{
-    io::ErrorKind,
-    time::{SystemTime, UNIX_EPOCH},
-};
+use std::io::ErrorKind;
 
-use async_trait::async_trait;
 use futures_util::{AsyncReadExt, AsyncWriteExt};
 use rand::{Rng, RngCore};
 use sillad::dialer::Dialer;
 use tap::Tap;
+use web_time::{SystemTime, UNIX_EPOCH};
 
 use crate::{handshake::Handshake, state::State, Cookie, SosistabPipe};
 
@@ -16,7 +13,6 @@ pub struct SosistabDialer<D: Dialer> {
     pub cookie: Cookie,
 }
 
-#[async_trait]
 impl<D: Dialer> Dialer for SosistabDialer<D> {
     type P = SosistabPipe<D::P>;
     #[tracing::instrument(skip(self))]
@@ -234,4 +234,9 @@ impl<P: Pipe> Pipe for SosistabPipe<P> {
     fn shared_secret(&self) -> Option<&[u8]> {
         Some(self.state.shared_secret())
     }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        self.lower.raw_fd()
+    }
 }
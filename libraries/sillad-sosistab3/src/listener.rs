@@ -8,7 +8,6 @@ use std::{
 use async_executor::Executor;
 
 use async_task::Task;
-use async_trait::async_trait;
 use futures_util::{AsyncReadExt, AsyncWriteExt};
 use once_cell::sync::Lazy;
 use rand::{Rng, RngCore};
@@ -28,7 +27,7 @@ impl<P: Pipe> SosistabListener<P> {
     /// Listens to incoming sosistab3 pipes by wrapping an existing sillad Listener.
     pub fn new(listener: impl Listener<P = P>, cookie: Cookie) -> Self {
         let (send_pipe, recv_pipe) = tachyonix::channel(1);
-        let _task = smolscale::spawn(listen_loop(listener, send_pipe, cookie));
+        let _task = geph5_rt::spawn(listen_loop(listener, send_pipe, cookie));
         Self { recv_pipe, _task }
     }
 }
@@ -42,7 +41,7 @@ async fn listen_loop<P: Pipe>(
     const WAIT_INTERVAL: Duration = Duration::from_secs(30);
 
     if std::env::var("SOSISTAB3_WAIT").is_ok() {
-        async_io::Timer::after(WAIT_INTERVAL).await;
+        geph5_rt::Timer::after(WAIT_INTERVAL).await;
     }
 
     let dedup = Mutex::new(Dedup::new(WAIT_INTERVAL * 2));
@@ -137,7 +136,6 @@ async fn listen_loop<P: Pipe>(
         .await
 }
 
-#[async_trait]
 impl<P: Pipe> Listener for SosistabListener<P> {
     type P = SosistabPipe<P>;
     async fn accept(&mut self) -> std::io::Result<Self::P> {
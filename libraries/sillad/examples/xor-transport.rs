@@ -0,0 +1,141 @@
+//! A toy custom transport, showing how to write dialers and listeners with native `async fn` in traits.
+//!
+//! Both sides XOR every byte with a fixed key. Run it with `cargo run --example xor-transport`.
+
+use std::{pin::Pin, task::Poll};
+
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use pin_project::pin_project;
+use sillad::{
+    dialer::Dialer,
+    listener::Listener,
+    tcp::{TcpDialer, TcpListener},
+    Pipe,
+};
+
+/// Wraps another dialer, XOR-ing everything that goes through its pipes.
+pub struct XorDialer<D: Dialer> {
+    pub inner: D,
+    pub key: u8,
+}
+
+impl<D: Dialer> Dialer for XorDialer<D> {
+    type P = XorPipe<D::P>;
+
+    // no #[async_trait] needed, and nothing gets boxed
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let inner = self.inner.dial().await?;
+        Ok(XorPipe {
+            inner,
+            key: self.key,
+        })
+    }
+}
+
+/// Wraps another listener, XOR-ing everything that goes through its pipes.
+pub struct XorListener<L: Listener> {
+    pub inner: L,
+    pub key: u8,
+}
+
+impl<L: Listener> Listener for XorListener<L> {
+    type P = XorPipe<L::P>;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        let inner = self.inner.accept().await?;
+        Ok(XorPipe {
+            inner,
+            key: self.key,
+        })
+    }
+}
+
+#[pin_project]
+pub struct XorPipe<P: Pipe> {
+    #[pin]
+    inner: P,
+    key: u8,
+}
+
+impl<P: Pipe> AsyncRead for XorPipe<P> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        let n = futures_util::ready!(this.inner.poll_read(cx, buf))?;
+        buf[..n].iter_mut().for_each(|b| *b ^= *this.key);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<P: Pipe> AsyncWrite for XorPipe<P> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        let xored: Vec<u8> = buf.iter().map(|b| b ^ *this.key).collect();
+        this.inner.poll_write(cx, &xored)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<P: Pipe> Pipe for XorPipe<P> {
+    fn protocol(&self) -> &str {
+        "xor"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.inner.remote_addr()
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    async_io::block_on(async {
+        let tcp_listener = TcpListener::bind("127.0.0.1:0".parse()?).await?;
+        let dest_addr = tcp_listener.local_addr().await;
+        let mut listener = XorListener {
+            inner: tcp_listener,
+            key: 0x42,
+        };
+        let dialer = XorDialer {
+            inner: TcpDialer { dest_addr },
+            key: 0x42,
+        };
+
+        let server = async {
+            let mut pipe = listener.accept().await?;
+            let mut buf = [0u8; 5];
+            pipe.read_exact(&mut buf).await?;
+            pipe.write_all(&buf).await?;
+            anyhow::Ok(())
+        };
+        let client = async {
+            let mut pipe = dialer.dial().await?;
+            pipe.write_all(b"hello").await?;
+            let mut buf = [0u8; 5];
+            pipe.read_exact(&mut buf).await?;
+            println!("echoed back: {}", String::from_utf8_lossy(&buf));
+            anyhow::Ok(())
+        };
+        let (server, client) = futures_lite::future::zip(server, client).await;
+        server?;
+        client
+    })
+}
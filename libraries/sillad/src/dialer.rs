@@ -1,15 +1,20 @@
-use std::{pin::Pin, sync::Arc};
+use std::{
+    pin::{pin, Pin},
+    sync::Arc,
+    time::Duration,
+};
 
 use crate::{EitherPipe, Pipe};
-use async_trait::async_trait;
 use futures_lite::{Future, FutureExt};
-use smol_timeout2::TimeoutExt;
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use geph5_rt::TimeoutExt;
 
-#[async_trait]
 /// Dialers create pipes by initiating a connection to some sort of "other side". Failures are indicated by the standard I/O error type.
+///
+/// Implementations can simply write `async fn dial(&self) -> std::io::Result<Self::P>`; the compiler checks that the returned future is `Send`.
 pub trait Dialer: Sync + Send + Sized + 'static {
     type P: Pipe;
-    async fn dial(&self) -> std::io::Result<Self::P>;
+    fn dial(&self) -> impl Future<Output = std::io::Result<Self::P>> + Send;
 }
 
 pub trait DialerExt: Dialer {
@@ -74,19 +79,17 @@ impl DynDialer {
     }
 }
 
-#[async_trait]
 impl Dialer for DynDialer {
     type P = Box<dyn Pipe>;
 
     async fn dial(&self) -> std::io::Result<Self::P> {
-        Ok(Box::new((self.raw_dial)().await?))
+        (self.raw_dial)().await
     }
 }
 
 /// RaceDialer is a dialer that races between two dialers.
 pub struct RaceDialer<L: Dialer, R: Dialer>(pub L, pub R);
 
-#[async_trait]
 impl<L: Dialer, R: Dialer> Dialer for RaceDialer<L, R> {
     type P = EitherPipe<L::P, R::P>;
     async fn dial(&self) -> std::io::Result<Self::P> {
@@ -103,8 +106,7 @@ where
     F1: Future<Output = Result<T, E>>,
     F2: Future<Output = Result<T, E>>,
 {
-    // unfortunately we do need to box here :(
-    match futures_util::future::select(Box::pin(f1), Box::pin(f2)).await {
+    match futures_util::future::select(pin!(f1), pin!(f2)).await {
         futures_util::future::Either::Left((Ok(val), _)) => Ok(val),
         futures_util::future::Either::Right((Ok(val), _)) => Ok(val),
         futures_util::future::Either::Left((Err(_), f2)) => f2.await,
@@ -115,7 +117,6 @@ where
 /// FailingDialer is a dialer that always fails and never returns anything.
 pub struct FailingDialer;
 
-#[async_trait]
 impl Dialer for FailingDialer {
     type P = Box<dyn Pipe>;
 
@@ -132,7 +133,6 @@ pub struct TimeoutDialer<D: Dialer> {
     timeout: std::time::Duration,
 }
 
-#[async_trait]
 impl<D: Dialer> Dialer for TimeoutDialer<D> {
     type P = D::P;
 
@@ -153,7 +153,6 @@ pub struct FallbackDialer<A: Dialer, B: Dialer> {
     fallback: B,
 }
 
-#[async_trait]
 impl<A: Dialer, B: Dialer> Dialer for FallbackDialer<A, B> {
     type P = EitherPipe<A::P, B::P>;
 
@@ -170,12 +169,140 @@ pub struct DelayDialer<D: Dialer> {
     delay: std::time::Duration,
 }
 
-#[async_trait]
 impl<D: Dialer> Dialer for DelayDialer<D> {
     type P = D::P;
 
     async fn dial(&self) -> std::io::Result<Self::P> {
-        async_io::Timer::after(self.delay).await;
+        geph5_rt::Timer::after(self.delay).await;
         self.dialer.dial().await
     }
 }
+
+/// HappyEyeballsDialer dials through several dialers in the style of Happy Eyeballs (RFC 8305). It starts with the first one, starts the next one whenever the stagger delay passes or an attempt fails, and returns whichever connects first.
+pub struct HappyEyeballsDialer<D: Dialer> {
+    dialers: Vec<D>,
+    stagger: Duration,
+}
+
+impl<D: Dialer> HappyEyeballsDialer<D> {
+    /// Creates a HappyEyeballsDialer that tries the dialers in the given order, 250 ms apart as RFC 8305 recommends.
+    pub fn new(dialers: Vec<D>) -> Self {
+        Self {
+            dialers,
+            stagger: Duration::from_millis(250),
+        }
+    }
+
+    /// Sets how long to wait for an attempt before starting the next one.
+    pub fn stagger(mut self, stagger: Duration) -> Self {
+        self.stagger = stagger;
+        self
+    }
+}
+
+impl<D: Dialer> Dialer for HappyEyeballsDialer<D> {
+    type P = D::P;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        enum Event<P> {
+            Done(std::io::Result<P>),
+            Stagger,
+        }
+
+        let mut waiting = self.dialers.iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_err = None;
+        loop {
+            if attempts.is_empty() {
+                match waiting.next() {
+                    Some(dialer) => attempts.push(dialer.dial()),
+                    None => {
+                        return Err(
+                            last_err.unwrap_or_else(|| std::io::Error::other("no dialers given"))
+                        )
+                    }
+                }
+            }
+            let more_waiting = waiting.len() > 0;
+            let event = async {
+                Event::Done(
+                    attempts
+                        .next()
+                        .await
+                        .expect("there is always an attempt in flight"),
+                )
+            }
+            .or(async {
+                if !more_waiting {
+                    futures_lite::future::pending::<()>().await;
+                }
+                geph5_rt::Timer::after(self.stagger).await;
+                Event::Stagger
+            })
+            .await;
+            match event {
+                Event::Done(Ok(pipe)) => return Ok(pipe),
+                Event::Done(Err(err)) => {
+                    last_err = Some(err);
+                    if let Some(dialer) = waiting.next() {
+                        attempts.push(dialer.dial());
+                    }
+                }
+                Event::Stagger => {
+                    if let Some(dialer) = waiting.next() {
+                        attempts.push(dialer.dial());
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::testing::{NullDialer, NullMode};
+
+    fn null_dialer() -> NullDialer {
+        NullDialer {
+            mode: NullMode::Connected,
+        }
+    }
+
+    #[test]
+    fn happy_eyeballs_staggers_slow_attempts() {
+        futures_lite::future::block_on(async {
+            let start = Instant::now();
+            HappyEyeballsDialer::new(vec![
+                null_dialer().delay(Duration::from_secs(10)).dynamic(),
+                null_dialer().dynamic(),
+            ])
+            .stagger(Duration::from_millis(50))
+            .dial()
+            .await
+            .unwrap();
+            let elapsed = start.elapsed();
+            assert!(elapsed >= Duration::from_millis(50));
+            assert!(elapsed < Duration::from_secs(5));
+        });
+    }
+
+    #[test]
+    fn happy_eyeballs_moves_on_after_failure() {
+        futures_lite::future::block_on(async {
+            let start = Instant::now();
+            HappyEyeballsDialer::new(vec![FailingDialer.dynamic(), null_dialer().dynamic()])
+                .stagger(Duration::from_secs(10))
+                .dial()
+                .await
+                .unwrap();
+            assert!(start.elapsed() < Duration::from_secs(5));
+            assert!(HappyEyeballsDialer::new(vec![FailingDialer, FailingDialer])
+                .dial()
+                .await
+                .is_err());
+        });
+    }
+}
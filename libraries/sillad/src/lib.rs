@@ -5,7 +5,11 @@ use pin_project::pin_project;
 
 pub mod dialer;
 pub mod listener;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod tcp;
+pub mod testing;
+#[cfg(unix)]
+pub mod unix;
 
 /// Sillad overall is based on returning connection-like items that implement AsyncRead and AsyncWrite, as well as a few other things. This is called a Pipe.
 pub trait Pipe: AsyncRead + AsyncWrite + Send + Unpin + 'static {
@@ -19,6 +23,12 @@ pub trait Pipe: AsyncRead + AsyncWrite + Send + Unpin + 'static {
 
     /// This might return a string that is some sort of human-readable identifier of the remote address.
     fn remote_addr(&self) -> Option<&str>;
+
+    /// If this pipe is ultimately carried over a single OS socket, returns its file descriptor, so that callers can look at socket-level statistics. The descriptor must not be read from or written to. Wrapping pipes may close it as soon as their connection fails, even while they are still alive, so callers that keep it around should duplicate it right away.
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        None
+    }
 }
 
 impl Pipe for Box<dyn Pipe> {
@@ -33,6 +43,11 @@ impl Pipe for Box<dyn Pipe> {
     fn remote_addr(&self) -> Option<&str> {
         (**self).remote_addr()
     }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        (**self).raw_fd()
+    }
 }
 
 /// EitherPipe is a pipe that is either left or right.
@@ -109,4 +124,12 @@ impl<L: Pipe, R: Pipe> Pipe for EitherPipe<L, R> {
             EitherPipe::Right(r) => r.remote_addr(),
         }
     }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        match self {
+            EitherPipe::Left(l) => l.raw_fd(),
+            EitherPipe::Right(r) => r.raw_fd(),
+        }
+    }
 }
@@ -1,12 +1,13 @@
-use async_trait::async_trait;
+use std::future::Future;
 
 use crate::{EitherPipe, Pipe};
 
-#[async_trait]
 /// Listeners accept incoming connections, creating streams with a "remote side". Failures are indicated by the standard I/O error type.
+///
+/// Implementations can simply write `async fn accept(&mut self) -> std::io::Result<Self::P>`; the compiler checks that the returned future is `Send`.
 pub trait Listener: Sync + Send + Sized + 'static {
     type P: Pipe;
-    async fn accept(&mut self) -> std::io::Result<Self::P>;
+    fn accept(&mut self) -> impl Future<Output = std::io::Result<Self::P>> + Send;
 }
 
 pub trait ListenerExt: Listener {
@@ -20,7 +21,6 @@ impl<T: Listener> ListenerExt for T {}
 /// JoinListener is a listener that listens to two different listeners.
 pub struct JoinListener<L: Listener, R: Listener>(pub L, pub R);
 
-#[async_trait]
 impl<L: Listener, R: Listener> Listener for JoinListener<L, R> {
     type P = EitherPipe<L::P, R::P>;
     async fn accept(&mut self) -> std::io::Result<Self::P> {
@@ -38,7 +38,6 @@ pub enum EitherListener<L: Listener, R: Listener> {
     Right(R),
 }
 
-#[async_trait]
 impl<L: Listener, R: Listener> Listener for EitherListener<L, R> {
     type P = EitherPipe<L::P, R::P>;
 
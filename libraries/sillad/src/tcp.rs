@@ -1,17 +1,16 @@
 use std::{
-    net::{SocketAddr, TcpStream},
+    net::{IpAddr, SocketAddr, TcpStream},
     time::Duration,
 };
 
 use async_io::Async;
-use async_trait::async_trait;
 
 use futures_lite::{AsyncRead, AsyncWrite};
 use pin_project::pin_project;
 use rand::Rng as _;
 
 use crate::{
-    dialer::{Dialer, DialerExt},
+    dialer::{Dialer, HappyEyeballsDialer},
     listener::Listener,
     Pipe,
 };
@@ -34,7 +33,6 @@ impl TcpListener {
     }
 }
 
-#[async_trait]
 impl Listener for TcpListener {
     type P = TcpPipe;
     async fn accept(&mut self) -> std::io::Result<Self::P> {
@@ -91,29 +89,18 @@ fn set_tcp_options(conn: &Async<TcpStream>) -> std::io::Result<()> {
     Ok(())
 }
 
-/// A HappyEyeballsTcpDialer is a dialer for TCP endpoints which tries the given addresses in sequence intelligently.
+/// A HappyEyeballsTcpDialer is a dialer for TCP endpoints which tries the given addresses in sequence intelligently, through a [HappyEyeballsDialer].
 pub struct HappyEyeballsTcpDialer(pub Vec<SocketAddr>);
 
-#[async_trait]
 impl Dialer for HappyEyeballsTcpDialer {
     type P = Box<dyn Pipe>;
     async fn dial(&self) -> std::io::Result<Self::P> {
-        let res = self
+        let dialers = self
             .0
             .iter()
-            .enumerate()
-            .map(|(idx, addr)| {
-                let delay = Duration::from_millis(250 * idx as u64);
-                TcpDialer { dest_addr: *addr }.delay(delay).dynamic()
-            })
-            .reduce(|a, b| a.race(b).dynamic());
-        match res {
-            None => Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "no addresses given",
-            )),
-            Some(dialer) => dialer.dial().await,
-        }
+            .map(|addr| TcpDialer { dest_addr: *addr })
+            .collect();
+        Ok(Box::new(HappyEyeballsDialer::new(dialers).dial().await?))
     }
 }
 
@@ -122,7 +109,6 @@ pub struct TcpDialer {
     pub dest_addr: SocketAddr,
 }
 
-#[async_trait]
 impl Dialer for TcpDialer {
     type P = TcpPipe;
     async fn dial(&self) -> std::io::Result<Self::P> {
@@ -135,6 +121,42 @@ impl Dialer for TcpDialer {
     }
 }
 
+/// A BoundTcpDialer is a dialer for TCP endpoints that connects from a particular local IP address, for hosts that have more than one.
+pub struct BoundTcpDialer {
+    pub bind_ip: IpAddr,
+    pub dest_addr: SocketAddr,
+}
+
+impl Dialer for BoundTcpDialer {
+    type P = TcpPipe;
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let socket = socket2::Socket::new(
+            socket2::Domain::for_address(self.dest_addr),
+            socket2::Type::STREAM,
+            Some(socket2::Protocol::TCP),
+        )?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::new(self.bind_ip, 0).into())?;
+        match socket.connect(&self.dest_addr.into()) {
+            Ok(()) => {}
+            #[cfg(unix)]
+            Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+        }
+        let inner = Async::new(TcpStream::from(socket))?;
+        // the connection is established once the socket becomes writable
+        inner.writable().await?;
+        if let Some(err) = inner.get_ref().take_error()? {
+            tracing::warn!("inner dial failed: {:?}", err);
+            return Err(err);
+        }
+        let _ =
+            set_tcp_options(&inner).inspect_err(|e| tracing::warn!("tcp option set fail: {:?}", e));
+        Ok(TcpPipe(inner, self.dest_addr.to_string()))
+    }
+}
+
 #[pin_project]
 pub struct TcpPipe(#[pin] Async<TcpStream>, String);
 
@@ -180,4 +202,57 @@ impl Pipe for TcpPipe {
     fn remote_addr(&self) -> Option<&str> {
         Some(&self.1)
     }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        use std::os::fd::AsRawFd;
+        Some(self.0.as_raw_fd())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn bound_dial() {
+        futures_lite::future::block_on(async {
+            let mut listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let dialer = BoundTcpDialer {
+                bind_ip: "127.0.0.1".parse().unwrap(),
+                dest_addr: listener.local_addr().await,
+            };
+            let (mut client, mut server) =
+                futures_util::future::try_join(dialer.dial(), listener.accept())
+                    .await
+                    .unwrap();
+            assert!(server.remote_addr().unwrap().starts_with("127.0.0.1:"));
+            client.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        });
+    }
+
+    #[test]
+    fn ipv6_dial() {
+        futures_lite::future::block_on(async {
+            let mut listener = TcpListener::bind("[::1]:0".parse().unwrap()).await.unwrap();
+            let dest_addr = listener.local_addr().await;
+            assert!(dest_addr.is_ipv6());
+            let (mut client, mut server) =
+                futures_util::future::try_join(TcpDialer { dest_addr }.dial(), listener.accept())
+                    .await
+                    .unwrap();
+            assert!(server.remote_addr().unwrap().starts_with("[::1]:"));
+            client.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        });
+    }
 }
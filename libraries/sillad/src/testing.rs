@@ -0,0 +1,139 @@
+use std::task::Poll;
+
+use futures_util::{AsyncRead, AsyncWrite};
+
+use crate::{dialer::Dialer, Pipe};
+
+/// How a [NullDialer]'s pipes behave.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NullMode {
+    /// Reads never complete. Writes are accepted and discarded.
+    SilentRead,
+    /// Writes never complete. Reads immediately return end-of-stream.
+    SilentWrite,
+    /// Reads immediately return end-of-stream, and writes are accepted and discarded.
+    Connected,
+}
+
+/// NullDialer is a dialer that always succeeds immediately, producing pipes that never carry any data. It is useful for testing read stalls, write stalls, and other flow-control behavior.
+pub struct NullDialer {
+    pub mode: NullMode,
+}
+
+impl Dialer for NullDialer {
+    type P = NullPipe;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        Ok(NullPipe { mode: self.mode })
+    }
+}
+
+/// The pipe produced by a [NullDialer].
+pub struct NullPipe {
+    mode: NullMode,
+}
+
+impl AsyncRead for NullPipe {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        _buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.mode {
+            NullMode::SilentRead => Poll::Pending,
+            NullMode::SilentWrite | NullMode::Connected => Poll::Ready(Ok(0)),
+        }
+    }
+}
+
+impl AsyncWrite for NullPipe {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.mode {
+            NullMode::SilentWrite => Poll::Pending,
+            NullMode::SilentRead | NullMode::Connected => Poll::Ready(Ok(buf.len())),
+        }
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.mode {
+            NullMode::SilentWrite => Poll::Pending,
+            NullMode::SilentRead | NullMode::Connected => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_close(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Pipe for NullPipe {
+    fn protocol(&self) -> &str {
+        "null"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::{AsyncReadExt, AsyncWriteExt};
+    use geph5_rt::TimeoutExt;
+
+    use super::*;
+
+    #[test]
+    fn test_null_modes() {
+        futures_lite::future::block_on(async {
+            let mut buf = [0u8; 16];
+
+            let mut pipe = NullDialer {
+                mode: NullMode::Connected,
+            }
+            .dial()
+            .await
+            .unwrap();
+            assert_eq!(pipe.read(&mut buf).await.unwrap(), 0);
+            pipe.write_all(b"hello").await.unwrap();
+
+            let mut pipe = NullDialer {
+                mode: NullMode::SilentRead,
+            }
+            .dial()
+            .await
+            .unwrap();
+            assert!(pipe
+                .read(&mut buf)
+                .timeout(Duration::from_millis(50))
+                .await
+                .is_none());
+            pipe.write_all(b"hello").await.unwrap();
+
+            let mut pipe = NullDialer {
+                mode: NullMode::SilentWrite,
+            }
+            .dial()
+            .await
+            .unwrap();
+            assert!(pipe
+                .write_all(b"hello")
+                .timeout(Duration::from_millis(50))
+                .await
+                .is_none());
+            assert_eq!(pipe.read(&mut buf).await.unwrap(), 0);
+        })
+    }
+}
@@ -0,0 +1,138 @@
+use std::{
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+};
+
+use async_io::Async;
+
+use futures_lite::{AsyncRead, AsyncWrite};
+use pin_project::pin_project;
+
+use crate::{dialer::Dialer, listener::Listener, Pipe};
+
+/// A UnixListener is a listener for Unix domain sockets, useful for talking to processes on the same machine.
+pub struct UnixListener {
+    inner: Async<std::os::unix::net::UnixListener>,
+    path: PathBuf,
+}
+
+impl UnixListener {
+    /// Creates a new UnixListener by listening at a particular path. Any stale socket file left at the path is removed first.
+    pub async fn bind(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        match std::fs::remove_file(&path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        let inner = Async::<std::os::unix::net::UnixListener>::bind(&path)?;
+        Ok(Self { inner, path })
+    }
+
+    /// Get the local listening path.
+    pub fn local_path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Listener for UnixListener {
+    type P = UnixPipe;
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        let (conn, _) = self
+            .inner
+            .accept()
+            .await
+            .inspect_err(|e| tracing::error!(err = debug(e), "failed to accept"))?;
+        // the peers of a listening socket are almost always unnamed, so we identify them by our own path
+        Ok(UnixPipe(conn, self.path.display().to_string()))
+    }
+}
+
+/// A UnixDialer is a dialer for Unix domain sockets. It is configured by its fields.
+pub struct UnixDialer {
+    pub dest_path: PathBuf,
+}
+
+impl Dialer for UnixDialer {
+    type P = UnixPipe;
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let inner = Async::<UnixStream>::connect(&self.dest_path)
+            .await
+            .inspect_err(|e| tracing::warn!("inner dial failed: {:?}", e))?;
+        Ok(UnixPipe(inner, self.dest_path.display().to_string()))
+    }
+}
+
+#[pin_project]
+pub struct UnixPipe(#[pin] Async<UnixStream>, String);
+
+impl AsyncRead for UnixPipe {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().0.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UnixPipe {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().0.poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().0.poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().0.poll_close(cx)
+    }
+}
+
+impl Pipe for UnixPipe {
+    fn protocol(&self) -> &str {
+        "unix"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        Some(&self.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn unix_roundtrip() {
+        futures_lite::future::block_on(async {
+            let path =
+                std::env::temp_dir().join(format!("sillad-test-{}.sock", std::process::id()));
+            let mut listener = UnixListener::bind(&path).await.unwrap();
+            let dialer = UnixDialer {
+                dest_path: path.clone(),
+            };
+            let (mut client, mut server) =
+                futures_util::future::try_join(dialer.dial(), listener.accept())
+                    .await
+                    .unwrap();
+            client.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+}